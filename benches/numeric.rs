@@ -0,0 +1,48 @@
+//! Times numeric loops, the workload NaN-boxed values are meant to speed up.
+//!
+//! Run with `cargo bench --bench numeric`.
+
+use std::time::{Duration, Instant};
+use zrak::vm::Vm;
+
+const BENCHES: &[(&str, &str)] = &[
+    (
+        "int sum",
+        "let sum = 0; let i = 0; while i < 1000000 { sum += i * 3 - 1; i += 1; }; sum;",
+    ),
+    (
+        "float sum",
+        "let sum = 0.0; let x = 0.5; for i in range(0, 1000000) { sum += x * 1.5 - 0.25; x += 0.125; }; sum;",
+    ),
+    (
+        "fib",
+        "fn fib(n) { if n < 2 { return n; }; fib(n - 1) + fib(n - 2) } fib(25);",
+    ),
+    (
+        "collatz",
+        "let longest = 0;
+        for n in range(1, 30000) {
+            let steps = 0;
+            while n != 1 { if n % 2 == 0 { n = n / 2; } else { n = 3 * n + 1; }; steps += 1; };
+            if steps > longest { longest = steps; };
+        };
+        longest;",
+    ),
+];
+
+const RUNS: u32 = 5;
+
+fn main() {
+    for (name, source) in BENCHES {
+        let mut best = Duration::MAX;
+        let mut result = String::new();
+        for _ in 0..RUNS {
+            let mut vm = Vm::new();
+            let start = Instant::now();
+            let value = vm.eval(source).unwrap();
+            best = best.min(start.elapsed());
+            result = vm.display(value);
+        }
+        println!("{:<10} {:>10.2?}  ({})", name, best, result);
+    }
+}
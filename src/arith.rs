@@ -0,0 +1,905 @@
+//! Semantics of the numeric operators.
+//!
+//! Ints are 64-bit and floats are IEEE doubles. An operator applied to two
+//! ints gives an int, except `**` with a negative exponent, which gives a
+//! float. Int results that don't fit in 64 bits promote to bignums, and
+//! bignums that fit turn back into ints. A float operand promotes the other
+//! operand to a float. Bitwise operators and shifts only take integers.
+//!
+//! Integer division by zero is an error while float division follows IEEE.
+//! Numbers compare by exact value, and NaN is unordered. Whether overflow
+//! promotes, fails or wraps and how `/` and `%` round is chosen by [`Arith`].
+
+use std::cmp::Ordering;
+
+use crate::bigint::{BigInt, MAX_BITS};
+use crate::bytecode::BinOp;
+use crate::error::{Error, Result};
+
+/// What happens when an int result does not fit in 64 bits.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Overflow {
+    /// Promote to a bignum.
+    #[default]
+    Promote,
+    /// Raise an integer overflow error.
+    Checked,
+    /// Wrap around in two's complement, keeping the low 64 bits.
+    Wrapping,
+}
+
+/// How `/` rounds integer quotients, and with it the sign of `%`. Either way
+/// `a == a / b * b + a % b`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Division {
+    /// Round toward zero, so `%` takes the sign of the dividend.
+    #[default]
+    Truncate,
+    /// Round toward negative infinity, so `%` takes the sign of the divisor.
+    Floor,
+}
+
+/// The configurable parts of the numeric semantics.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Arith {
+    pub overflow: Overflow,
+    pub division: Division,
+}
+
+/// A number operand or result. Bignums only hold values outside the range
+/// of ints.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Num {
+    Int(i64),
+    Big(BigInt),
+    Float(f64),
+}
+
+impl Num {
+    /// Turns `big` into an int if it fits.
+    pub fn from_big(big: BigInt) -> Num {
+        match big.to_i64() {
+            Some(i) => Num::Int(i),
+            None => Num::Big(big),
+        }
+    }
+
+    fn to_f64(&self) -> f64 {
+        match self {
+            Num::Int(i) => *i as f64,
+            Num::Big(big) => big.to_f64(),
+            Num::Float(f) => *f,
+        }
+    }
+
+    /// Bits of the magnitude of an int or bignum.
+    fn bits(&self) -> u64 {
+        match self {
+            Num::Int(i) => 64 - i.unsigned_abs().leading_zeros() as u64,
+            Num::Big(big) => big.bits(),
+            Num::Float(_) => 0,
+        }
+    }
+
+    fn to_big(&self) -> BigInt {
+        match self {
+            Num::Int(i) => BigInt::from(*i),
+            Num::Big(big) => big.clone(),
+            Num::Float(_) => unreachable!(),
+        }
+    }
+}
+
+impl Arith {
+    /// Applies an arithmetic, bitwise or shift operator to two numbers.
+    pub fn binary(self, op: BinOp, left: &Num, right: &Num) -> Result<Num> {
+        match (left, right) {
+            (Num::Int(a), Num::Int(b)) => self.int(op, *a, *b),
+            (Num::Float(_), _) | (_, Num::Float(_)) => {
+                self.float(op, left.to_f64(), right.to_f64())
+            }
+            _ => self.big(op, &left.to_big(), &right.to_big()),
+        }
+    }
+
+    /// Applies unary `-` to a number.
+    pub fn negate(self, num: &Num) -> Result<Num> {
+        match num {
+            Num::Int(i) => self.fit(i.checked_neg(), i.wrapping_neg(), || Ok(-&BigInt::from(*i))),
+            Num::Big(big) => self.fit_big(-big),
+            Num::Float(f) => Ok(Num::Float(-f)),
+        }
+    }
+
+    fn int(self, op: BinOp, a: i64, b: i64) -> Result<Num> {
+        let big = BigInt::from;
+        let value = match op {
+            BinOp::Add => {
+                return self.fit(
+                    a.checked_add(b),
+                    a.wrapping_add(b),
+                    || Ok(&big(a) + &big(b)),
+                )
+            }
+            BinOp::Sub => {
+                return self.fit(
+                    a.checked_sub(b),
+                    a.wrapping_sub(b),
+                    || Ok(&big(a) - &big(b)),
+                )
+            }
+            BinOp::Mul => {
+                return self.fit(
+                    a.checked_mul(b),
+                    a.wrapping_mul(b),
+                    || Ok(&big(a) * &big(b)),
+                )
+            }
+            BinOp::Div | BinOp::Mod if b == 0 => return Err(Error::DivisionByZero),
+            // The only quotient that overflows, and it has no remainder.
+            BinOp::Div if a == i64::MIN && b == -1 => return self.fit(None, a, || Ok(-&big(a))),
+            BinOp::Div => {
+                if self.division == Division::Floor && a % b != 0 && (a < 0) != (b < 0) {
+                    a / b - 1
+                } else {
+                    a / b
+                }
+            }
+            BinOp::Mod => {
+                // The remainder always fits, even for `i64::MIN % -1`.
+                let rem = a.wrapping_rem(b);
+                if self.division == Division::Floor && rem != 0 && (rem < 0) != (b < 0) {
+                    rem + b
+                } else {
+                    rem
+                }
+            }
+            BinOp::Pow if b < 0 => return Ok(Num::Float((a as f64).powf(b as f64))),
+            BinOp::Pow => return self.pow(a, b),
+            BinOp::BitAnd => a & b,
+            BinOp::BitOr => a | b,
+            BinOp::BitXor => a ^ b,
+            BinOp::Shl | BinOp::Shr if b < 0 => return Err(Error::NegativeShift(b)),
+            BinOp::Shl if b >= 64 => {
+                return self.fit((a == 0).then_some(0), 0, || shl_big(&big(a), b as u64))
+            }
+            BinOp::Shl => {
+                let shifted = a << b;
+                let checked = (shifted >> b == a).then_some(shifted);
+                return self.fit(checked, shifted, || shl_big(&big(a), b as u64));
+            }
+            BinOp::Shr => a >> b.min(63),
+            _ => return Err(Error::InvalidOperands(symbol(op))),
+        };
+        Ok(Num::Int(value))
+    }
+
+    /// Raises `base` to the non-negative `exp` by repeated squaring.
+    fn pow(self, base: i64, exp: i64) -> Result<Num> {
+        let (mut square, mut rest) = (base, exp);
+        let mut acc = 1i64;
+        let mut overflowed = false;
+        while rest > 0 {
+            if rest & 1 == 1 {
+                let (product, o) = acc.overflowing_mul(square);
+                acc = product;
+                overflowed |= o;
+            }
+            rest >>= 1;
+            // A square that overflows is a factor of the result, so the
+            // result overflows too.
+            if rest > 0 {
+                let (product, o) = square.overflowing_mul(square);
+                square = product;
+                overflowed |= o;
+            }
+        }
+        self.fit((!overflowed).then_some(acc), acc, || {
+            pow_big(&BigInt::from(base), exp as u64)
+        })
+    }
+
+    fn big(self, op: BinOp, a: &BigInt, b: &BigInt) -> Result<Num> {
+        // Shift amounts and exponents too large for an int only leave
+        // results that are too large too, except for a few bases.
+        let amount = b.to_i64().map_or(u64::MAX, |b| b as u64);
+        let result = match op {
+            BinOp::Add => a + b,
+            BinOp::Sub => a - b,
+            BinOp::Mul => mul_big(a, b)?,
+            BinOp::Div | BinOp::Mod if b.is_zero() => return Err(Error::DivisionByZero),
+            BinOp::Div | BinOp::Mod => {
+                let (quot, rem) = match self.division {
+                    Division::Truncate => a.div_rem(b),
+                    Division::Floor => a.div_rem_floor(b),
+                };
+                if op == BinOp::Div {
+                    quot
+                } else {
+                    rem
+                }
+            }
+            BinOp::Pow if b.is_negative() => return Ok(Num::Float(a.to_f64().powf(b.to_f64()))),
+            // 0, 1 and -1 only depend on whether the exponent is odd.
+            BinOp::Pow if a.bits() <= 1 && b.to_i64().is_none() => {
+                let odd = !(b & &BigInt::from(1)).is_zero();
+                pow_big(a, if odd { 1 } else { 2 })?
+            }
+            BinOp::Pow => pow_big(a, amount)?,
+            BinOp::BitAnd => a & b,
+            BinOp::BitOr => a | b,
+            BinOp::BitXor => a ^ b,
+            BinOp::Shl | BinOp::Shr if b.is_negative() => {
+                return Err(Error::NegativeShift(b.to_i64().unwrap_or(i64::MIN)))
+            }
+            BinOp::Shl => shl_big(a, amount)?,
+            BinOp::Shr => a >> amount,
+            _ => return Err(Error::InvalidOperands(symbol(op))),
+        };
+        self.fit_big(result)
+    }
+
+    fn float(self, op: BinOp, a: f64, b: f64) -> Result<Num> {
+        let value = match op {
+            BinOp::Add => a + b,
+            BinOp::Sub => a - b,
+            BinOp::Mul => a * b,
+            BinOp::Div => a / b,
+            BinOp::Mod => {
+                let rem = a % b;
+                if self.division == Division::Floor && rem != 0.0 && (rem < 0.0) != (b < 0.0) {
+                    rem + b
+                } else {
+                    rem
+                }
+            }
+            BinOp::Pow => a.powf(b),
+            _ => return Err(Error::InvalidOperands(symbol(op))),
+        };
+        Ok(Num::Float(value))
+    }
+
+    /// Picks the `checked` result of an int operation or, if it overflowed,
+    /// the `wrapped` or `exact` one.
+    fn fit(
+        self,
+        checked: Option<i64>,
+        wrapped: i64,
+        exact: impl FnOnce() -> Result<BigInt>,
+    ) -> Result<Num> {
+        match (checked, self.overflow) {
+            (Some(i), _) => Ok(Num::Int(i)),
+            (None, Overflow::Promote) => Ok(Num::from_big(exact()?)),
+            (None, Overflow::Wrapping) => Ok(Num::Int(wrapped)),
+            (None, Overflow::Checked) => Err(Error::IntegerOverflow),
+        }
+    }
+
+    /// Applies the overflow mode to a bignum result.
+    fn fit_big(self, big: BigInt) -> Result<Num> {
+        match (Num::from_big(big), self.overflow) {
+            (Num::Big(_), Overflow::Checked) => Err(Error::IntegerOverflow),
+            (Num::Big(big), Overflow::Wrapping) => Ok(Num::Int(big.wrapping_i64())),
+            (num, _) => Ok(num),
+        }
+    }
+}
+
+/// How many bits the result of `*`, `**` or `<<` on two integers can have at
+/// most, or `None` for other operators, whose results grow by a bit at most.
+pub fn result_bits(op: BinOp, left: &Num, right: &Num) -> Option<u64> {
+    if matches!(left, Num::Float(_)) || matches!(right, Num::Float(_)) {
+        return None;
+    }
+    // Negative exponents and shift amounts do not grow the result.
+    let amount = match right {
+        Num::Int(i) => (*i).max(0) as u64,
+        Num::Big(big) if big.is_negative() => 0,
+        _ => u64::MAX,
+    };
+    match op {
+        BinOp::Mul => Some(left.bits() + right.bits()),
+        // 0, 1 and -1 only take one bit whatever the exponent.
+        BinOp::Pow if left.bits() <= 1 => Some(1),
+        BinOp::Pow => Some(left.bits().saturating_mul(amount)),
+        BinOp::Shl => Some(left.bits().saturating_add(amount)),
+        _ => None,
+    }
+}
+
+/// `a * b`, refusing results of more than [`MAX_BITS`].
+fn mul_big(a: &BigInt, b: &BigInt) -> Result<BigInt> {
+    if a.bits() + b.bits() > MAX_BITS {
+        return Err(Error::IntegerOverflow);
+    }
+    Ok(a * b)
+}
+
+/// `base ** exp`, refusing results of more than [`MAX_BITS`].
+fn pow_big(base: &BigInt, exp: u64) -> Result<BigInt> {
+    if base.bits() > 1 && base.bits().saturating_mul(exp) > MAX_BITS {
+        return Err(Error::IntegerOverflow);
+    }
+    Ok(base.pow(exp))
+}
+
+/// `value << bits`, refusing results of more than [`MAX_BITS`].
+fn shl_big(value: &BigInt, bits: u64) -> Result<BigInt> {
+    if value.is_zero() {
+        Ok(BigInt::zero())
+    } else if value.bits().saturating_add(bits) > MAX_BITS {
+        Err(Error::IntegerOverflow)
+    } else {
+        Ok(value << bits)
+    }
+}
+
+/// Orders two numbers by exact value. Returns `None` if either is NaN.
+pub fn compare(left: &Num, right: &Num) -> Option<Ordering> {
+    match (left, right) {
+        (Num::Int(a), Num::Int(b)) => Some(a.cmp(b)),
+        (Num::Float(a), Num::Float(b)) => a.partial_cmp(b),
+        (Num::Int(a), Num::Float(b)) => compare_int_float(*a, *b),
+        (Num::Float(a), Num::Int(b)) => compare_int_float(*b, *a).map(Ordering::reverse),
+        (Num::Big(a), Num::Float(b)) => a.cmp_f64(*b),
+        (Num::Float(a), Num::Big(b)) => b.cmp_f64(*a).map(Ordering::reverse),
+        _ => Some(left.to_big().cmp(&right.to_big())),
+    }
+}
+
+/// Compares without converting `a` to a float, which would round ints
+/// beyond 2^53.
+fn compare_int_float(a: i64, b: f64) -> Option<Ordering> {
+    // 2^63, the first float above the int range.
+    const LIMIT: f64 = 9_223_372_036_854_775_808.0;
+    if b.is_nan() {
+        None
+    } else if b >= LIMIT {
+        Some(Ordering::Less)
+    } else if b < -LIMIT {
+        Some(Ordering::Greater)
+    } else {
+        let whole = b.trunc();
+        Some(a.cmp(&(whole as i64)).then(whole.partial_cmp(&b)?))
+    }
+}
+
+pub fn symbol(op: BinOp) -> &'static str {
+    match op {
+        BinOp::Add => "+",
+        BinOp::Sub => "-",
+        BinOp::Mul => "*",
+        BinOp::Div => "/",
+        BinOp::Mod => "%",
+        BinOp::Pow => "**",
+        BinOp::BitAnd => "&",
+        BinOp::BitOr => "|",
+        BinOp::BitXor => "^",
+        BinOp::Shl => "<<",
+        BinOp::Shr => ">>",
+        BinOp::Eq => "==",
+        BinOp::NotEq => "!=",
+        BinOp::Less => "<",
+        BinOp::Greater => ">",
+        BinOp::LessEq => "<=",
+        BinOp::GreaterEq => ">=",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX: i64 = i64::MAX;
+    const MIN: i64 = i64::MIN;
+
+    enum Expect {
+        Int(i64),
+        Big(&'static str),
+        Float(f64),
+        Err(Error),
+    }
+
+    use Expect::{Big as B, Err as E, Float as F, Int as I};
+
+    const PROMOTE: Arith = Arith {
+        overflow: Overflow::Promote,
+        division: Division::Truncate,
+    };
+    const CHECKED: Arith = Arith {
+        overflow: Overflow::Checked,
+        division: Division::Truncate,
+    };
+    const WRAPPING: Arith = Arith {
+        overflow: Overflow::Wrapping,
+        division: Division::Truncate,
+    };
+    const FLOOR: Arith = Arith {
+        overflow: Overflow::Checked,
+        division: Division::Floor,
+    };
+    const PROMOTE_FLOOR: Arith = Arith {
+        overflow: Overflow::Promote,
+        division: Division::Floor,
+    };
+
+    fn big(s: &str) -> Num {
+        Num::from_big(BigInt::from_decimal(s).unwrap())
+    }
+
+    fn check(arith: Arith, op: BinOp, left: Num, right: Num, expect: Expect) {
+        let got = arith.binary(op, &left, &right);
+        let ok = match (&got, &expect) {
+            (Ok(Num::Int(a)), I(b)) => a == b,
+            (Ok(Num::Big(a)), B(b)) => a.to_string() == *b,
+            (Ok(Num::Float(a)), F(b)) => a == b || a.is_nan() && b.is_nan(),
+            (Err(a), E(b)) => format!("{:?}", a) == format!("{:?}", b),
+            _ => false,
+        };
+        assert!(
+            ok,
+            "{:?} {} {:?} with {:?} gave {:?}",
+            left,
+            symbol(op),
+            right,
+            arith,
+            got,
+        );
+    }
+
+    #[test]
+    fn test_int_table() {
+        use BinOp::*;
+        let table = [
+            // Addition, subtraction and multiplication.
+            (CHECKED, Add, 2, 3, I(5)),
+            (CHECKED, Add, MAX, 1, E(Error::IntegerOverflow)),
+            (WRAPPING, Add, MAX, 1, I(MIN)),
+            (CHECKED, Add, MIN, -1, E(Error::IntegerOverflow)),
+            (CHECKED, Sub, 2, 3, I(-1)),
+            (CHECKED, Sub, MIN, 1, E(Error::IntegerOverflow)),
+            (WRAPPING, Sub, MIN, 1, I(MAX)),
+            (CHECKED, Sub, 0, MIN, E(Error::IntegerOverflow)),
+            (CHECKED, Mul, -4, 5, I(-20)),
+            (CHECKED, Mul, MAX, 2, E(Error::IntegerOverflow)),
+            (WRAPPING, Mul, MAX, 2, I(-2)),
+            (CHECKED, Mul, MIN, -1, E(Error::IntegerOverflow)),
+            (WRAPPING, Mul, MIN, -1, I(MIN)),
+            // Truncating division.
+            (CHECKED, Div, 7, 2, I(3)),
+            (CHECKED, Div, -7, 2, I(-3)),
+            (CHECKED, Div, 7, -2, I(-3)),
+            (CHECKED, Div, -7, -2, I(3)),
+            (CHECKED, Div, 6, -2, I(-3)),
+            (CHECKED, Mod, 7, 2, I(1)),
+            (CHECKED, Mod, -7, 2, I(-1)),
+            (CHECKED, Mod, 7, -2, I(1)),
+            (CHECKED, Mod, -7, -2, I(-1)),
+            (CHECKED, Mod, 6, -2, I(0)),
+            // Floor division.
+            (FLOOR, Div, 7, 2, I(3)),
+            (FLOOR, Div, -7, 2, I(-4)),
+            (FLOOR, Div, 7, -2, I(-4)),
+            (FLOOR, Div, -7, -2, I(3)),
+            (FLOOR, Div, 6, -2, I(-3)),
+            (FLOOR, Mod, 7, 2, I(1)),
+            (FLOOR, Mod, -7, 2, I(1)),
+            (FLOOR, Mod, 7, -2, I(-1)),
+            (FLOOR, Mod, -7, -2, I(-1)),
+            (FLOOR, Mod, 6, -2, I(0)),
+            // Division edge cases.
+            (CHECKED, Div, 1, 0, E(Error::DivisionByZero)),
+            (WRAPPING, Div, 1, 0, E(Error::DivisionByZero)),
+            (FLOOR, Mod, 1, 0, E(Error::DivisionByZero)),
+            (CHECKED, Div, MIN, -1, E(Error::IntegerOverflow)),
+            (FLOOR, Div, MIN, -1, E(Error::IntegerOverflow)),
+            (WRAPPING, Div, MIN, -1, I(MIN)),
+            (CHECKED, Mod, MIN, -1, I(0)),
+            (FLOOR, Mod, MIN, -1, I(0)),
+            (FLOOR, Div, MIN, 2, I(MIN / 2)),
+            (FLOOR, Mod, MIN, MAX, I(MAX - 1)),
+            // Exponentiation.
+            (CHECKED, Pow, 2, 10, I(1024)),
+            (CHECKED, Pow, -3, 3, I(-27)),
+            (CHECKED, Pow, 0, 0, I(1)),
+            (CHECKED, Pow, 5, 0, I(1)),
+            (CHECKED, Pow, -1, MAX, I(-1)),
+            (CHECKED, Pow, 1, MAX, I(1)),
+            (CHECKED, Pow, 0, MAX, I(0)),
+            (CHECKED, Pow, 2, 62, I(1 << 62)),
+            (CHECKED, Pow, 2, 63, E(Error::IntegerOverflow)),
+            (CHECKED, Pow, -2, 63, I(MIN)),
+            (CHECKED, Pow, -2, 64, E(Error::IntegerOverflow)),
+            (CHECKED, Pow, 3, 39, I(4_052_555_153_018_976_267)),
+            (CHECKED, Pow, 3, 40, E(Error::IntegerOverflow)),
+            (CHECKED, Pow, 10, 100, E(Error::IntegerOverflow)),
+            (WRAPPING, Pow, 2, 64, I(0)),
+            (WRAPPING, Pow, 3, 40, I(3i64.wrapping_pow(40))),
+            (CHECKED, Pow, 2, -1, F(0.5)),
+            (CHECKED, Pow, -2, -2, F(0.25)),
+            (CHECKED, Pow, 0, -1, F(f64::INFINITY)),
+            // Bitwise operators.
+            (CHECKED, BitAnd, 0b1100, 0b1010, I(0b1000)),
+            (CHECKED, BitOr, 0b1100, 0b1010, I(0b1110)),
+            (CHECKED, BitXor, 0b1100, 0b1010, I(0b0110)),
+            (CHECKED, BitAnd, -1, MIN, I(MIN)),
+            // Shifts.
+            (CHECKED, Shl, 1, 4, I(16)),
+            (CHECKED, Shl, -1, 63, I(MIN)),
+            (CHECKED, Shl, 1, 63, E(Error::IntegerOverflow)),
+            (WRAPPING, Shl, 1, 63, I(MIN)),
+            (CHECKED, Shl, 3, 62, E(Error::IntegerOverflow)),
+            (WRAPPING, Shl, 3, 62, I(MIN | 1 << 62)),
+            (CHECKED, Shl, 0, 64, I(0)),
+            (CHECKED, Shl, 1, 64, E(Error::IntegerOverflow)),
+            (WRAPPING, Shl, 1, 64, I(0)),
+            (WRAPPING, Shl, 1, 1000, I(0)),
+            (CHECKED, Shr, 16, 4, I(1)),
+            (CHECKED, Shr, -16, 4, I(-1)),
+            (CHECKED, Shr, -17, 4, I(-2)),
+            (CHECKED, Shr, MAX, 64, I(0)),
+            (CHECKED, Shr, MIN, 64, I(-1)),
+            (CHECKED, Shr, 5, MAX, I(0)),
+            (CHECKED, Shl, 1, -1, E(Error::NegativeShift(-1))),
+            (WRAPPING, Shr, 1, -1, E(Error::NegativeShift(-1))),
+            // Promotion to bignums.
+            (PROMOTE, Add, MAX, 1, B("9223372036854775808")),
+            (PROMOTE, Sub, MIN, 1, B("-9223372036854775809")),
+            (
+                PROMOTE,
+                Mul,
+                MAX,
+                MAX,
+                B("85070591730234615847396907784232501249"),
+            ),
+            (PROMOTE, Mul, MIN, -1, B("9223372036854775808")),
+            (PROMOTE, Div, MIN, -1, B("9223372036854775808")),
+            (PROMOTE_FLOOR, Div, MIN, -1, B("9223372036854775808")),
+            (PROMOTE, Mod, MIN, -1, I(0)),
+            (PROMOTE, Pow, 2, 64, B("18446744073709551616")),
+            (PROMOTE, Pow, -3, 41, B("-36472996377170786403")),
+            (PROMOTE, Pow, 2, 62, I(1 << 62)),
+            (PROMOTE, Pow, 3, MAX, E(Error::IntegerOverflow)),
+            (PROMOTE, Shl, 1, 64, B("18446744073709551616")),
+            (PROMOTE, Shl, -1, 64, B("-18446744073709551616")),
+            (PROMOTE, Shl, 3, 62, B("13835058055282163712")),
+            (PROMOTE, Shl, 0, MAX, I(0)),
+            (PROMOTE, Shl, 1, MAX, E(Error::IntegerOverflow)),
+            (PROMOTE, Add, 2, 3, I(5)),
+        ];
+        for (arith, op, a, b, expect) in table {
+            check(arith, op, Num::Int(a), Num::Int(b), expect);
+        }
+    }
+
+    #[test]
+    fn test_big_table() {
+        use BinOp::*;
+        let two_64 = "18446744073709551616";
+        let table = [
+            (
+                PROMOTE,
+                Add,
+                big(two_64),
+                Num::Int(1),
+                B("18446744073709551617"),
+            ),
+            (PROMOTE, Sub, big(two_64), big(two_64), I(0)),
+            (
+                PROMOTE,
+                Sub,
+                Num::Int(MIN),
+                big("9223372036854775808"),
+                B("-18446744073709551616"),
+            ),
+            (
+                PROMOTE,
+                Add,
+                big("-9223372036854775809"),
+                Num::Int(1),
+                I(MIN),
+            ),
+            (
+                PROMOTE,
+                Mul,
+                big(two_64),
+                Num::Int(-2),
+                B("-36893488147419103232"),
+            ),
+            (
+                PROMOTE,
+                Div,
+                big(two_64),
+                Num::Int(3),
+                I(6148914691236517205),
+            ),
+            (
+                PROMOTE,
+                Div,
+                big(two_64),
+                Num::Int(-3),
+                I(-6148914691236517205),
+            ),
+            (
+                PROMOTE_FLOOR,
+                Div,
+                big(two_64),
+                Num::Int(-3),
+                I(-6148914691236517206),
+            ),
+            (PROMOTE, Mod, big(two_64), Num::Int(-3), I(1)),
+            (PROMOTE_FLOOR, Mod, big(two_64), Num::Int(-3), I(-2)),
+            (PROMOTE, Mod, Num::Int(7), big(two_64), I(7)),
+            (
+                PROMOTE_FLOOR,
+                Mod,
+                Num::Int(-7),
+                big(two_64),
+                B("18446744073709551609"),
+            ),
+            (
+                PROMOTE,
+                Div,
+                big(two_64),
+                Num::Int(0),
+                E(Error::DivisionByZero),
+            ),
+            (PROMOTE, Div, Num::Int(1), big(two_64), I(0)),
+            (
+                PROMOTE,
+                Pow,
+                big(two_64),
+                Num::Int(2),
+                B("340282366920938463463374607431768211456"),
+            ),
+            (PROMOTE, Pow, big(two_64), Num::Int(0), I(1)),
+            (
+                PROMOTE,
+                Pow,
+                big(two_64),
+                Num::Int(-1),
+                F(1.0 / 18446744073709551616.0),
+            ),
+            (
+                PROMOTE,
+                Pow,
+                big(two_64),
+                Num::Int(MAX),
+                E(Error::IntegerOverflow),
+            ),
+            (PROMOTE, Pow, Num::Int(-1), big(two_64), I(1)),
+            (
+                PROMOTE,
+                Pow,
+                Num::Int(-1),
+                big("18446744073709551617"),
+                I(-1),
+            ),
+            (PROMOTE, Pow, Num::Int(0), big(two_64), I(0)),
+            (
+                PROMOTE,
+                Pow,
+                Num::Int(2),
+                big(two_64),
+                E(Error::IntegerOverflow),
+            ),
+            (PROMOTE, BitAnd, big(two_64), Num::Int(-1), B(two_64)),
+            (
+                PROMOTE,
+                BitAnd,
+                big("-18446744073709551617"),
+                Num::Int(-1),
+                B("-18446744073709551617"),
+            ),
+            (
+                PROMOTE,
+                BitOr,
+                big(two_64),
+                Num::Int(1),
+                B("18446744073709551617"),
+            ),
+            (PROMOTE, BitXor, big(two_64), big(two_64), I(0)),
+            (
+                PROMOTE,
+                BitXor,
+                big(two_64),
+                Num::Int(-1),
+                B("-18446744073709551617"),
+            ),
+            (
+                PROMOTE,
+                Shl,
+                big(two_64),
+                Num::Int(1),
+                B("36893488147419103232"),
+            ),
+            (
+                PROMOTE,
+                Shr,
+                big(two_64),
+                Num::Int(1),
+                B("9223372036854775808"),
+            ),
+            (
+                PROMOTE,
+                Shr,
+                big("-18446744073709551617"),
+                Num::Int(64),
+                I(-2),
+            ),
+            (PROMOTE, Shr, big(two_64), big(two_64), I(0)),
+            (
+                PROMOTE,
+                Shl,
+                big(two_64),
+                big(two_64),
+                E(Error::IntegerOverflow),
+            ),
+            (PROMOTE, Shl, Num::Int(0), big(two_64), I(0)),
+            (
+                PROMOTE,
+                Shl,
+                big(two_64),
+                Num::Int(-1),
+                E(Error::NegativeShift(-1)),
+            ),
+            // The other overflow modes apply to bignum results too.
+            (
+                CHECKED,
+                Add,
+                big(two_64),
+                Num::Int(1),
+                E(Error::IntegerOverflow),
+            ),
+            (
+                CHECKED,
+                Sub,
+                big(two_64),
+                Num::Int(1),
+                E(Error::IntegerOverflow),
+            ),
+            (CHECKED, Sub, big(two_64), big("18446744073709551615"), I(1)),
+            (CHECKED, Mod, big(two_64), Num::Int(10), I(6)),
+            (WRAPPING, Add, big(two_64), Num::Int(5), I(5)),
+            (
+                WRAPPING,
+                Sub,
+                Num::Int(0),
+                big("9223372036854775809"),
+                I(MAX),
+            ),
+            // Mixed with floats.
+            (
+                PROMOTE,
+                Add,
+                big(two_64),
+                Num::Float(0.5),
+                F(18446744073709551616.0),
+            ),
+            (
+                PROMOTE,
+                Div,
+                Num::Float(1.0),
+                big(two_64),
+                F(1.0 / 18446744073709551616.0),
+            ),
+            (
+                PROMOTE,
+                BitAnd,
+                big(two_64),
+                Num::Float(1.0),
+                E(Error::InvalidOperands("&")),
+            ),
+        ];
+        for (arith, op, a, b, expect) in table {
+            check(arith, op, a, b, expect);
+        }
+    }
+
+    #[test]
+    fn test_float_table() {
+        use BinOp::*;
+        let inf = f64::INFINITY;
+        let nan = f64::NAN;
+        let (int, float) = (Num::Int, Num::Float);
+        let table = [
+            // Promotion of ints.
+            (CHECKED, Add, int(1), float(0.5), F(1.5)),
+            (CHECKED, Sub, float(0.5), int(1), F(-0.5)),
+            (CHECKED, Mul, int(MAX), float(2.0), F(MAX as f64 * 2.0)),
+            (CHECKED, Div, int(7), float(2.0), F(3.5)),
+            (CHECKED, Pow, int(2), float(0.5), F(2f64.sqrt())),
+            (CHECKED, Pow, float(2.0), int(3), F(8.0)),
+            (WRAPPING, Add, float(1e308), float(1e308), F(inf)),
+            // Division.
+            (CHECKED, Div, float(7.0), float(2.0), F(3.5)),
+            (FLOOR, Div, float(-7.0), float(2.0), F(-3.5)),
+            (CHECKED, Div, float(1.0), int(0), F(inf)),
+            (CHECKED, Div, float(-1.0), float(0.0), F(-inf)),
+            (CHECKED, Div, float(0.0), float(0.0), F(nan)),
+            (CHECKED, Mod, float(-7.5), float(2.0), F(-1.5)),
+            (FLOOR, Mod, float(-7.5), float(2.0), F(0.5)),
+            (CHECKED, Mod, float(7.5), float(-2.0), F(1.5)),
+            (FLOOR, Mod, float(7.5), float(-2.0), F(-0.5)),
+            (FLOOR, Mod, float(-4.0), float(2.0), F(0.0)),
+            (CHECKED, Mod, float(1.0), float(0.0), F(nan)),
+            (CHECKED, Pow, float(nan), int(0), F(1.0)),
+            // Operators that only take integers.
+            (
+                CHECKED,
+                BitAnd,
+                float(1.0),
+                int(1),
+                E(Error::InvalidOperands("&")),
+            ),
+            (
+                CHECKED,
+                Shl,
+                int(1),
+                float(1.0),
+                E(Error::InvalidOperands("<<")),
+            ),
+        ];
+        for (arith, op, a, b, expect) in table {
+            check(arith, op, a, b, expect);
+        }
+    }
+
+    #[test]
+    fn test_negate() {
+        assert_eq!(Num::Int(-5), CHECKED.negate(&Num::Int(5)).unwrap());
+        assert!(matches!(
+            CHECKED.negate(&Num::Int(MIN)),
+            Err(Error::IntegerOverflow)
+        ));
+        assert_eq!(Num::Int(MIN), WRAPPING.negate(&Num::Int(MIN)).unwrap());
+        assert_eq!(
+            big("9223372036854775808"),
+            PROMOTE.negate(&Num::Int(MIN)).unwrap()
+        );
+        assert_eq!(
+            Num::Int(MIN),
+            PROMOTE.negate(&big("9223372036854775808")).unwrap()
+        );
+        assert_eq!(Num::Float(-0.5), CHECKED.negate(&Num::Float(0.5)).unwrap());
+    }
+
+    #[test]
+    fn test_compare() {
+        use Ordering::*;
+        let exact = 1i64 << 53;
+        let (int, float) = (Num::Int, Num::Float);
+        let table = [
+            (int(1), int(2), Some(Less)),
+            (int(2), float(1.5), Some(Greater)),
+            (int(1), float(1.0), Some(Equal)),
+            (int(-2), float(-1.5), Some(Less)),
+            (int(-1), float(-1.5), Some(Greater)),
+            (float(0.5), int(1), Some(Less)),
+            // Integers that floats can't represent exactly.
+            (int(exact + 1), float(exact as f64), Some(Greater)),
+            (float(exact as f64), int(exact + 1), Some(Less)),
+            (int(MAX), float(MAX as f64), Some(Less)),
+            (int(MIN), float(MIN as f64), Some(Equal)),
+            (int(MIN), float(-1e300), Some(Greater)),
+            (int(MAX), float(f64::INFINITY), Some(Less)),
+            (int(MIN), float(f64::NEG_INFINITY), Some(Greater)),
+            (float(-0.0), int(0), Some(Equal)),
+            (int(0), float(f64::NAN), None),
+            (float(f64::NAN), float(f64::NAN), None),
+            (big("9223372036854775808"), int(MAX), Some(Greater)),
+            (int(MIN), big("-9223372036854775809"), Some(Greater)),
+            (big("9223372036854775808"), float(MAX as f64), Some(Equal)),
+            (float(1e19), big("10000000000000000001"), Some(Less)),
+            (
+                big("-10000000000000000001"),
+                big("-10000000000000000000"),
+                Some(Less),
+            ),
+            (big("10000000000000000000"), float(f64::NAN), None),
+        ];
+        for (a, b, expect) in table {
+            assert_eq!(compare(&a, &b), expect, "{:?} <=> {:?}", a, b);
+        }
+    }
+}
@@ -1,268 +1,462 @@
 use crate::str_interner::IntStr;
-use crate::token::{Assign, CmpOp, FactorOp, ShiftOp, TermOp};
+use crate::token::{Assign, CmpOp, FactorOp, RangeOp, ShiftOp, Span, TermOp};
 use std::collections::HashMap;
 
-#[derive(Debug)]
+/// Spans stored inside a declaration are relative to the start of the
+/// top-level declaration containing it, so a declaration stays the same when
+/// text before it is edited.
+#[derive(Debug, PartialEq)]
 pub struct Program {
     pub decls: Vec<Decl>,
+    /// Byte range of each declaration in `decls`.
+    pub spans: Vec<Span>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Decl {
     Use(UseDecl),
     Mod(ModDecl),
     Struct(StructDecl),
+    Enum(EnumDecl),
+    Trait(TraitDecl),
+    Impl(ImplDecl),
     Fun(FunDecl),
     Var(VarDecl),
     Stmt(Stmt),
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct UseDecl {
     pub path: Vec<IntStr>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct ModDecl {
     pub ident: IntStr,
     pub decls: Vec<Decl>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct StructDecl {
     pub ident: IntStr,
     pub methods: HashMap<IntStr, FunDecl>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
+pub struct EnumDecl {
+    pub ident: IntStr,
+    pub variants: Vec<Variant>,
+    pub methods: HashMap<IntStr, FunDecl>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Variant {
+    pub ident: IntStr,
+    pub fields: VariantFields,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum VariantFields {
+    Unit,
+    Tuple(Vec<IntStr>),
+    Struct(Vec<IntStr>),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct TraitDecl {
+    pub ident: IntStr,
+    /// Methods without a default implementation.
+    pub required: Vec<IntStr>,
+    pub methods: HashMap<IntStr, FunDecl>,
+}
+
+/// Implementation of the trait `trait_ident` for the struct or enum `ident`.
+#[derive(Debug, PartialEq)]
+pub struct ImplDecl {
+    pub trait_ident: IntStr,
+    pub ident: IntStr,
+    pub methods: HashMap<IntStr, FunDecl>,
+}
+
+#[derive(Debug, PartialEq)]
 pub struct FunDecl {
     pub ident: IntStr,
-    pub params: Vec<IntStr>,
+    pub params: Vec<Param>,
+    pub rest: Option<IntStr>,
     pub block: Block,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
+pub struct Param {
+    pub ident: IntStr,
+    pub default: Option<Expr>,
+}
+
+#[derive(Debug, PartialEq)]
 pub struct VarDecl {
     pub ident: IntStr,
     pub expr: Expr,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Stmt {
     Return(Option<Expr>),
     Break(Option<Expr>),
+    Throw(Expr),
     Assignment(Assignment),
     Expr(Expr),
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Assignment {
     pub lcall: LCall,
     pub assigner: Assign,
     pub expr: Expr,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct LCall {
     pub head: LCallHead,
     pub tail: Vec<LCallPart>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum LCallHead {
     Ident(IntStr),
     SelfKw,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum LCallPart {
     Dot(IntStr),
     Brkts(Box<Expr>),
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Expr {
-    pub logic_or: LogicOr,
-}
-
-#[derive(Debug)]
+    pub pipe: Pipe,
+    pub span: Span,
+}
+
+/// `x |> f(a)` calls `f(x, a)`, and `x |> f` calls `f(x)` if `f` is not a
+/// call.
+///
+/// The stages of a chain are kept in a list, as are the operands of a chain of
+/// the binary operators below, so that long chains do not nest.
+#[derive(Debug, PartialEq)]
+pub enum Pipe {
+    Next(Coalesce),
+    Current(Box<Coalesce>, Vec<Coalesce>),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Coalesce {
+    Next(LogicOr),
+    Current(Box<LogicOr>, Vec<LogicOr>),
+}
+
+impl Coalesce {
+    /// The call the expression consists of, if it has no operators.
+    pub fn as_call(&self) -> Option<&Call> {
+        match self {
+            Coalesce::Next(LogicOr::Next(LogicAnd::Next(Cmp::Next(Range::Next(BitOr::Next(
+                BitXor::Next(BitAnd::Next(Shift::Next(Term::Next(Factor::Next(Unary::Next(
+                    Power::Next(call),
+                )))))),
+            )))))) => Some(call),
+            _ => None,
+        }
+    }
+
+    pub fn as_call_mut(&mut self) -> Option<&mut Call> {
+        match self {
+            Coalesce::Next(LogicOr::Next(LogicAnd::Next(Cmp::Next(Range::Next(BitOr::Next(
+                BitXor::Next(BitAnd::Next(Shift::Next(Term::Next(Factor::Next(Unary::Next(
+                    Power::Next(call),
+                )))))),
+            )))))) => Some(call),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
 pub enum LogicOr {
     Next(LogicAnd),
-    Current(LogicAnd, Box<LogicOr>),
+    Current(Box<LogicAnd>, Vec<LogicAnd>),
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum LogicAnd {
     Next(Cmp),
-    Current(Cmp, Box<LogicAnd>),
+    Current(Box<Cmp>, Vec<Cmp>),
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Cmp {
+    Next(Range),
+    Current {
+        left: Box<Range>,
+        rest: Vec<(CmpOp, Range)>,
+    },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Range {
     Next(BitOr),
+    /// Either end may be left open, as in `..n` or `n..`.
     Current {
-        left: BitOr,
-        op: CmpOp,
-        cmp: Box<Cmp>,
+        start: Option<Box<BitOr>>,
+        op: RangeOp,
+        end: Option<Box<BitOr>>,
     },
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum BitOr {
     Next(BitXor),
-    Current(BitXor, Box<BitOr>),
+    Current(Box<BitXor>, Vec<BitXor>),
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum BitXor {
     Next(BitAnd),
-    Current(BitAnd, Box<BitXor>),
+    Current(Box<BitAnd>, Vec<BitAnd>),
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum BitAnd {
     Next(Shift),
-    Current(Shift, Box<BitAnd>),
+    Current(Box<Shift>, Vec<Shift>),
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Shift {
     Next(Term),
     Current {
-        left: Term,
-        op: ShiftOp,
-        shift: Box<Shift>,
+        left: Box<Term>,
+        rest: Vec<(ShiftOp, Term)>,
     },
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Term {
     Next(Factor),
     Current {
-        left: Factor,
-        op: TermOp,
-        term: Box<Term>,
+        left: Box<Factor>,
+        rest: Vec<(TermOp, Factor)>,
     },
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Factor {
     Next(Unary),
     Current {
-        left: Unary,
-        op: FactorOp,
-        factor: Box<Factor>,
+        left: Box<Unary>,
+        rest: Vec<(FactorOp, Unary)>,
     },
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Unary {
-    Next(Call),
+    Next(Power),
     Current { op: UnaryOp, unary: Box<Unary> },
 }
 
-#[derive(Debug)]
+/// `**`, which binds tighter than unary operators on its left and is right
+/// associative, so `-2 ** 2` is `-(2 ** 2)`.
+#[derive(Debug, PartialEq)]
+pub enum Power {
+    Next(Call),
+    Current { base: Box<Call>, exp: Box<Unary> },
+}
+
+#[derive(Debug, PartialEq)]
 pub enum UnaryOp {
     Negate,
     Not,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Call {
     pub head: Primary,
     pub tail: Vec<CallPart>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum CallPart {
     Dot(IntStr),
+    Path(IntStr),
     Brkts(Box<Expr>),
-    FunCall(Vec<Expr>),
+    FunCall(FunCall),
     QMark,
+    /// `?.`, ending the chain with null if the value so far is null. It is
+    /// followed by the `Dot`, `Brkts` or `FunCall` it applies to.
+    NullSafe,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
+pub struct FunCall {
+    pub args: Vec<Arg>,
+    pub span: Span,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Arg {
+    Positional(Expr),
+    Named {
+        ident: IntStr,
+        span: Span,
+        expr: Expr,
+    },
+    Spread(Expr),
+}
+
+#[derive(Debug, PartialEq)]
 pub enum Primary {
     SelfKw,
     Prnth(Box<Expr>),
     Ident(IntStr),
-    For(For),
-    While(While),
+    For(Box<For>),
+    While(Box<While>),
     Loop(Loop),
-    If(If),
-    Closure(Closure),
+    If(Box<If>),
+    Try(Box<Try>),
+    /// Suspends the enclosing function, making it a generator.
+    Yield(Box<Expr>),
+    Closure(Box<Closure>),
     Block(Block),
-    Literal(Literal),
+    Literal(Box<Literal>),
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct For {
     pub ident: IntStr,
     pub expr: Box<Expr>,
     pub block: Block,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct While {
     pub cond: Box<Expr>,
     pub block: Block,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Loop {
     pub block: Block,
 }
 
-#[derive(Debug)]
+/// At least one of `catch` and `finally` is set.
+#[derive(Debug, PartialEq)]
+pub struct Try {
+    pub block: Block,
+    pub catch: Option<Catch>,
+    pub finally: Option<Block>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Catch {
+    pub ident: IntStr,
+    pub block: Block,
+}
+
+/// The branches of `if a {} else if b {} else {}` in order, kept in a list
+/// so that long chains do not nest.
+#[derive(Debug, PartialEq)]
 pub struct If {
+    pub branches: Vec<IfBranch>,
+    pub els: Option<Block>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct IfBranch {
+    /// For `if let`, `cond` is the value matched against the pattern.
+    pub pattern: Option<Pattern>,
     pub cond: Box<Expr>,
     pub block: Block,
-    pub els: Option<Else>,
 }
 
-#[derive(Debug)]
-pub enum Else {
-    If(Box<If>),
-    Block(Block),
+/// Matches a value of the enum `ident` holding `variant`, binding its fields
+/// by position or by name.
+#[derive(Debug, PartialEq)]
+pub struct Pattern {
+    pub ident: IntStr,
+    pub variant: IntStr,
+    pub fields: VariantFields,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Closure {
     pub params: Vec<IntStr>,
-    pub block: Block,
+    pub body: Box<Expr>,
+    /// Bindings of enclosing functions used by the closure, filled in by the
+    /// resolver.
+    pub captures: Vec<Capture>,
+    pub captures_self: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
+pub struct Capture {
+    pub ident: IntStr,
+    pub mode: CaptureMode,
+}
+
+/// Bindings that are assigned to after their declaration are captured by
+/// reference so every closure and the enclosing function see the same value.
+/// All others are copied into the closure.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CaptureMode {
+    ByRef,
+    ByValue,
+}
+
+#[derive(Debug, PartialEq)]
 pub struct Block {
     pub decls: Vec<Decl>,
     pub expr: Option<Box<Expr>>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Literal {
     Bool(bool),
     Null,
     Int(i64),
+    BigInt(IntStr),
     Float(f64),
     Char(char),
     Str(IntStr),
     Struct(StructLit),
     Map(MapLit),
+    Set(SetLit),
     Array(ArrayLit),
+    Tuple(TupleLit),
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct StructLit {
     pub ident: IntStr,
+    /// Set when constructing a struct-like enum variant.
+    pub variant: Option<IntStr>,
     pub fields: Vec<(IntStr, Expr)>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct MapLit {
     pub fields: Vec<(Expr, Expr)>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
+pub struct SetLit {
+    pub elems: Vec<Expr>,
+}
+
+#[derive(Debug, PartialEq)]
 pub struct ArrayLit {
     pub elems: Vec<Expr>,
 }
+
+#[derive(Debug, PartialEq)]
+pub struct TupleLit {
+    pub elems: Vec<Expr>,
+}
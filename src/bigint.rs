@@ -0,0 +1,742 @@
+//! Arbitrary-precision integers, which ints promote to when a result does not
+//! fit in 64 bits.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::{Add, BitAnd, BitOr, BitXor, Mul, Neg, Shl, Shr, Sub};
+
+/// Results that would have more bits than this are refused by the operators
+/// that can grow a number quickly, `*`, `**` and `<<`.
+pub const MAX_BITS: u64 = 1 << 20;
+
+/// Operands with fewer limbs than this are multiplied the schoolbook way.
+const KARATSUBA_LIMBS: usize = 32;
+
+/// An integer as sign and magnitude. The magnitude is in base 2^32, least
+/// significant limb first, without leading zero limbs. Zero is never
+/// negative.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BigInt {
+    negative: bool,
+    mag: Vec<u32>,
+}
+
+impl BigInt {
+    fn new(negative: bool, mut mag: Vec<u32>) -> BigInt {
+        while mag.last() == Some(&0) {
+            mag.pop();
+        }
+        BigInt {
+            negative: negative && !mag.is_empty(),
+            mag,
+        }
+    }
+
+    pub fn zero() -> BigInt {
+        BigInt::new(false, Vec::new())
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.mag.is_empty()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    /// Number of bits of the magnitude.
+    pub fn bits(&self) -> u64 {
+        match self.mag.last() {
+            Some(top) => self.mag.len() as u64 * 32 - top.leading_zeros() as u64,
+            None => 0,
+        }
+    }
+
+    pub fn to_i64(&self) -> Option<i64> {
+        if self.mag.len() > 2 {
+            return None;
+        }
+        let mag = self.low_u64();
+        if self.negative {
+            (mag <= 1 << 63).then(|| mag.wrapping_neg() as i64)
+        } else {
+            i64::try_from(mag).ok()
+        }
+    }
+
+    /// The low 64 bits of the two's complement representation.
+    pub fn wrapping_i64(&self) -> i64 {
+        let mag = self.low_u64();
+        if self.negative {
+            mag.wrapping_neg() as i64
+        } else {
+            mag as i64
+        }
+    }
+
+    fn low_u64(&self) -> u64 {
+        let limb = |i| self.mag.get(i).copied().unwrap_or(0) as u64;
+        limb(1) << 32 | limb(0)
+    }
+
+    /// The nearest float, or an infinity if the number is out of range.
+    pub fn to_f64(&self) -> f64 {
+        // Float parsing rounds correctly, which converting limb by limb
+        // would not.
+        self.to_string().parse().unwrap()
+    }
+
+    /// Converts a finite float without a fractional part.
+    fn from_integral_f64(f: f64) -> BigInt {
+        let bits = f.to_bits();
+        let exp = (bits >> 52 & 0x7ff) as i64;
+        let mantissa = bits & ((1 << 52) - 1);
+        let (mantissa, shift) = match exp {
+            0 => (mantissa, -1074),
+            _ => (mantissa | 1 << 52, exp - 1075),
+        };
+        let mag = BigInt::from(mantissa as i64);
+        let mag = if shift >= 0 {
+            &mag << shift as u64
+        } else {
+            &mag >> shift.unsigned_abs()
+        };
+        if f < 0.0 {
+            -&mag
+        } else {
+            mag
+        }
+    }
+
+    /// Compares with a float by exact value. Returns `None` for NaN.
+    pub fn cmp_f64(&self, f: f64) -> Option<Ordering> {
+        if f.is_nan() {
+            None
+        } else if f.is_infinite() {
+            Some(if f > 0.0 {
+                Ordering::Less
+            } else {
+                Ordering::Greater
+            })
+        } else {
+            let whole = f.trunc();
+            let ordering = self.cmp(&BigInt::from_integral_f64(whole));
+            Some(ordering.then(whole.partial_cmp(&f)?))
+        }
+    }
+
+    /// Parses decimal digits with an optional leading `-`.
+    pub fn from_decimal(s: &str) -> Option<BigInt> {
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, s),
+        };
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let mut mag = Vec::new();
+        for chunk in digits.as_bytes().chunks(9) {
+            let chunk = std::str::from_utf8(chunk).unwrap();
+            let scale = 10u32.pow(chunk.len() as u32);
+            mul_add_small(&mut mag, scale, chunk.parse().unwrap());
+        }
+        Some(BigInt::new(negative, mag))
+    }
+
+    /// Quotient rounded toward zero and remainder with the sign of `self`.
+    /// Panics if `other` is zero.
+    pub fn div_rem(&self, other: &BigInt) -> (BigInt, BigInt) {
+        let (quot, rem) = div_rem_mag(&self.mag, &other.mag);
+        (
+            BigInt::new(self.negative != other.negative, quot),
+            BigInt::new(self.negative, rem),
+        )
+    }
+
+    /// Quotient rounded toward negative infinity and remainder with the sign
+    /// of `other`. Panics if `other` is zero.
+    pub fn div_rem_floor(&self, other: &BigInt) -> (BigInt, BigInt) {
+        let (quot, rem) = self.div_rem(other);
+        if !rem.is_zero() && rem.negative != other.negative {
+            (&quot - &BigInt::from(1), &rem + other)
+        } else {
+            (quot, rem)
+        }
+    }
+
+    pub fn pow(&self, mut exp: u64) -> BigInt {
+        let mut base = self.clone();
+        let mut acc = BigInt::from(1);
+        while exp > 0 {
+            if exp & 1 == 1 {
+                acc = &acc * &base;
+            }
+            exp >>= 1;
+            if exp > 0 {
+                base = &base * &base;
+            }
+        }
+        acc
+    }
+
+    /// The two's complement representation in `len` limbs, which must leave
+    /// room for the sign bit.
+    fn to_twos(&self, len: usize) -> Vec<u32> {
+        let mut limbs = self.mag.clone();
+        limbs.resize(len, 0);
+        if self.negative {
+            for limb in &mut limbs {
+                *limb = !*limb;
+            }
+            increment(&mut limbs);
+        }
+        limbs
+    }
+
+    fn from_twos(mut limbs: Vec<u32>) -> BigInt {
+        let negative = limbs.last().is_some_and(|top| top >> 31 == 1);
+        if negative {
+            for limb in &mut limbs {
+                *limb = !*limb;
+            }
+            increment(&mut limbs);
+        }
+        BigInt::new(negative, limbs)
+    }
+
+    fn bitwise(&self, other: &BigInt, op: impl Fn(u32, u32) -> u32) -> BigInt {
+        let len = self.mag.len().max(other.mag.len()) + 1;
+        let limbs = self
+            .to_twos(len)
+            .into_iter()
+            .zip(other.to_twos(len))
+            .map(|(a, b)| op(a, b))
+            .collect();
+        BigInt::from_twos(limbs)
+    }
+}
+
+impl From<i64> for BigInt {
+    fn from(i: i64) -> BigInt {
+        let mag = i.unsigned_abs();
+        BigInt::new(i < 0, vec![mag as u32, (mag >> 32) as u32])
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &BigInt) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => cmp_mag(&self.mag, &other.mag),
+            (true, true) => cmp_mag(&other.mag, &self.mag),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &BigInt) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_zero() {
+            return write!(f, "0");
+        }
+        // Nine decimal digits at a time, least significant first.
+        let mut chunks = Vec::new();
+        let mut mag = self.mag.clone();
+        while !mag.is_empty() {
+            chunks.push(div_rem_small(&mut mag, 1_000_000_000));
+        }
+        if self.negative {
+            write!(f, "-")?;
+        }
+        write!(f, "{}", chunks.pop().unwrap())?;
+        for chunk in chunks.iter().rev() {
+            write!(f, "{:09}", chunk)?;
+        }
+        Ok(())
+    }
+}
+
+impl Neg for &BigInt {
+    type Output = BigInt;
+
+    fn neg(self) -> BigInt {
+        BigInt::new(!self.negative, self.mag.clone())
+    }
+}
+
+impl Add for &BigInt {
+    type Output = BigInt;
+
+    fn add(self, other: &BigInt) -> BigInt {
+        if self.negative == other.negative {
+            return BigInt::new(self.negative, add_mag(&self.mag, &other.mag));
+        }
+        match cmp_mag(&self.mag, &other.mag) {
+            Ordering::Less => BigInt::new(other.negative, sub_mag(&other.mag, &self.mag)),
+            _ => BigInt::new(self.negative, sub_mag(&self.mag, &other.mag)),
+        }
+    }
+}
+
+impl Sub for &BigInt {
+    type Output = BigInt;
+
+    fn sub(self, other: &BigInt) -> BigInt {
+        self + &-other
+    }
+}
+
+impl Mul for &BigInt {
+    type Output = BigInt;
+
+    fn mul(self, other: &BigInt) -> BigInt {
+        BigInt::new(
+            self.negative != other.negative,
+            mul_mag(&self.mag, &other.mag),
+        )
+    }
+}
+
+impl BitAnd for &BigInt {
+    type Output = BigInt;
+
+    fn bitand(self, other: &BigInt) -> BigInt {
+        self.bitwise(other, |a, b| a & b)
+    }
+}
+
+impl BitOr for &BigInt {
+    type Output = BigInt;
+
+    fn bitor(self, other: &BigInt) -> BigInt {
+        self.bitwise(other, |a, b| a | b)
+    }
+}
+
+impl BitXor for &BigInt {
+    type Output = BigInt;
+
+    fn bitxor(self, other: &BigInt) -> BigInt {
+        self.bitwise(other, |a, b| a ^ b)
+    }
+}
+
+impl Shl<u64> for &BigInt {
+    type Output = BigInt;
+
+    fn shl(self, bits: u64) -> BigInt {
+        let mut mag = vec![0; (bits / 32) as usize];
+        mag.extend(shl_bits(&self.mag, (bits % 32) as u32));
+        BigInt::new(self.negative, mag)
+    }
+}
+
+/// Shifts right rounding toward negative infinity, like `>>` on ints.
+impl Shr<u64> for &BigInt {
+    type Output = BigInt;
+
+    fn shr(self, bits: u64) -> BigInt {
+        let limbs = (bits / 32).min(self.mag.len() as u64) as usize;
+        let (dropped, kept) = self.mag.split_at(limbs);
+        let shifted = BigInt::new(self.negative, shr_bits(kept, (bits % 32) as u32));
+        let inexact = dropped.iter().any(|&limb| limb != 0)
+            || kept
+                .first()
+                .is_some_and(|&limb| limb & ((1 << (bits % 32)) - 1) != 0);
+        if self.negative && inexact {
+            &shifted - &BigInt::from(1)
+        } else {
+            shifted
+        }
+    }
+}
+
+fn cmp_mag(a: &[u32], b: &[u32]) -> Ordering {
+    a.len()
+        .cmp(&b.len())
+        .then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn add_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let (long, short) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+    let mut out = Vec::with_capacity(long.len() + 1);
+    let mut carry = 0;
+    for (i, &limb) in long.iter().enumerate() {
+        let sum = limb as u64 + short.get(i).copied().unwrap_or(0) as u64 + carry;
+        out.push(sum as u32);
+        carry = sum >> 32;
+    }
+    out.push(carry as u32);
+    out
+}
+
+/// `a - b` for `a >= b`.
+fn sub_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut out = Vec::with_capacity(a.len());
+    let mut borrow = 0;
+    for (i, &limb) in a.iter().enumerate() {
+        let diff = limb as i64 - b.get(i).copied().unwrap_or(0) as i64 - borrow;
+        out.push(diff as u32);
+        borrow = (diff < 0) as i64;
+    }
+    out
+}
+
+/// Multiplies with Karatsuba's method, splitting the longer operand in
+/// halves, or in pieces as long as the other one if that is much shorter.
+fn mul_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let (a, b) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+    if b.len() < KARATSUBA_LIMBS {
+        return mul_schoolbook(a, b);
+    }
+    let mut out = vec![0u32; a.len() + b.len()];
+    if b.len() <= a.len() / 2 {
+        for (i, piece) in a.chunks(b.len()).enumerate() {
+            add_at(&mut out, &mul_mag(piece, b), i * b.len());
+        }
+        return out;
+    }
+
+    let half = a.len() / 2;
+    let (a0, a1) = a.split_at(half);
+    let (b0, b1) = b.split_at(half);
+    let low = mul_mag(a0, b0);
+    let high = mul_mag(a1, b1);
+    // (a0 + a1)(b0 + b1) - low - high is the middle part.
+    let mut middle = mul_mag(&add_mag(a0, a1), &add_mag(b0, b1));
+    sub_at(&mut middle, &low);
+    sub_at(&mut middle, &high);
+    add_at(&mut out, &low, 0);
+    add_at(&mut out, &middle, half);
+    add_at(&mut out, &high, 2 * half);
+    out
+}
+
+/// Adds `b * 2^(32 * offset)` to `a`, which must be long enough to hold the
+/// sum.
+fn add_at(a: &mut [u32], b: &[u32], offset: usize) {
+    let mut carry = 0;
+    for (i, limb) in a[offset..].iter_mut().enumerate() {
+        if i >= b.len() && carry == 0 {
+            break;
+        }
+        let sum = *limb as u64 + b.get(i).copied().unwrap_or(0) as u64 + carry;
+        *limb = sum as u32;
+        carry = sum >> 32;
+    }
+}
+
+/// Subtracts `b` from `a`, which must be at least `b`.
+fn sub_at(a: &mut [u32], b: &[u32]) {
+    let mut borrow = 0;
+    for (i, limb) in a.iter_mut().enumerate() {
+        if i >= b.len() && borrow == 0 {
+            break;
+        }
+        let diff = *limb as i64 - b.get(i).copied().unwrap_or(0) as i64 - borrow;
+        *limb = diff as u32;
+        borrow = (diff < 0) as i64;
+    }
+}
+
+fn mul_schoolbook(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut out = vec![0u32; a.len() + b.len()];
+    for (i, &x) in a.iter().enumerate() {
+        let mut carry = 0;
+        for (j, &y) in b.iter().enumerate() {
+            let t = x as u64 * y as u64 + out[i + j] as u64 + carry;
+            out[i + j] = t as u32;
+            carry = t >> 32;
+        }
+        out[i + b.len()] = carry as u32;
+    }
+    out
+}
+
+/// `mag = mag * scale + add`.
+fn mul_add_small(mag: &mut Vec<u32>, scale: u32, add: u32) {
+    let mut carry = add as u64;
+    for limb in mag.iter_mut() {
+        let t = *limb as u64 * scale as u64 + carry;
+        *limb = t as u32;
+        carry = t >> 32;
+    }
+    if carry > 0 {
+        mag.push(carry as u32);
+    }
+}
+
+/// Divides `mag` in place, returning the remainder.
+fn div_rem_small(mag: &mut Vec<u32>, divisor: u32) -> u32 {
+    let mut rem = 0u64;
+    for limb in mag.iter_mut().rev() {
+        let t = rem << 32 | *limb as u64;
+        *limb = (t / divisor as u64) as u32;
+        rem = t % divisor as u64;
+    }
+    while mag.last() == Some(&0) {
+        mag.pop();
+    }
+    rem as u32
+}
+
+fn increment(limbs: &mut [u32]) {
+    for limb in limbs {
+        let (sum, overflow) = limb.overflowing_add(1);
+        *limb = sum;
+        if !overflow {
+            break;
+        }
+    }
+}
+
+/// Shifts left by fewer than 32 bits, adding a limb for the bits shifted out.
+fn shl_bits(mag: &[u32], bits: u32) -> Vec<u32> {
+    if bits == 0 {
+        return mag.to_vec();
+    }
+    let mut out = Vec::with_capacity(mag.len() + 1);
+    let mut carry = 0;
+    for &limb in mag {
+        out.push(limb << bits | carry);
+        carry = limb >> (32 - bits);
+    }
+    out.push(carry);
+    out
+}
+
+/// Shifts right by fewer than 32 bits.
+fn shr_bits(mag: &[u32], bits: u32) -> Vec<u32> {
+    if bits == 0 {
+        return mag.to_vec();
+    }
+    (0..mag.len())
+        .map(|i| mag[i] >> bits | mag.get(i + 1).map_or(0, |&next| next << (32 - bits)))
+        .collect()
+}
+
+/// Long division of magnitudes, Knuth's algorithm D.
+fn div_rem_mag(u: &[u32], v: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    assert!(!v.is_empty(), "division by zero");
+    if cmp_mag(u, v) == Ordering::Less {
+        return (Vec::new(), u.to_vec());
+    }
+    if let [divisor] = v {
+        let mut quot = u.to_vec();
+        let rem = div_rem_small(&mut quot, *divisor);
+        return (quot, vec![rem]);
+    }
+
+    // Normalize so the top limb of the divisor has its high bit set, which
+    // keeps each estimated quotient limb at most two too large.
+    let shift = v.last().unwrap().leading_zeros();
+    let mut vn = shl_bits(v, shift);
+    vn.truncate(v.len());
+    let mut un = shl_bits(u, shift);
+    un.resize(u.len() + 1, 0);
+
+    let n = vn.len();
+    let m = u.len() - n;
+    let mut quot = vec![0u32; m + 1];
+    let base = 1u64 << 32;
+    for j in (0..=m).rev() {
+        let num = (un[j + n] as u64) << 32 | un[j + n - 1] as u64;
+        let mut qhat = num / vn[n - 1] as u64;
+        let mut rhat = num % vn[n - 1] as u64;
+        while qhat >= base || qhat * vn[n - 2] as u64 > (rhat << 32 | un[j + n - 2] as u64) {
+            qhat -= 1;
+            rhat += vn[n - 1] as u64;
+            if rhat >= base {
+                break;
+            }
+        }
+
+        // Subtract `qhat * vn` from the current window of `un`.
+        let mut borrow = 0i64;
+        let mut carry = 0u64;
+        for i in 0..n {
+            let product = qhat * vn[i] as u64 + carry;
+            carry = product >> 32;
+            let diff = un[i + j] as i64 - borrow - (product & 0xffff_ffff) as i64;
+            un[i + j] = diff as u32;
+            borrow = (diff < 0) as i64;
+        }
+        let diff = un[j + n] as i64 - borrow - carry as i64;
+        un[j + n] = diff as u32;
+
+        // The estimate was one too large, add one divisor back.
+        if diff < 0 {
+            qhat -= 1;
+            let mut carry = 0u64;
+            for i in 0..n {
+                let sum = un[i + j] as u64 + vn[i] as u64 + carry;
+                un[i + j] = sum as u32;
+                carry = sum >> 32;
+            }
+            un[j + n] = un[j + n].wrapping_add(carry as u32);
+        }
+        quot[j] = qhat as u32;
+    }
+
+    (quot, shr_bits(&un[..n], shift))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn big(i: i128) -> BigInt {
+        BigInt::from_decimal(&i.to_string()).unwrap()
+    }
+
+    /// Pseudo-random values of every size up to 127 bits, including the
+    /// boundaries of the limbs.
+    fn samples() -> Vec<i128> {
+        let mut out = vec![0, 1, -1, 2, -2];
+        for bits in [31, 32, 33, 63, 64, 65, 95, 96, 97, 126] {
+            let edge = 1i128 << bits;
+            out.extend([edge, -edge, edge - 1, 1 - edge]);
+        }
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        for i in 0..60 {
+            let mut next = || {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state
+            };
+            let wide = ((next() as u128) << 64 | next() as u128) as i128;
+            out.push(wide >> (i * 2 % 120 + 1));
+        }
+        out
+    }
+
+    #[test]
+    fn test_against_i128() {
+        let samples = samples();
+        for &a in &samples {
+            let x = big(a);
+            assert_eq!(a.to_string(), x.to_string());
+            assert_eq!(i64::try_from(a).ok(), x.to_i64());
+            assert_eq!(a as i64, x.wrapping_i64());
+            assert_eq!(-a, (-&x).to_string().parse::<i128>().unwrap());
+            for shift in [0, 1, 31, 32, 33, 64, 100] {
+                if let Some(shifted) = a.checked_mul(1 << shift) {
+                    assert_eq!(big(shifted), &x << shift, "{} << {}", a, shift);
+                }
+                assert_eq!(big(a >> shift), &x >> shift, "{} >> {}", a, shift);
+            }
+            for &b in &samples {
+                let y = big(b);
+                let ctx = format!("{} and {}", a, b);
+                assert_eq!(a.cmp(&b), x.cmp(&y), "{}", ctx);
+                if let Some(sum) = a.checked_add(b) {
+                    assert_eq!(big(sum), &x + &y, "{}", ctx);
+                }
+                if let Some(diff) = a.checked_sub(b) {
+                    assert_eq!(big(diff), &x - &y, "{}", ctx);
+                }
+                if let Some(product) = a.checked_mul(b) {
+                    assert_eq!(big(product), &x * &y, "{}", ctx);
+                }
+                assert_eq!(big(a & b), &x & &y, "{}", ctx);
+                assert_eq!(big(a | b), &x | &y, "{}", ctx);
+                assert_eq!(big(a ^ b), &x ^ &y, "{}", ctx);
+                if b != 0 {
+                    assert_eq!((big(a / b), big(a % b)), x.div_rem(&y), "{}", ctx);
+                    let floor = (big(a.div_euclid(b)), big(a.rem_euclid(b)));
+                    let floor = if b < 0 && a.rem_euclid(b) != 0 {
+                        (&floor.0 - &big(1), &floor.1 + &y)
+                    } else {
+                        floor
+                    };
+                    assert_eq!(floor, x.div_rem_floor(&y), "{}", ctx);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_large_values() {
+        let ten = BigInt::from(10);
+        let a = &ten.pow(50) + &BigInt::from(7);
+        let b = &ten.pow(40) + &BigInt::from(3);
+        let product = &a * &b;
+        assert_eq!(
+            "1000000000000000000000000000000000000000300000000070000000000000000000000000000000000000021",
+            product.to_string()
+        );
+        assert_eq!((a.clone(), BigInt::zero()), product.div_rem(&b));
+        let (quot, rem) = (&product + &BigInt::from(5)).div_rem(&a);
+        assert_eq!((b, BigInt::from(5)), (quot, rem));
+
+        let factorial = (1..=30).fold(BigInt::from(1), |acc, i| &acc * &BigInt::from(i));
+        assert_eq!("265252859812191058636308480000000", factorial.to_string());
+        assert_eq!(
+            Some(factorial.clone()),
+            BigInt::from_decimal(&factorial.to_string())
+        );
+        assert_eq!(
+            "265252859812191058636308480000000".parse::<f64>().unwrap(),
+            factorial.to_f64()
+        );
+        assert_eq!(f64::INFINITY, ten.pow(400).to_f64());
+        assert_eq!(None, BigInt::from_decimal("12a"));
+        assert_eq!(None, BigInt::from_decimal("-"));
+    }
+
+    #[test]
+    fn test_karatsuba_matches_schoolbook() {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut limbs = |len: usize| -> Vec<u32> {
+            (0..len)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    // Runs of all-ones limbs exercise the carries.
+                    if state.is_multiple_of(5) {
+                        u32::MAX
+                    } else {
+                        state as u32
+                    }
+                })
+                .collect()
+        };
+        for (a, b) in [
+            (32, 32),
+            (33, 64),
+            (100, 99),
+            (257, 40),
+            (300, 7),
+            (1000, 999),
+        ] {
+            let (a, b) = (limbs(a), limbs(b));
+            assert_eq!(mul_schoolbook(&a, &b), mul_mag(&a, &b));
+            assert_eq!(mul_schoolbook(&a, &b), mul_mag(&b, &a));
+        }
+    }
+
+    #[test]
+    fn test_cmp_f64() {
+        let two_64 = &BigInt::from(1) << 64;
+        assert_eq!(
+            Some(Ordering::Equal),
+            two_64.cmp_f64(18446744073709551616.0)
+        );
+        assert_eq!(Some(Ordering::Greater), two_64.cmp_f64(1e19 - 1e4));
+        assert_eq!(Some(Ordering::Less), two_64.cmp_f64(18446744073709555712.0));
+        assert_eq!(Some(Ordering::Less), (-&two_64).cmp_f64(0.5));
+        assert_eq!(Some(Ordering::Greater), two_64.cmp_f64(f64::NEG_INFINITY));
+        assert_eq!(Some(Ordering::Less), two_64.cmp_f64(f64::INFINITY));
+        assert_eq!(None, two_64.cmp_f64(f64::NAN));
+        assert_eq!(Some(Ordering::Greater), BigInt::from(3).cmp_f64(2.5));
+        assert_eq!(Some(Ordering::Less), BigInt::from(-3).cmp_f64(-2.5));
+    }
+}
@@ -0,0 +1,220 @@
+use crate::str_interner::IntStr;
+use crate::token::Span;
+use crate::value::VariantKind;
+use std::rc::Rc;
+
+/// Instructions of the stack machine. Jump targets are indices into the code
+/// of the enclosing `Proto`, other `u32` operands index its tables.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    Const(u32),
+    Null,
+    True,
+    False,
+    Pop,
+    /// Pops this many values.
+    PopN(u32),
+    /// Pops this many values from below the top one.
+    Squash(u32),
+    Dup,
+    /// Duplicates the two values on top.
+    Dup2,
+    GetLocal(u32),
+    SetLocal(u32),
+    /// Reads a variable captured by reference.
+    GetUpvalue(u32),
+    SetUpvalue(u32),
+    /// Reads a variable captured by value.
+    GetCaptured(u32),
+    GetGlobal(IntStr),
+    SetGlobal(IntStr),
+    DefineGlobal(IntStr),
+    GetField(IntStr),
+    SetField(IntStr),
+    GetPath(IntStr),
+    Index,
+    SetIndex,
+    Binary(BinOp),
+    /// Creates a range from the start and end on top, either of which may be
+    /// null. The operand tells whether the end is included.
+    Range(bool),
+    Negate,
+    Not,
+    Jump(u32),
+    JumpIfFalse(u32),
+    /// Jumps without popping the condition if it is falsy.
+    JumpIfFalseKeep(u32),
+    /// Jumps without popping the condition if it is truthy.
+    JumpIfTrueKeep(u32),
+    /// Jumps without popping the value if it is null.
+    JumpIfNullKeep(u32),
+    JumpIfNotNullKeep(u32),
+    /// Jumps if the parameter in the slot was passed by the caller.
+    JumpIfGiven(u32, u32),
+    Call(u32),
+    /// Call whose arguments are described by an entry in `arg_shapes`.
+    CallArgs(u32),
+    /// Calls a method or field of the value below the arguments.
+    Invoke(IntStr, u32),
+    InvokeArgs(IntStr, u32),
+    Return,
+    Closure(u32),
+    NewArray(u32),
+    NewTuple(u32),
+    NewMap(u32),
+    NewSet(u32),
+    /// Creates an instance of the struct below the field values, which are
+    /// named by an entry in `names`.
+    NewInstance(u32),
+    NewVariant(IntStr, u32),
+    Struct(u32),
+    Enum(u32),
+    Trait(u32),
+    /// Implements a trait for the struct or enum on top of the stack.
+    Impl(u32),
+    /// Runs the body of a module and pushes the module.
+    Module(u32, IntStr),
+    CurrentModule,
+    /// Looks up a path from `names`.
+    Use(u32),
+    Iter,
+    /// Pushes the next value of the iterator on top, or jumps if it is done.
+    ForIter(u32),
+    /// Matches the value below the enum on top against a pattern, pushing the
+    /// bound fields on success and jumping otherwise.
+    MatchVariant(u32, u32),
+    /// Unwraps the `Result::Ok` or other successful value on top and jumps.
+    /// Leaves `null` and `Result::Err` in place.
+    Try(u32),
+    /// Starts a `try` block whose handler is at the target.
+    PushHandler(u32),
+    PopHandler,
+    Throw,
+    /// Suspends the running generator, handing the value on top to the one
+    /// resuming it.
+    Yield,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
+    Eq,
+    NotEq,
+    Less,
+    Greater,
+    LessEq,
+    GreaterEq,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProtoKind {
+    Script,
+    Module,
+    Function,
+    Method,
+    Closure,
+}
+
+/// Compiled function. Slot 0 of its frame holds the callee, or the receiver
+/// for methods, and the parameters follow.
+#[derive(Debug)]
+pub struct Proto {
+    pub ident: Option<IntStr>,
+    pub kind: ProtoKind,
+    pub params: Vec<IntStr>,
+    /// Number of leading parameters without a default.
+    pub required: usize,
+    pub rest: bool,
+    /// Whether the function contains `yield`, so calling it creates a
+    /// generator.
+    pub generator: bool,
+    pub upvalues: Vec<UpvalueDesc>,
+    pub code: Vec<Op>,
+    /// Source location of each instruction, empty where there is none.
+    pub spans: Vec<Span>,
+    pub consts: Vec<Const>,
+    pub protos: Vec<Rc<Proto>>,
+    pub arg_shapes: Vec<Vec<ArgKind>>,
+    pub names: Vec<Vec<IntStr>>,
+    pub types: Vec<TypeDesc>,
+    pub patterns: Vec<PatternDesc>,
+    /// Source text the spans point into.
+    pub source: Rc<str>,
+}
+
+impl Proto {
+    pub fn new(ident: Option<IntStr>, kind: ProtoKind, source: Rc<str>) -> Proto {
+        Proto {
+            ident,
+            kind,
+            params: Vec::new(),
+            required: 0,
+            rest: false,
+            generator: false,
+            upvalues: Vec::new(),
+            code: Vec::new(),
+            spans: Vec::new(),
+            consts: Vec::new(),
+            protos: Vec::new(),
+            arg_shapes: Vec::new(),
+            names: Vec::new(),
+            types: Vec::new(),
+            patterns: Vec::new(),
+            source,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Const {
+    Int(i64),
+    /// Decimal digits of a bignum.
+    BigInt(IntStr),
+    Float(f64),
+    Char(char),
+    Str(IntStr),
+}
+
+/// Where a closure takes a capture from when it is created: a local of the
+/// enclosing function or one of the enclosing closure's captures.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UpvalueDesc {
+    pub is_local: bool,
+    pub idx: u32,
+    pub by_ref: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArgKind {
+    Positional,
+    Named(IntStr),
+    Spread,
+}
+
+/// Struct, enum, trait or impl declaration. Fields not used by the kind of
+/// declaration are empty.
+#[derive(Debug, Default)]
+pub struct TypeDesc {
+    pub ident: Option<IntStr>,
+    pub variants: Vec<(IntStr, VariantKind, Vec<IntStr>)>,
+    pub required: Vec<IntStr>,
+    /// Methods and the index of their `Proto`.
+    pub methods: Vec<(IntStr, u32)>,
+}
+
+#[derive(Debug)]
+pub struct PatternDesc {
+    pub variant: IntStr,
+    pub kind: VariantKind,
+    pub fields: Vec<IntStr>,
+}
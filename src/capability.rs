@@ -0,0 +1,76 @@
+//! Permissions for the host functions a script may call.
+
+use std::fmt;
+
+/// Kind of access to the host that a module of native functions needs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Capability {
+    FileIo,
+    Time,
+    Random,
+    Env,
+    Process,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 5] = [
+        Capability::FileIo,
+        Capability::Time,
+        Capability::Random,
+        Capability::Env,
+        Capability::Process,
+    ];
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Capability::FileIo => "file I/O",
+            Capability::Time => "time",
+            Capability::Random => "randomness",
+            Capability::Env => "environment",
+            Capability::Process => "process",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Set of capabilities granted to the scripts of a `Vm`, set with
+/// `Vm::set_capabilities`. None are granted by default, so that a host only
+/// exposes what it grants explicitly.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Capabilities(u8);
+
+impl Capabilities {
+    pub fn none() -> Capabilities {
+        Capabilities(0)
+    }
+
+    pub fn all() -> Capabilities {
+        Capability::ALL
+            .iter()
+            .fold(Capabilities::none(), |caps, &cap| caps.with(cap))
+    }
+
+    pub fn with(self, cap: Capability) -> Capabilities {
+        Capabilities(self.0 | cap.bit())
+    }
+
+    pub fn without(self, cap: Capability) -> Capabilities {
+        Capabilities(self.0 & !cap.bit())
+    }
+
+    pub fn contains(self, cap: Capability) -> bool {
+        self.0 & cap.bit() != 0
+    }
+}
+
+impl Default for Capabilities {
+    fn default() -> Capabilities {
+        Capabilities::none()
+    }
+}
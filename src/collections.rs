@@ -0,0 +1,631 @@
+//! Methods of arrays, maps and sets.
+//!
+//! `push`, `pop`, `insert`, `remove`, `sort`, `sort_by` and `reverse` change
+//! an array in place, the other methods leave their receiver alone. Maps
+//! and sets keep their entries in insertion order, and so does everything
+//! built from them.
+
+use std::cmp::Ordering;
+
+use crate::error::{Error, Result};
+use crate::value::{BuiltinKind, NativeFn, ObjRef, Object, Set, Unpacked, Value};
+use crate::vm::Vm;
+
+const ARRAY_METHODS: &[(&str, NativeFn)] = &[
+    ("push", push),
+    ("pop", pop),
+    ("insert", insert),
+    ("remove", remove),
+    ("len", len),
+    ("map", map),
+    ("filter", filter),
+    ("reduce", reduce),
+    ("sort", sort),
+    ("sort_by", sort_by),
+    ("reverse", reverse),
+    ("contains", contains),
+    ("index_of", index_of),
+    ("zip", zip),
+    ("enumerate", enumerate),
+    ("chunks", chunks),
+];
+
+const MAP_METHODS: &[(&str, NativeFn)] = &[
+    ("len", map_len),
+    ("keys", keys),
+    ("values", values),
+    ("entries", entries),
+    ("get", get),
+    ("remove", map_remove),
+    ("contains_key", contains_key),
+    ("merge", merge),
+];
+
+const SET_METHODS: &[(&str, NativeFn)] = &[
+    ("len", set_len),
+    ("add", add),
+    ("remove", set_remove),
+    ("contains", set_contains),
+    ("union", union),
+    ("intersection", intersection),
+    ("difference", difference),
+];
+
+/// Adds the array, map and set methods to `vm`.
+pub fn define(vm: &mut Vm) {
+    for &(ident, fun) in ARRAY_METHODS {
+        vm.define_method(BuiltinKind::Array, ident, fun);
+    }
+    for &(ident, fun) in MAP_METHODS {
+        vm.define_method(BuiltinKind::Map, ident, fun);
+    }
+    for &(ident, fun) in SET_METHODS {
+        vm.define_method(BuiltinKind::Set, ident, fun);
+    }
+}
+
+/// The receiver of a method, after checking it got between `min` and `max`
+/// arguments.
+fn receiver(args: &[Value], min: usize, max: usize) -> Result<ObjRef> {
+    if args.len() > max + 1 {
+        return Err(Error::TypeError("too many arguments"));
+    }
+    if args.len() < min + 1 {
+        return Err(Error::TypeError("missing argument"));
+    }
+    match args[0].unpack() {
+        Unpacked::Obj(obj) => Ok(obj),
+        _ => unreachable!(),
+    }
+}
+
+fn elems(vm: &Vm, obj: ObjRef) -> &Vec<Value> {
+    match vm.heap().get(obj) {
+        Object::Array(elems) => elems,
+        _ => unreachable!(),
+    }
+}
+
+fn elems_mut(vm: &mut Vm, obj: ObjRef) -> &mut Vec<Value> {
+    match vm.heap_mut().get_mut(obj) {
+        Object::Array(elems) => elems,
+        _ => unreachable!(),
+    }
+}
+
+fn array_arg(vm: &Vm, arg: Value) -> Result<&Vec<Value>> {
+    match arg.unpack() {
+        Unpacked::Obj(obj) => match vm.heap().get(obj) {
+            Object::Array(elems) => Ok(elems),
+            _ => Err(Error::TypeError("expected an array")),
+        },
+        _ => Err(Error::TypeError("expected an array")),
+    }
+}
+
+/// An index into an array of `len` elements.
+fn index(vm: &Vm, arg: Value, len: usize) -> Result<usize> {
+    match vm.int(arg) {
+        Some(i) => usize::try_from(i)
+            .ok()
+            .filter(|&idx| idx < len)
+            .ok_or(Error::IndexOutOfRange(i)),
+        _ => Err(Error::TypeError("index must be an int")),
+    }
+}
+
+fn push(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let obj = receiver(args, 1, usize::MAX - 1)?;
+    vm.check_array_len(elems(vm, obj).len() + args.len() - 1)?;
+    elems_mut(vm, obj).extend_from_slice(&args[1..]);
+    vm.heap_mut().resized(obj);
+    Ok(Value::NULL)
+}
+
+/// Removes and returns the last element, or null if there is none.
+fn pop(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let obj = receiver(args, 0, 0)?;
+    Ok(elems_mut(vm, obj).pop().unwrap_or(Value::NULL))
+}
+
+fn insert(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let obj = receiver(args, 2, 2)?;
+    let len = elems(vm, obj).len();
+    vm.check_array_len(len + 1)?;
+    // Inserting right after the last element is allowed.
+    let idx = index(vm, args[1], len + 1)?;
+    elems_mut(vm, obj).insert(idx, args[2]);
+    vm.heap_mut().resized(obj);
+    Ok(Value::NULL)
+}
+
+fn remove(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let obj = receiver(args, 1, 1)?;
+    let idx = index(vm, args[1], elems(vm, obj).len())?;
+    Ok(elems_mut(vm, obj).remove(idx))
+}
+
+fn len(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let obj = receiver(args, 0, 0)?;
+    Ok(Value::int(elems(vm, obj).len() as i64))
+}
+
+fn map(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let obj = receiver(args, 1, 1)?;
+    let mut mapped = Vec::new();
+    for elem in elems(vm, obj).clone() {
+        mapped.push(vm.call(args[1], &[elem])?);
+    }
+    vm.alloc_array(mapped)
+}
+
+fn filter(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let obj = receiver(args, 1, 1)?;
+    let mut kept = Vec::new();
+    for elem in elems(vm, obj).clone() {
+        if vm.call(args[1], &[elem])?.is_truthy() {
+            kept.push(elem);
+        }
+    }
+    vm.alloc_array(kept)
+}
+
+/// Folds the elements with `f(acc, elem)`, starting from the initial value
+/// if given and from the first element otherwise.
+fn reduce(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let obj = receiver(args, 1, 2)?;
+    let mut elems = elems(vm, obj).clone().into_iter();
+    let mut acc = match args.get(2) {
+        Some(&init) => init,
+        None => elems.next().ok_or(Error::TypeError(
+            "reduce of an empty array without initial value",
+        ))?,
+    };
+    for elem in elems {
+        acc = vm.call(args[1], &[acc, elem])?;
+    }
+    Ok(acc)
+}
+
+/// Sorts numbers, chars or strings in ascending order.
+fn sort(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let obj = receiver(args, 0, 0)?;
+    let sorted = merge_sort(vm, elems(vm, obj).clone(), &mut |vm, a, b| {
+        vm.compare(a, b)
+            .ok_or(Error::TypeError("values cannot be compared"))
+    })?;
+    *elems_mut(vm, obj) = sorted;
+    Ok(Value::NULL)
+}
+
+/// Sorts with a closure taking two elements and returning a negative
+/// number if the first goes before the second, a positive one if it goes
+/// after and zero if their order does not matter. Changing the array from
+/// the closure is an error.
+fn sort_by(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let obj = receiver(args, 1, 1)?;
+    let compare = args[1];
+    let original = elems(vm, obj).clone();
+    let sorted = merge_sort(vm, original.clone(), &mut |vm, a, b| {
+        let result = vm.call(compare, &[a, b])?;
+        vm.compare(result, Value::int(0))
+            .ok_or(Error::TypeError("sort_by closure must return a number"))
+    })?;
+    let elems = elems_mut(vm, obj);
+    let unchanged =
+        elems.len() == original.len() && elems.iter().zip(&original).all(|(a, b)| a.is(*b));
+    if !unchanged {
+        return Err(Error::TypeError("array changed while sorting"));
+    }
+    *elems = sorted;
+    Ok(Value::NULL)
+}
+
+/// Stable sort that, unlike the one of `slice`, lets the comparison fail
+/// and tolerates comparisons that are not a total order.
+fn merge_sort(
+    vm: &mut Vm,
+    mut elems: Vec<Value>,
+    compare: &mut impl FnMut(&mut Vm, Value, Value) -> Result<Ordering>,
+) -> Result<Vec<Value>> {
+    if elems.len() < 2 {
+        return Ok(elems);
+    }
+    let right = elems.split_off(elems.len() / 2);
+    let left = merge_sort(vm, elems, compare)?;
+    let right = merge_sort(vm, right, compare)?;
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    let (mut left, mut right) = (left.into_iter().peekable(), right.into_iter().peekable());
+    while let (Some(&a), Some(&b)) = (left.peek(), right.peek()) {
+        if compare(vm, b, a)? == Ordering::Less {
+            merged.push(b);
+            right.next();
+        } else {
+            merged.push(a);
+            left.next();
+        }
+    }
+    merged.extend(left);
+    merged.extend(right);
+    Ok(merged)
+}
+
+fn reverse(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let obj = receiver(args, 0, 0)?;
+    elems_mut(vm, obj).reverse();
+    Ok(Value::NULL)
+}
+
+fn contains(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    Ok(Value::bool(index_of(vm, args)? != Value::NULL))
+}
+
+/// The index of the first element equal to the argument, or null.
+fn index_of(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let obj = receiver(args, 1, 1)?;
+    for (idx, elem) in elems(vm, obj).clone().into_iter().enumerate() {
+        if vm.values_eq(elem, args[1])? {
+            return Ok(Value::int(idx as i64));
+        }
+    }
+    Ok(Value::NULL)
+}
+
+/// Pairs up the elements of two arrays as `[a, b]` arrays, stopping at the
+/// end of the shorter one.
+fn zip(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let obj = receiver(args, 1, 1)?;
+    let pairs: Vec<_> = elems(vm, obj)
+        .iter()
+        .zip(array_arg(vm, args[1])?)
+        .map(|(&a, &b)| vec![a, b])
+        .collect();
+    let pairs = pairs
+        .into_iter()
+        .map(|pair| vm.alloc_array(pair))
+        .collect::<Result<_>>()?;
+    vm.alloc_array(pairs)
+}
+
+/// The elements as `[index, elem]` arrays.
+fn enumerate(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let obj = receiver(args, 0, 0)?;
+    let pairs = elems(vm, obj).clone();
+    let pairs = pairs
+        .into_iter()
+        .enumerate()
+        .map(|(idx, elem)| vm.alloc_array(vec![Value::int(idx as i64), elem]))
+        .collect::<Result<_>>()?;
+    vm.alloc_array(pairs)
+}
+
+/// Splits into arrays of the given size, the last one holding the rest.
+fn chunks(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let obj = receiver(args, 1, 1)?;
+    let size = match vm.int(args[1]) {
+        Some(size) if size > 0 => size as usize,
+        _ => return Err(Error::TypeError("chunk size must be a positive int")),
+    };
+    let chunks: Vec<_> = elems(vm, obj).chunks(size).map(<[_]>::to_vec).collect();
+    let chunks = chunks
+        .into_iter()
+        .map(|chunk| vm.alloc_array(chunk))
+        .collect::<Result<_>>()?;
+    vm.alloc_array(chunks)
+}
+
+fn entries_of(vm: &Vm, obj: ObjRef) -> &Vec<(Value, Value)> {
+    match vm.heap().get(obj) {
+        Object::Map(map) => &map.entries,
+        _ => unreachable!(),
+    }
+}
+
+fn map_len(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let obj = receiver(args, 0, 0)?;
+    Ok(Value::int(entries_of(vm, obj).len() as i64))
+}
+
+fn keys(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let obj = receiver(args, 0, 0)?;
+    let keys = entries_of(vm, obj).iter().map(|&(k, _)| k).collect();
+    vm.alloc_array(keys)
+}
+
+fn values(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let obj = receiver(args, 0, 0)?;
+    let values = entries_of(vm, obj).iter().map(|&(_, v)| v).collect();
+    vm.alloc_array(values)
+}
+
+/// The entries as `[key, value]` arrays, like iterating over the map.
+fn entries(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let obj = receiver(args, 0, 0)?;
+    let entries = entries_of(vm, obj).clone();
+    let entries = entries
+        .into_iter()
+        .map(|(k, v)| vm.alloc_array(vec![k, v]))
+        .collect::<Result<_>>()?;
+    vm.alloc_array(entries)
+}
+
+/// The value of a key, or the default, null unless given.
+fn get(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let obj = receiver(args, 1, 2)?;
+    let default = args.get(2).copied().unwrap_or(Value::NULL);
+    Ok(vm.map_get(obj, args[1])?.unwrap_or(default))
+}
+
+/// Removes a key, returning its value or null if it was missing.
+fn map_remove(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let obj = receiver(args, 1, 1)?;
+    Ok(vm.map_remove(obj, args[1])?.unwrap_or(Value::NULL))
+}
+
+fn contains_key(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let obj = receiver(args, 1, 1)?;
+    Ok(Value::bool(vm.find_key(obj, args[1])?.1.is_some()))
+}
+
+/// A new map with the entries of the receiver and then those of the
+/// argument, whose values win for keys in both.
+fn merge(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let obj = receiver(args, 1, 1)?;
+    let other = match args[1].unpack() {
+        Unpacked::Obj(other) if matches!(vm.heap().get(other), Object::Map(_)) => other,
+        _ => return Err(Error::TypeError("merge takes a map")),
+    };
+    let merged = match vm.heap().get(obj) {
+        Object::Map(map) => map.clone(),
+        _ => unreachable!(),
+    };
+    let merged = vm.heap_mut().alloc(Object::Map(merged));
+    for (key, value) in entries_of(vm, other).clone() {
+        vm.map_insert(merged, key, value)?;
+    }
+    Ok(Value::obj(merged))
+}
+
+fn set_elems(vm: &Vm, obj: ObjRef) -> &Vec<Value> {
+    match vm.heap().get(obj) {
+        Object::Set(set) => &set.elems,
+        _ => unreachable!(),
+    }
+}
+
+fn set_arg(vm: &Vm, arg: Value) -> Result<ObjRef> {
+    match arg.unpack() {
+        Unpacked::Obj(obj) if matches!(vm.heap().get(obj), Object::Set(_)) => Ok(obj),
+        _ => Err(Error::TypeError("expected a set")),
+    }
+}
+
+fn set_len(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let obj = receiver(args, 0, 0)?;
+    Ok(Value::int(set_elems(vm, obj).len() as i64))
+}
+
+/// Adds an element, returning whether it was missing.
+fn add(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let obj = receiver(args, 1, 1)?;
+    Ok(Value::bool(vm.set_insert(obj, args[1])?))
+}
+
+/// Removes an element, returning whether it was present.
+fn set_remove(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let obj = receiver(args, 1, 1)?;
+    Ok(Value::bool(vm.set_remove(obj, args[1])?))
+}
+
+fn set_contains(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let obj = receiver(args, 1, 1)?;
+    Ok(Value::bool(vm.find_key(obj, args[1])?.1.is_some()))
+}
+
+/// The elements of the receiver followed by those only in the argument.
+fn union(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let obj = receiver(args, 1, 1)?;
+    let other = set_arg(vm, args[1])?;
+    let set = match vm.heap().get(obj) {
+        Object::Set(set) => set.clone(),
+        _ => unreachable!(),
+    };
+    let set = vm.heap_mut().alloc(Object::Set(set));
+    for elem in set_elems(vm, other).clone() {
+        vm.set_insert(set, elem)?;
+    }
+    Ok(Value::obj(set))
+}
+
+/// The elements of the receiver that are also in the argument.
+fn intersection(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    filter_set(vm, args, true)
+}
+
+/// The elements of the receiver that are not in the argument.
+fn difference(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    filter_set(vm, args, false)
+}
+
+/// A new set with the elements of the receiver whose presence in the
+/// argument is `present`.
+fn filter_set(vm: &mut Vm, args: &[Value], present: bool) -> Result<Value> {
+    let obj = receiver(args, 1, 1)?;
+    let other = set_arg(vm, args[1])?;
+    let set = vm.heap_mut().alloc(Object::Set(Set::default()));
+    for elem in set_elems(vm, obj).clone() {
+        if vm.find_key(other, elem)?.1.is_some() == present {
+            vm.set_insert(set, elem)?;
+        }
+    }
+    Ok(Value::obj(set))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::testing::{eval, eval_err};
+
+    #[test]
+    fn test_array_mutation() {
+        assert_eq!(
+            "[[0, 1, 4, 2], 3, 4, null, 2]",
+            eval(
+                "let a = [1];
+                a.push(2, 3);
+                a.insert(0, 0);
+                a.insert(4, 4);
+                let last = a.pop();
+                a.insert(2, 4);
+                let e = [];
+                [a, a.remove(4), last, e.pop(), [1, 2].len()];"
+            )
+        );
+        assert!(matches!(
+            eval_err("[1].insert(2, 0);"),
+            Error::IndexOutOfRange(2)
+        ));
+        assert!(matches!(
+            eval_err("[1].remove(-1);"),
+            Error::IndexOutOfRange(-1)
+        ));
+    }
+
+    #[test]
+    fn test_array_closures() {
+        assert_eq!(
+            "[[2, 4, 6], [1, 3], 6, 16, 7]",
+            eval(
+                "let a = [1, 2, 3];
+                [a.map(|x| x * 2), a.filter(|x| x % 2 == 1), a.reduce(|s, x| s + x),
+                    a.reduce(|s, x| s + x, 10), [7].reduce(|s, x| s + x)];"
+            )
+        );
+        assert!(matches!(
+            eval_err("[].reduce(|s, x| s + x);"),
+            Error::TypeError(_)
+        ));
+    }
+
+    #[test]
+    fn test_sorting() {
+        assert_eq!(
+            "[[1, 2.5, 3, 10], [\"b\", \"ab\", \"ba\", \"abc\"], [3, 2, 1]]",
+            eval(
+                "let a = [10, 3, 2.5, 1];
+                a.sort();
+                let s = [\"abc\", \"b\", \"ab\", \"ba\"];
+                s.sort_by(|x, y| x.len() - y.len());
+                let r = [1, 2, 3];
+                r.reverse();
+                [a, s, r];"
+            )
+        );
+        assert!(matches!(
+            eval_err("[1, \"a\"].sort();"),
+            Error::TypeError(_)
+        ));
+        assert!(matches!(
+            eval_err("[1, 2].sort_by(|x, y| null);"),
+            Error::TypeError(_)
+        ));
+        for source in [
+            "let a = [2, 1]; a.sort_by(|x, y| { a.push(3); x - y }); a;",
+            "let a = [2, 1]; a.sort_by(|x, y| { a[0] = 5; x - y }); a;",
+        ] {
+            assert!(matches!(
+                eval_err(source),
+                Error::TypeError("array changed while sorting")
+            ));
+        }
+        assert_eq!(
+            "[NaN, 1.0]",
+            eval("let a = [0.0 / 0.0, 1.0]; a.sort_by(|x, y| 0); a;")
+        );
+    }
+
+    #[test]
+    fn test_array_queries() {
+        assert_eq!(
+            "[true, false, 1, null, [[1, 'a'], [2, 'b']], [[0, 'a'], [1, 'b']], [[1, 2], [3, 4], [5]]]",
+            eval(
+                "let a = [1, 2, 3, 2];
+                [a.contains(3), a.contains(4), a.index_of(2), a.index_of(4),
+                    [1, 2, 3].zip(['a', 'b']), ['a', 'b'].enumerate(),
+                    [1, 2, 3, 4, 5].chunks(2)];"
+            )
+        );
+        assert!(matches!(eval_err("[1].chunks(0);"), Error::TypeError(_)));
+    }
+
+    #[test]
+    fn test_map_methods() {
+        assert_eq!(
+            "[[\"b\", \"a\", 3], [1, 2, 4], [[\"b\", 1], [\"a\", 2], [3, 4]], 2, null, 0, 3]",
+            eval(
+                "let m = map { \"b\": 1, \"a\": 2, 3: 4 };
+                [m.keys(), m.values(), m.entries(), m.get(\"a\"), m.get(\"c\"),
+                    m.get(\"c\", 0), m.len()];"
+            )
+        );
+        assert_eq!(
+            "[2, null, false, true, map { \"b\": 1, 3: 5, \"c\": 6 }, [\"b\", 3]]",
+            eval(
+                "let m = map { \"b\": 1, \"a\": 2, 3: 4 };
+                let removed = [m.remove(\"a\"), m.remove(\"a\")];
+                let merged = m.merge(map { 3: 5, \"c\": 6 });
+                [removed[0], removed[1], m.contains_key(\"a\"), m.contains_key(3), merged, m.keys()];"
+            )
+        );
+    }
+
+    #[test]
+    fn test_sets() {
+        assert_eq!(
+            "[set { 3, 1, \"a\" }, set {}, 3, [true, false], [true, false], [true, false]]",
+            eval(
+                "let s = set { 3, 1, 3.0, \"a\", \"a\" };
+                let e = set {};
+                [s, e, s.len(), [s.add(2), s.add(1)], [s.remove(2), s.remove(2)],
+                    [s.contains(1.0), s.contains('a')]];"
+            )
+        );
+        assert_eq!(
+            "[set { 1, 2, 3, 4 }, set { 2, 3 }, set { 1 }, [3, 1, 2]]",
+            eval(
+                "let a = set { 1, 2, 3 };
+                let b = set { 2, 3, 4 };
+                let order = [];
+                for x in set { 3, 1, 2 } { order.push(x); };
+                [a.union(b), a.intersection(b), a.difference(b), order];"
+            )
+        );
+        assert!(matches!(
+            eval_err("set { 1 }.union([2]);"),
+            Error::TypeError(_)
+        ));
+    }
+
+    #[test]
+    fn test_removal_keeps_order() {
+        assert_eq!(
+            "[[\"a\", \"c\", \"d\", \"b\"], [1, 3, 4, 5]]",
+            eval(
+                "let m = map { \"a\": 1, \"b\": 2, \"c\": 3, \"d\": 4 };
+                m.remove(\"b\");
+                m[\"b\"] = 5;
+                [m.keys(), [m[\"a\"], m[\"c\"], m[\"d\"], m[\"b\"]]];"
+            )
+        );
+        assert_eq!(
+            "[set { 1, 3, 4, 2 }, [true, true, true]]",
+            eval(
+                "let s = set { 1, 2, 3, 4 };
+                s.remove(2);
+                s.add(2);
+                s.remove(5);
+                [s, [s.contains(3), s.contains(4), s.contains(2)]];"
+            )
+        );
+    }
+}
@@ -0,0 +1,1391 @@
+use crate::ast;
+use crate::bytecode::{
+    ArgKind, BinOp, Const, Op, PatternDesc, Proto, ProtoKind, TypeDesc, UpvalueDesc,
+};
+use crate::error::{Error, Result};
+use crate::str_interner::{IntStr, Interner};
+use crate::token::{Assign, CmpOp, FactorOp, RangeOp, ShiftOp, Span, TermOp};
+use crate::value::VariantKind;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Compiles a resolved program into the `Proto` of its top-level code, which
+/// returns the value of the program's last expression statement.
+pub fn compile(program: &ast::Program, source: Rc<str>, interner: &mut Interner) -> Result<Proto> {
+    let mut compiler = Compiler::new(source, interner);
+    compiler.script(program)?;
+    Ok(compiler.funs.pop().unwrap().proto)
+}
+
+struct Compiler<'a> {
+    self_ident: IntStr,
+    funs: Vec<FunState<'a>>,
+    /// Start of the top-level declaration being compiled.
+    decl_start: usize,
+    /// Source location of the innermost expression being compiled.
+    span: Span,
+    source: Rc<str>,
+}
+
+struct FunState<'a> {
+    proto: Proto,
+    locals: Vec<Local>,
+    /// Stack depth at the start of each open scope.
+    scopes: Vec<usize>,
+    /// Number of values on the stack above the frame base.
+    depth: usize,
+    loops: Vec<LoopState>,
+    /// `try` blocks and `catch` blocks with a `finally` that are open.
+    tries: Vec<TryState<'a>>,
+    captures: HashMap<IntStr, ast::CaptureMode>,
+}
+
+struct Local {
+    ident: IntStr,
+    slot: usize,
+}
+
+struct LoopState {
+    depth: usize,
+    /// Number of enclosing tries when the loop starts.
+    tries: usize,
+    breaks: Vec<usize>,
+}
+
+/// What leaving a `try` or `catch` block early, by `break` or `return`,
+/// has to do.
+#[derive(Clone, Copy)]
+struct TryState<'a> {
+    finally: Option<&'a ast::Block>,
+    handler: bool,
+}
+
+enum Var {
+    Local(u32),
+    Upvalue(u32),
+    Captured(u32),
+    Global(IntStr),
+}
+
+impl<'a> FunState<'a> {
+    fn new(proto: Proto) -> FunState<'a> {
+        FunState {
+            proto,
+            locals: Vec::new(),
+            scopes: Vec::new(),
+            depth: 1,
+            loops: Vec::new(),
+            tries: Vec::new(),
+            captures: HashMap::new(),
+        }
+    }
+
+    fn local(&self, ident: IntStr) -> Option<usize> {
+        self.locals
+            .iter()
+            .rev()
+            .find(|local| local.ident == ident)
+            .map(|local| local.slot)
+    }
+
+    /// Whether declarations go to the module's globals.
+    fn is_global_scope(&self) -> bool {
+        matches!(self.proto.kind, ProtoKind::Script | ProtoKind::Module) && self.scopes.is_empty()
+    }
+}
+
+impl<'a> Compiler<'a> {
+    fn new(source: Rc<str>, interner: &mut Interner) -> Compiler<'a> {
+        Compiler {
+            self_ident: interner.intern_str("self"),
+            funs: Vec::new(),
+            decl_start: 0,
+            span: Span::default(),
+            source,
+        }
+    }
+
+    fn fun(&mut self) -> &mut FunState<'a> {
+        self.funs.last_mut().unwrap()
+    }
+
+    fn script(&mut self, program: &'a ast::Program) -> Result<()> {
+        self.funs
+            .push(FunState::new(self.proto(None, ProtoKind::Script)));
+
+        let last = program.decls.len().checked_sub(1);
+        for (idx, (decl, span)) in program.decls.iter().zip(&program.spans).enumerate() {
+            self.decl_start = span.start;
+            self.span = *span;
+            match decl {
+                ast::Decl::Stmt(ast::Stmt::Expr(expr)) if Some(idx) == last => {
+                    self.expr(expr)?;
+                    self.emit(Op::Return);
+                    return Ok(());
+                }
+                decl => self.decl(decl)?,
+            }
+        }
+        self.emit(Op::Null);
+        self.emit(Op::Return);
+        Ok(())
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        self.emit_spanned(op, self.span)
+    }
+
+    fn emit_spanned(&mut self, op: Op, span: Span) -> usize {
+        let effect = self.stack_effect(op);
+        let fun = self.fun();
+        fun.depth = (fun.depth as isize + effect) as usize;
+        fun.proto.code.push(op);
+        fun.proto.spans.push(span);
+        fun.proto.code.len() - 1
+    }
+
+    fn stack_effect(&self, op: Op) -> isize {
+        let proto = &self.funs.last().unwrap().proto;
+        match op {
+            Op::Const(_) | Op::Null | Op::True | Op::False | Op::Dup => 1,
+            Op::Pop => -1,
+            Op::PopN(n) | Op::Squash(n) => -(n as isize),
+            Op::Dup2 => 2,
+            Op::GetLocal(_) | Op::GetUpvalue(_) | Op::GetCaptured(_) | Op::GetGlobal(_) => 1,
+            Op::SetLocal(_) | Op::SetUpvalue(_) | Op::SetGlobal(_) | Op::DefineGlobal(_) => -1,
+            Op::GetField(_) | Op::GetPath(_) => 0,
+            Op::SetField(_) => -2,
+            Op::Index => -1,
+            Op::SetIndex => -3,
+            Op::Binary(_) | Op::Range(_) => -1,
+            Op::Negate | Op::Not => 0,
+            Op::Jump(_) | Op::JumpIfFalseKeep(_) | Op::JumpIfTrueKeep(_) => 0,
+            Op::JumpIfNullKeep(_) | Op::JumpIfNotNullKeep(_) => 0,
+            Op::JumpIfGiven(_, _) => 0,
+            Op::JumpIfFalse(_) => -1,
+            Op::Call(argc) | Op::Invoke(_, argc) => -(argc as isize),
+            Op::CallArgs(shape) | Op::InvokeArgs(_, shape) => {
+                -(proto.arg_shapes[shape as usize].len() as isize)
+            }
+            Op::Return => -1,
+            Op::Closure(_) => 1,
+            Op::NewArray(n) => 1 - n as isize,
+            Op::NewTuple(n) => 1 - n as isize,
+            Op::NewMap(n) => 1 - 2 * n as isize,
+            Op::NewSet(n) => 1 - n as isize,
+            Op::NewInstance(names) | Op::NewVariant(_, names) => {
+                -(proto.names[names as usize].len() as isize)
+            }
+            Op::Struct(_) | Op::Enum(_) | Op::Trait(_) => 1,
+            Op::Impl(_) => -2,
+            Op::Module(_, _) | Op::CurrentModule | Op::Use(_) => 1,
+            Op::Iter | Op::Try(_) => 0,
+            Op::PushHandler(_) | Op::PopHandler => 0,
+            Op::Throw | Op::Yield => -1,
+            Op::ForIter(_) => 1,
+            Op::MatchVariant(pattern, _) => {
+                proto.patterns[pattern as usize].fields.len() as isize - 1
+            }
+        }
+    }
+
+    fn proto(&self, ident: Option<IntStr>, kind: ProtoKind) -> Proto {
+        Proto::new(ident, kind, self.source.clone())
+    }
+
+    fn emit_jump(&mut self, op: Op) -> usize {
+        self.emit(op)
+    }
+
+    /// Points the jump at `idx` to the next instruction.
+    fn patch(&mut self, idx: usize) {
+        let target = self.fun().proto.code.len() as u32;
+        let op = &mut self.fun().proto.code[idx];
+        *op = match *op {
+            Op::Jump(_) => Op::Jump(target),
+            Op::JumpIfFalse(_) => Op::JumpIfFalse(target),
+            Op::JumpIfFalseKeep(_) => Op::JumpIfFalseKeep(target),
+            Op::JumpIfTrueKeep(_) => Op::JumpIfTrueKeep(target),
+            Op::JumpIfNullKeep(_) => Op::JumpIfNullKeep(target),
+            Op::JumpIfNotNullKeep(_) => Op::JumpIfNotNullKeep(target),
+            Op::JumpIfGiven(slot, _) => Op::JumpIfGiven(slot, target),
+            Op::ForIter(_) => Op::ForIter(target),
+            Op::MatchVariant(pattern, _) => Op::MatchVariant(pattern, target),
+            Op::Try(_) => Op::Try(target),
+            Op::PushHandler(_) => Op::PushHandler(target),
+            op => unreachable!("{:?} is not a jump", op),
+        };
+    }
+
+    fn constant(&mut self, constant: Const) -> u32 {
+        let consts = &mut self.fun().proto.consts;
+        match consts.iter().position(|c| *c == constant) {
+            Some(idx) => idx as u32,
+            None => {
+                consts.push(constant);
+                consts.len() as u32 - 1
+            }
+        }
+    }
+
+    fn names(&mut self, names: Vec<IntStr>) -> u32 {
+        let table = &mut self.fun().proto.names;
+        table.push(names);
+        table.len() as u32 - 1
+    }
+
+    fn absolute(&self, span: Span) -> Span {
+        Span::new(span.start + self.decl_start, span.end + self.decl_start)
+    }
+
+    fn begin_scope(&mut self) {
+        let fun = self.fun();
+        fun.scopes.push(fun.depth);
+    }
+
+    /// Drops the locals of the innermost scope, keeping the value on top if
+    /// `keep_top` is set.
+    fn end_scope(&mut self, keep_top: bool) {
+        let start = self.fun().scopes.pop().unwrap();
+        let fun = self.fun();
+        while fun.locals.last().is_some_and(|local| local.slot >= start) {
+            fun.locals.pop();
+        }
+        let count = (fun.depth - start - keep_top as usize) as u32;
+        if count > 0 {
+            self.emit(if keep_top {
+                Op::Squash(count)
+            } else {
+                Op::PopN(count)
+            });
+        }
+    }
+
+    /// Binds the value on top of the stack to `ident`.
+    fn define(&mut self, ident: IntStr) {
+        if self.fun().is_global_scope() {
+            self.emit(Op::DefineGlobal(ident));
+        } else {
+            let fun = self.fun();
+            let slot = fun.depth - 1;
+            fun.locals.push(Local { ident, slot });
+        }
+    }
+
+    fn resolve(&mut self, ident: IntStr) -> Var {
+        let fun = self.funs.len() - 1;
+        if let Some(slot) = self.funs[fun].local(ident) {
+            return Var::Local(slot as u32);
+        }
+        match self.upvalue(fun, ident) {
+            Some((idx, true)) => Var::Upvalue(idx as u32),
+            Some((idx, false)) => Var::Captured(idx as u32),
+            None => Var::Global(ident),
+        }
+    }
+
+    /// Finds `ident` in the functions enclosing the closure `fun`, returning
+    /// the index of the capture and whether it is by reference.
+    fn upvalue(&mut self, fun: usize, ident: IntStr) -> Option<(usize, bool)> {
+        if fun == 0 || self.funs[fun].proto.kind != ProtoKind::Closure {
+            return None;
+        }
+        // Without capture analysis every capture is by reference, which is
+        // always correct.
+        let by_ref = ident != self.self_ident
+            && self.funs[fun].captures.get(&ident) != Some(&ast::CaptureMode::ByValue);
+
+        let desc = match self.funs[fun - 1].local(ident) {
+            Some(slot) => UpvalueDesc {
+                is_local: true,
+                idx: slot as u32,
+                by_ref,
+            },
+            None => {
+                let (idx, _) = self.upvalue(fun - 1, ident)?;
+                UpvalueDesc {
+                    is_local: false,
+                    idx: idx as u32,
+                    by_ref,
+                }
+            }
+        };
+
+        let upvalues = &mut self.funs[fun].proto.upvalues;
+        let idx = match upvalues.iter().position(|other| *other == desc) {
+            Some(idx) => idx,
+            None => {
+                upvalues.push(desc);
+                upvalues.len() - 1
+            }
+        };
+        Some((idx, by_ref))
+    }
+
+    fn get_var(&mut self, ident: IntStr) {
+        let op = match self.resolve(ident) {
+            Var::Local(slot) => Op::GetLocal(slot),
+            Var::Upvalue(idx) => Op::GetUpvalue(idx),
+            Var::Captured(idx) => Op::GetCaptured(idx),
+            Var::Global(ident) => Op::GetGlobal(ident),
+        };
+        self.emit(op);
+    }
+
+    fn set_var(&mut self, ident: IntStr) -> Result<()> {
+        let op = match self.resolve(ident) {
+            Var::Local(slot) => Op::SetLocal(slot),
+            Var::Upvalue(idx) => Op::SetUpvalue(idx),
+            Var::Captured(_) => return Err(Error::UnassignableExpression),
+            Var::Global(ident) => Op::SetGlobal(ident),
+        };
+        self.emit(op);
+        Ok(())
+    }
+
+    fn get_self(&mut self) -> Result<()> {
+        match self.resolve(self.self_ident) {
+            Var::Global(_) => Err(Error::SelfOutsideMethod),
+            _ => {
+                self.get_var(self.self_ident);
+                Ok(())
+            }
+        }
+    }
+
+    fn decl(&mut self, decl: &'a ast::Decl) -> Result<()> {
+        match decl {
+            ast::Decl::Use(use_decl) => {
+                let names = self.names(use_decl.path.clone());
+                self.emit(Op::Use(names));
+                self.define(*use_decl.path.last().unwrap());
+            }
+            ast::Decl::Mod(mod_decl) => {
+                let proto = self.module(mod_decl)?;
+                self.emit(Op::Module(proto, mod_decl.ident));
+                self.define(mod_decl.ident);
+            }
+            ast::Decl::Struct(struct_decl) => {
+                let desc = TypeDesc {
+                    ident: Some(struct_decl.ident),
+                    methods: self.methods(&struct_decl.methods)?,
+                    ..TypeDesc::default()
+                };
+                let desc = self.type_desc(desc);
+                self.emit(Op::Struct(desc));
+                self.define(struct_decl.ident);
+            }
+            ast::Decl::Enum(enum_decl) => {
+                let variants = enum_decl
+                    .variants
+                    .iter()
+                    .map(|variant| {
+                        let (kind, fields) = variant_fields(&variant.fields);
+                        (variant.ident, kind, fields)
+                    })
+                    .collect();
+                let desc = TypeDesc {
+                    ident: Some(enum_decl.ident),
+                    variants,
+                    methods: self.methods(&enum_decl.methods)?,
+                    ..TypeDesc::default()
+                };
+                let desc = self.type_desc(desc);
+                self.emit(Op::Enum(desc));
+                self.define(enum_decl.ident);
+            }
+            ast::Decl::Trait(trait_decl) => {
+                let desc = TypeDesc {
+                    ident: Some(trait_decl.ident),
+                    required: trait_decl.required.clone(),
+                    methods: self.methods(&trait_decl.methods)?,
+                    ..TypeDesc::default()
+                };
+                let desc = self.type_desc(desc);
+                self.emit(Op::Trait(desc));
+                self.define(trait_decl.ident);
+            }
+            ast::Decl::Impl(impl_decl) => {
+                let desc = TypeDesc {
+                    methods: self.methods(&impl_decl.methods)?,
+                    ..TypeDesc::default()
+                };
+                let desc = self.type_desc(desc);
+                self.get_var(impl_decl.trait_ident);
+                self.get_var(impl_decl.ident);
+                self.emit(Op::Impl(desc));
+            }
+            ast::Decl::Fun(fun) => {
+                let proto = self.function(fun, ProtoKind::Function)?;
+                self.emit(Op::Closure(proto));
+                self.define(fun.ident);
+            }
+            ast::Decl::Var(var) => {
+                self.expr(&var.expr)?;
+                self.define(var.ident);
+            }
+            ast::Decl::Stmt(stmt) => self.stmt(stmt)?,
+        }
+        Ok(())
+    }
+
+    fn type_desc(&mut self, desc: TypeDesc) -> u32 {
+        let types = &mut self.fun().proto.types;
+        types.push(desc);
+        types.len() as u32 - 1
+    }
+
+    fn methods(
+        &mut self,
+        methods: &'a HashMap<IntStr, ast::FunDecl>,
+    ) -> Result<Vec<(IntStr, u32)>> {
+        let mut compiled = methods
+            .values()
+            .map(|method| Ok((method.ident, self.function(method, ProtoKind::Method)?)))
+            .collect::<Result<Vec<_>>>()?;
+        compiled.sort_by_key(|&(_, proto)| proto);
+        Ok(compiled)
+    }
+
+    fn push_proto(&mut self, proto: Proto) -> u32 {
+        let protos = &mut self.fun().proto.protos;
+        protos.push(Rc::new(proto));
+        protos.len() as u32 - 1
+    }
+
+    fn module(&mut self, mod_decl: &'a ast::ModDecl) -> Result<u32> {
+        self.funs.push(FunState::new(
+            self.proto(Some(mod_decl.ident), ProtoKind::Module),
+        ));
+        for decl in &mod_decl.decls {
+            self.decl(decl)?;
+        }
+        self.emit(Op::CurrentModule);
+        self.emit(Op::Return);
+        let fun = self.funs.pop().unwrap();
+        Ok(self.push_proto(fun.proto))
+    }
+
+    fn function(&mut self, fun: &'a ast::FunDecl, kind: ProtoKind) -> Result<u32> {
+        let mut proto = self.proto(Some(fun.ident), kind);
+        proto.params = fun.params.iter().map(|param| param.ident).collect();
+        proto.required = fun
+            .params
+            .iter()
+            .take_while(|param| param.default.is_none())
+            .count();
+        proto.rest = fun.rest.is_some();
+        self.funs.push(FunState::new(proto));
+
+        if kind == ProtoKind::Method {
+            let self_ident = self.self_ident;
+            self.fun().locals.push(Local {
+                ident: self_ident,
+                slot: 0,
+            });
+        }
+        for (idx, param) in fun.params.iter().enumerate() {
+            let slot = idx + 1;
+            self.fun().locals.push(Local {
+                ident: param.ident,
+                slot,
+            });
+        }
+        if let Some(rest) = fun.rest {
+            let slot = fun.params.len() + 1;
+            self.fun().locals.push(Local { ident: rest, slot });
+        }
+        self.fun().depth = 1 + fun.params.len() + fun.rest.is_some() as usize;
+
+        for (idx, param) in fun.params.iter().enumerate() {
+            if let Some(default) = &param.default {
+                let slot = idx as u32 + 1;
+                let jump = self.emit_jump(Op::JumpIfGiven(slot, 0));
+                self.expr(default)?;
+                self.emit(Op::SetLocal(slot));
+                self.patch(jump);
+            }
+        }
+
+        self.block(&fun.block)?;
+        self.emit(Op::Return);
+        let fun = self.funs.pop().unwrap();
+        Ok(self.push_proto(fun.proto))
+    }
+
+    fn closure(&mut self, closure: &'a ast::Closure) -> Result<u32> {
+        let mut proto = self.proto(None, ProtoKind::Closure);
+        proto.params = closure.params.clone();
+        proto.required = closure.params.len();
+        let mut state = FunState::new(proto);
+        state.captures = closure
+            .captures
+            .iter()
+            .map(|capture| (capture.ident, capture.mode))
+            .collect();
+        for (idx, &ident) in closure.params.iter().enumerate() {
+            state.locals.push(Local {
+                ident,
+                slot: idx + 1,
+            });
+        }
+        state.depth = 1 + closure.params.len();
+        self.funs.push(state);
+
+        self.expr(&closure.body)?;
+        self.emit(Op::Return);
+        let fun = self.funs.pop().unwrap();
+        Ok(self.push_proto(fun.proto))
+    }
+
+    fn stmt(&mut self, stmt: &'a ast::Stmt) -> Result<()> {
+        match stmt {
+            ast::Stmt::Return(expr) => {
+                if matches!(self.fun().proto.kind, ProtoKind::Script | ProtoKind::Module) {
+                    return Err(Error::ReturnOutsideFunction);
+                }
+                self.opt_expr(expr.as_ref())?;
+                self.emit_return()?;
+            }
+            ast::Stmt::Break(expr) => {
+                let depth = self.fun().depth;
+                let (loop_depth, loop_tries) = match self.fun().loops.last() {
+                    Some(state) => (state.depth, state.tries),
+                    None => return Err(Error::BreakOutsideLoop),
+                };
+                self.opt_expr(expr.as_ref())?;
+                self.unwind_tries(loop_tries)?;
+                let count = (depth - loop_depth) as u32;
+                if count > 0 {
+                    self.emit(Op::Squash(count));
+                }
+                let jump = self.emit_jump(Op::Jump(0));
+                let fun = self.fun();
+                fun.loops.last_mut().unwrap().breaks.push(jump);
+                fun.depth = depth;
+            }
+            ast::Stmt::Throw(expr) => {
+                self.expr(expr)?;
+                let span = self.absolute(expr.span);
+                self.emit_spanned(Op::Throw, span);
+            }
+            ast::Stmt::Assignment(assignment) => self.assignment(assignment)?,
+            ast::Stmt::Expr(expr) => {
+                self.expr(expr)?;
+                self.emit(Op::Pop);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the value on top of the stack, running the `finally` blocks
+    /// the function is in.
+    fn emit_return(&mut self) -> Result<()> {
+        self.unwind_tries(0)?;
+        self.emit(Op::Return);
+        Ok(())
+    }
+
+    /// Leaves the tries above the first `to`, keeping the value on top.
+    fn unwind_tries(&mut self, to: usize) -> Result<()> {
+        let tries = self.fun().tries.clone();
+        for (idx, state) in tries.iter().enumerate().skip(to).rev() {
+            // A `finally` block is not inside its own try.
+            self.fun().tries.truncate(idx);
+            if state.handler {
+                self.emit(Op::PopHandler);
+            }
+            if let Some(finally) = state.finally {
+                self.block(finally)?;
+                self.emit(Op::Pop);
+            }
+        }
+        self.fun().tries = tries;
+        Ok(())
+    }
+
+    fn opt_expr(&mut self, expr: Option<&'a ast::Expr>) -> Result<()> {
+        match expr {
+            Some(expr) => self.expr(expr),
+            None => {
+                self.emit(Op::Null);
+                Ok(())
+            }
+        }
+    }
+
+    fn assignment(&mut self, assignment: &'a ast::Assignment) -> Result<()> {
+        let op = assign_op(assignment.assigner);
+        let lcall = &assignment.lcall;
+
+        let (last, init) = match lcall.tail.split_last() {
+            Some(split) => split,
+            None => {
+                let ident = match lcall.head {
+                    ast::LCallHead::Ident(ident) => ident,
+                    ast::LCallHead::SelfKw => return Err(Error::UnassignableExpression),
+                };
+                if let Some(op) = op {
+                    self.get_var(ident);
+                    self.expr(&assignment.expr)?;
+                    self.emit(Op::Binary(op));
+                } else {
+                    self.expr(&assignment.expr)?;
+                }
+                return self.set_var(ident);
+            }
+        };
+
+        match lcall.head {
+            ast::LCallHead::Ident(ident) => self.get_var(ident),
+            ast::LCallHead::SelfKw => self.get_self()?,
+        }
+        for part in init {
+            match part {
+                ast::LCallPart::Dot(ident) => {
+                    self.emit(Op::GetField(*ident));
+                }
+                ast::LCallPart::Brkts(expr) => {
+                    self.expr(expr)?;
+                    self.emit(Op::Index);
+                }
+            }
+        }
+
+        match last {
+            ast::LCallPart::Dot(ident) => {
+                if let Some(op) = op {
+                    self.emit(Op::Dup);
+                    self.emit(Op::GetField(*ident));
+                    self.expr(&assignment.expr)?;
+                    self.emit(Op::Binary(op));
+                } else {
+                    self.expr(&assignment.expr)?;
+                }
+                self.emit(Op::SetField(*ident));
+            }
+            ast::LCallPart::Brkts(idx) => {
+                self.expr(idx)?;
+                if let Some(op) = op {
+                    self.emit(Op::Dup2);
+                    self.emit(Op::Index);
+                    self.expr(&assignment.expr)?;
+                    self.emit(Op::Binary(op));
+                } else {
+                    self.expr(&assignment.expr)?;
+                }
+                self.emit(Op::SetIndex);
+            }
+        }
+        Ok(())
+    }
+
+    fn block(&mut self, block: &'a ast::Block) -> Result<()> {
+        self.begin_scope();
+        self.block_inner(block)?;
+        self.end_scope(true);
+        Ok(())
+    }
+
+    fn block_inner(&mut self, block: &'a ast::Block) -> Result<()> {
+        for decl in &block.decls {
+            self.decl(decl)?;
+        }
+        self.opt_expr(block.expr.as_deref())
+    }
+
+    fn expr(&mut self, expr: &'a ast::Expr) -> Result<()> {
+        let span = self.absolute(expr.span);
+        let span = std::mem::replace(&mut self.span, span);
+        let res = self.pipe(&expr.pipe);
+        self.span = span;
+        res
+    }
+
+    /// Compiles `x |> f(a)` as `f(x, a)`, so `f` is evaluated before `x`. The
+    /// functions of a chain of stages are evaluated from the last to the first.
+    fn pipe(&mut self, pipe: &'a ast::Pipe) -> Result<()> {
+        let (first, stages) = match pipe {
+            ast::Pipe::Next(next) => return self.coalesce(next),
+            ast::Pipe::Current(first, stages) => (first, stages),
+        };
+        let mut calls = Vec::new();
+        for stage in stages.iter().rev() {
+            let call = stage
+                .as_call()
+                .and_then(|call| match call.tail.split_last() {
+                    Some((ast::CallPart::FunCall(fun_call), rest)) => Some((call, fun_call, rest)),
+                    _ => None,
+                });
+            let (call, fun_call, rest) = match call {
+                Some(call) => call,
+                None => {
+                    self.coalesce(stage)?;
+                    calls.push(None);
+                    continue;
+                }
+            };
+            let (method, rest) = match rest.split_last() {
+                Some((ast::CallPart::Dot(ident), rest)) => (Some(*ident), rest),
+                _ => (None, rest),
+            };
+            let null_safe = self.call_parts(&call.head, rest)?;
+            calls.push(Some((fun_call, method, null_safe)));
+        }
+
+        self.coalesce(first)?;
+        for call in calls.into_iter().rev() {
+            match call {
+                Some((fun_call, method, null_safe)) => {
+                    self.fun_call(fun_call, method, true)?;
+                    for jump in null_safe {
+                        self.patch(jump);
+                    }
+                }
+                None => {
+                    self.emit(Op::Call(1));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn coalesce(&mut self, coalesce: &'a ast::Coalesce) -> Result<()> {
+        let (left, rest) = match coalesce {
+            ast::Coalesce::Next(next) => return self.logic_or(next),
+            ast::Coalesce::Current(left, rest) => (left, rest),
+        };
+        self.logic_or(left)?;
+        let mut jumps = Vec::new();
+        for next in rest {
+            jumps.push(self.emit_jump(Op::JumpIfNotNullKeep(0)));
+            self.emit(Op::Pop);
+            self.logic_or(next)?;
+        }
+        for jump in jumps {
+            self.patch(jump);
+        }
+        Ok(())
+    }
+
+    fn logic_or(&mut self, logic_or: &'a ast::LogicOr) -> Result<()> {
+        let (left, rest) = match logic_or {
+            ast::LogicOr::Next(next) => return self.logic_and(next),
+            ast::LogicOr::Current(left, rest) => (left, rest),
+        };
+        self.logic_and(left)?;
+        let mut jumps = Vec::new();
+        for next in rest {
+            jumps.push(self.emit_jump(Op::JumpIfTrueKeep(0)));
+            self.emit(Op::Pop);
+            self.logic_and(next)?;
+        }
+        for jump in jumps {
+            self.patch(jump);
+        }
+        Ok(())
+    }
+
+    fn logic_and(&mut self, logic_and: &'a ast::LogicAnd) -> Result<()> {
+        let (left, rest) = match logic_and {
+            ast::LogicAnd::Next(next) => return self.cmp(next),
+            ast::LogicAnd::Current(left, rest) => (left, rest),
+        };
+        self.cmp(left)?;
+        let mut jumps = Vec::new();
+        for next in rest {
+            jumps.push(self.emit_jump(Op::JumpIfFalseKeep(0)));
+            self.emit(Op::Pop);
+            self.cmp(next)?;
+        }
+        for jump in jumps {
+            self.patch(jump);
+        }
+        Ok(())
+    }
+
+    // Chains of binary operators are evaluated left to right: `a - b - c` is
+    // `(a - b) - c`.
+
+    fn cmp(&mut self, cmp: &'a ast::Cmp) -> Result<()> {
+        let (left, rest) = match cmp {
+            ast::Cmp::Next(next) => return self.range(next),
+            ast::Cmp::Current { left, rest } => (left, rest),
+        };
+        self.range(left)?;
+        for (op, next) in rest {
+            self.range(next)?;
+            self.emit(Op::Binary(cmp_op(*op)));
+        }
+        Ok(())
+    }
+
+    fn range(&mut self, range: &'a ast::Range) -> Result<()> {
+        let (start, op, end) = match range {
+            ast::Range::Next(next) => return self.bit_or(next),
+            ast::Range::Current { start, op, end } => (start, op, end),
+        };
+        for bound in [start, end] {
+            match bound {
+                Some(bound) => self.bit_or(bound)?,
+                None => {
+                    self.emit(Op::Null);
+                }
+            }
+        }
+        self.emit(Op::Range(*op == RangeOp::Inclusive));
+        Ok(())
+    }
+
+    fn bit_or(&mut self, bit_or: &'a ast::BitOr) -> Result<()> {
+        let (left, rest) = match bit_or {
+            ast::BitOr::Next(next) => return self.bit_xor(next),
+            ast::BitOr::Current(left, rest) => (left, rest),
+        };
+        self.bit_xor(left)?;
+        for next in rest {
+            self.bit_xor(next)?;
+            self.emit(Op::Binary(BinOp::BitOr));
+        }
+        Ok(())
+    }
+
+    fn bit_xor(&mut self, bit_xor: &'a ast::BitXor) -> Result<()> {
+        let (left, rest) = match bit_xor {
+            ast::BitXor::Next(next) => return self.bit_and(next),
+            ast::BitXor::Current(left, rest) => (left, rest),
+        };
+        self.bit_and(left)?;
+        for next in rest {
+            self.bit_and(next)?;
+            self.emit(Op::Binary(BinOp::BitXor));
+        }
+        Ok(())
+    }
+
+    fn bit_and(&mut self, bit_and: &'a ast::BitAnd) -> Result<()> {
+        let (left, rest) = match bit_and {
+            ast::BitAnd::Next(next) => return self.shift(next),
+            ast::BitAnd::Current(left, rest) => (left, rest),
+        };
+        self.shift(left)?;
+        for next in rest {
+            self.shift(next)?;
+            self.emit(Op::Binary(BinOp::BitAnd));
+        }
+        Ok(())
+    }
+
+    fn shift(&mut self, shift: &'a ast::Shift) -> Result<()> {
+        let (left, rest) = match shift {
+            ast::Shift::Next(next) => return self.term(next),
+            ast::Shift::Current { left, rest } => (left, rest),
+        };
+        self.term(left)?;
+        for (op, next) in rest {
+            self.term(next)?;
+            self.emit(Op::Binary(match op {
+                ShiftOp::Left => BinOp::Shl,
+                ShiftOp::Right => BinOp::Shr,
+            }));
+        }
+        Ok(())
+    }
+
+    fn term(&mut self, term: &'a ast::Term) -> Result<()> {
+        let (left, rest) = match term {
+            ast::Term::Next(next) => return self.factor(next),
+            ast::Term::Current { left, rest } => (left, rest),
+        };
+        self.factor(left)?;
+        for (op, next) in rest {
+            self.factor(next)?;
+            self.emit(Op::Binary(match op {
+                TermOp::Add => BinOp::Add,
+                TermOp::Sub => BinOp::Sub,
+            }));
+        }
+        Ok(())
+    }
+
+    fn factor(&mut self, factor: &'a ast::Factor) -> Result<()> {
+        let (left, rest) = match factor {
+            ast::Factor::Next(next) => return self.unary(next),
+            ast::Factor::Current { left, rest } => (left, rest),
+        };
+        self.unary(left)?;
+        for (op, next) in rest {
+            self.unary(next)?;
+            self.emit(Op::Binary(match op {
+                FactorOp::Mul => BinOp::Mul,
+                FactorOp::Div => BinOp::Div,
+                FactorOp::Mod => BinOp::Mod,
+            }));
+        }
+        Ok(())
+    }
+
+    fn unary(&mut self, unary: &'a ast::Unary) -> Result<()> {
+        match unary {
+            ast::Unary::Next(power) => self.power(power),
+            ast::Unary::Current { op, unary } => {
+                self.unary(unary)?;
+                self.emit(match op {
+                    ast::UnaryOp::Negate => Op::Negate,
+                    ast::UnaryOp::Not => Op::Not,
+                });
+                Ok(())
+            }
+        }
+    }
+
+    fn power(&mut self, power: &'a ast::Power) -> Result<()> {
+        match power {
+            ast::Power::Next(call) => self.call(call),
+            ast::Power::Current { base, exp } => {
+                self.call(base)?;
+                self.unary(exp)?;
+                self.emit(Op::Binary(BinOp::Pow));
+                Ok(())
+            }
+        }
+    }
+
+    fn call(&mut self, call: &'a ast::Call) -> Result<()> {
+        for jump in self.call_parts(&call.head, &call.tail)? {
+            self.patch(jump);
+        }
+        Ok(())
+    }
+
+    /// Compiles `head` followed by the parts of `tail`, returning the jumps of
+    /// `?.` to patch to the end of the chain.
+    fn call_parts(
+        &mut self,
+        head: &'a ast::Primary,
+        tail: &'a [ast::CallPart],
+    ) -> Result<Vec<usize>> {
+        self.primary(head)?;
+
+        let mut null_safe = Vec::new();
+        let mut parts = tail.iter().peekable();
+        while let Some(part) = parts.next() {
+            match part {
+                ast::CallPart::NullSafe => null_safe.push(self.emit_jump(Op::JumpIfNullKeep(0))),
+                ast::CallPart::Dot(ident) => match parts.peek() {
+                    Some(ast::CallPart::FunCall(fun_call)) => {
+                        parts.next();
+                        self.fun_call(fun_call, Some(*ident), false)?;
+                    }
+                    _ => {
+                        self.emit(Op::GetField(*ident));
+                    }
+                },
+                ast::CallPart::Path(ident) => {
+                    self.emit(Op::GetPath(*ident));
+                }
+                ast::CallPart::Brkts(expr) => {
+                    self.expr(expr)?;
+                    self.emit(Op::Index);
+                }
+                ast::CallPart::FunCall(fun_call) => self.fun_call(fun_call, None, false)?,
+                ast::CallPart::QMark => {
+                    if matches!(self.fun().proto.kind, ProtoKind::Script | ProtoKind::Module) {
+                        return Err(Error::QMarkOutsideFunction);
+                    }
+                    // On failure `Try` falls through to the early return.
+                    let depth = self.fun().depth;
+                    let success = self.emit_jump(Op::Try(0));
+                    self.emit_return()?;
+                    self.fun().depth = depth;
+                    self.patch(success);
+                }
+            }
+        }
+        Ok(null_safe)
+    }
+
+    /// Calls the value on top of the stack, or its method `method`. If
+    /// `piped` is set, the value piped into the call is already pushed as its
+    /// first argument.
+    fn fun_call(
+        &mut self,
+        fun_call: &'a ast::FunCall,
+        method: Option<IntStr>,
+        piped: bool,
+    ) -> Result<()> {
+        let mut shape = Vec::new();
+        if piped {
+            shape.push(ArgKind::Positional);
+        }
+        for arg in &fun_call.args {
+            match arg {
+                ast::Arg::Positional(expr) => {
+                    shape.push(ArgKind::Positional);
+                    self.expr(expr)?;
+                }
+                ast::Arg::Named { ident, expr, .. } => {
+                    shape.push(ArgKind::Named(*ident));
+                    self.expr(expr)?;
+                }
+                ast::Arg::Spread(expr) => {
+                    shape.push(ArgKind::Spread);
+                    self.expr(expr)?;
+                }
+            }
+        }
+
+        // Calls are located from the start of the expression they are in.
+        let span = Span::new(self.span.start, self.absolute(fun_call.span).end);
+        let op = if shape.iter().all(|kind| *kind == ArgKind::Positional) {
+            let argc = shape.len() as u32;
+            match method {
+                Some(ident) => Op::Invoke(ident, argc),
+                None => Op::Call(argc),
+            }
+        } else {
+            let shapes = &mut self.fun().proto.arg_shapes;
+            shapes.push(shape);
+            let shape = shapes.len() as u32 - 1;
+            match method {
+                Some(ident) => Op::InvokeArgs(ident, shape),
+                None => Op::CallArgs(shape),
+            }
+        };
+        self.emit_spanned(op, span);
+        Ok(())
+    }
+
+    fn primary(&mut self, primary: &'a ast::Primary) -> Result<()> {
+        match primary {
+            ast::Primary::SelfKw => self.get_self()?,
+            ast::Primary::Prnth(expr) => self.expr(expr)?,
+            ast::Primary::Ident(ident) => self.get_var(*ident),
+            ast::Primary::For(for_loop) => self.for_loop(for_loop)?,
+            ast::Primary::While(while_loop) => self.while_loop(while_loop)?,
+            ast::Primary::Loop(loop_loop) => self.loop_loop(loop_loop)?,
+            ast::Primary::If(if_expr) => self.if_expr(if_expr)?,
+            ast::Primary::Try(try_expr) => self.try_expr(try_expr)?,
+            ast::Primary::Yield(expr) => {
+                if matches!(self.fun().proto.kind, ProtoKind::Script | ProtoKind::Module) {
+                    return Err(Error::YieldOutsideFunction);
+                }
+                self.expr(expr)?;
+                self.fun().proto.generator = true;
+                self.emit(Op::Yield);
+                // `yield` itself evaluates to null once resumed.
+                self.emit(Op::Null);
+            }
+            ast::Primary::Closure(closure) => {
+                let proto = self.closure(closure)?;
+                self.emit(Op::Closure(proto));
+            }
+            ast::Primary::Block(block) => self.block(block)?,
+            ast::Primary::Literal(literal) => self.literal(literal)?,
+        }
+        Ok(())
+    }
+
+    fn begin_loop(&mut self) {
+        let fun = self.fun();
+        let depth = fun.depth;
+        let tries = fun.tries.len();
+        fun.loops.push(LoopState {
+            depth,
+            tries,
+            breaks: Vec::new(),
+        });
+    }
+
+    /// Patches the breaks of the innermost loop, which leave their value at
+    /// the loop's depth.
+    fn end_loop(&mut self) {
+        let state = self.fun().loops.pop().unwrap();
+        for jump in state.breaks {
+            self.patch(jump);
+        }
+        self.fun().depth = state.depth + 1;
+    }
+
+    fn for_loop(&mut self, for_loop: &'a ast::For) -> Result<()> {
+        self.begin_loop();
+        self.expr(&for_loop.expr)?;
+        self.emit(Op::Iter);
+
+        let start = self.fun().proto.code.len() as u32;
+        let exit = self.emit_jump(Op::ForIter(0));
+        self.begin_scope();
+        self.fun().scopes.pop();
+        let depth = self.fun().depth;
+        self.fun().scopes.push(depth - 1);
+        self.define(for_loop.ident);
+        self.block(&for_loop.block)?;
+        self.emit(Op::Pop);
+        self.end_scope(false);
+        self.emit(Op::Jump(start));
+
+        self.patch(exit);
+        self.emit(Op::Pop);
+        self.emit(Op::Null);
+        self.end_loop();
+        Ok(())
+    }
+
+    fn while_loop(&mut self, while_loop: &'a ast::While) -> Result<()> {
+        self.begin_loop();
+        let start = self.fun().proto.code.len() as u32;
+        self.expr(&while_loop.cond)?;
+        let exit = self.emit_jump(Op::JumpIfFalse(0));
+        self.block(&while_loop.block)?;
+        self.emit(Op::Pop);
+        self.emit(Op::Jump(start));
+        self.patch(exit);
+        self.emit(Op::Null);
+        self.end_loop();
+        Ok(())
+    }
+
+    fn loop_loop(&mut self, loop_loop: &'a ast::Loop) -> Result<()> {
+        self.begin_loop();
+        let start = self.fun().proto.code.len() as u32;
+        self.block(&loop_loop.block)?;
+        self.emit(Op::Pop);
+        self.emit(Op::Jump(start));
+        self.end_loop();
+        Ok(())
+    }
+
+    fn if_expr(&mut self, if_expr: &'a ast::If) -> Result<()> {
+        let mut ends = Vec::new();
+        for branch in &if_expr.branches {
+            ends.push(self.if_branch(branch)?);
+        }
+        match &if_expr.els {
+            Some(block) => self.block(block)?,
+            None => {
+                self.emit(Op::Null);
+            }
+        }
+        for end in ends {
+            self.patch(end);
+        }
+        Ok(())
+    }
+
+    /// Compiles a branch of an `if`, returning the jump past the whole `if`
+    /// taken after its block. Nothing is left on the stack when the branch
+    /// is not taken.
+    fn if_branch(&mut self, branch: &'a ast::IfBranch) -> Result<usize> {
+        let pattern = match &branch.pattern {
+            Some(pattern) => pattern,
+            None => {
+                self.expr(&branch.cond)?;
+                let els = self.emit_jump(Op::JumpIfFalse(0));
+                self.block(&branch.block)?;
+                let end = self.emit_jump(Op::Jump(0));
+                self.patch(els);
+                self.fun().depth -= 1;
+                return Ok(end);
+            }
+        };
+
+        let (kind, fields) = variant_fields(&pattern.fields);
+        let patterns = &mut self.fun().proto.patterns;
+        patterns.push(PatternDesc {
+            variant: pattern.variant,
+            kind,
+            fields: fields.clone(),
+        });
+        let pattern_idx = patterns.len() as u32 - 1;
+
+        self.begin_scope();
+        self.expr(&branch.cond)?;
+        self.get_var(pattern.ident);
+        let els = self.emit_jump(Op::MatchVariant(pattern_idx, 0));
+        let first = self.fun().depth - fields.len();
+        for (idx, &field) in fields.iter().enumerate() {
+            self.fun().locals.push(Local {
+                ident: field,
+                slot: first + idx,
+            });
+        }
+        self.block_inner(&branch.block)?;
+        self.end_scope(true);
+        let end = self.emit_jump(Op::Jump(0));
+
+        // Only the matched value is left when the pattern does not match.
+        self.patch(els);
+        self.emit(Op::Pop);
+        Ok(end)
+    }
+
+    fn try_expr(&mut self, try_expr: &'a ast::Try) -> Result<()> {
+        let depth = self.fun().depth;
+        let finally = try_expr.finally.as_ref();
+
+        let handler = self.emit_jump(Op::PushHandler(0));
+        self.fun().tries.push(TryState {
+            finally,
+            handler: true,
+        });
+        self.block(&try_expr.block)?;
+        self.fun().tries.pop();
+        self.emit(Op::PopHandler);
+        let end = self.emit_jump(Op::Jump(0));
+
+        // A handler leaves the thrown value where the value of the block
+        // would have been. Without a `catch` it goes straight to rethrowing.
+        let rethrow = match &try_expr.catch {
+            Some(catch) => {
+                self.patch(handler);
+                let rethrow = match finally {
+                    Some(_) => {
+                        self.fun().tries.push(TryState {
+                            finally,
+                            handler: true,
+                        });
+                        Some((self.emit_jump(Op::PushHandler(0)), depth + 2))
+                    }
+                    None => None,
+                };
+                self.fun().scopes.push(depth);
+                self.define(catch.ident);
+                self.block(&catch.block)?;
+                self.end_scope(true);
+                if rethrow.is_some() {
+                    self.fun().tries.pop();
+                    self.emit(Op::PopHandler);
+                }
+                rethrow
+            }
+            None => Some((handler, depth + 1)),
+        };
+        self.patch(end);
+
+        let (rethrow, rethrow_depth) = match (finally, rethrow) {
+            (Some(_), Some(rethrow)) => rethrow,
+            _ => return Ok(()),
+        };
+        let finally = finally.unwrap();
+        self.block(finally)?;
+        self.emit(Op::Pop);
+        let done = self.emit_jump(Op::Jump(0));
+
+        self.patch(rethrow);
+        self.fun().depth = rethrow_depth;
+        self.block(finally)?;
+        self.emit(Op::Pop);
+        self.emit(Op::Throw);
+        self.patch(done);
+        self.fun().depth = depth + 1;
+        Ok(())
+    }
+
+    fn literal(&mut self, literal: &'a ast::Literal) -> Result<()> {
+        match literal {
+            ast::Literal::Bool(true) => {
+                self.emit(Op::True);
+            }
+            ast::Literal::Bool(false) => {
+                self.emit(Op::False);
+            }
+            ast::Literal::Null => {
+                self.emit(Op::Null);
+            }
+            ast::Literal::Int(i) => {
+                let idx = self.constant(Const::Int(*i));
+                self.emit(Op::Const(idx));
+            }
+            ast::Literal::BigInt(digits) => {
+                let idx = self.constant(Const::BigInt(*digits));
+                self.emit(Op::Const(idx));
+            }
+            ast::Literal::Float(f) => {
+                let idx = self.constant(Const::Float(*f));
+                self.emit(Op::Const(idx));
+            }
+            ast::Literal::Char(c) => {
+                let idx = self.constant(Const::Char(*c));
+                self.emit(Op::Const(idx));
+            }
+            ast::Literal::Str(s) => {
+                let idx = self.constant(Const::Str(*s));
+                self.emit(Op::Const(idx));
+            }
+            ast::Literal::Struct(struct_lit) => {
+                self.get_var(struct_lit.ident);
+                for (_, expr) in &struct_lit.fields {
+                    self.expr(expr)?;
+                }
+                let names = struct_lit.fields.iter().map(|(ident, _)| *ident).collect();
+                let names = self.names(names);
+                match struct_lit.variant {
+                    Some(variant) => self.emit(Op::NewVariant(variant, names)),
+                    None => self.emit(Op::NewInstance(names)),
+                };
+            }
+            ast::Literal::Map(map_lit) => {
+                for (key, value) in &map_lit.fields {
+                    self.expr(key)?;
+                    self.expr(value)?;
+                }
+                self.emit(Op::NewMap(map_lit.fields.len() as u32));
+            }
+            ast::Literal::Set(set_lit) => {
+                for elem in &set_lit.elems {
+                    self.expr(elem)?;
+                }
+                self.emit(Op::NewSet(set_lit.elems.len() as u32));
+            }
+            ast::Literal::Array(array_lit) => {
+                for elem in &array_lit.elems {
+                    self.expr(elem)?;
+                }
+                self.emit(Op::NewArray(array_lit.elems.len() as u32));
+            }
+            ast::Literal::Tuple(tuple_lit) => {
+                for elem in &tuple_lit.elems {
+                    self.expr(elem)?;
+                }
+                self.emit(Op::NewTuple(tuple_lit.elems.len() as u32));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn variant_fields(fields: &ast::VariantFields) -> (VariantKind, Vec<IntStr>) {
+    match fields {
+        ast::VariantFields::Unit => (VariantKind::Unit, Vec::new()),
+        ast::VariantFields::Tuple(fields) => (VariantKind::Tuple, fields.clone()),
+        ast::VariantFields::Struct(fields) => (VariantKind::Struct, fields.clone()),
+    }
+}
+
+fn cmp_op(op: CmpOp) -> BinOp {
+    match op {
+        CmpOp::Eq => BinOp::Eq,
+        CmpOp::NotEq => BinOp::NotEq,
+        CmpOp::Less => BinOp::Less,
+        CmpOp::Greater => BinOp::Greater,
+        CmpOp::LessEq => BinOp::LessEq,
+        CmpOp::GreaterEq => BinOp::GreaterEq,
+    }
+}
+
+fn assign_op(assign: Assign) -> Option<BinOp> {
+    match assign {
+        Assign::Assign => None,
+        Assign::Add => Some(BinOp::Add),
+        Assign::Sub => Some(BinOp::Sub),
+        Assign::Mul => Some(BinOp::Mul),
+        Assign::Div => Some(BinOp::Div),
+        Assign::Mod => Some(BinOp::Mod),
+        Assign::Pow => Some(BinOp::Pow),
+        Assign::BitAnd => Some(BinOp::BitAnd),
+        Assign::BitOr => Some(BinOp::BitOr),
+        Assign::BitXor => Some(BinOp::BitXor),
+        Assign::LeftShift => Some(BinOp::Shl),
+        Assign::RightShift => Some(BinOp::Shr),
+    }
+}
@@ -1,20 +1,187 @@
-use crate::str_interner::IntStr;
-use crate::token::Token;
+use crate::capability::Capability;
+use crate::str_interner::{IntStr, Interner};
+use crate::token::{Span, Token};
+use crate::value::Value;
 use std::num;
+use std::ops::Range;
 use std::result::Result as StdResult;
 
 #[derive(Debug)]
 pub enum Error {
     UnexpectedCharacter(char),
     ParseFloatError(num::ParseFloatError),
-    ParseIntError(num::ParseIntError),
     UnclosedStringLiteral,
     UnclosedCharLiteral,
     EmptyCharLiteral,
     InvalidEscapeSequence,
+    /// Tuple field with a leading zero, as in `t.01`.
+    InvalidTupleIndex,
     UnexpectedToken(Token),
+    /// Edit of a document whose byte range is reversed, goes past the end
+    /// or splits a char.
+    InvalidEditRange(Range<usize>),
     MethodDefinedTwice(IntStr),
+    VariantDefinedTwice(IntStr),
+    FieldDefinedTwice(IntStr),
     UnassignableExpression,
+    ParamDefinedTwice(IntStr, Span),
+    RequiredParamAfterDefault(IntStr, Span),
+    RestParamNotLast(Span),
+    PositionalArgAfterNamed(Span),
+    ArgGivenTwice(IntStr, Span),
+    UnknownArgName(IntStr, Span),
+    TooManyArgs {
+        expected: usize,
+        found: usize,
+        span: Span,
+    },
+    MissingArg(IntStr, Span),
+    BreakOutsideLoop,
+    ReturnOutsideFunction,
+    SelfOutsideMethod,
+    QMarkOutsideFunction,
+    YieldOutsideFunction,
+    UndefinedVariable(IntStr),
+    UndefinedField(IntStr),
+    MissingField(IntStr),
+    UndefinedMethod(IntStr),
+    UndefinedVariant(IntStr),
+    MissingTraitMethod {
+        trait_ident: IntStr,
+        method: IntStr,
+    },
+    InvalidOperands(&'static str),
+    TypeError(&'static str),
+    IndexOutOfRange(i64),
+    DivisionByZero,
+    IntegerOverflow,
+    NegativeShift(i64),
+    /// Value that cannot be a map key or set element, described by the
+    /// message.
+    UnhashableKey(&'static str),
+    /// Reference to a module of host functions needing a capability that is
+    /// not granted, or call of one of its functions.
+    CapabilityDenied(IntStr, Capability, Span),
+    /// Source nested deeper than the parser's limit.
+    NestingTooDeep,
+    /// Value nested deeper than `vm::MAX_VALUE_DEPTH`, compared or hashed.
+    ValueTooDeep,
+    StepLimitExceeded,
+    DeadlineExceeded,
+    CallDepthExceeded,
+    HeapLimitExceeded,
+    /// String longer than the limit `max`, with the length it would have had.
+    StringTooLong {
+        len: usize,
+        max: usize,
+    },
+    /// Array, tuple, map or set longer than the limit `max`, with the length
+    /// it would have had.
+    ArrayTooLong {
+        len: usize,
+        max: usize,
+    },
+    /// Value thrown by a script and not caught.
+    Thrown(Value),
+}
+
+impl Error {
+    /// Describes the error, looking names up in `interner`.
+    pub fn message(&self, interner: &Interner) -> String {
+        let name = |ident: &IntStr| interner.lookup(*ident).unwrap_or("?");
+        match self {
+            Error::UnexpectedCharacter(c) => format!("unexpected character {:?}", c),
+            Error::ParseFloatError(err) => format!("invalid float literal: {}", err),
+            Error::UnclosedStringLiteral => "unclosed string literal".to_owned(),
+            Error::UnclosedCharLiteral => "unclosed char literal".to_owned(),
+            Error::EmptyCharLiteral => "empty char literal".to_owned(),
+            Error::InvalidEscapeSequence => "invalid escape sequence".to_owned(),
+            Error::InvalidTupleIndex => "invalid tuple index".to_owned(),
+            Error::UnexpectedToken(token) => format!("unexpected token {:?}", token),
+            Error::InvalidEditRange(range) => {
+                format!("invalid edit range {}..{}", range.start, range.end)
+            }
+            Error::MethodDefinedTwice(ident) => format!("method `{}` defined twice", name(ident)),
+            Error::VariantDefinedTwice(ident) => {
+                format!("variant `{}` defined twice", name(ident))
+            }
+            Error::FieldDefinedTwice(ident) => format!("field `{}` defined twice", name(ident)),
+            Error::UnassignableExpression => "expression cannot be assigned to".to_owned(),
+            Error::ParamDefinedTwice(ident, _) => {
+                format!("parameter `{}` defined twice", name(ident))
+            }
+            Error::RequiredParamAfterDefault(ident, _) => format!(
+                "parameter `{}` without a default follows one with a default",
+                name(ident)
+            ),
+            Error::RestParamNotLast(_) => "rest parameter must come last".to_owned(),
+            Error::PositionalArgAfterNamed(_) => {
+                "positional argument follows a named one".to_owned()
+            }
+            Error::ArgGivenTwice(ident, _) => format!("argument `{}` given twice", name(ident)),
+            Error::UnknownArgName(ident, _) => format!("no parameter named `{}`", name(ident)),
+            Error::TooManyArgs {
+                expected, found, ..
+            } => format!(
+                "expected at most {} argument{}, found {}",
+                expected,
+                if *expected == 1 { "" } else { "s" },
+                found
+            ),
+            Error::MissingArg(ident, _) => format!("missing argument `{}`", name(ident)),
+            Error::BreakOutsideLoop => "`break` outside of a loop".to_owned(),
+            Error::ReturnOutsideFunction => "`return` outside of a function".to_owned(),
+            Error::SelfOutsideMethod => "`self` outside of a method".to_owned(),
+            Error::QMarkOutsideFunction => "`?` outside of a function".to_owned(),
+            Error::YieldOutsideFunction => "`yield` outside of a function".to_owned(),
+            Error::UndefinedVariable(ident) => format!("undefined variable `{}`", name(ident)),
+            Error::UndefinedField(ident) => format!("undefined field `{}`", name(ident)),
+            Error::MissingField(ident) => format!("missing field `{}`", name(ident)),
+            Error::UndefinedMethod(ident) => format!("undefined method `{}`", name(ident)),
+            Error::UndefinedVariant(ident) => format!("undefined variant `{}`", name(ident)),
+            Error::MissingTraitMethod {
+                trait_ident,
+                method,
+            } => format!(
+                "missing method `{}` of trait `{}`",
+                name(method),
+                name(trait_ident)
+            ),
+            Error::InvalidOperands(op) => format!("invalid operands for `{}`", op),
+            Error::TypeError(message) => message.to_string(),
+            Error::IndexOutOfRange(idx) => format!("index {} out of range", idx),
+            Error::DivisionByZero => "division by zero".to_owned(),
+            Error::IntegerOverflow => "integer overflow".to_owned(),
+            Error::NegativeShift(amount) => format!("negative shift amount {}", amount),
+            Error::UnhashableKey(what) => format!("{} cannot be a map key", what),
+            Error::CapabilityDenied(ident, cap, _) => format!(
+                "`{}` needs the {} capability, which is not granted",
+                name(ident),
+                cap
+            ),
+            Error::NestingTooDeep => "expression nested too deeply".to_owned(),
+            Error::ValueTooDeep => "value nested too deeply".to_owned(),
+            Error::StepLimitExceeded => "step limit exceeded".to_owned(),
+            Error::DeadlineExceeded => "time limit exceeded".to_owned(),
+            Error::CallDepthExceeded => "call depth limit exceeded".to_owned(),
+            Error::HeapLimitExceeded => "heap limit exceeded".to_owned(),
+            Error::StringTooLong { len, max } => {
+                format!("string of length {} exceeds the limit of {}", len, max)
+            }
+            Error::ArrayTooLong { len, max } => {
+                format!("collection of length {} exceeds the limit of {}", len, max)
+            }
+            Error::Thrown(_) => "uncaught exception".to_owned(),
+        }
+    }
+
+    /// Whether the error stops the script even inside a `try` block.
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            Error::StepLimitExceeded | Error::DeadlineExceeded | Error::HeapLimitExceeded
+        )
+    }
 }
 
 pub type Result<T> = StdResult<T, Error>;
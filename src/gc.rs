@@ -0,0 +1,178 @@
+//! The `gc` module: `gc.collect` and `gc.stats`.
+
+use crate::error::{Error, Result};
+use crate::value::{Map, NativeFn, Object, Value};
+use crate::vm::Vm;
+
+const FUNCTIONS: &[(&str, NativeFn)] = &[("collect", collect), ("stats", stats)];
+
+/// Adds the global module `gc` to `vm`.
+pub fn define(vm: &mut Vm) {
+    vm.define_module("gc", FUNCTIONS);
+}
+
+/// Collects garbage, returning the number of objects freed, or null when
+/// called back from a native function, which postpones the collection.
+fn collect(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    if !args.is_empty() {
+        return Err(Error::TypeError("too many arguments"));
+    }
+    Ok(vm
+        .collect()
+        .map_or(Value::NULL, |freed| Value::int(freed as i64)))
+}
+
+/// Map of the number of `collections` so far, of objects `freed` by them,
+/// and of the `objects` and estimated `bytes` of the heap.
+fn stats(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    if !args.is_empty() {
+        return Err(Error::TypeError("too many arguments"));
+    }
+    let stats = vm.gc_stats();
+    let map = vm.heap_mut().alloc(Object::Map(Map::default()));
+    for (key, count) in [
+        ("collections", stats.collections),
+        ("freed", stats.freed),
+        ("objects", stats.objects as u64),
+        ("bytes", stats.bytes as u64),
+    ] {
+        let key = vm.alloc_str(key.to_owned());
+        vm.map_insert(map, key, Value::int(count as i64))?;
+    }
+    Ok(Value::obj(map))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::eval_in;
+
+    #[test]
+    fn test_cycles_are_freed() {
+        let mut vm = Vm::new();
+        eval_in(
+            &mut vm,
+            "struct Node {
+                fn link(other) { self.next = other; other.prev = self; }
+                fn callback() { || self }
+            }
+            fn garbage(n) {
+                for i in range(0, n) {
+                    let a = new Node {};
+                    let b = new Node {};
+                    a.link(b);
+                    b.link(a);
+                    a.cb = a.callback();
+                    let m = map {};
+                    m[\"self\"] = m;
+                    let arr = [a, m];
+                    arr.push(arr);
+                };
+            }
+            garbage(10);",
+        );
+        vm.collect();
+        let before = vm.gc_stats();
+        eval_in(&mut vm, "garbage(1000);");
+        assert!(vm.collect().unwrap() >= 5000);
+        assert_eq!(before.objects, vm.gc_stats().objects);
+        assert_eq!(before.bytes, vm.gc_stats().bytes);
+    }
+
+    #[test]
+    fn test_reachable_values_survive() {
+        let mut vm = Vm::new();
+        eval_in(
+            &mut vm,
+            "struct Node { fn sum() { self.value + (self.next?.value ?? 0) } }
+            fn counter() { let n = 0; || { n += 1; n } }
+            fn numbers() { let i = 0; loop { yield [i]; i += 1; } }
+            let nodes = [];
+            for i in range(0, 100) { nodes.push(new Node { value: i }); };
+            for i in range(0, 100) { nodes[i].next = nodes[(i + 1) % 100]; };
+            let count = counter();
+            count();
+            let gen = numbers();
+            gen.next();
+            let keys = map { new Node { value: -1 }: \"key\", (1, \"a\"): set { (2,) } };",
+        );
+        assert!(vm.collect().unwrap() > 0);
+        assert_eq!(
+            "[198, 2, [1], 2, 2]",
+            eval_in(
+                &mut vm,
+                "gc.collect();
+                [nodes[99].next.sum() + nodes[98].sum(), count(), gen.next(),
+                    keys.len(), keys[(1, \"a\")].len() + [\"x\"].len()];"
+            )
+        );
+    }
+
+    #[test]
+    fn test_automatic_collection() {
+        let mut vm = Vm::new();
+        eval_in(
+            &mut vm,
+            "struct Pair {}
+            for i in range(0, 50000) {
+                let a = new Pair { data: [i, i, i, i] };
+                let b = new Pair { other: a };
+                a.other = b;
+            };",
+        );
+        let stats = vm.gc_stats();
+        assert!(stats.collections > 0, "{:?}", stats);
+        assert!(stats.bytes < 4 << 20, "{:?}", stats);
+        // Collections do not disturb a heap limit the live values fit in.
+        let mut vm = Vm::new();
+        vm.set_limits(crate::limits::Limits {
+            max_heap_bytes: Some(2 << 20),
+            ..Default::default()
+        });
+        assert_eq!(
+            "50000",
+            eval_in(
+                &mut vm,
+                "let n = 0; for i in range(0, 50000) { let a = [i, i, i]; n += 1; }; n;"
+            )
+        );
+    }
+
+    #[test]
+    fn test_handles_and_callbacks() {
+        let mut vm = Vm::new();
+        let value = vm.eval("let t = (1, [2]); t;").unwrap();
+        let handle = vm.handle(value);
+        vm.eval("t = null;").unwrap();
+        vm.collect();
+        assert_eq!("(1, [2])", vm.display(vm.handle_value(&handle)));
+        vm.release(handle);
+        assert!(vm.collect().unwrap() >= 2);
+
+        // The value last returned to the host survives until the next one.
+        let value = vm.eval("[3, [4]];").unwrap();
+        vm.eval("gc.collect(); null;").unwrap();
+        assert_eq!("[3, [4]]", vm.display(value));
+        let thrown = match vm.eval("throw [5];") {
+            Err(Error::Thrown(value)) => value,
+            result => panic!("{:?}", result),
+        };
+        vm.collect();
+        assert_eq!("[5]", vm.display(thrown));
+        vm.eval("null;").unwrap();
+        assert!(vm.collect().unwrap() >= 1);
+
+        // Natives calling back postpone collections until they return.
+        assert_eq!(
+            "[null, null]",
+            eval_in(&mut vm, "[1, 2].map(|x| gc.collect());")
+        );
+        let collections = vm.gc_stats().collections;
+        eval_in(&mut vm, "[1].map(|x| gc.collect()); null;");
+        assert_eq!(collections + 1, vm.gc_stats().collections);
+        assert_eq!(
+            "[\"collections\", \"freed\", \"objects\", \"bytes\"]",
+            eval_in(&mut vm, "gc.stats().keys();")
+        );
+    }
+}
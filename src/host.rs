@@ -0,0 +1,314 @@
+//! Modules of functions with access to the host: `fs`, `time`, `random`,
+//! `env` and `process`. Each needs a capability, which `Vm::set_capabilities`
+//! may withhold from scripts.
+
+use crate::capability::Capability;
+use crate::error::{Error, Result};
+use crate::value::{Map, NativeFn, Object, Unpacked, Value};
+use crate::vm::Vm;
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::io::{self, Read};
+use std::process::Stdio;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{env, fs, process, thread};
+
+const FS: &[(&str, NativeFn)] = &[("read", read), ("write", write), ("exists", exists)];
+const TIME: &[(&str, NativeFn)] = &[("now", now)];
+const RANDOM: &[(&str, NativeFn)] = &[("float", random_float), ("int", random_int)];
+const ENV: &[(&str, NativeFn)] = &[("get", env_get), ("args", env_args)];
+const PROCESS: &[(&str, NativeFn)] = &[("id", process_id), ("run", run)];
+
+/// Adds the host modules to `vm`.
+pub fn define(vm: &mut Vm) {
+    vm.define_host_module("fs", Capability::FileIo, FS);
+    vm.define_host_module("time", Capability::Time, TIME);
+    vm.define_host_module("random", Capability::Random, RANDOM);
+    vm.define_host_module("env", Capability::Env, ENV);
+    vm.define_host_module("process", Capability::Process, PROCESS);
+}
+
+fn arity(args: &[Value], min: usize, max: usize) -> Result<()> {
+    if args.len() < min {
+        return Err(Error::TypeError("missing argument"));
+    }
+    if args.len() > max {
+        return Err(Error::TypeError("too many arguments"));
+    }
+    Ok(())
+}
+
+fn str_arg(vm: &Vm, arg: Value) -> Result<&str> {
+    vm.str(arg).ok_or(Error::TypeError("expected a string"))
+}
+
+/// Wraps the outcome of an I/O operation in a `Result`, with the message of
+/// the error for `Result::Err`.
+fn io_result(vm: &mut Vm, result: std::io::Result<Value>) -> Value {
+    match result {
+        Ok(value) => vm.ok(value),
+        Err(err) => {
+            let message = vm.alloc_str(err.to_string());
+            vm.err(message)
+        }
+    }
+}
+
+/// Reads all of `reader`, or only `limit` bytes and one more if it has more,
+/// so that the length limit can refuse a large input without loading it.
+fn read_limited(reader: impl Read, limit: Option<usize>) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let limit = limit.map_or(u64::MAX, |max| max as u64 + 1);
+    reader.take(limit).read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Reads a UTF-8 file into a string.
+fn read(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    arity(args, 1, 1)?;
+    let limit = vm.limits().max_str_len;
+    let file = fs::File::open(str_arg(vm, args[0])?);
+    let bytes = match file.and_then(|file| read_limited(file, limit)) {
+        Ok(bytes) => bytes,
+        Err(err) => return Ok(io_result(vm, Err(err))),
+    };
+    vm.check_str_len(bytes.len())?;
+    let result = String::from_utf8(bytes)
+        .map(|contents| vm.alloc_str(contents))
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "stream did not contain valid UTF-8",
+            )
+        });
+    Ok(io_result(vm, result))
+}
+
+/// Writes a string to a file, replacing its contents.
+fn write(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    arity(args, 2, 2)?;
+    let result = fs::write(str_arg(vm, args[0])?, str_arg(vm, args[1])?);
+    Ok(io_result(vm, result.map(|()| Value::NULL)))
+}
+
+fn exists(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    arity(args, 1, 1)?;
+    let path = str_arg(vm, args[0])?;
+    Ok(Value::bool(fs::exists(path).unwrap_or(false)))
+}
+
+/// Seconds since the Unix epoch.
+fn now(_: &mut Vm, args: &[Value]) -> Result<Value> {
+    arity(args, 0, 0)?;
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    Ok(Value::float(elapsed.as_secs_f64()))
+}
+
+thread_local! {
+    static RNG_STATE: Cell<u64> = Cell::new(RandomState::new().hash_one(0u64) | 1);
+}
+
+/// Next number of a xorshift64* generator, which is not suitable for
+/// cryptography.
+fn next_random() -> u64 {
+    RNG_STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        state.set(x);
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    })
+}
+
+/// Float in `[0, 1)`.
+fn random_float(_: &mut Vm, args: &[Value]) -> Result<Value> {
+    arity(args, 0, 0)?;
+    Ok(Value::float(
+        (next_random() >> 11) as f64 / (1u64 << 53) as f64,
+    ))
+}
+
+/// Int in `[low, high)`.
+fn random_int(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    arity(args, 2, 2)?;
+    let (low, high) = match (vm.int(args[0]), vm.int(args[1])) {
+        (Some(low), Some(high)) => (low, high),
+        _ => return Err(Error::TypeError("bounds must be ints")),
+    };
+    if low >= high {
+        return Err(Error::TypeError("empty range"));
+    }
+    let span = high.abs_diff(low);
+    Ok(vm.int_value(low.wrapping_add((next_random() % span) as i64)))
+}
+
+/// Value of an environment variable, or null if it is not set.
+fn env_get(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    arity(args, 1, 1)?;
+    match env::var(str_arg(vm, args[0])?) {
+        Ok(value) => Ok(vm.alloc_str(value)),
+        Err(_) => Ok(Value::NULL),
+    }
+}
+
+/// Arguments the host process was started with.
+fn env_args(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    arity(args, 0, 0)?;
+    let args = env::args().map(|arg| vm.alloc_str(arg)).collect();
+    vm.alloc_array(args)
+}
+
+fn process_id(_: &mut Vm, args: &[Value]) -> Result<Value> {
+    arity(args, 0, 0)?;
+    Ok(Value::int(process::id().into()))
+}
+
+/// Runs a program with an array of string arguments and waits for it,
+/// returning its exit status and output as a map.
+fn run(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    arity(args, 1, 2)?;
+    let mut command = process::Command::new(str_arg(vm, args[0])?);
+    if let Some(&arg) = args.get(1) {
+        let elems = match arg.unpack() {
+            Unpacked::Obj(obj) => match vm.heap().get(obj) {
+                Object::Array(elems) => elems,
+                _ => return Err(Error::TypeError("arguments must be an array")),
+            },
+            _ => return Err(Error::TypeError("arguments must be an array")),
+        };
+        for &elem in elems {
+            command.arg(str_arg(vm, elem)?);
+        }
+    }
+    let limit = vm.limits().max_str_len;
+    let (status, stdout, stderr) = match output(&mut command, limit) {
+        Ok(output) => output,
+        Err(err) => return Ok(io_result(vm, Err(err))),
+    };
+    let map = vm.heap_mut().alloc(Object::Map(Map::default()));
+    let status = status
+        .code()
+        .map_or(Value::NULL, |code| Value::int(code.into()));
+    let key = vm.alloc_str("status".to_owned());
+    vm.map_insert(map, key, status)?;
+    for (key, bytes) in [("stdout", stdout), ("stderr", stderr)] {
+        vm.check_str_len(bytes.len())?;
+        let key = vm.alloc_str(key.to_owned());
+        let value = vm.alloc_str(String::from_utf8_lossy(&bytes).into_owned());
+        vm.map_insert(map, key, value)?;
+    }
+    Ok(vm.ok(Value::obj(map)))
+}
+
+/// Runs `command` to its end, returning its exit status and what it wrote to
+/// stdout and stderr, as read by `read_limited`. A program writing more than
+/// `limit` bytes to either is killed.
+fn output(
+    command: &mut process::Command,
+    limit: Option<usize>,
+) -> io::Result<(process::ExitStatus, Vec<u8>, Vec<u8>)> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let too_long = |bytes: &io::Result<Vec<u8>>| matches!((bytes, limit), (Ok(bytes), Some(max)) if bytes.len() > max);
+    // Both pipes are read at once, so that the program cannot block writing
+    // to a full one.
+    let stderr = child.stderr.take().unwrap();
+    let stderr = thread::spawn(move || read_limited(stderr, limit));
+    let stdout = read_limited(child.stdout.take().unwrap(), limit);
+    if too_long(&stdout) {
+        let _ = child.kill();
+    }
+    let stderr = stderr.join().unwrap();
+    if too_long(&stderr) {
+        let _ = child.kill();
+    }
+    let status = child.wait()?;
+    Ok((status, stdout?, stderr?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capability::Capabilities;
+    use crate::limits::Limits;
+    use crate::testing::eval_in;
+
+    fn host_vm() -> Vm {
+        let mut vm = Vm::new();
+        vm.set_capabilities(Capabilities::all());
+        vm
+    }
+
+    #[test]
+    fn test_fs() {
+        let mut vm = host_vm();
+        let path = env::temp_dir().join(format!("zrak-host-{}.txt", process::id()));
+        let path = path.to_str().unwrap();
+        assert_eq!(
+            "[Result::Ok(null), true, Result::Ok(\"hé\\n\")]",
+            eval_in(
+                &mut vm,
+                &format!(
+                    "let path = {:?}; [fs.write(path, \"hé\\n\"), fs.exists(path), fs.read(path)];",
+                    path
+                )
+            )
+        );
+        fs::remove_file(path).unwrap();
+        assert!(eval_in(&mut vm, &format!("fs.read({:?});", path)).starts_with("Result::Err("));
+    }
+
+    #[test]
+    fn test_length_limit() {
+        let mut vm = host_vm();
+        vm.set_limits(Limits {
+            max_str_len: Some(100),
+            ..Limits::default()
+        });
+        let path = env::temp_dir().join(format!("zrak-host-limit-{}.txt", process::id()));
+        fs::write(&path, "a".repeat(1000)).unwrap();
+        let source = format!("try {{ fs.read({:?}) }} catch e {{ e.message }};", path);
+        let message = eval_in(&mut vm, &source);
+        fs::remove_file(&path).unwrap();
+        assert_eq!("string of length 101 exceeds the limit of 100", message);
+
+        // Output that never ends is cut short too.
+        assert_eq!(
+            "[\"string of length 101 exceeds the limit of 100\", \
+            Result::Ok(map { \"status\": 0, \"stdout\": \"a\\n\", \"stderr\": \"\" })]",
+            eval_in(
+                &mut vm,
+                "[try { process.run(\"yes\") } catch e { e.message }, process.run(\"echo\", [\"a\"])];"
+            )
+        );
+    }
+
+    #[test]
+    fn test_random() {
+        let mut vm = host_vm();
+        assert_eq!(
+            "true",
+            eval_in(
+                &mut vm,
+                "let ok = true;
+                for i in range(0, 1000) {
+                    let n = random.int(-3, 3);
+                    let f = random.float();
+                    ok = ok && n >= -3 && n < 3 && f >= 0 && f < 1;
+                };
+                ok;"
+            )
+        );
+        assert!(matches!(
+            vm.eval("random.int(1, 1);"),
+            Err(Error::TypeError("empty range"))
+        ));
+    }
+}
@@ -0,0 +1,382 @@
+use crate::ast;
+use crate::error::{Error, Result};
+use crate::parser::Parser;
+use crate::scanner::Scanner;
+use crate::str_interner::Interner;
+use crate::token::{Delimiter, Span, Token};
+use std::mem;
+use std::ops::Range;
+
+/// Replacement of the bytes in `range` with `text`.
+#[derive(Debug, Clone)]
+pub struct Edit {
+    pub range: Range<usize>,
+    pub text: String,
+}
+
+/// Source text together with its most recent parse, updated in place as
+/// edits arrive.
+///
+/// Top-level declarations that an edit cannot have affected are reused as they
+/// are. An edit that stays within the body of a top-level function only
+/// reparses that body.
+#[derive(Debug)]
+pub struct Document {
+    source: String,
+    program: Option<ast::Program>,
+}
+
+impl Document {
+    pub fn new(source: String) -> Document {
+        Document {
+            source,
+            program: None,
+        }
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// The parse result, unless the source has not been parsed yet or failed
+    /// to parse after the last edit.
+    pub fn program(&self) -> Option<&ast::Program> {
+        self.program.as_ref()
+    }
+
+    /// Parses the whole source unless an up to date parse result exists.
+    pub fn parse(&mut self, interner: &mut Interner) -> Result<&ast::Program> {
+        if self.program.is_none() {
+            self.program = Some(Parser::new(Scanner::new(&self.source, interner)).program()?);
+        }
+        Ok(self.program().unwrap())
+    }
+
+    /// Applies `edit` to the source and updates the parse result, returning the
+    /// indices of the top-level declarations that had to be reparsed.
+    ///
+    /// On error the source keeps the edit and the next call reparses it fully,
+    /// unless the range of the edit is invalid, which leaves the document as
+    /// it was.
+    pub fn edit(&mut self, edit: &Edit, interner: &mut Interner) -> Result<Range<usize>> {
+        let range = &edit.range;
+        if range.start > range.end
+            || !self.source.is_char_boundary(range.start)
+            || !self.source.is_char_boundary(range.end)
+        {
+            return Err(Error::InvalidEditRange(range.clone()));
+        }
+        let old_source = mem::take(&mut self.source);
+        self.source = format!(
+            "{}{}{}",
+            &old_source[..edit.range.start],
+            edit.text,
+            &old_source[edit.range.end..]
+        );
+
+        let mut program = match self.program.take() {
+            Some(program) => program,
+            None => {
+                let program = Parser::new(Scanner::new(&self.source, interner)).program()?;
+                let len = program.decls.len();
+                self.program = Some(program);
+                return Ok(0..len);
+            }
+        };
+
+        let delta = edit.text.len() as isize - edit.range.len() as isize;
+
+        if let Some(idx) =
+            Self::reparse_body(&mut program, &old_source, &self.source, edit, interner)
+        {
+            program.spans[idx].end = shift(program.spans[idx].end, delta);
+            for span in &mut program.spans[idx + 1..] {
+                *span = shift_span(*span, delta);
+            }
+            self.program = Some(program);
+            return Ok(idx..idx + 1);
+        }
+
+        // The declaration right before the edit is reparsed as well since the
+        // edit may change the token it looked ahead at.
+        let affected = program
+            .spans
+            .iter()
+            .position(|span| span.end >= edit.range.start)
+            .unwrap_or(program.spans.len());
+        let first = affected.saturating_sub(1);
+        let start = match program.spans.get(first) {
+            Some(span) => span.start.min(edit.range.start),
+            None => 0,
+        };
+
+        let ast::Program {
+            mut decls,
+            mut spans,
+        } = program;
+        let old_spans = spans.split_off(first);
+        let mut old_decls = decls.split_off(first);
+
+        let mut parser = Parser::new(Scanner::new_at(&self.source, interner, start));
+        let mut old_idx = 0;
+        while let Some((decl, span)) = parser.next_decl()? {
+            decls.push(decl);
+            spans.push(span);
+
+            // Once a declaration ends where an old one ended after the edit,
+            // the rest of the source is unchanged and parses the same way.
+            while let Some(old) = old_spans.get(old_idx) {
+                if old.end < edit.range.end || shift(old.end, delta) < span.end {
+                    old_idx += 1;
+                } else {
+                    break;
+                }
+            }
+            if let Some(old) = old_spans.get(old_idx) {
+                if shift(old.end, delta) == span.end {
+                    let reparsed = first..decls.len();
+                    decls.extend(old_decls.drain(old_idx + 1..));
+                    spans.extend(
+                        old_spans[old_idx + 1..]
+                            .iter()
+                            .map(|&span| shift_span(span, delta)),
+                    );
+                    self.program = Some(ast::Program { decls, spans });
+                    return Ok(reparsed);
+                }
+            }
+        }
+
+        let reparsed = first..decls.len();
+        self.program = Some(ast::Program { decls, spans });
+        Ok(reparsed)
+    }
+
+    /// Reparses only the body of the top-level function containing `edit`, if
+    /// there is one and the edit leaves the braces around its body alone.
+    /// Returns the index of the updated declaration.
+    fn reparse_body(
+        program: &mut ast::Program,
+        old_source: &str,
+        new_source: &str,
+        edit: &Edit,
+        interner: &mut Interner,
+    ) -> Option<usize> {
+        let idx = program
+            .spans
+            .iter()
+            .position(|span| span.start <= edit.range.start && edit.range.end <= span.end)?;
+        let span = program.spans[idx];
+        let fun = match &mut program.decls[idx] {
+            ast::Decl::Fun(fun) => fun,
+            _ => return None,
+        };
+
+        let body_start = body_start(old_source, span, interner)?;
+        if edit.range.start <= body_start || edit.range.end >= span.end {
+            return None;
+        }
+
+        let delta = edit.text.len() as isize - edit.range.len() as isize;
+        let mut parser = Parser::new(Scanner::new_at(new_source, interner, body_start));
+        parser.set_decl_start(span.start);
+        let block = parser.block().ok()?;
+        if parser.last_end() != shift(span.end, delta) {
+            return None;
+        }
+
+        fun.block = block;
+        Some(idx)
+    }
+}
+
+/// Finds the opening brace of the body of the function declared in `span`.
+fn body_start(source: &str, span: Span, interner: &mut Interner) -> Option<usize> {
+    let mut scanner = Scanner::new_at(source, interner, span.start);
+    let mut depth = 0;
+    loop {
+        match scanner.get_next().ok()? {
+            Token::Delimiter(Delimiter::OpenPrnth) => depth += 1,
+            Token::Delimiter(Delimiter::ClosePrnth) => {
+                depth -= 1;
+                if depth == 0 {
+                    break;
+                }
+            }
+            Token::Eof => return None,
+            _ => (),
+        }
+    }
+
+    match scanner.get_next().ok()? {
+        Token::Delimiter(Delimiter::OpenCurly) => Some(scanner.last_span().start),
+        _ => None,
+    }
+}
+
+fn shift(offset: usize, delta: isize) -> usize {
+    (offset as isize + delta) as usize
+}
+
+fn shift_span(span: Span, delta: isize) -> Span {
+    Span::new(shift(span.start, delta), shift(span.end, delta))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "use std::io;
+let x = 1;
+fn add(a, b) {
+    let c = a + b;
+    c
+}
+struct Point {
+    fn len() { self.x * self.x + self.y * self.y }
+}
+x = add(x, 2);
+";
+
+    fn full_parse(source: &str, interner: &mut Interner) -> ast::Program {
+        Parser::new(Scanner::new(source, interner))
+            .program()
+            .unwrap()
+    }
+
+    fn check_edit(start: &str, end: &str, text: &str) -> Range<usize> {
+        let mut interner = Interner::new();
+        let mut doc = Document::new(SOURCE.to_owned());
+        doc.parse(&mut interner).unwrap();
+
+        let start = SOURCE.find(start).unwrap();
+        let end = start + SOURCE[start..].find(end).unwrap();
+        let edit = Edit {
+            range: start..end,
+            text: text.to_owned(),
+        };
+        let reparsed = doc.edit(&edit, &mut interner).unwrap();
+
+        let expected = full_parse(doc.source(), &mut interner);
+        assert_eq!(&expected, doc.program().unwrap());
+        reparsed
+    }
+
+    #[test]
+    fn test_edit_inside_function_body() {
+        assert_eq!(2..3, check_edit("a + b", ";", "a * b - 3"));
+    }
+
+    #[test]
+    fn test_edit_adds_statement_to_function_body() {
+        assert_eq!(2..3, check_edit("    c\n", "    c\n", "    let d = 4;\n"));
+    }
+
+    #[test]
+    fn test_edit_top_level_decl() {
+        assert_eq!(0..2, check_edit("1;", ";", "[1, 2, 3]"));
+    }
+
+    #[test]
+    fn test_edit_function_signature() {
+        assert_eq!(1..3, check_edit("add(a, b)", " {", "add(a, b, c)"));
+    }
+
+    #[test]
+    fn test_edit_inserts_decls() {
+        assert_eq!(
+            2..6,
+            check_edit("struct", "struct", "let y = 2;\nlet z = y;\n")
+        );
+    }
+
+    #[test]
+    fn test_edit_removes_decls() {
+        assert_eq!(0..2, check_edit("let x", "fn add", ""));
+    }
+
+    #[test]
+    fn test_edit_breaks_decls() {
+        let mut interner = Interner::new();
+        let mut doc = Document::new("let a = 1;\nlet b = 2;\nlet c = 3;\n".to_owned());
+        doc.parse(&mut interner).unwrap();
+        let edit = Edit {
+            range: 9..15,
+            text: " + ".to_owned(),
+        };
+        assert!(doc.edit(&edit, &mut interner).is_err());
+        assert_eq!("let a = 1 + b = 2;\nlet c = 3;\n", doc.source());
+        assert!(doc.program().is_none());
+    }
+
+    #[test]
+    fn test_invalid_edit_range() {
+        let mut interner = Interner::new();
+        let mut doc = Document::new("let s = \"é\";".to_owned());
+        doc.parse(&mut interner).unwrap();
+        for range in [Range { start: 3, end: 2 }, 10..11, 0..100] {
+            let edit = Edit {
+                range,
+                text: String::new(),
+            };
+            assert!(matches!(
+                doc.edit(&edit, &mut interner),
+                Err(Error::InvalidEditRange(_))
+            ));
+        }
+        assert_eq!("let s = \"é\";", doc.source());
+        assert!(doc.program().is_some());
+    }
+
+    #[test]
+    fn test_error_spans_are_absolute() {
+        let mut interner = Interner::new();
+        let mut doc = Document::new(SOURCE.to_owned());
+        doc.parse(&mut interner).unwrap();
+        let start = SOURCE.find("a + b").unwrap();
+        let edit = Edit {
+            range: start..start + 5,
+            text: "f(a: 1, a: 2)".to_owned(),
+        };
+        match doc.edit(&edit, &mut interner) {
+            Err(Error::ArgGivenTwice(_, span)) => assert_eq!(start + 8, span.start),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_edit_at_end() {
+        assert_eq!(
+            3..6,
+            check_edit("x = add", "\n", "x = add(x, 2);\nlet w = x;")
+        );
+    }
+
+    #[test]
+    fn test_edit_unbalances_function_body() {
+        check_edit("    c\n", "}", "    c\n}\nfn sub(a, b) { a - b ");
+    }
+
+    #[test]
+    fn test_recovers_after_error() {
+        let mut interner = Interner::new();
+        let mut doc = Document::new(SOURCE.to_owned());
+        doc.parse(&mut interner).unwrap();
+
+        let start = SOURCE.find("x = add").unwrap();
+        let broken = Edit {
+            range: start..start,
+            text: "fn ".to_owned(),
+        };
+        assert!(doc.edit(&broken, &mut interner).is_err());
+        assert!(doc.program().is_none());
+
+        let fixed = Edit {
+            range: start..start + 3,
+            text: String::new(),
+        };
+        doc.edit(&fixed, &mut interner).unwrap();
+        let expected = full_parse(SOURCE, &mut interner);
+        assert_eq!(&expected, doc.program().unwrap());
+    }
+}
@@ -0,0 +1,599 @@
+//! The `json` module: `json.parse` and `json.stringify`.
+//!
+//! JSON objects become maps with their keys in document order, arrays become
+//! arrays, and numbers become ints, or bignums for large ones, unless they
+//! have a fraction or exponent.
+
+use crate::bigint::{BigInt, MAX_BITS};
+use crate::error::{Error, Result};
+use crate::value::{Map, NativeFn, ObjRef, Object, Unpacked, Value};
+use crate::vm::Vm;
+use std::fmt::Write;
+use std::result::Result as StdResult;
+
+const FUNCTIONS: &[(&str, NativeFn)] = &[("parse", parse), ("stringify", stringify)];
+
+/// Deepest nesting of arrays and objects `json.parse` accepts and
+/// `json.stringify` produces.
+const MAX_DEPTH: usize = 512;
+
+/// Most digits of an int `json.parse` accepts, enough for any bignum of up to
+/// `MAX_BITS` bits.
+const MAX_INT_DIGITS: usize = (MAX_BITS as f64 * std::f64::consts::LOG10_2) as usize + 1;
+
+/// Adds the global module `json` to `vm`.
+pub fn define(vm: &mut Vm) {
+    vm.define_module("json", FUNCTIONS);
+}
+
+/// Parses a JSON document into `Result::Ok`, or returns `Result::Err` with a
+/// message giving the line and column of the error.
+fn parse(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    if args.len() > 1 {
+        return Err(Error::TypeError("too many arguments"));
+    }
+    let source = args
+        .first()
+        .and_then(|&value| vm.str(value))
+        .ok_or(Error::TypeError("expected a string"))?;
+    let mut parser = Parser { source, pos: 0 };
+    match parser.document() {
+        Ok(json) => {
+            let value = json.into_value(vm)?;
+            Ok(vm.ok(value))
+        }
+        Err(err) => {
+            let (line, col) = parser.line_col(err.pos);
+            let message = format!("{} at line {}, column {}", err.message, line, col);
+            let message = vm.alloc_str(message);
+            Ok(vm.err(message))
+        }
+    }
+}
+
+/// Encodes a value as JSON, on one line unless `pretty` is true.
+fn stringify(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let pretty = match args {
+        [_] => false,
+        [_, pretty] if matches!(pretty.unpack(), Unpacked::Bool(_)) => pretty.is_truthy(),
+        [_, _] => return Err(Error::TypeError("pretty must be a bool")),
+        [] => return Err(Error::TypeError("missing argument")),
+        _ => return Err(Error::TypeError("too many arguments")),
+    };
+    let mut encoder = Encoder {
+        vm,
+        out: String::new(),
+        pretty,
+        open: Vec::new(),
+    };
+    encoder.value(args[0])?;
+    let out = encoder.out;
+    vm.check_str_len(out.len())?;
+    Ok(vm.alloc_str(out))
+}
+
+/// Document parsed before it is turned into values.
+enum Json {
+    Null,
+    Bool(bool),
+    Int(i64),
+    BigInt(BigInt),
+    Float(f64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn into_value(self, vm: &mut Vm) -> Result<Value> {
+        Ok(match self {
+            Json::Null => Value::NULL,
+            Json::Bool(b) => Value::bool(b),
+            Json::Int(i) => vm.int_value(i),
+            Json::BigInt(big) => vm.alloc_big(big),
+            Json::Float(f) => Value::float(f),
+            Json::Str(s) => vm.alloc_str(s),
+            Json::Array(elems) => {
+                let elems = elems
+                    .into_iter()
+                    .map(|elem| elem.into_value(vm))
+                    .collect::<Result<_>>()?;
+                vm.alloc_array(elems)?
+            }
+            // A key given twice keeps its position and takes the last value.
+            Json::Object(members) => {
+                let map = vm.heap_mut().alloc(Object::Map(Map::default()));
+                for (key, value) in members {
+                    let key = vm.alloc_str(key);
+                    let value = value.into_value(vm)?;
+                    vm.map_insert(map, key, value)?;
+                }
+                Value::obj(map)
+            }
+        })
+    }
+}
+
+struct SyntaxError {
+    message: &'static str,
+    /// Byte offset of the error in the source.
+    pos: usize,
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn document(&mut self) -> StdResult<Json, SyntaxError> {
+        let json = self.value(0)?;
+        self.skip_whitespace();
+        match self.peek() {
+            None => Ok(json),
+            Some(_) => Err(self.error("unexpected trailing characters")),
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> StdResult<Json, SyntaxError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('{') | Some('[') if depth == MAX_DEPTH => Err(self.error("nesting too deep")),
+            Some('{') => self.object(depth + 1),
+            Some('[') => self.array(depth + 1),
+            Some('"') => Ok(Json::Str(self.string()?)),
+            Some('-' | '0'..='9') => self.number(),
+            Some('t') => self.keyword("true", Json::Bool(true)),
+            Some('f') => self.keyword("false", Json::Bool(false)),
+            Some('n') => self.keyword("null", Json::Null),
+            Some(_) => Err(self.error("expected a value")),
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+
+    fn object(&mut self, depth: usize) -> StdResult<Json, SyntaxError> {
+        self.pos += 1;
+        let mut members = Vec::new();
+        self.skip_whitespace();
+        if self.eat('}') {
+            return Ok(Json::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some('"') {
+                return Err(self.error("expected a string key"));
+            }
+            let key = self.string()?;
+            self.skip_whitespace();
+            if !self.eat(':') {
+                return Err(self.error("expected ':'"));
+            }
+            members.push((key, self.value(depth)?));
+            self.skip_whitespace();
+            if self.eat('}') {
+                return Ok(Json::Object(members));
+            }
+            if !self.eat(',') {
+                return Err(self.error("expected ',' or '}'"));
+            }
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> StdResult<Json, SyntaxError> {
+        self.pos += 1;
+        let mut elems = Vec::new();
+        self.skip_whitespace();
+        if self.eat(']') {
+            return Ok(Json::Array(elems));
+        }
+        loop {
+            elems.push(self.value(depth)?);
+            self.skip_whitespace();
+            if self.eat(']') {
+                return Ok(Json::Array(elems));
+            }
+            if !self.eat(',') {
+                return Err(self.error("expected ',' or ']'"));
+            }
+        }
+    }
+
+    fn string(&mut self) -> StdResult<String, SyntaxError> {
+        self.pos += 1;
+        let mut s = String::new();
+        loop {
+            let c = match self.peek() {
+                Some(c) => c,
+                None => return Err(self.error("unclosed string")),
+            };
+            match c {
+                '"' => {
+                    self.pos += 1;
+                    return Ok(s);
+                }
+                '\\' => {
+                    self.pos += 1;
+                    s.push(self.escape()?);
+                }
+                '\0'..='\x1f' => return Err(self.error("control character in string")),
+                c => {
+                    self.pos += c.len_utf8();
+                    s.push(c);
+                }
+            }
+        }
+    }
+
+    /// Parses the escape sequence after a backslash.
+    fn escape(&mut self) -> StdResult<char, SyntaxError> {
+        let c = match self.peek() {
+            Some('"') => '"',
+            Some('\\') => '\\',
+            Some('/') => '/',
+            Some('b') => '\u{8}',
+            Some('f') => '\u{c}',
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('t') => '\t',
+            Some('u') => {
+                let start = self.pos - 1;
+                self.pos += 1;
+                let high = self.hex4()?;
+                let code = match high {
+                    0xd800..=0xdbff if self.source[self.pos..].starts_with("\\u") => {
+                        self.pos += 2;
+                        match self.hex4()? {
+                            low @ 0xdc00..=0xdfff => {
+                                0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+                            }
+                            _ => 0xd800,
+                        }
+                    }
+                    code => code,
+                };
+                return char::from_u32(code).ok_or(SyntaxError {
+                    message: "invalid unicode escape",
+                    pos: start,
+                });
+            }
+            _ => return Err(self.error("invalid escape sequence")),
+        };
+        self.pos += 1;
+        Ok(c)
+    }
+
+    fn hex4(&mut self) -> StdResult<u32, SyntaxError> {
+        let digits = self.source.get(self.pos..self.pos + 4);
+        match digits.filter(|digits| digits.bytes().all(|b| b.is_ascii_hexdigit())) {
+            Some(digits) => {
+                self.pos += 4;
+                Ok(u32::from_str_radix(digits, 16).unwrap())
+            }
+            None => Err(self.error("invalid unicode escape")),
+        }
+    }
+
+    fn number(&mut self) -> StdResult<Json, SyntaxError> {
+        let start = self.pos;
+        self.eat('-');
+        match self.peek() {
+            Some('0') => self.pos += 1,
+            Some('1'..='9') => self.digits(),
+            _ => return Err(self.error("invalid number")),
+        }
+        let mut float = false;
+        if self.eat('.') {
+            float = true;
+            if !self.peek().is_some_and(|c| c.is_ascii_digit()) {
+                return Err(self.error("invalid number"));
+            }
+            self.digits();
+        }
+        if self.eat('e') || self.eat('E') {
+            float = true;
+            if !self.eat('+') {
+                self.eat('-');
+            }
+            if !self.peek().is_some_and(|c| c.is_ascii_digit()) {
+                return Err(self.error("invalid number"));
+            }
+            self.digits();
+        }
+
+        let num = &self.source[start..self.pos];
+        let out_of_range = SyntaxError {
+            message: "number out of range",
+            pos: start,
+        };
+        Ok(if float {
+            match num.parse().unwrap() {
+                f if f64::is_infinite(f) => return Err(out_of_range),
+                f => Json::Float(f),
+            }
+        } else if num.trim_start_matches('-').len() > MAX_INT_DIGITS {
+            return Err(out_of_range);
+        } else {
+            match num.parse() {
+                Ok(i) => Json::Int(i),
+                Err(_) => Json::BigInt(BigInt::from_decimal(num).unwrap()),
+            }
+        })
+    }
+
+    fn digits(&mut self) {
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+    }
+
+    fn keyword(&mut self, keyword: &str, json: Json) -> StdResult<Json, SyntaxError> {
+        if self.source[self.pos..].starts_with(keyword) {
+            self.pos += keyword.len();
+            Ok(json)
+        } else {
+            Err(self.error("expected a value"))
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(' ' | '\t' | '\n' | '\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.source[self.pos..].chars().next()
+    }
+
+    fn eat(&mut self, expected: char) -> bool {
+        let matches = self.peek() == Some(expected);
+        if matches {
+            self.pos += expected.len_utf8();
+        }
+        matches
+    }
+
+    fn error(&self, message: &'static str) -> SyntaxError {
+        SyntaxError {
+            message,
+            pos: self.pos,
+        }
+    }
+
+    /// Line and column, both counted from 1, of the byte offset `pos`.
+    /// Columns count chars.
+    fn line_col(&self, pos: usize) -> (usize, usize) {
+        let before = &self.source[..pos];
+        let line_start = before.rfind('\n').map_or(0, |idx| idx + 1);
+        let line = before.matches('\n').count() + 1;
+        (line, before[line_start..].chars().count() + 1)
+    }
+}
+
+struct Encoder<'a> {
+    vm: &'a Vm,
+    out: String,
+    pretty: bool,
+    /// Arrays, maps and instances being encoded, to detect cycles.
+    open: Vec<ObjRef>,
+}
+
+impl Encoder<'_> {
+    fn value(&mut self, value: Value) -> Result<()> {
+        let obj = match value.unpack() {
+            Unpacked::Obj(obj) => obj,
+            _ => return self.scalar(value),
+        };
+        let vm = self.vm;
+        match vm.heap().get(obj) {
+            Object::Str(s) => self.string(s),
+            Object::BigInt(big) => write!(self.out, "{}", big).unwrap(),
+            Object::Array(elems) | Object::Tuple(elems) => {
+                self.open(obj)?;
+                self.out.push('[');
+                for (idx, &elem) in elems.iter().enumerate() {
+                    self.separator(idx);
+                    self.value(elem)?;
+                }
+                self.close(elems.is_empty(), ']');
+            }
+            Object::Map(map) => {
+                self.open(obj)?;
+                self.out.push('{');
+                for (idx, &(key, value)) in map.entries.iter().enumerate() {
+                    let key = vm
+                        .str(key)
+                        .ok_or(Error::TypeError("JSON object keys must be strings"))?;
+                    self.member(idx, key, value)?;
+                }
+                self.close(map.entries.is_empty(), '}');
+            }
+            Object::Instance(instance) => {
+                self.open(obj)?;
+                self.out.push('{');
+                for (idx, &(field, value)) in instance.fields.iter().enumerate() {
+                    let field = vm.interner().lookup(field).unwrap_or("?");
+                    self.member(idx, field, value)?;
+                }
+                self.close(instance.fields.is_empty(), '}');
+            }
+            _ => return Err(Error::TypeError("value cannot be encoded as JSON")),
+        }
+        Ok(())
+    }
+
+    fn scalar(&mut self, value: Value) -> Result<()> {
+        match value.unpack() {
+            Unpacked::Null => self.out.push_str("null"),
+            Unpacked::Bool(b) => write!(self.out, "{}", b).unwrap(),
+            Unpacked::Int(i) => write!(self.out, "{}", i).unwrap(),
+            Unpacked::Float(f) if !f.is_finite() => {
+                return Err(Error::TypeError("JSON cannot encode NaN or infinity"))
+            }
+            Unpacked::Float(f) => write!(self.out, "{:?}", f).unwrap(),
+            Unpacked::Char(c) => self.string(&c.to_string()),
+            Unpacked::Obj(_) => unreachable!(),
+        }
+        Ok(())
+    }
+
+    fn open(&mut self, obj: ObjRef) -> Result<()> {
+        if self.open.contains(&obj) {
+            return Err(Error::TypeError("cannot encode a cyclic value as JSON"));
+        }
+        if self.open.len() == MAX_DEPTH {
+            return Err(Error::ValueTooDeep);
+        }
+        self.open.push(obj);
+        Ok(())
+    }
+
+    fn close(&mut self, empty: bool, delimiter: char) {
+        self.open.pop();
+        if !empty {
+            self.newline();
+        }
+        self.out.push(delimiter);
+    }
+
+    fn member(&mut self, idx: usize, key: &str, value: Value) -> Result<()> {
+        self.separator(idx);
+        self.string(key);
+        self.out.push_str(if self.pretty { ": " } else { ":" });
+        self.value(value)
+    }
+
+    /// Starts the element at `idx` of an array or object.
+    fn separator(&mut self, idx: usize) {
+        if idx > 0 {
+            self.out.push(',');
+        }
+        self.newline();
+    }
+
+    fn newline(&mut self) {
+        if self.pretty {
+            self.out.push('\n');
+            self.out.push_str(&"  ".repeat(self.open.len()));
+        }
+    }
+
+    fn string(&mut self, s: &str) {
+        self.out.push('"');
+        for c in s.chars() {
+            match c {
+                '"' => self.out.push_str("\\\""),
+                '\\' => self.out.push_str("\\\\"),
+                '\n' => self.out.push_str("\\n"),
+                '\r' => self.out.push_str("\\r"),
+                '\t' => self.out.push_str("\\t"),
+                '\u{8}' => self.out.push_str("\\b"),
+                '\u{c}' => self.out.push_str("\\f"),
+                '\0'..='\x1f' => write!(self.out, "\\u{:04x}", c as u32).unwrap(),
+                c => self.out.push(c),
+            }
+        }
+        self.out.push('"');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::testing::{eval, eval_err};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            r#"Result::Ok(map { "b": [1, -2.5, 100.0, true, null], "a": "é😀\n", "n": 123456789012345678901234567890 })"#,
+            eval(
+                r#"json.parse("{ \"b\": [1, -2.5, 1e2, true, null], \"a\": \"\\u00e9\\ud83d\\ude00\\n\",
+                    \"n\": 123456789012345678901234567890 }");"#
+            )
+        );
+        assert_eq!(
+            r#"[Result::Ok(map { "a": 2 }), Result::Ok(map {}), Result::Ok([])]"#,
+            eval(r#"[json.parse("{\"a\": 1, \"a\": 2}"), json.parse("{}"), json.parse(" [ ] ")];"#)
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |json: &str| {
+            let source = format!("json.parse({:?});", json);
+            eval(&source)
+        };
+        assert_eq!(
+            r#"Result::Err("expected ':' at line 3, column 7")"#,
+            error("{\n  \"a\": 1,\n  \"b\" 2\n}")
+        );
+        assert_eq!(
+            r#"Result::Err("expected ',' or ']' at line 1, column 4")"#,
+            error("[1 2]")
+        );
+        assert_eq!(
+            r#"Result::Err("unexpected end of input at line 1, column 7")"#,
+            error("{\"é\": ")
+        );
+        assert_eq!(
+            r#"Result::Err("invalid number at line 1, column 2")"#,
+            error("-.5")
+        );
+        assert_eq!(
+            r#"Result::Err("invalid unicode escape at line 1, column 2")"#,
+            error("\"\\ud800\"")
+        );
+        assert_eq!(
+            r#"Result::Err("unexpected trailing characters at line 1, column 6")"#,
+            error("true x")
+        );
+        assert_eq!(
+            r#"Result::Err("nesting too deep at line 1, column 513")"#,
+            error(&"[".repeat(600))
+        );
+        assert_eq!(
+            r#"Result::Err("number out of range at line 1, column 2")"#,
+            error("[-1e400]")
+        );
+        assert_eq!(
+            r#"Result::Err("number out of range at line 1, column 1")"#,
+            error(&"9".repeat(400000))
+        );
+    }
+
+    #[test]
+    fn test_stringify() {
+        assert_eq!(
+            r#"{"b":[1,2.5,"x\"y\n"],"a":{},"t":[1,"c"],"p":{"x":1}}"#,
+            eval(
+                r#"struct P {}
+                json.stringify(map { "b": [1, 2.5, "x\"y\n"], "a": map {}, "t": (1, 'c'),
+                    "p": new P { x: 1 } });"#
+            )
+        );
+        assert_eq!(
+            "{\n  \"a\": [\n    1,\n    []\n  ],\n  \"b\": null\n}",
+            eval(r#"json.stringify(map { "a": [1, []], "b": null }, true);"#)
+        );
+        assert_eq!(
+            "[[1],[1]]",
+            eval("let shared = [1]; json.stringify([shared, shared]);")
+        );
+        assert!(matches!(
+            eval_err("let a = [1]; a.push(map { \"a\": a }); json.stringify(a);"),
+            Error::TypeError("cannot encode a cyclic value as JSON")
+        ));
+        assert!(matches!(
+            eval_err("let a = []; for i in range(0, 10000) { a = [a]; }; json.stringify(a);"),
+            Error::ValueTooDeep
+        ));
+        assert!(matches!(
+            eval_err("json.stringify(map { 1: 2 });"),
+            Error::TypeError(_)
+        ));
+        assert!(matches!(
+            eval_err("json.stringify(0.0 / 0.0);"),
+            Error::TypeError(_)
+        ));
+    }
+}
@@ -1,6 +1,23 @@
+pub mod arith;
 pub mod ast;
+pub mod bigint;
+pub mod bytecode;
+pub mod capability;
+pub mod collections;
+pub mod compiler;
 pub mod error;
+pub mod gc;
+pub mod host;
+pub mod incremental;
+pub mod json;
+pub mod limits;
 pub mod parser;
+pub mod resolver;
 pub mod scanner;
 pub mod str_interner;
+pub mod strings;
+#[cfg(test)]
+mod testing;
 pub mod token;
+pub mod value;
+pub mod vm;
@@ -0,0 +1,72 @@
+//! Limits on the resources a script may use, for running untrusted code.
+
+use std::time::Duration;
+
+/// Resource limits of a `Vm`, set with `Vm::set_limits`. `None` means no
+/// limit.
+///
+/// Running out of steps, time or heap is fatal: `try` blocks do not catch
+/// it and the script stops. Going over the call depth or a length limit
+/// throws an error the script can catch.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// Instructions executed by an `eval`, or by a `call` from the host.
+    pub max_steps: Option<u64>,
+    /// Wall-clock time an `eval`, or a `call` from the host, may take. It is
+    /// checked every `DEADLINE_INTERVAL` instructions, so a single slow
+    /// native call, or operator on large bignums, can overrun it. Setting
+    /// `max_int_bits` too bounds the latter.
+    pub timeout: Option<Duration>,
+    /// Depth of nested calls of script functions. Their frames live on the
+    /// heap, so this bounds memory rather than the Rust stack. Calls back
+    /// into scripts from natives such as `map` do take Rust stack, and are
+    /// bounded by `MAX_REENTRIES` whatever this is.
+    pub max_call_depth: Option<usize>,
+    /// Nesting of expressions and blocks accepted by the parser. Unlike
+    /// calls, it is bounded by the Rust stack of the parser, resolver and
+    /// compiler, which is why it is a limit of its own that cannot be turned
+    /// off. The default fits in the 8 MiB stack of a main thread even in a
+    /// debug build; threads with smaller stacks may need a lower limit.
+    pub max_nesting: usize,
+    /// Estimated size of the objects on the heap, checked after every
+    /// instruction. Garbage is collected before it counts as exceeded, when
+    /// no native is running. Length limits keep single instructions from
+    /// allocating much beyond it.
+    pub max_heap_bytes: Option<usize>,
+    /// Bytes of a string.
+    pub max_str_len: Option<usize>,
+    /// Elements of an array or tuple, or entries of a map or set.
+    pub max_array_len: Option<usize>,
+    /// Bits of a bignum. An operator that could give a larger one fails with
+    /// an integer overflow before computing it, which bounds the time a
+    /// single `*`, `**` or `<<` takes. Bignums never exceed `MAX_BITS`.
+    pub max_int_bits: Option<u64>,
+}
+
+/// Instructions between two checks of the deadline.
+pub const DEADLINE_INTERVAL: u64 = 1024;
+
+/// Default of `Limits::max_call_depth`.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 10_000;
+
+/// Default of `Limits::max_nesting`.
+pub const DEFAULT_MAX_NESTING: usize = 200;
+
+/// Most calls from natives back into scripts that may be running at once,
+/// which fit in the 8 MiB stack of a main thread even in a debug build.
+pub const MAX_REENTRIES: usize = 200;
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_steps: None,
+            timeout: None,
+            max_call_depth: Some(DEFAULT_MAX_CALL_DEPTH),
+            max_nesting: DEFAULT_MAX_NESTING,
+            max_heap_bytes: None,
+            max_str_len: None,
+            max_array_len: None,
+            max_int_bits: None,
+        }
+    }
+}
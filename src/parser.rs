@@ -1,25 +1,78 @@
 use crate::ast;
 use crate::error::{Error, Result};
+use crate::limits::DEFAULT_MAX_NESTING;
 use crate::scanner::Scanner;
 use crate::str_interner::IntStr;
-use crate::token::{Assign, Delimiter, Keyword, Literal, Operator, TermOp, Token};
+use crate::token::{Assign, Delimiter, Keyword, Literal, Operator, RangeOp, Span, TermOp, Token};
 use std::collections::HashMap;
 
 pub struct Parser<'a> {
     scanner: Scanner<'a>,
+    /// Start of the top-level declaration being parsed.
+    decl_start: usize,
+    depth: usize,
+    max_nesting: usize,
 }
 
 impl<'a> Parser<'a> {
     pub fn new(scanner: Scanner<'a>) -> Parser<'a> {
-        Parser { scanner }
+        Parser {
+            scanner,
+            decl_start: 0,
+            depth: 0,
+            max_nesting: DEFAULT_MAX_NESTING,
+        }
+    }
+
+    /// Sets how deeply expressions and blocks may nest. Chains of binary
+    /// operators and of `else if` do not count as nesting.
+    pub fn set_max_nesting(&mut self, max_nesting: usize) {
+        self.max_nesting = max_nesting;
+    }
+
+    /// Parses with `parse` one level deeper, failing beyond the maximum
+    /// nesting so that the recursion cannot overflow the stack.
+    fn nested<T>(&mut self, parse: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        if self.depth >= self.max_nesting {
+            return Err(Error::NestingTooDeep);
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
     }
 
     pub fn program(&mut self) -> Result<ast::Program> {
         let mut decls = Vec::new();
-        while self.scanner.peek_next()? != Token::Eof {
-            decls.push(self.decl()?);
+        let mut spans = Vec::new();
+        while let Some((decl, span)) = self.next_decl()? {
+            decls.push(decl);
+            spans.push(span);
         }
-        Ok(ast::Program { decls })
+        Ok(ast::Program { decls, spans })
+    }
+
+    /// Parses the next top-level declaration together with the byte range it
+    /// covers, or returns `None` once the input is exhausted.
+    pub fn next_decl(&mut self) -> Result<Option<(ast::Decl, Span)>> {
+        if self.scanner.peek_next()? == Token::Eof {
+            return Ok(None);
+        }
+        self.decl_start = self.scanner.peek_span()?.start;
+        let decl = self.decl()?;
+        let end = self.scanner.last_span().end;
+        Ok(Some((decl, Span::new(self.decl_start, end))))
+    }
+
+    /// Sets the start of the top-level declaration that spans of the parsed
+    /// nodes are relative to, for parsing part of a declaration.
+    pub fn set_decl_start(&mut self, start: usize) {
+        self.decl_start = start;
+    }
+
+    /// Byte offset just past the last consumed token.
+    pub fn last_end(&self) -> usize {
+        self.scanner.last_span().end
     }
 
     fn decl(&mut self) -> Result<ast::Decl> {
@@ -27,7 +80,10 @@ impl<'a> Parser<'a> {
             Token::Keyword(Keyword::Use) => self.use_decl().map(ast::Decl::Use),
             Token::Keyword(Keyword::Mod) => self.mod_decl().map(ast::Decl::Mod),
             Token::Keyword(Keyword::Struct) => self.struct_decl().map(ast::Decl::Struct),
-            Token::Keyword(Keyword::Fn) => self.fun_decl().map(ast::Decl::Fun),
+            Token::Keyword(Keyword::Enum) => self.enum_decl().map(ast::Decl::Enum),
+            Token::Keyword(Keyword::Trait) => self.trait_decl().map(ast::Decl::Trait),
+            Token::Keyword(Keyword::Impl) => self.impl_decl().map(ast::Decl::Impl),
+            Token::Keyword(Keyword::Fn) => self.fun_decl(false).map(ast::Decl::Fun),
             Token::Keyword(Keyword::Let) => self.var_decl().map(ast::Decl::Var),
             token => {
                 self.scanner.putback(token);
@@ -51,6 +107,10 @@ impl<'a> Parser<'a> {
     }
 
     fn mod_decl(&mut self) -> Result<ast::ModDecl> {
+        self.nested(Self::mod_body)
+    }
+
+    fn mod_body(&mut self) -> Result<ast::ModDecl> {
         let ident = self.ident()?;
 
         self.consume(Token::Delimiter(Delimiter::OpenCurly))?;
@@ -78,12 +138,43 @@ impl<'a> Parser<'a> {
 
         loop {
             match self.scanner.get_next()? {
-                Token::Keyword(Keyword::Fn) => {
-                    let fun = self.fun_decl()?;
-                    if methods.contains_key(&fun.ident) {
-                        return Err(Error::MethodDefinedTwice(fun.ident));
+                Token::Keyword(Keyword::Fn) => self.method(&mut methods)?,
+                Token::Delimiter(Delimiter::CloseCurly) => {
+                    break;
+                }
+                token => return Err(Error::UnexpectedToken(token)),
+            }
+        }
+
+        Ok(ast::StructDecl { ident, methods })
+    }
+
+    fn enum_decl(&mut self) -> Result<ast::EnumDecl> {
+        let ident = self.ident()?;
+
+        self.consume(Token::Delimiter(Delimiter::OpenCurly))?;
+
+        let mut variants: Vec<ast::Variant> = Vec::new();
+        let mut methods = HashMap::new();
+
+        loop {
+            match self.scanner.get_next()? {
+                Token::Keyword(Keyword::Fn) => self.method(&mut methods)?,
+                Token::Ident(variant) if methods.is_empty() => {
+                    if variants.iter().any(|other| other.ident == variant) {
+                        return Err(Error::VariantDefinedTwice(variant));
+                    }
+                    let fields = self.variant_fields()?;
+                    variants.push(ast::Variant {
+                        ident: variant,
+                        fields,
+                    });
+                    match self.scanner.get_next()? {
+                        Token::Delimiter(Delimiter::Comma) => (),
+                        token @ (Token::Delimiter(Delimiter::CloseCurly)
+                        | Token::Keyword(Keyword::Fn)) => self.scanner.putback(token),
+                        token => return Err(Error::UnexpectedToken(token)),
                     }
-                    methods.insert(fun.ident, fun);
                 }
                 Token::Delimiter(Delimiter::CloseCurly) => {
                     break;
@@ -92,25 +183,212 @@ impl<'a> Parser<'a> {
             }
         }
 
-        Ok(ast::StructDecl { ident, methods })
+        Ok(ast::EnumDecl {
+            ident,
+            variants,
+            methods,
+        })
     }
 
-    fn fun_decl(&mut self) -> Result<ast::FunDecl> {
+    fn trait_decl(&mut self) -> Result<ast::TraitDecl> {
+        let ident = self.ident()?;
+
+        self.consume(Token::Delimiter(Delimiter::OpenCurly))?;
+
+        let mut required = Vec::new();
+        let mut methods = HashMap::new();
+
+        loop {
+            match self.scanner.get_next()? {
+                Token::Keyword(Keyword::Fn) => {
+                    let method = self.ident()?;
+                    if required.contains(&method) || methods.contains_key(&method) {
+                        return Err(Error::MethodDefinedTwice(method));
+                    }
+                    self.consume(Token::Delimiter(Delimiter::OpenPrnth))?;
+                    let (params, rest) = self.fun_params(true)?;
+                    match self.scanner.peek_next()? {
+                        Token::Delimiter(Delimiter::Semicolon) => {
+                            self.scanner.get_next()?;
+                            required.push(method);
+                        }
+                        _ => {
+                            let block = self.block()?;
+                            methods.insert(
+                                method,
+                                ast::FunDecl {
+                                    ident: method,
+                                    params,
+                                    rest,
+                                    block,
+                                },
+                            );
+                        }
+                    }
+                }
+                Token::Delimiter(Delimiter::CloseCurly) => break,
+                token => return Err(Error::UnexpectedToken(token)),
+            }
+        }
+
+        Ok(ast::TraitDecl {
+            ident,
+            required,
+            methods,
+        })
+    }
+
+    fn impl_decl(&mut self) -> Result<ast::ImplDecl> {
+        let trait_ident = self.ident()?;
+        self.consume(Token::Keyword(Keyword::For))?;
+        let ident = self.ident()?;
+
+        self.consume(Token::Delimiter(Delimiter::OpenCurly))?;
+
+        let mut methods = HashMap::new();
+
+        loop {
+            match self.scanner.get_next()? {
+                Token::Keyword(Keyword::Fn) => self.method(&mut methods)?,
+                Token::Delimiter(Delimiter::CloseCurly) => break,
+                token => return Err(Error::UnexpectedToken(token)),
+            }
+        }
+
+        Ok(ast::ImplDecl {
+            trait_ident,
+            ident,
+            methods,
+        })
+    }
+
+    fn method(&mut self, methods: &mut HashMap<IntStr, ast::FunDecl>) -> Result<()> {
+        let fun = self.fun_decl(true)?;
+        if methods.contains_key(&fun.ident) {
+            return Err(Error::MethodDefinedTwice(fun.ident));
+        }
+        methods.insert(fun.ident, fun);
+        Ok(())
+    }
+
+    fn variant_fields(&mut self) -> Result<ast::VariantFields> {
+        let fields = match self.scanner.get_next()? {
+            Token::Delimiter(Delimiter::OpenPrnth) => {
+                ast::VariantFields::Tuple(self.fields(Token::Delimiter(Delimiter::ClosePrnth))?)
+            }
+            Token::Delimiter(Delimiter::OpenCurly) => {
+                ast::VariantFields::Struct(self.fields(Token::Delimiter(Delimiter::CloseCurly))?)
+            }
+            token => {
+                self.scanner.putback(token);
+                ast::VariantFields::Unit
+            }
+        };
+
+        Ok(fields)
+    }
+
+    fn fields(&mut self, sentinel: Token) -> Result<Vec<IntStr>> {
+        let mut fields = Vec::new();
+
+        loop {
+            match self.scanner.get_next()? {
+                t if t == sentinel => break,
+                Token::Ident(field) => {
+                    if fields.contains(&field) {
+                        return Err(Error::FieldDefinedTwice(field));
+                    }
+                    fields.push(field);
+                    match self.scanner.get_next()? {
+                        Token::Delimiter(Delimiter::Comma) => (),
+                        t if t == sentinel => break,
+                        token => return Err(Error::UnexpectedToken(token)),
+                    }
+                }
+                token => return Err(Error::UnexpectedToken(token)),
+            }
+        }
+
+        Ok(fields)
+    }
+
+    fn fun_decl(&mut self, method: bool) -> Result<ast::FunDecl> {
         let ident = self.ident()?;
 
         self.consume(Token::Delimiter(Delimiter::OpenPrnth))?;
-        let params = self.params()?;
-        self.consume(Token::Delimiter(Delimiter::OpenPrnth))?;
+        let (params, rest) = self.fun_params(method)?;
 
         let block = self.block()?;
 
         Ok(ast::FunDecl {
             ident,
             params,
+            rest,
             block,
         })
     }
 
+    fn fun_params(&mut self, method: bool) -> Result<(Vec<ast::Param>, Option<IntStr>)> {
+        let mut params: Vec<ast::Param> = Vec::new();
+
+        // Methods may spell out the receiver, which is always bound to `self`.
+        if self.scanner.peek_next()? == Token::Keyword(Keyword::SelfKw) {
+            if !method {
+                return Err(Error::SelfOutsideMethod);
+            }
+            self.scanner.get_next()?;
+            if self.scanner.peek_next()? == Token::Delimiter(Delimiter::Comma) {
+                self.scanner.get_next()?;
+            }
+        }
+
+        loop {
+            match self.scanner.get_next()? {
+                Token::Delimiter(Delimiter::ClosePrnth) => return Ok((params, None)),
+                Token::Delimiter(Delimiter::Ellipsis) => {
+                    let ident = self.ident()?;
+                    let span = self.scanner.last_span();
+                    if params.iter().any(|param| param.ident == ident) {
+                        return Err(Error::ParamDefinedTwice(ident, span));
+                    }
+                    if self.scanner.peek_next()? == Token::Delimiter(Delimiter::Comma) {
+                        self.scanner.get_next()?;
+                    }
+                    return match self.scanner.get_next()? {
+                        Token::Delimiter(Delimiter::ClosePrnth) => Ok((params, Some(ident))),
+                        _ => Err(Error::RestParamNotLast(span)),
+                    };
+                }
+                Token::Ident(ident) => {
+                    let span = self.scanner.last_span();
+                    if params.iter().any(|param| param.ident == ident) {
+                        return Err(Error::ParamDefinedTwice(ident, span));
+                    }
+
+                    let default = match self.scanner.get_next()? {
+                        Token::Assign(Assign::Assign) => Some(self.expr()?),
+                        token => {
+                            self.scanner.putback(token);
+                            None
+                        }
+                    };
+                    let after_default = params.last().is_some_and(|p| p.default.is_some());
+                    if after_default && default.is_none() {
+                        return Err(Error::RequiredParamAfterDefault(ident, span));
+                    }
+                    params.push(ast::Param { ident, default });
+
+                    match self.scanner.get_next()? {
+                        Token::Delimiter(Delimiter::Comma) => (),
+                        Token::Delimiter(Delimiter::ClosePrnth) => return Ok((params, None)),
+                        token => return Err(Error::UnexpectedToken(token)),
+                    }
+                }
+                token => return Err(Error::UnexpectedToken(token)),
+            }
+        }
+    }
+
     fn params(&mut self) -> Result<Vec<IntStr>> {
         let mut params = Vec::new();
 
@@ -156,16 +434,26 @@ impl<'a> Parser<'a> {
                     self.scanner.get_next()?;
                     Ok(ast::Stmt::Return(None))
                 } else {
-                    self.expr().map(|expr| ast::Stmt::Return(Some(expr)))
+                    let expr = self.expr()?;
+                    self.consume(Token::Delimiter(Delimiter::Semicolon))?;
+                    Ok(ast::Stmt::Return(Some(expr)))
                 }
             }
+            Token::Keyword(Keyword::Throw) => {
+                self.scanner.get_next()?;
+                let expr = self.expr()?;
+                self.consume(Token::Delimiter(Delimiter::Semicolon))?;
+                Ok(ast::Stmt::Throw(expr))
+            }
             Token::Keyword(Keyword::Break) => {
                 self.scanner.get_next()?;
                 if self.scanner.peek_next()? == Token::Delimiter(Delimiter::Semicolon) {
                     self.scanner.get_next()?;
                     Ok(ast::Stmt::Break(None))
                 } else {
-                    self.expr().map(|expr| ast::Stmt::Break(Some(expr)))
+                    let expr = self.expr()?;
+                    self.consume(Token::Delimiter(Delimiter::Semicolon))?;
+                    Ok(ast::Stmt::Break(Some(expr)))
                 }
             }
             _ => match self.assignment_or_expr()? {
@@ -201,12 +489,15 @@ impl<'a> Parser<'a> {
     fn lcall(expr: ast::Expr) -> Result<ast::LCall> {
         let call = match expr {
             ast::Expr {
-                logic_or:
-                    ast::LogicOr::Next(ast::LogicAnd::Next(ast::Cmp::Next(ast::BitOr::Next(
-                        ast::BitXor::Next(ast::BitAnd::Next(ast::Shift::Next(ast::Term::Next(
-                            ast::Factor::Next(ast::Unary::Next(call)),
+                pipe:
+                    ast::Pipe::Next(ast::Coalesce::Next(ast::LogicOr::Next(ast::LogicAnd::Next(
+                        ast::Cmp::Next(ast::Range::Next(ast::BitOr::Next(ast::BitXor::Next(
+                            ast::BitAnd::Next(ast::Shift::Next(ast::Term::Next(
+                                ast::Factor::Next(ast::Unary::Next(ast::Power::Next(call))),
+                            ))),
                         )))),
                     )))),
+                ..
             } => call,
             _ => return Err(Error::UnassignableExpression),
         };
@@ -231,148 +522,230 @@ impl<'a> Parser<'a> {
     }
 
     fn expr(&mut self) -> Result<ast::Expr> {
-        self.logic_or().map(|logic_or| ast::Expr { logic_or })
+        let start = self.scanner.peek_span()?.start;
+        let pipe = self.nested(Self::pipe)?;
+        let span = self.relative(Span::new(start, self.scanner.last_span().end));
+        Ok(ast::Expr { pipe, span })
+    }
+
+    fn pipe(&mut self) -> Result<ast::Pipe> {
+        let left = self.coalesce()?;
+        let mut rest = Vec::new();
+        while self.next_is(Token::Operator(Operator::Pipe))? {
+            rest.push(self.coalesce()?);
+        }
+        Ok(if rest.is_empty() {
+            ast::Pipe::Next(left)
+        } else {
+            ast::Pipe::Current(Box::new(left), rest)
+        })
+    }
+
+    fn coalesce(&mut self) -> Result<ast::Coalesce> {
+        let left = self.logic_or()?;
+        let mut rest = Vec::new();
+        while self.next_is(Token::Operator(Operator::Coalesce))? {
+            rest.push(self.logic_or()?);
+        }
+        Ok(if rest.is_empty() {
+            ast::Coalesce::Next(left)
+        } else {
+            ast::Coalesce::Current(Box::new(left), rest)
+        })
     }
 
     fn logic_or(&mut self) -> Result<ast::LogicOr> {
         let left = self.logic_and()?;
-        let logic_or = match self.scanner.get_next()? {
-            Token::Operator(Operator::LogicOr) => {
-                ast::LogicOr::Current(left, Box::new(self.logic_or()?))
-            }
-            token => {
-                self.scanner.putback(token);
-                ast::LogicOr::Next(left)
-            }
-        };
-
-        Ok(logic_or)
+        let mut rest = Vec::new();
+        while self.next_is(Token::Operator(Operator::LogicOr))? {
+            rest.push(self.logic_and()?);
+        }
+        Ok(if rest.is_empty() {
+            ast::LogicOr::Next(left)
+        } else {
+            ast::LogicOr::Current(Box::new(left), rest)
+        })
     }
 
     fn logic_and(&mut self) -> Result<ast::LogicAnd> {
         let left = self.cmp()?;
-        let logic_and = match self.scanner.get_next()? {
-            Token::Operator(Operator::LogicAnd) => {
-                ast::LogicAnd::Current(left, Box::new(self.logic_and()?))
+        let mut rest = Vec::new();
+        while self.next_is(Token::Operator(Operator::LogicAnd))? {
+            rest.push(self.cmp()?);
+        }
+        Ok(if rest.is_empty() {
+            ast::LogicAnd::Next(left)
+        } else {
+            ast::LogicAnd::Current(Box::new(left), rest)
+        })
+    }
+
+    fn cmp(&mut self) -> Result<ast::Cmp> {
+        let left = self.range()?;
+        let mut rest = Vec::new();
+        loop {
+            match self.scanner.get_next()? {
+                Token::Operator(Operator::Cmp(op)) => rest.push((op, self.range()?)),
+                token => {
+                    self.scanner.putback(token);
+                    break;
+                }
             }
-            token => {
-                self.scanner.putback(token);
-                ast::LogicAnd::Next(left)
+        }
+        Ok(if rest.is_empty() {
+            ast::Cmp::Next(left)
+        } else {
+            ast::Cmp::Current {
+                left: Box::new(left),
+                rest,
             }
-        };
-
-        Ok(logic_and)
+        })
     }
 
-    fn cmp(&mut self) -> Result<ast::Cmp> {
-        let left = self.bit_or()?;
-        let cmp = match self.scanner.get_next()? {
-            Token::Operator(Operator::Cmp(op)) => ast::Cmp::Current {
-                left,
-                op,
-                cmp: Box::new(self.cmp()?),
-            },
+    fn range(&mut self) -> Result<ast::Range> {
+        let start = match self.scanner.get_next()? {
+            Token::Operator(Operator::Range(op)) => return self.range_end(None, op),
             token => {
                 self.scanner.putback(token);
-                ast::Cmp::Next(left)
+                self.bit_or()?
             }
         };
-
-        Ok(cmp)
-    }
-
-    fn bit_or(&mut self) -> Result<ast::BitOr> {
-        let left = self.bit_xor()?;
-        let bit_or = match self.scanner.get_next()? {
-            Token::Operator(Operator::BitOr) => ast::BitOr::Current(left, Box::new(self.bit_or()?)),
+        match self.scanner.get_next()? {
+            Token::Operator(Operator::Range(op)) => self.range_end(Some(Box::new(start)), op),
             token => {
                 self.scanner.putback(token);
-                ast::BitOr::Next(left)
+                Ok(ast::Range::Next(start))
             }
+        }
+    }
+
+    /// Parses the end of a range after its operator. The end is left open if
+    /// the range is followed by a token that cannot start an expression.
+    fn range_end(&mut self, start: Option<Box<ast::BitOr>>, op: RangeOp) -> Result<ast::Range> {
+        let token = self.scanner.peek_next()?;
+        let open = matches!(
+            token,
+            Token::Delimiter(
+                Delimiter::ClosePrnth
+                    | Delimiter::CloseBrkt
+                    | Delimiter::OpenCurly
+                    | Delimiter::CloseCurly
+                    | Delimiter::Comma
+                    | Delimiter::Semicolon
+            ) | Token::Operator(Operator::Cmp(_) | Operator::LogicAnd | Operator::LogicOr)
+                | Token::Eof
+        );
+        let end = if open {
+            None
+        } else {
+            Some(Box::new(self.bit_or()?))
         };
+        Ok(ast::Range::Current { start, op, end })
+    }
 
-        Ok(bit_or)
+    fn bit_or(&mut self) -> Result<ast::BitOr> {
+        let left = self.bit_xor()?;
+        let mut rest = Vec::new();
+        while self.next_is(Token::Operator(Operator::BitOr))? {
+            rest.push(self.bit_xor()?);
+        }
+        Ok(if rest.is_empty() {
+            ast::BitOr::Next(left)
+        } else {
+            ast::BitOr::Current(Box::new(left), rest)
+        })
     }
 
     fn bit_xor(&mut self) -> Result<ast::BitXor> {
         let left = self.bit_and()?;
-        let bit_xor = match self.scanner.get_next()? {
-            Token::Operator(Operator::BitXor) => {
-                ast::BitXor::Current(left, Box::new(self.bit_xor()?))
-            }
-            token => {
-                self.scanner.putback(token);
-                ast::BitXor::Next(left)
-            }
-        };
-
-        Ok(bit_xor)
+        let mut rest = Vec::new();
+        while self.next_is(Token::Operator(Operator::BitXor))? {
+            rest.push(self.bit_and()?);
+        }
+        Ok(if rest.is_empty() {
+            ast::BitXor::Next(left)
+        } else {
+            ast::BitXor::Current(Box::new(left), rest)
+        })
     }
 
     fn bit_and(&mut self) -> Result<ast::BitAnd> {
         let left = self.shift()?;
-        let bit_and = match self.scanner.get_next()? {
-            Token::Operator(Operator::BitAnd) => {
-                ast::BitAnd::Current(left, Box::new(self.bit_and()?))
-            }
-            token => {
-                self.scanner.putback(token);
-                ast::BitAnd::Next(left)
-            }
-        };
-
-        Ok(bit_and)
+        let mut rest = Vec::new();
+        while self.next_is(Token::Operator(Operator::BitAnd))? {
+            rest.push(self.shift()?);
+        }
+        Ok(if rest.is_empty() {
+            ast::BitAnd::Next(left)
+        } else {
+            ast::BitAnd::Current(Box::new(left), rest)
+        })
     }
 
     fn shift(&mut self) -> Result<ast::Shift> {
         let left = self.term()?;
-        let shift = match self.scanner.get_next()? {
-            Token::Operator(Operator::Shift(op)) => ast::Shift::Current {
-                left,
-                op,
-                shift: Box::new(self.shift()?),
-            },
-            token => {
-                self.scanner.putback(token);
-                ast::Shift::Next(left)
+        let mut rest = Vec::new();
+        loop {
+            match self.scanner.get_next()? {
+                Token::Operator(Operator::Shift(op)) => rest.push((op, self.term()?)),
+                token => {
+                    self.scanner.putback(token);
+                    break;
+                }
             }
-        };
-
-        Ok(shift)
+        }
+        Ok(if rest.is_empty() {
+            ast::Shift::Next(left)
+        } else {
+            ast::Shift::Current {
+                left: Box::new(left),
+                rest,
+            }
+        })
     }
 
     fn term(&mut self) -> Result<ast::Term> {
         let left = self.factor()?;
-        let term = match self.scanner.get_next()? {
-            Token::Operator(Operator::Term(op)) => ast::Term::Current {
-                left,
-                op,
-                term: Box::new(self.term()?),
-            },
-            token => {
-                self.scanner.putback(token);
-                ast::Term::Next(left)
+        let mut rest = Vec::new();
+        loop {
+            match self.scanner.get_next()? {
+                Token::Operator(Operator::Term(op)) => rest.push((op, self.factor()?)),
+                token => {
+                    self.scanner.putback(token);
+                    break;
+                }
             }
-        };
-
-        Ok(term)
+        }
+        Ok(if rest.is_empty() {
+            ast::Term::Next(left)
+        } else {
+            ast::Term::Current {
+                left: Box::new(left),
+                rest,
+            }
+        })
     }
 
     fn factor(&mut self) -> Result<ast::Factor> {
         let left = self.unary()?;
-        let term = match self.scanner.get_next()? {
-            Token::Operator(Operator::Factor(op)) => ast::Factor::Current {
-                left,
-                op,
-                factor: Box::new(self.factor()?),
-            },
-            token => {
-                self.scanner.putback(token);
-                ast::Factor::Next(left)
+        let mut rest = Vec::new();
+        loop {
+            match self.scanner.get_next()? {
+                Token::Operator(Operator::Factor(op)) => rest.push((op, self.unary()?)),
+                token => {
+                    self.scanner.putback(token);
+                    break;
+                }
             }
-        };
-
-        Ok(term)
+        }
+        Ok(if rest.is_empty() {
+            ast::Factor::Next(left)
+        } else {
+            ast::Factor::Current {
+                left: Box::new(left),
+                rest,
+            }
+        })
     }
 
     fn unary(&mut self) -> Result<ast::Unary> {
@@ -381,16 +754,30 @@ impl<'a> Parser<'a> {
             Token::Operator(Operator::Term(TermOp::Sub)) => ast::UnaryOp::Negate,
             token => {
                 self.scanner.putback(token);
-                return Ok(ast::Unary::Next(self.call()?));
+                return Ok(ast::Unary::Next(self.power()?));
             }
         };
 
         Ok(ast::Unary::Current {
             op,
-            unary: Box::new(self.unary()?),
+            unary: Box::new(self.nested(Self::unary)?),
         })
     }
 
+    fn power(&mut self) -> Result<ast::Power> {
+        let base = self.call()?;
+        match self.scanner.get_next()? {
+            Token::Operator(Operator::Pow) => Ok(ast::Power::Current {
+                base: Box::new(base),
+                exp: Box::new(self.nested(Self::unary)?),
+            }),
+            token => {
+                self.scanner.putback(token);
+                Ok(ast::Power::Next(base))
+            }
+        }
+    }
+
     fn call(&mut self) -> Result<ast::Call> {
         let head = self.primary()?;
         let mut tail = Vec::new();
@@ -401,16 +788,35 @@ impl<'a> Parser<'a> {
                     let ident = self.ident()?;
                     tail.push(ast::CallPart::Dot(ident))
                 }
+                Token::Delimiter(Delimiter::Doublecolon) => {
+                    let ident = self.ident()?;
+                    tail.push(ast::CallPart::Path(ident))
+                }
                 Token::Delimiter(Delimiter::OpenBrkt) => {
                     let expr = self.expr()?;
                     self.consume(Token::Delimiter(Delimiter::CloseBrkt))?;
                     tail.push(ast::CallPart::Brkts(Box::new(expr)))
                 }
                 Token::Delimiter(Delimiter::OpenPrnth) => {
-                    let args = self.expr_list(Token::Delimiter(Delimiter::ClosePrnth))?;
-                    tail.push(ast::CallPart::FunCall(args))
+                    let start = self.scanner.last_span().start;
+                    let args = self.args()?;
+                    let span = Span::new(start, self.scanner.last_span().end);
+                    tail.push(ast::CallPart::FunCall(ast::FunCall {
+                        args,
+                        span: self.relative(span),
+                    }))
                 }
                 Token::Operator(Operator::QMark) => tail.push(ast::CallPart::QMark),
+                Token::Operator(Operator::QMarkDot) => {
+                    tail.push(ast::CallPart::NullSafe);
+                    match self.scanner.get_next()? {
+                        Token::Ident(ident) => tail.push(ast::CallPart::Dot(ident)),
+                        token @ Token::Delimiter(Delimiter::OpenBrkt | Delimiter::OpenPrnth) => {
+                            self.scanner.putback(token)
+                        }
+                        token => return Err(Error::UnexpectedToken(token)),
+                    }
+                }
                 token => {
                     self.scanner.putback(token);
                     break;
@@ -421,27 +827,85 @@ impl<'a> Parser<'a> {
         Ok(ast::Call { head, tail })
     }
 
-    fn expr_list(&mut self, sentinel: Token) -> Result<Vec<ast::Expr>> {
-        let mut expr_list = Vec::new();
+    fn args(&mut self) -> Result<Vec<ast::Arg>> {
+        let mut args: Vec<ast::Arg> = Vec::new();
 
         loop {
-            match self.scanner.get_next()? {
-                t if t == sentinel => {
-                    break;
-                }
+            let start = self.scanner.peek_span()?.start;
+            let arg = match self.scanner.get_next()? {
+                Token::Delimiter(Delimiter::ClosePrnth) => break,
+                Token::Delimiter(Delimiter::Ellipsis) => ast::Arg::Spread(self.expr()?),
                 token => {
                     self.scanner.putback(token);
-                    expr_list.push(self.expr()?);
+                    let expr = self.expr()?;
                     match self.scanner.get_next()? {
-                        Token::Delimiter(Delimiter::Comma) => (),
-                        t if t == sentinel => break,
-                        token => return Err(Error::UnexpectedToken(token)),
+                        Token::Delimiter(Delimiter::Colon) => {
+                            let span = Span::new(start, self.scanner.last_span().start);
+                            let ident = Self::expr_ident(&expr).ok_or(Error::UnexpectedToken(
+                                Token::Delimiter(Delimiter::Colon),
+                            ))?;
+                            let given_twice = args.iter().any(|arg| {
+                                matches!(arg, ast::Arg::Named { ident: other, .. } if *other == ident)
+                            });
+                            // Like those of other errors, the span of the
+                            // error is absolute while that of the node is not.
+                            if given_twice {
+                                return Err(Error::ArgGivenTwice(ident, span));
+                            }
+                            ast::Arg::Named {
+                                ident,
+                                span: self.relative(span),
+                                expr: self.expr()?,
+                            }
+                        }
+                        token => {
+                            self.scanner.putback(token);
+                            ast::Arg::Positional(expr)
+                        }
                     }
                 }
+            };
+
+            let after_named = args
+                .last()
+                .is_some_and(|arg| matches!(arg, ast::Arg::Named { .. }));
+            if after_named && !matches!(arg, ast::Arg::Named { .. }) {
+                return Err(Error::PositionalArgAfterNamed(Span::new(
+                    start,
+                    self.scanner.last_span().end,
+                )));
+            }
+            args.push(arg);
+
+            match self.scanner.get_next()? {
+                Token::Delimiter(Delimiter::Comma) => (),
+                Token::Delimiter(Delimiter::ClosePrnth) => break,
+                token => return Err(Error::UnexpectedToken(token)),
             }
         }
 
-        Ok(expr_list)
+        Ok(args)
+    }
+
+    /// Returns the identifier if `expr` consists of nothing else.
+    fn expr_ident(expr: &ast::Expr) -> Option<IntStr> {
+        match expr {
+            ast::Expr {
+                pipe:
+                    ast::Pipe::Next(ast::Coalesce::Next(ast::LogicOr::Next(ast::LogicAnd::Next(
+                        ast::Cmp::Next(ast::Range::Next(ast::BitOr::Next(ast::BitXor::Next(
+                            ast::BitAnd::Next(ast::Shift::Next(ast::Term::Next(
+                                ast::Factor::Next(ast::Unary::Next(ast::Power::Next(ast::Call {
+                                    head: ast::Primary::Ident(ident),
+                                    tail,
+                                }))),
+                            ))),
+                        )))),
+                    )))),
+                ..
+            } if tail.is_empty() => Some(*ident),
+            _ => None,
+        }
     }
 
     fn primary(&mut self) -> Result<ast::Primary> {
@@ -449,22 +913,32 @@ impl<'a> Parser<'a> {
             Token::Keyword(Keyword::SelfKw) => ast::Primary::SelfKw,
             Token::Delimiter(Delimiter::OpenPrnth) => {
                 let expr = self.expr()?;
-                self.consume(Token::Delimiter(Delimiter::ClosePrnth))?;
-                ast::Primary::Prnth(Box::new(expr))
+                match self.scanner.get_next()? {
+                    Token::Delimiter(Delimiter::ClosePrnth) => ast::Primary::Prnth(Box::new(expr)),
+                    Token::Delimiter(Delimiter::Comma) => {
+                        ast::Primary::Literal(Box::new(ast::Literal::Tuple(self.tuple_lit(expr)?)))
+                    }
+                    token => return Err(Error::UnexpectedToken(token)),
+                }
             }
             Token::Ident(ident) => ast::Primary::Ident(ident),
-            Token::Keyword(Keyword::For) => ast::Primary::For(self.for_loop()?),
-            Token::Keyword(Keyword::While) => ast::Primary::While(self.while_loop()?),
+            Token::Keyword(Keyword::For) => ast::Primary::For(Box::new(self.for_loop()?)),
+            Token::Keyword(Keyword::While) => ast::Primary::While(Box::new(self.while_loop()?)),
             Token::Keyword(Keyword::Loop) => ast::Primary::Loop(self.loop_loop()?),
-            Token::Keyword(Keyword::If) => ast::Primary::If(self.if_expr()?),
-            Token::Operator(Operator::BitOr) => ast::Primary::Closure(self.closure()?),
+            Token::Keyword(Keyword::If) => ast::Primary::If(Box::new(self.if_expr()?)),
+            Token::Keyword(Keyword::Try) => ast::Primary::Try(Box::new(self.try_expr()?)),
+            Token::Keyword(Keyword::Yield) => ast::Primary::Yield(Box::new(self.expr()?)),
+            Token::Operator(Operator::BitOr) => ast::Primary::Closure(Box::new(self.closure()?)),
+            Token::Operator(Operator::LogicOr) => {
+                ast::Primary::Closure(Box::new(self.closure_body(Vec::new())?))
+            }
             Token::Delimiter(Delimiter::OpenCurly) => {
                 self.scanner.putback(Token::Delimiter(Delimiter::OpenCurly));
                 ast::Primary::Block(self.block()?)
             }
             token => {
                 self.scanner.putback(token);
-                ast::Primary::Literal(self.literal()?)
+                ast::Primary::Literal(Box::new(self.literal()?))
             }
         };
 
@@ -500,42 +974,100 @@ impl<'a> Parser<'a> {
         Ok(ast::Loop { block })
     }
 
-    fn if_expr(&mut self) -> Result<ast::If> {
-        let cond = self.expr()?;
+    fn try_expr(&mut self) -> Result<ast::Try> {
         let block = self.block()?;
 
-        let els = match self.scanner.get_next()? {
-            Token::Keyword(Keyword::Else) => Some(self.els()?),
+        let catch = match self.scanner.get_next()? {
+            Token::Keyword(Keyword::Catch) => {
+                let ident = self.ident()?;
+                let block = self.block()?;
+                Some(ast::Catch { ident, block })
+            }
+            token => {
+                self.scanner.putback(token);
+                None
+            }
+        };
+        let finally = match self.scanner.get_next()? {
+            Token::Keyword(Keyword::Finally) => Some(self.block()?),
+            token if catch.is_none() => return Err(Error::UnexpectedToken(token)),
             token => {
                 self.scanner.putback(token);
                 None
             }
         };
 
-        Ok(ast::If {
-            cond: Box::new(cond),
+        Ok(ast::Try {
             block,
-            els,
+            catch,
+            finally,
         })
     }
 
-    fn els(&mut self) -> Result<ast::Else> {
-        let els = match self.scanner.get_next()? {
-            Token::Keyword(Keyword::If) => ast::Else::If(Box::new(self.if_expr()?)),
+    fn if_expr(&mut self) -> Result<ast::If> {
+        let mut branches = vec![self.if_branch()?];
+        let els = loop {
+            if !self.next_is(Token::Keyword(Keyword::Else))? {
+                break None;
+            }
+            if !self.next_is(Token::Keyword(Keyword::If))? {
+                break Some(self.block()?);
+            }
+            branches.push(self.if_branch()?);
+        };
+
+        Ok(ast::If { branches, els })
+    }
+
+    fn if_branch(&mut self) -> Result<ast::IfBranch> {
+        let pattern = match self.scanner.get_next()? {
+            Token::Keyword(Keyword::Let) => {
+                let pattern = self.pattern()?;
+                self.consume(Token::Assign(Assign::Assign))?;
+                Some(pattern)
+            }
             token => {
                 self.scanner.putback(token);
-                ast::Else::Block(self.block()?)
+                None
             }
         };
+        let cond = self.expr()?;
+        let block = self.block()?;
 
-        Ok(els)
+        Ok(ast::IfBranch {
+            pattern,
+            cond: Box::new(cond),
+            block,
+        })
+    }
+
+    fn pattern(&mut self) -> Result<ast::Pattern> {
+        let ident = self.ident()?;
+        self.consume(Token::Delimiter(Delimiter::Doublecolon))?;
+        let variant = self.ident()?;
+        let fields = self.variant_fields()?;
+
+        Ok(ast::Pattern {
+            ident,
+            variant,
+            fields,
+        })
     }
 
     fn closure(&mut self) -> Result<ast::Closure> {
         let params = self.params()?;
         self.consume(Token::Operator(Operator::BitOr))?;
-        let block = self.block()?;
-        Ok(ast::Closure { params, block })
+        self.closure_body(params)
+    }
+
+    fn closure_body(&mut self, params: Vec<IntStr>) -> Result<ast::Closure> {
+        let body = self.expr()?;
+        Ok(ast::Closure {
+            params,
+            body: Box::new(body),
+            captures: Vec::new(),
+            captures_self: false,
+        })
     }
 
     fn literal(&mut self) -> Result<ast::Literal> {
@@ -543,11 +1075,13 @@ impl<'a> Parser<'a> {
             Token::Literal(Literal::Bool(b)) => ast::Literal::Bool(b),
             Token::Literal(Literal::Null) => ast::Literal::Null,
             Token::Literal(Literal::Int(i)) => ast::Literal::Int(i),
+            Token::Literal(Literal::BigInt(digits)) => ast::Literal::BigInt(digits),
             Token::Literal(Literal::Float(f)) => ast::Literal::Float(f),
             Token::Literal(Literal::Char(c)) => ast::Literal::Char(c),
             Token::Literal(Literal::Str(s)) => ast::Literal::Str(s),
             Token::Keyword(Keyword::New) => ast::Literal::Struct(self.struct_lit()?),
             Token::Keyword(Keyword::Map) => ast::Literal::Map(self.map_lit()?),
+            Token::Keyword(Keyword::Set) => ast::Literal::Set(self.set_lit()?),
             Token::Delimiter(Delimiter::OpenBrkt) => ast::Literal::Array(self.array_lit()?),
             token => return Err(Error::UnexpectedToken(token)),
         };
@@ -557,6 +1091,13 @@ impl<'a> Parser<'a> {
 
     fn struct_lit(&mut self) -> Result<ast::StructLit> {
         let ident = self.ident()?;
+        let variant = match self.scanner.get_next()? {
+            Token::Delimiter(Delimiter::Doublecolon) => Some(self.ident()?),
+            token => {
+                self.scanner.putback(token);
+                None
+            }
+        };
         self.consume(Token::Delimiter(Delimiter::OpenCurly))?;
         let mut fields = Vec::new();
         loop {
@@ -576,7 +1117,11 @@ impl<'a> Parser<'a> {
             }
         }
 
-        Ok(ast::StructLit { ident, fields })
+        Ok(ast::StructLit {
+            ident,
+            variant,
+            fields,
+        })
     }
 
     fn map_lit(&mut self) -> Result<ast::MapLit> {
@@ -604,6 +1149,50 @@ impl<'a> Parser<'a> {
         Ok(ast::MapLit { fields })
     }
 
+    fn set_lit(&mut self) -> Result<ast::SetLit> {
+        self.consume(Token::Delimiter(Delimiter::OpenCurly))?;
+
+        let mut elems = Vec::new();
+        loop {
+            match self.scanner.get_next()? {
+                Token::Delimiter(Delimiter::CloseCurly) => break,
+                token => {
+                    self.scanner.putback(token);
+                    elems.push(self.expr()?);
+                    match self.scanner.get_next()? {
+                        Token::Delimiter(Delimiter::Comma) => (),
+                        Token::Delimiter(Delimiter::CloseCurly) => break,
+                        token => return Err(Error::UnexpectedToken(token)),
+                    }
+                }
+            }
+        }
+
+        Ok(ast::SetLit { elems })
+    }
+
+    /// Parses the rest of a tuple literal after its first element and comma,
+    /// which tell it apart from a parenthesized expression.
+    fn tuple_lit(&mut self, first: ast::Expr) -> Result<ast::TupleLit> {
+        let mut elems = vec![first];
+        loop {
+            match self.scanner.get_next()? {
+                Token::Delimiter(Delimiter::ClosePrnth) => break,
+                token => {
+                    self.scanner.putback(token);
+                    elems.push(self.expr()?);
+                    match self.scanner.get_next()? {
+                        Token::Delimiter(Delimiter::Comma) => (),
+                        Token::Delimiter(Delimiter::ClosePrnth) => break,
+                        token => return Err(Error::UnexpectedToken(token)),
+                    }
+                }
+            }
+        }
+
+        Ok(ast::TupleLit { elems })
+    }
+
     fn array_lit(&mut self) -> Result<ast::ArrayLit> {
         let mut elems = Vec::new();
         loop {
@@ -625,7 +1214,11 @@ impl<'a> Parser<'a> {
         Ok(ast::ArrayLit { elems })
     }
 
-    fn block(&mut self) -> Result<ast::Block> {
+    pub fn block(&mut self) -> Result<ast::Block> {
+        self.nested(Self::block_body)
+    }
+
+    fn block_body(&mut self) -> Result<ast::Block> {
         self.consume(Token::Delimiter(Delimiter::OpenCurly))?;
 
         let mut decls = Vec::new();
@@ -636,10 +1229,14 @@ impl<'a> Parser<'a> {
                 Token::Keyword(Keyword::Use)
                 | Token::Keyword(Keyword::Mod)
                 | Token::Keyword(Keyword::Struct)
+                | Token::Keyword(Keyword::Enum)
+                | Token::Keyword(Keyword::Trait)
+                | Token::Keyword(Keyword::Impl)
                 | Token::Keyword(Keyword::Fn)
                 | Token::Keyword(Keyword::Let)
                 | Token::Keyword(Keyword::Return)
-                | Token::Keyword(Keyword::Break) => {
+                | Token::Keyword(Keyword::Break)
+                | Token::Keyword(Keyword::Throw) => {
                     self.scanner.putback(token);
                     decls.push(self.decl()?);
                 }
@@ -671,6 +1268,10 @@ impl<'a> Parser<'a> {
         }
     }
 
+    fn relative(&self, span: Span) -> Span {
+        Span::new(span.start - self.decl_start, span.end - self.decl_start)
+    }
+
     fn ident(&mut self) -> Result<IntStr> {
         match self.scanner.get_next()? {
             Token::Ident(ident) => Ok(ident),
@@ -686,9 +1287,118 @@ impl<'a> Parser<'a> {
             Err(Error::UnexpectedToken(token))
         }
     }
+
+    /// Consumes the next token if it is `expected`.
+    fn next_is(&mut self, expected: Token) -> Result<bool> {
+        let token = self.scanner.get_next()?;
+        if token == expected {
+            Ok(true)
+        } else {
+            self.scanner.putback(token);
+            Ok(false)
+        }
+    }
 }
 
 enum Either<A, B> {
     A(A),
     B(B),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::str_interner::Interner;
+
+    fn parse(source: &str, interner: &mut Interner) -> Result<ast::Program> {
+        Parser::new(Scanner::new(source, interner)).program()
+    }
+
+    #[test]
+    fn test_enum_decl() {
+        let mut interner = Interner::new();
+        let program = parse(
+            "enum Shape {
+                Circle(r),
+                Rect { w, h },
+                Empty,
+                fn area() { 0 }
+            }",
+            &mut interner,
+        )
+        .unwrap();
+
+        let enum_decl = match &program.decls[0] {
+            ast::Decl::Enum(enum_decl) => enum_decl,
+            decl => panic!("unexpected decl {:?}", decl),
+        };
+        let r = interner.intern_str("r");
+        let w = interner.intern_str("w");
+        let h = interner.intern_str("h");
+        let fields: Vec<_> = enum_decl.variants.iter().map(|v| &v.fields).collect();
+        assert_eq!(
+            vec![
+                &ast::VariantFields::Tuple(vec![r]),
+                &ast::VariantFields::Struct(vec![w, h]),
+                &ast::VariantFields::Unit,
+            ],
+            fields
+        );
+        assert!(enum_decl.methods.contains_key(&interner.intern_str("area")));
+
+        assert!(parse("enum E { A, B, fn f() {} }", &mut interner).is_ok());
+        assert!(parse("enum E { A B }", &mut interner).is_err());
+        assert!(parse("enum E { fn f() {} A }", &mut interner).is_err());
+        assert!(matches!(
+            parse("enum E { A, A }", &mut interner),
+            Err(Error::VariantDefinedTwice(_))
+        ));
+        assert!(matches!(
+            parse("enum E { A(x, x) }", &mut interner),
+            Err(Error::FieldDefinedTwice(_))
+        ));
+    }
+
+    #[test]
+    fn test_variants_and_patterns() {
+        let mut interner = Interner::new();
+        let program = parse(
+            "let a = Shape::Circle(1.0);
+            let b = new Shape::Rect { w: 1, h: 2 };
+            let c = Shape::Empty;
+            let area = if let Shape::Rect { w, h } = b { w * h } else if let Shape::Circle(r) = a { r } else { 0 };",
+            &mut interner,
+        )
+        .unwrap();
+        assert_eq!(4, program.decls.len());
+
+        let shape = interner.intern_str("Shape");
+        let circle = interner.intern_str("Circle");
+        match &program.decls[0] {
+            ast::Decl::Var(ast::VarDecl {
+                expr:
+                    ast::Expr {
+                        pipe:
+                            ast::Pipe::Next(ast::Coalesce::Next(ast::LogicOr::Next(
+                                ast::LogicAnd::Next(ast::Cmp::Next(ast::Range::Next(
+                                    ast::BitOr::Next(ast::BitXor::Next(ast::BitAnd::Next(
+                                        ast::Shift::Next(ast::Term::Next(ast::Factor::Next(
+                                            ast::Unary::Next(ast::Power::Next(call)),
+                                        ))),
+                                    ))),
+                                ))),
+                            ))),
+                        ..
+                    },
+                ..
+            }) => {
+                assert_eq!(ast::Primary::Ident(shape), call.head);
+                assert_eq!(ast::CallPart::Path(circle), call.tail[0]);
+            }
+            decl => panic!("unexpected decl {:?}", decl),
+        }
+
+        assert!(parse("if let Shape = s {}", &mut interner).is_err());
+        assert!(parse("Shape::Circle = 1;", &mut interner).is_err());
+    }
+}
@@ -0,0 +1,1157 @@
+use crate::ast;
+use crate::capability::Capability;
+use crate::error::{Error, Result};
+use crate::str_interner::IntStr;
+use crate::token::Span;
+use std::collections::{HashMap, HashSet};
+
+/// Resolves names in `program` and records the captures of every closure.
+///
+/// Names that are not bound by an enclosing function, closure or block are
+/// globals and are never captured. Function bodies do not see the locals of
+/// the code around them, only closures do.
+///
+/// Calls to top-level functions by name are checked against their parameters.
+pub fn resolve(program: &mut ast::Program) -> Result<()> {
+    resolve_denying(program, &HashMap::new())
+}
+
+/// Resolves `program` like `resolve`, failing on any reference to one of the
+/// `denied` globals the program does not define itself. They are the modules
+/// of host functions needing a capability the script is not granted.
+pub fn resolve_denying(
+    program: &mut ast::Program,
+    denied: &HashMap<IntStr, Capability>,
+) -> Result<()> {
+    // Whether a capture is by value, and whether a call to a global function
+    // can be checked, depends on assignments that may come later, so the
+    // first pass only collects those.
+    let mut resolver = Resolver::new(HashSet::new(), HashSet::new(), HashMap::new());
+    resolver.check_calls = false;
+    resolver.program(program)?;
+
+    let mut defined = HashSet::new();
+    global_idents(&program.decls, &mut defined);
+    let denied = denied
+        .iter()
+        .filter(|(ident, _)| !defined.contains(ident))
+        .map(|(&ident, &cap)| (ident, cap))
+        .collect();
+    let mut resolver = Resolver::new(resolver.reassigned, resolver.assigned_globals, denied);
+    resolver.program(program)
+}
+
+/// Collects the globals `decls` define, including those of modules.
+fn global_idents(decls: &[ast::Decl], idents: &mut HashSet<IntStr>) {
+    for decl in decls {
+        let ident = match decl {
+            ast::Decl::Fun(fun) => fun.ident,
+            // `use fs;` would otherwise count as defining `fs` itself.
+            ast::Decl::Use(use_decl) if use_decl.path.len() > 1 => *use_decl.path.last().unwrap(),
+            ast::Decl::Mod(mod_decl) => {
+                global_idents(&mod_decl.decls, idents);
+                mod_decl.ident
+            }
+            ast::Decl::Struct(struct_decl) => struct_decl.ident,
+            ast::Decl::Enum(enum_decl) => enum_decl.ident,
+            ast::Decl::Trait(trait_decl) => trait_decl.ident,
+            ast::Decl::Var(var) => var.ident,
+            ast::Decl::Use(_) | ast::Decl::Impl(_) | ast::Decl::Stmt(_) => continue,
+        };
+        idents.insert(ident);
+    }
+}
+
+type BindingId = usize;
+
+struct Resolver {
+    frames: Vec<Frame>,
+    signatures: HashMap<IntStr, Signature>,
+    /// Start of the top-level declaration being resolved.
+    decl_start: usize,
+    next_id: BindingId,
+    reassigned: HashSet<BindingId>,
+    /// Bindings known to be reassigned from a previous pass.
+    known_reassigned: HashSet<BindingId>,
+    /// Globals assigned to anywhere, whose calls cannot be checked.
+    assigned_globals: HashSet<IntStr>,
+    /// Globals known to be assigned to from a previous pass.
+    known_assigned_globals: HashSet<IntStr>,
+    /// Whether calls to global functions are checked against their
+    /// parameters.
+    check_calls: bool,
+    denied: HashMap<IntStr, Capability>,
+    /// Span of the innermost expression or declaration being resolved.
+    span: Span,
+}
+
+struct Frame {
+    kind: FrameKind,
+    scopes: Vec<Vec<(IntStr, BindingId)>>,
+    captures: Vec<(IntStr, BindingId)>,
+    has_self: bool,
+    captures_self: bool,
+}
+
+struct Signature {
+    params: Vec<IntStr>,
+    required: usize,
+    rest: bool,
+}
+
+impl Signature {
+    fn new(fun: &ast::FunDecl) -> Signature {
+        Signature {
+            params: fun.params.iter().map(|param| param.ident).collect(),
+            required: fun
+                .params
+                .iter()
+                .take_while(|param| param.default.is_none())
+                .count(),
+            rest: fun.rest.is_some(),
+        }
+    }
+
+    fn check(&self, call: &ast::FunCall, piped: bool, decl_start: usize) -> Result<()> {
+        let absolute = |span: Span| Span::new(span.start + decl_start, span.end + decl_start);
+
+        let mut given = vec![false; self.params.len()];
+        let mut positional = 0;
+        if piped {
+            if let Some(given) = given.first_mut() {
+                *given = true;
+            }
+            positional += 1;
+        }
+        let mut spread = false;
+        for arg in &call.args {
+            match arg {
+                ast::Arg::Positional(_) => {
+                    // After a spread argument the position is unknown.
+                    if let (false, Some(given)) = (spread, given.get_mut(positional)) {
+                        *given = true;
+                    }
+                    positional += 1;
+                }
+                ast::Arg::Spread(_) => spread = true,
+                ast::Arg::Named { ident, span, .. } => {
+                    let idx = self
+                        .params
+                        .iter()
+                        .position(|param| param == ident)
+                        .ok_or(Error::UnknownArgName(*ident, absolute(*span)))?;
+                    if given[idx] {
+                        return Err(Error::ArgGivenTwice(*ident, absolute(*span)));
+                    }
+                    given[idx] = true;
+                }
+            }
+        }
+
+        // The number of values a spread argument expands to is only known at
+        // runtime.
+        if spread {
+            return Ok(());
+        }
+        if positional > self.params.len() && !self.rest {
+            return Err(Error::TooManyArgs {
+                expected: self.params.len(),
+                found: positional,
+                span: absolute(call.span),
+            });
+        }
+        match (0..self.required).find(|&idx| !given[idx]) {
+            Some(idx) => Err(Error::MissingArg(self.params[idx], absolute(call.span))),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum FrameKind {
+    Global,
+    Function,
+    Closure,
+}
+
+impl Frame {
+    fn new(kind: FrameKind, has_self: bool) -> Frame {
+        Frame {
+            kind,
+            scopes: vec![Vec::new()],
+            captures: Vec::new(),
+            has_self,
+            captures_self: false,
+        }
+    }
+
+    fn lookup(&self, ident: IntStr) -> Option<BindingId> {
+        self.scopes
+            .iter()
+            .rev()
+            .flat_map(|scope| scope.iter().rev())
+            .find(|(name, _)| *name == ident)
+            .map(|&(_, id)| id)
+    }
+}
+
+impl Resolver {
+    fn new(
+        known_reassigned: HashSet<BindingId>,
+        known_assigned_globals: HashSet<IntStr>,
+        denied: HashMap<IntStr, Capability>,
+    ) -> Resolver {
+        Resolver {
+            frames: Vec::new(),
+            signatures: HashMap::new(),
+            decl_start: 0,
+            next_id: 0,
+            reassigned: HashSet::new(),
+            known_reassigned,
+            assigned_globals: HashSet::new(),
+            known_assigned_globals,
+            check_calls: true,
+            denied,
+            span: Span::default(),
+        }
+    }
+
+    fn program(&mut self, program: &mut ast::Program) -> Result<()> {
+        let mut shadowed = HashSet::new();
+        for decl in &program.decls {
+            match decl {
+                ast::Decl::Fun(fun) => {
+                    if self
+                        .signatures
+                        .insert(fun.ident, Signature::new(fun))
+                        .is_some()
+                    {
+                        shadowed.insert(fun.ident);
+                    }
+                }
+                ast::Decl::Use(use_decl) => {
+                    shadowed.insert(*use_decl.path.last().unwrap());
+                }
+                ast::Decl::Mod(mod_decl) => {
+                    shadowed.insert(mod_decl.ident);
+                }
+                ast::Decl::Struct(struct_decl) => {
+                    shadowed.insert(struct_decl.ident);
+                }
+                ast::Decl::Enum(enum_decl) => {
+                    shadowed.insert(enum_decl.ident);
+                }
+                ast::Decl::Trait(trait_decl) => {
+                    shadowed.insert(trait_decl.ident);
+                }
+                ast::Decl::Impl(_) => (),
+                ast::Decl::Var(var) => {
+                    shadowed.insert(var.ident);
+                }
+                ast::Decl::Stmt(_) => (),
+            }
+        }
+        // Only names bound to nothing but a single function can be checked.
+        shadowed.extend(&self.known_assigned_globals);
+        for ident in shadowed {
+            self.signatures.remove(&ident);
+        }
+
+        self.frames.push(Frame::new(FrameKind::Global, false));
+        for (decl, span) in program.decls.iter_mut().zip(&program.spans) {
+            self.decl_start = span.start;
+            self.span = *span;
+            self.decl(decl)?;
+        }
+        self.frames.pop();
+        Ok(())
+    }
+
+    fn frame(&mut self) -> &mut Frame {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn declare(&mut self, ident: IntStr) {
+        let frame = self.frames.last_mut().unwrap();
+        // The outermost scope of the program holds globals.
+        if frame.kind == FrameKind::Global && frame.scopes.len() == 1 {
+            return;
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        frame.scopes.last_mut().unwrap().push((ident, id));
+    }
+
+    fn begin_scope(&mut self) {
+        self.frame().scopes.push(Vec::new());
+    }
+
+    fn end_scope(&mut self) {
+        self.frame().scopes.pop();
+    }
+
+    /// Finds the binding `ident` refers to, adding it to the captures of all
+    /// closures between its declaration and the use.
+    fn lookup(&mut self, ident: IntStr) -> Option<BindingId> {
+        let mut found = None;
+        for (idx, frame) in self.frames.iter().enumerate().rev() {
+            if let Some(id) = frame.lookup(ident) {
+                found = Some((idx, id));
+                break;
+            }
+            if frame.kind != FrameKind::Closure {
+                break;
+            }
+        }
+
+        let (idx, id) = found?;
+        for frame in &mut self.frames[idx + 1..] {
+            if !frame.captures.iter().any(|&(_, captured)| captured == id) {
+                frame.captures.push((ident, id));
+            }
+        }
+        Some(id)
+    }
+
+    /// Fails if the global `ident` is denied to the script.
+    fn check_global(&self, ident: IntStr) -> Result<()> {
+        match self.denied.get(&ident) {
+            Some(&cap) => Err(Error::CapabilityDenied(ident, cap, self.span)),
+            None => Ok(()),
+        }
+    }
+
+    fn lookup_self(&mut self) {
+        let owner = match self
+            .frames
+            .iter()
+            .rposition(|frame| frame.kind != FrameKind::Closure)
+        {
+            Some(idx) if self.frames[idx].has_self => idx,
+            _ => return,
+        };
+        for frame in &mut self.frames[owner + 1..] {
+            frame.captures_self = true;
+        }
+    }
+
+    fn decl(&mut self, decl: &mut ast::Decl) -> Result<()> {
+        match decl {
+            ast::Decl::Use(use_decl) => {
+                if self.lookup(use_decl.path[0]).is_none() {
+                    self.check_global(use_decl.path[0])?;
+                }
+                self.declare(*use_decl.path.last().unwrap());
+                Ok(())
+            }
+            ast::Decl::Mod(mod_decl) => {
+                self.declare(mod_decl.ident);
+                for decl in &mut mod_decl.decls {
+                    self.decl(decl)?;
+                }
+                Ok(())
+            }
+            ast::Decl::Struct(struct_decl) => {
+                self.declare(struct_decl.ident);
+                for method in struct_decl.methods.values_mut() {
+                    self.function(method, true)?;
+                }
+                Ok(())
+            }
+            ast::Decl::Enum(enum_decl) => {
+                self.declare(enum_decl.ident);
+                for method in enum_decl.methods.values_mut() {
+                    self.function(method, true)?;
+                }
+                Ok(())
+            }
+            ast::Decl::Trait(trait_decl) => {
+                self.declare(trait_decl.ident);
+                for method in trait_decl.methods.values_mut() {
+                    self.function(method, true)?;
+                }
+                Ok(())
+            }
+            ast::Decl::Impl(impl_decl) => {
+                self.lookup(impl_decl.trait_ident);
+                self.lookup(impl_decl.ident);
+                for method in impl_decl.methods.values_mut() {
+                    self.function(method, true)?;
+                }
+                Ok(())
+            }
+            ast::Decl::Fun(fun) => {
+                self.declare(fun.ident);
+                self.function(fun, false)
+            }
+            ast::Decl::Var(var) => {
+                self.expr(&mut var.expr)?;
+                self.declare(var.ident);
+                Ok(())
+            }
+            ast::Decl::Stmt(stmt) => self.stmt(stmt),
+        }
+    }
+
+    fn function(&mut self, fun: &mut ast::FunDecl, is_method: bool) -> Result<()> {
+        self.frames.push(Frame::new(FrameKind::Function, is_method));
+        let res = self.function_inner(fun);
+        self.frames.pop();
+        res
+    }
+
+    fn function_inner(&mut self, fun: &mut ast::FunDecl) -> Result<()> {
+        // Defaults may refer to the parameters before them.
+        for param in &mut fun.params {
+            if let Some(default) = &mut param.default {
+                self.expr(default)?;
+            }
+            self.declare(param.ident);
+        }
+        if let Some(rest) = fun.rest {
+            self.declare(rest);
+        }
+        self.block(&mut fun.block)
+    }
+
+    fn stmt(&mut self, stmt: &mut ast::Stmt) -> Result<()> {
+        match stmt {
+            ast::Stmt::Return(expr) | ast::Stmt::Break(expr) => match expr {
+                Some(expr) => self.expr(expr),
+                None => Ok(()),
+            },
+            ast::Stmt::Throw(expr) => self.expr(expr),
+            ast::Stmt::Assignment(assignment) => {
+                self.expr(&mut assignment.expr)?;
+                match assignment.lcall.head {
+                    ast::LCallHead::Ident(ident) => {
+                        let id = self.lookup(ident);
+                        match (id, assignment.lcall.tail.is_empty()) {
+                            (Some(id), true) => {
+                                self.reassigned.insert(id);
+                            }
+                            (None, true) => {
+                                self.assigned_globals.insert(ident);
+                            }
+                            _ => (),
+                        }
+                    }
+                    ast::LCallHead::SelfKw => self.lookup_self(),
+                }
+                for part in &mut assignment.lcall.tail {
+                    match part {
+                        ast::LCallPart::Dot(_) => (),
+                        ast::LCallPart::Brkts(expr) => self.expr(expr)?,
+                    }
+                }
+                Ok(())
+            }
+            ast::Stmt::Expr(expr) => self.expr(expr),
+        }
+    }
+
+    fn block(&mut self, block: &mut ast::Block) -> Result<()> {
+        self.begin_scope();
+        let res = self.block_inner(block);
+        self.end_scope();
+        res
+    }
+
+    fn block_inner(&mut self, block: &mut ast::Block) -> Result<()> {
+        for decl in &mut block.decls {
+            self.decl(decl)?;
+        }
+        if let Some(expr) = &mut block.expr {
+            self.expr(expr)?;
+        }
+        Ok(())
+    }
+
+    fn expr(&mut self, expr: &mut ast::Expr) -> Result<()> {
+        let outer = self.span;
+        self.span = Span::new(
+            expr.span.start + self.decl_start,
+            expr.span.end + self.decl_start,
+        );
+        let res = self.pipe(&mut expr.pipe);
+        self.span = outer;
+        res
+    }
+
+    fn pipe(&mut self, pipe: &mut ast::Pipe) -> Result<()> {
+        match pipe {
+            ast::Pipe::Next(next) => self.coalesce(next),
+            ast::Pipe::Current(left, rest) => {
+                self.coalesce(left)?;
+                for stage in rest {
+                    match stage.as_call_mut() {
+                        Some(call) => self.call(call, true)?,
+                        None => self.coalesce(stage)?,
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn coalesce(&mut self, coalesce: &mut ast::Coalesce) -> Result<()> {
+        match coalesce {
+            ast::Coalesce::Next(next) => self.logic_or(next),
+            ast::Coalesce::Current(left, rest) => {
+                self.logic_or(left)?;
+                for next in rest {
+                    self.logic_or(next)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn logic_or(&mut self, logic_or: &mut ast::LogicOr) -> Result<()> {
+        match logic_or {
+            ast::LogicOr::Next(next) => self.logic_and(next),
+            ast::LogicOr::Current(left, rest) => {
+                self.logic_and(left)?;
+                for next in rest {
+                    self.logic_and(next)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn logic_and(&mut self, logic_and: &mut ast::LogicAnd) -> Result<()> {
+        match logic_and {
+            ast::LogicAnd::Next(next) => self.cmp(next),
+            ast::LogicAnd::Current(left, rest) => {
+                self.cmp(left)?;
+                for next in rest {
+                    self.cmp(next)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn cmp(&mut self, cmp: &mut ast::Cmp) -> Result<()> {
+        match cmp {
+            ast::Cmp::Next(next) => self.range(next),
+            ast::Cmp::Current { left, rest } => {
+                self.range(left)?;
+                for (_, next) in rest {
+                    self.range(next)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn range(&mut self, range: &mut ast::Range) -> Result<()> {
+        match range {
+            ast::Range::Next(next) => self.bit_or(next),
+            ast::Range::Current { start, end, .. } => {
+                if let Some(start) = start {
+                    self.bit_or(start)?;
+                }
+                match end {
+                    Some(end) => self.bit_or(end),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+
+    fn bit_or(&mut self, bit_or: &mut ast::BitOr) -> Result<()> {
+        match bit_or {
+            ast::BitOr::Next(next) => self.bit_xor(next),
+            ast::BitOr::Current(left, rest) => {
+                self.bit_xor(left)?;
+                for next in rest {
+                    self.bit_xor(next)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn bit_xor(&mut self, bit_xor: &mut ast::BitXor) -> Result<()> {
+        match bit_xor {
+            ast::BitXor::Next(next) => self.bit_and(next),
+            ast::BitXor::Current(left, rest) => {
+                self.bit_and(left)?;
+                for next in rest {
+                    self.bit_and(next)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn bit_and(&mut self, bit_and: &mut ast::BitAnd) -> Result<()> {
+        match bit_and {
+            ast::BitAnd::Next(next) => self.shift(next),
+            ast::BitAnd::Current(left, rest) => {
+                self.shift(left)?;
+                for next in rest {
+                    self.shift(next)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn shift(&mut self, shift: &mut ast::Shift) -> Result<()> {
+        match shift {
+            ast::Shift::Next(next) => self.term(next),
+            ast::Shift::Current { left, rest } => {
+                self.term(left)?;
+                for (_, next) in rest {
+                    self.term(next)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn term(&mut self, term: &mut ast::Term) -> Result<()> {
+        match term {
+            ast::Term::Next(next) => self.factor(next),
+            ast::Term::Current { left, rest } => {
+                self.factor(left)?;
+                for (_, next) in rest {
+                    self.factor(next)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn factor(&mut self, factor: &mut ast::Factor) -> Result<()> {
+        match factor {
+            ast::Factor::Next(next) => self.unary(next),
+            ast::Factor::Current { left, rest } => {
+                self.unary(left)?;
+                for (_, next) in rest {
+                    self.unary(next)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn unary(&mut self, unary: &mut ast::Unary) -> Result<()> {
+        match unary {
+            ast::Unary::Next(power) => self.power(power),
+            ast::Unary::Current { unary, .. } => self.unary(unary),
+        }
+    }
+
+    fn power(&mut self, power: &mut ast::Power) -> Result<()> {
+        match power {
+            ast::Power::Next(call) => self.call(call, false),
+            ast::Power::Current { base, exp } => {
+                self.call(base, false)?;
+                self.unary(exp)
+            }
+        }
+    }
+
+    /// Resolves `call`, checking the arguments of calls to global functions.
+    /// A call `piped` into by `|>` gets the piped value as its first argument.
+    fn call(&mut self, call: &mut ast::Call, piped: bool) -> Result<()> {
+        match call.head {
+            ast::Primary::Ident(ident) => {
+                let is_global = self.lookup(ident).is_none();
+                if is_global {
+                    self.check_global(ident)?;
+                }
+                let fun_call = match (piped, &call.tail[..]) {
+                    (false, [ast::CallPart::FunCall(fun_call), ..])
+                    | (true, [ast::CallPart::FunCall(fun_call)]) => Some(fun_call),
+                    _ => None,
+                };
+                if let (true, Some(fun_call)) = (is_global && self.check_calls, fun_call) {
+                    if let Some(signature) = self.signatures.get(&ident) {
+                        signature.check(fun_call, piped, self.decl_start)?;
+                    }
+                }
+            }
+            _ => self.primary(&mut call.head)?,
+        }
+
+        for part in &mut call.tail {
+            match part {
+                ast::CallPart::Dot(_)
+                | ast::CallPart::Path(_)
+                | ast::CallPart::QMark
+                | ast::CallPart::NullSafe => (),
+                ast::CallPart::Brkts(expr) => self.expr(expr)?,
+                ast::CallPart::FunCall(fun_call) => {
+                    for arg in &mut fun_call.args {
+                        match arg {
+                            ast::Arg::Positional(expr)
+                            | ast::Arg::Named { expr, .. }
+                            | ast::Arg::Spread(expr) => self.expr(expr)?,
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn primary(&mut self, primary: &mut ast::Primary) -> Result<()> {
+        match primary {
+            ast::Primary::SelfKw => {
+                self.lookup_self();
+                Ok(())
+            }
+            ast::Primary::Prnth(expr) => self.expr(expr),
+            ast::Primary::Ident(ident) => match self.lookup(*ident) {
+                Some(_) => Ok(()),
+                None => self.check_global(*ident),
+            },
+            ast::Primary::For(for_loop) => {
+                self.expr(&mut for_loop.expr)?;
+                self.begin_scope();
+                self.declare(for_loop.ident);
+                let res = self.block_inner(&mut for_loop.block);
+                self.end_scope();
+                res
+            }
+            ast::Primary::While(while_loop) => {
+                self.expr(&mut while_loop.cond)?;
+                self.block(&mut while_loop.block)
+            }
+            ast::Primary::Loop(loop_loop) => self.block(&mut loop_loop.block),
+            ast::Primary::If(if_expr) => self.if_expr(if_expr),
+            ast::Primary::Try(try_expr) => {
+                self.block(&mut try_expr.block)?;
+                if let Some(catch) = &mut try_expr.catch {
+                    self.begin_scope();
+                    self.declare(catch.ident);
+                    let res = self.block_inner(&mut catch.block);
+                    self.end_scope();
+                    res?;
+                }
+                match &mut try_expr.finally {
+                    Some(finally) => self.block(finally),
+                    None => Ok(()),
+                }
+            }
+            ast::Primary::Yield(expr) => self.expr(expr),
+            ast::Primary::Closure(closure) => self.closure(closure),
+            ast::Primary::Block(block) => self.block(block),
+            ast::Primary::Literal(literal) => self.literal(literal),
+        }
+    }
+
+    fn if_expr(&mut self, if_expr: &mut ast::If) -> Result<()> {
+        for branch in &mut if_expr.branches {
+            self.if_branch(branch)?;
+        }
+        match &mut if_expr.els {
+            Some(block) => self.block(block),
+            None => Ok(()),
+        }
+    }
+
+    fn if_branch(&mut self, branch: &mut ast::IfBranch) -> Result<()> {
+        self.expr(&mut branch.cond)?;
+        match &branch.pattern {
+            Some(pattern) => {
+                self.begin_scope();
+                match &pattern.fields {
+                    ast::VariantFields::Unit => (),
+                    ast::VariantFields::Tuple(fields) | ast::VariantFields::Struct(fields) => {
+                        for &field in fields {
+                            self.declare(field);
+                        }
+                    }
+                }
+                let res = self.block_inner(&mut branch.block);
+                self.end_scope();
+                res
+            }
+            None => self.block(&mut branch.block),
+        }
+    }
+
+    fn closure(&mut self, closure: &mut ast::Closure) -> Result<()> {
+        self.frames.push(Frame::new(FrameKind::Closure, false));
+        for &param in &closure.params {
+            self.declare(param);
+        }
+        let res = self.expr(&mut closure.body);
+        let frame = self.frames.pop().unwrap();
+        res?;
+
+        closure.captures = frame
+            .captures
+            .into_iter()
+            .map(|(ident, id)| ast::Capture {
+                ident,
+                mode: if self.known_reassigned.contains(&id) {
+                    ast::CaptureMode::ByRef
+                } else {
+                    ast::CaptureMode::ByValue
+                },
+            })
+            .collect();
+        closure.captures_self = frame.captures_self;
+        Ok(())
+    }
+
+    fn literal(&mut self, literal: &mut ast::Literal) -> Result<()> {
+        match literal {
+            ast::Literal::Struct(struct_lit) => {
+                for (_, expr) in &mut struct_lit.fields {
+                    self.expr(expr)?;
+                }
+                Ok(())
+            }
+            ast::Literal::Map(map_lit) => {
+                for (key, value) in &mut map_lit.fields {
+                    self.expr(key)?;
+                    self.expr(value)?;
+                }
+                Ok(())
+            }
+            ast::Literal::Set(set_lit) => {
+                for elem in &mut set_lit.elems {
+                    self.expr(elem)?;
+                }
+                Ok(())
+            }
+            ast::Literal::Array(array_lit) => {
+                for elem in &mut array_lit.elems {
+                    self.expr(elem)?;
+                }
+                Ok(())
+            }
+            ast::Literal::Tuple(tuple_lit) => {
+                for elem in &mut tuple_lit.elems {
+                    self.expr(elem)?;
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::scanner::Scanner;
+    use crate::str_interner::Interner;
+
+    fn parse(source: &str, interner: &mut Interner) -> ast::Program {
+        let mut program = Parser::new(Scanner::new(source, interner))
+            .program()
+            .unwrap();
+        resolve(&mut program).unwrap();
+        program
+    }
+
+    /// Returns the closures in the order their bodies end.
+    fn closures(program: &ast::Program) -> Vec<&ast::Closure> {
+        fn visit<'a>(expr: &'a ast::Expr, out: &mut Vec<&'a ast::Closure>) {
+            // The tests only nest closures directly inside each other, in
+            // `let` initializers or in call arguments.
+            let call = match &expr.pipe {
+                ast::Pipe::Next(ast::Coalesce::Next(ast::LogicOr::Next(ast::LogicAnd::Next(
+                    ast::Cmp::Next(ast::Range::Next(ast::BitOr::Next(ast::BitXor::Next(
+                        ast::BitAnd::Next(ast::Shift::Next(ast::Term::Next(ast::Factor::Next(
+                            ast::Unary::Next(ast::Power::Next(call)),
+                        )))),
+                    )))),
+                )))) => call,
+                _ => return,
+            };
+            for part in &call.tail {
+                if let ast::CallPart::FunCall(fun_call) = part {
+                    for arg in &fun_call.args {
+                        if let ast::Arg::Positional(expr) = arg {
+                            visit(expr, out);
+                        }
+                    }
+                }
+            }
+            match &call.head {
+                ast::Primary::Closure(closure) => {
+                    visit(&closure.body, out);
+                    out.push(closure);
+                }
+                ast::Primary::Block(block) => visit_block(block, out),
+                _ => (),
+            }
+        }
+
+        fn visit_block<'a>(block: &'a ast::Block, out: &mut Vec<&'a ast::Closure>) {
+            block.decls.iter().for_each(|decl| visit_decl(decl, out));
+            if let Some(expr) = &block.expr {
+                visit(expr, out);
+            }
+        }
+
+        fn visit_decl<'a>(decl: &'a ast::Decl, out: &mut Vec<&'a ast::Closure>) {
+            match decl {
+                ast::Decl::Fun(fun) => visit_block(&fun.block, out),
+                ast::Decl::Struct(s) => s.methods.values().for_each(|f| visit_block(&f.block, out)),
+                ast::Decl::Var(var) => visit(&var.expr, out),
+                ast::Decl::Stmt(ast::Stmt::Expr(expr)) => visit(expr, out),
+                _ => (),
+            }
+        }
+
+        let mut out = Vec::new();
+        program
+            .decls
+            .iter()
+            .for_each(|decl| visit_decl(decl, &mut out));
+        out
+    }
+
+    fn captures(closure: &ast::Closure, interner: &Interner) -> Vec<(String, ast::CaptureMode)> {
+        closure
+            .captures
+            .iter()
+            .map(|c| (interner.lookup(c.ident).unwrap().to_owned(), c.mode))
+            .collect()
+    }
+
+    #[test]
+    fn test_closure_syntax() {
+        let mut interner = Interner::new();
+        let program = parse(
+            "let a = xs.map(|x| x * 2);
+            let b = || 1;
+            let c = |x, y| { x + y };
+            let d = |x| |y| x + y;",
+            &mut interner,
+        );
+        let closures = closures(&program);
+        let params: Vec<_> = closures.iter().map(|c| c.params.len()).collect();
+        assert_eq!(vec![1, 0, 2, 1, 1], params);
+    }
+
+    #[test]
+    fn test_globals_are_not_captured() {
+        let mut interner = Interner::new();
+        let program = parse("let g = 1; fn f() { g } let c = || g + f();", &mut interner);
+        assert!(closures(&program)[0].captures.is_empty());
+    }
+
+    #[test]
+    fn test_capture_modes() {
+        use ast::CaptureMode::*;
+
+        let mut interner = Interner::new();
+        let program = parse(
+            "fn f(a, b) {
+                let c = 0;
+                let inc = || { c += a; };
+                let get = || b + c;
+                let put = |x| { b.field = x; };
+                a = 1;
+            }",
+            &mut interner,
+        );
+        let closures = closures(&program);
+        assert_eq!(
+            vec![("a".to_owned(), ByRef), ("c".to_owned(), ByRef)],
+            captures(closures[0], &interner)
+        );
+        assert_eq!(
+            vec![("b".to_owned(), ByValue), ("c".to_owned(), ByRef)],
+            captures(closures[1], &interner)
+        );
+        assert_eq!(
+            vec![("b".to_owned(), ByValue)],
+            captures(closures[2], &interner)
+        );
+    }
+
+    #[test]
+    fn test_nested_captures() {
+        use ast::CaptureMode::*;
+
+        let mut interner = Interner::new();
+        let program = parse(
+            "fn f(a) {
+                let outer = |x| { let y = x; || a + y + x };
+            }",
+            &mut interner,
+        );
+        let closures = closures(&program);
+        assert_eq!(
+            vec![
+                ("a".to_owned(), ByValue),
+                ("y".to_owned(), ByValue),
+                ("x".to_owned(), ByValue)
+            ],
+            captures(closures[0], &interner)
+        );
+        assert_eq!(
+            vec![("a".to_owned(), ByValue)],
+            captures(closures[1], &interner)
+        );
+    }
+
+    #[test]
+    fn test_function_bodies_do_not_capture() {
+        let mut interner = Interner::new();
+        let program = parse(
+            "fn f(a) {
+                fn g() { || a }
+                || a
+            }",
+            &mut interner,
+        );
+        let closures = closures(&program);
+        assert!(closures[0].captures.is_empty());
+        assert_eq!(1, closures[1].captures.len());
+    }
+
+    #[test]
+    fn test_captures_self() {
+        let mut interner = Interner::new();
+        let program = parse(
+            "struct S {
+                fn get() { || || self.x }
+            }
+            let c = || 1;",
+            &mut interner,
+        );
+        let closures = closures(&program);
+        assert!(closures[0].captures_self);
+        assert!(closures[1].captures_self);
+        assert!(!closures[2].captures_self);
+    }
+
+    fn resolve_err(source: &str, interner: &mut Interner) -> Error {
+        let mut program = Parser::new(Scanner::new(source, interner))
+            .program()
+            .unwrap();
+        resolve(&mut program).unwrap_err()
+    }
+
+    #[test]
+    fn test_call_arity() {
+        let mut interner = Interner::new();
+        let fun = "fn f(a, b = 2, c = 3) { a + b + c }\n";
+        for call in [
+            "f(1);",
+            "f(1, 2, 3);",
+            "f(a: 1);",
+            "f(1, c: 4);",
+            "f(c: 1, a: 2);",
+            "f(...xs);",
+            "f(...xs, 1, 2, 3, 4);",
+            "let g = |f| f(1, 2, 3, 4, 5);",
+        ] {
+            parse(&format!("{}{}", fun, call), &mut interner);
+        }
+
+        let source = format!("{}{}", fun, "let x = f(1, 2, 3, 4);");
+        match resolve_err(&source, &mut interner) {
+            Error::TooManyArgs {
+                expected: 3,
+                found: 4,
+                span,
+            } => assert_eq!("(1, 2, 3, 4)", &source[span.start..span.end]),
+            err => panic!("unexpected error {:?}", err),
+        }
+
+        let source = format!("{}{}", fun, "f(b: 1);");
+        match resolve_err(&source, &mut interner) {
+            Error::MissingArg(ident, span) => {
+                assert_eq!("a", interner.lookup(ident).unwrap());
+                assert_eq!("(b: 1)", &source[span.start..span.end]);
+            }
+            err => panic!("unexpected error {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_call_named_args() {
+        let mut interner = Interner::new();
+        let fun = "fn f(a, ...rest) { a }\n";
+
+        let source = format!("{}{}", fun, "let x = { f(1, d: 2) };");
+        match resolve_err(&source, &mut interner) {
+            Error::UnknownArgName(ident, span) => {
+                assert_eq!("d", interner.lookup(ident).unwrap());
+                assert_eq!("d", &source[span.start..span.end]);
+            }
+            err => panic!("unexpected error {:?}", err),
+        }
+
+        let source = format!("{}{}", fun, "f(1, rest: 2);");
+        assert!(matches!(
+            resolve_err(&source, &mut interner),
+            Error::UnknownArgName(..)
+        ));
+
+        let source = format!("{}{}", fun, "f(1, a: 2);");
+        match resolve_err(&source, &mut interner) {
+            Error::ArgGivenTwice(ident, span) => {
+                assert_eq!("a", interner.lookup(ident).unwrap());
+                assert_eq!(source.rfind('a').unwrap(), span.start);
+            }
+            err => panic!("unexpected error {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_shadowed_functions_are_not_checked() {
+        let mut interner = Interner::new();
+        parse(
+            "fn f(a) { a } fn g(f) { f(1, 2) } let x = f(1);",
+            &mut interner,
+        );
+        parse("fn f(a) { a } let f = 1; f(1, 2);", &mut interner);
+        parse("fn f(a) { a } f = |a, b| a; f(1, 2);", &mut interner);
+        parse(
+            "fn f(a) { a } fn g() { f(1, 2) } fn h() { f = |a, b| a; }",
+            &mut interner,
+        );
+    }
+
+    #[test]
+    fn test_param_errors() {
+        let mut interner = Interner::new();
+        let mut parse_err = |source: &str| {
+            Parser::new(Scanner::new(source, &mut interner))
+                .program()
+                .unwrap_err()
+        };
+
+        assert!(matches!(
+            parse_err("fn f(a, b = 1, c) {}"),
+            Error::RequiredParamAfterDefault(_, Span { start: 15, end: 16 })
+        ));
+        assert!(matches!(
+            parse_err("fn f(a, a) {}"),
+            Error::ParamDefinedTwice(_, Span { start: 8, end: 9 })
+        ));
+        assert!(matches!(
+            parse_err("fn f(...a, b) {}"),
+            Error::RestParamNotLast(Span { start: 8, end: 9 })
+        ));
+        assert!(matches!(
+            parse_err("fn f(self, a) {}"),
+            Error::SelfOutsideMethod
+        ));
+        assert!(matches!(
+            parse_err("f(a: 1, 2);"),
+            Error::PositionalArgAfterNamed(Span { start: 8, end: 9 })
+        ));
+        assert!(matches!(
+            parse_err("f(a: 1, a: 2);"),
+            Error::ArgGivenTwice(_, Span { start: 8, end: 9 })
+        ));
+    }
+}
@@ -1,7 +1,8 @@
 use crate::error::Error;
 use crate::str_interner::Interner;
 use crate::token::{
-    Assign, CmpOp, Delimiter, FactorOp, Keyword, Literal, Operator, ShiftOp, TermOp, Token,
+    Assign, CmpOp, Delimiter, FactorOp, Keyword, Literal, Operator, RangeOp, ShiftOp, Span, TermOp,
+    Token,
 };
 use std::iter::Peekable;
 use std::str::{CharIndices, FromStr};
@@ -11,43 +12,89 @@ pub struct Scanner<'a> {
     input_str: &'a str,
     pos: Pos,
     interner: &'a mut Interner,
-    buf: Option<Token>,
+    buf: Option<(Token, Span)>,
+    base: usize,
+    last: Span,
+    prev: Span,
+    after_dot: bool,
 }
 
 impl<'a> Scanner<'a> {
     pub fn new(input: &'a str, interner: &'a mut Interner) -> Scanner<'a> {
+        Scanner::new_at(input, interner, 0)
+    }
+
+    /// Creates a scanner that starts scanning `input` at byte offset `start`.
+    /// Spans of the produced tokens are still relative to the start of `input`.
+    pub fn new_at(input: &'a str, interner: &'a mut Interner, start: usize) -> Scanner<'a> {
+        let mut pos = Pos::default();
+        for c in input[..start].chars() {
+            if c == '\n' {
+                pos.line += 1;
+                pos.col = 1;
+            } else {
+                pos.col += 1;
+            }
+        }
+
         Scanner {
-            input: input.char_indices().peekable(),
-            input_str: input,
-            pos: Pos::default(),
+            input: input[start..].char_indices().peekable(),
+            input_str: &input[start..],
+            pos,
             interner,
             buf: None,
+            base: start,
+            last: Span::new(start, start),
+            prev: Span::new(start, start),
+            after_dot: false,
         }
     }
 
     pub fn putback(&mut self, token: Token) {
         assert!(self.buf.is_none());
-        self.buf = Some(token);
+        self.buf = Some((token, self.last));
+        self.last = self.prev;
     }
 
     pub fn peek_next(&mut self) -> Result<Token, Error> {
         let token = self.get_next()?;
-        self.buf = Some(token);
+        self.putback(token);
         Ok(token)
     }
 
+    /// Span of the token most recently returned by `get_next` that was not put back.
+    pub fn last_span(&self) -> Span {
+        self.last
+    }
+
+    /// Span of the token that the next call to `get_next` will return.
+    pub fn peek_span(&mut self) -> Result<Span, Error> {
+        let token = self.get_next()?;
+        let span = self.last;
+        self.putback(token);
+        Ok(span)
+    }
+
     pub fn get_next(&mut self) -> Result<Token, Error> {
-        if let Some(token) = self.buf.take() {
+        self.prev = self.last;
+
+        if let Some((token, span)) = self.buf.take() {
+            self.last = span;
             return Ok(token);
         }
 
         let c = match self.skip_whitespace() {
             Some(c) => c,
-            None => return Ok(Token::Eof),
+            None => {
+                let end = self.base + self.input_str.len();
+                self.last = Span::new(end, end);
+                return Ok(Token::Eof);
+            }
         };
+        let start = self.base + self.pos.idx;
 
         let token = match c {
-            '?' => Token::Operator(Operator::QMark),
+            '?' => self.qmark(),
             '=' => self.assign(),
             '+' => self.add(),
             '-' => self.sub(),
@@ -66,7 +113,7 @@ impl<'a> Scanner<'a> {
             ')' => Token::Delimiter(Delimiter::ClosePrnth),
             '{' => Token::Delimiter(Delimiter::OpenCurly),
             '}' => Token::Delimiter(Delimiter::CloseCurly),
-            '.' => Token::Delimiter(Delimiter::Dot),
+            '.' => self.dot(),
             ',' => Token::Delimiter(Delimiter::Comma),
             '\'' => self.character()?,
             '"' => self.string()?,
@@ -83,9 +130,52 @@ impl<'a> Scanner<'a> {
             }
         };
 
+        let end = match self.input.peek() {
+            Some(&(i, _)) => i,
+            None => self.input_str.len(),
+        };
+        self.last = Span::new(start, self.base + end);
+        self.after_dot = matches!(
+            token,
+            Token::Delimiter(Delimiter::Dot) | Token::Operator(Operator::QMarkDot)
+        );
+
         Ok(token)
     }
 
+    /// `?.` and `??` take precedence over the postfix `?`, so unwrapping
+    /// before a field access or unwrapping twice needs parentheses, as in
+    /// `(x?).y`.
+    fn qmark(&mut self) -> Token {
+        let rest = &self.input_str[self.pos.idx..];
+        if rest.starts_with("?.") && !rest.starts_with("?..") {
+            self.advance().unwrap();
+            Token::Operator(Operator::QMarkDot)
+        } else if self.advance_if('?').is_some() {
+            Token::Operator(Operator::Coalesce)
+        } else {
+            Token::Operator(Operator::QMark)
+        }
+    }
+
+    fn dot(&mut self) -> Token {
+        let rest = &self.input_str[self.pos.idx..];
+        if rest.starts_with("...") {
+            self.advance().unwrap();
+            self.advance().unwrap();
+            Token::Delimiter(Delimiter::Ellipsis)
+        } else if rest.starts_with("..=") {
+            self.advance().unwrap();
+            self.advance().unwrap();
+            Token::Operator(Operator::Range(RangeOp::Inclusive))
+        } else if rest.starts_with("..") {
+            self.advance().unwrap();
+            Token::Operator(Operator::Range(RangeOp::Exclusive))
+        } else {
+            Token::Delimiter(Delimiter::Dot)
+        }
+    }
+
     fn colon(&mut self) -> Token {
         if self.advance_if(':').is_some() {
             Token::Delimiter(Delimiter::Doublecolon)
@@ -97,20 +187,25 @@ impl<'a> Scanner<'a> {
     fn ident(&mut self) -> Token {
         let start = self.pos.idx;
         let end = loop {
-            let c = match self.input.peek() {
-                Some(c) => c.1,
+            let (i, c) = match self.input.peek() {
+                Some(&c) => c,
                 None => break self.input_str.len(),
             };
 
             if c.is_ascii_alphanumeric() || c == '_' {
                 self.advance().unwrap();
             } else {
-                break self.pos.idx + c.len_utf8();
+                break i;
             }
         };
 
         let ident = &self.input_str[start..end];
 
+        // Field and method names may be keywords, as in `xs.map(f)`.
+        if self.after_dot {
+            return Token::Ident(self.interner.intern_str(ident));
+        }
+
         match ident {
             "true" => Token::Literal(Literal::Bool(true)),
             "false" => Token::Literal(Literal::Bool(false)),
@@ -122,15 +217,24 @@ impl<'a> Scanner<'a> {
             "if" => Token::Keyword(Keyword::If),
             "else" => Token::Keyword(Keyword::Else),
             "struct" => Token::Keyword(Keyword::Struct),
+            "enum" => Token::Keyword(Keyword::Enum),
+            "trait" => Token::Keyword(Keyword::Trait),
+            "impl" => Token::Keyword(Keyword::Impl),
             "fn" => Token::Keyword(Keyword::Fn),
             "let" => Token::Keyword(Keyword::Let),
             "self" => Token::Keyword(Keyword::SelfKw),
             "return" => Token::Keyword(Keyword::Return),
             "break" => Token::Keyword(Keyword::Break),
             "map" => Token::Keyword(Keyword::Map),
+            "set" => Token::Keyword(Keyword::Set),
             "new" => Token::Keyword(Keyword::New),
             "use" => Token::Keyword(Keyword::Use),
             "mod" => Token::Keyword(Keyword::Mod),
+            "throw" => Token::Keyword(Keyword::Throw),
+            "try" => Token::Keyword(Keyword::Try),
+            "catch" => Token::Keyword(Keyword::Catch),
+            "finally" => Token::Keyword(Keyword::Finally),
+            "yield" => Token::Keyword(Keyword::Yield),
             _ => Token::Ident(self.interner.intern_str(ident)),
         }
     }
@@ -139,35 +243,46 @@ impl<'a> Scanner<'a> {
         let mut had_dot = false;
         let start = self.pos.idx;
         let end = loop {
-            let c = match self.input.peek() {
-                Some(c) => c.1,
+            let (i, c) = match self.input.peek() {
+                Some(&c) => c,
                 None => break self.input_str.len(),
             };
 
             if c.is_ascii_digit() {
                 self.advance().unwrap();
             } else if c == '.' {
-                if had_dot {
-                    break self.pos.idx + c.len_utf8();
+                // A dot not followed by a digit starts a range, as in `0..n`,
+                // and tuple fields are numbered, as in `t.0.1`.
+                let fraction = self.input_str[i + 1..].starts_with(|c: char| c.is_ascii_digit());
+                if had_dot || !fraction || self.after_dot {
+                    break i;
                 } else {
                     had_dot = true;
                     self.advance().unwrap();
                 }
             } else {
-                break self.pos.idx + c.len_utf8();
+                break i;
             }
         };
 
         let num = &self.input_str[start..end];
+        if self.after_dot {
+            if num.len() > 1 && num.starts_with('0') {
+                return Err(Error::InvalidTupleIndex);
+            }
+            return Ok(Token::Ident(self.interner.intern_str(num)));
+        }
 
         let num = if had_dot {
             Token::Literal(Literal::Float(
                 f64::from_str(num).map_err(Error::ParseFloatError)?,
             ))
         } else {
-            Token::Literal(Literal::Int(
-                i64::from_str(num).map_err(Error::ParseIntError)?,
-            ))
+            match i64::from_str(num) {
+                Ok(i) => Token::Literal(Literal::Int(i)),
+                // The literal is all digits, so it can only be too large.
+                Err(_) => Token::Literal(Literal::BigInt(self.interner.intern_str(num))),
+            }
         };
 
         Ok(num)
@@ -224,13 +339,13 @@ impl<'a> Scanner<'a> {
                     _ => return Err(Error::InvalidEscapeSequence),
                 };
                 match self.advance() {
-                    Some(q) if q == '\'' => Ok(Token::Literal(Literal::Char(c))),
+                    Some('\'') => Ok(Token::Literal(Literal::Char(c))),
                     _ => Err(Error::UnclosedCharLiteral),
                 }
             }
             '\'' => Err(Error::EmptyCharLiteral),
             _ => match self.advance() {
-                Some(q) if q == '\'' => Ok(Token::Literal(Literal::Char(c))),
+                Some('\'') => Ok(Token::Literal(Literal::Char(c))),
                 _ => Err(Error::UnclosedCharLiteral),
             },
         }
@@ -324,6 +439,10 @@ impl<'a> Scanner<'a> {
                 self.advance().unwrap();
                 Token::Assign(Assign::BitOr)
             }
+            '>' => {
+                self.advance().unwrap();
+                Token::Operator(Operator::Pipe)
+            }
             _ => Token::Operator(Operator::BitOr),
         }
     }
@@ -345,7 +464,13 @@ impl<'a> Scanner<'a> {
     }
 
     fn mul(&mut self) -> Token {
-        if self.advance_if('=').is_some() {
+        if self.advance_if('*').is_some() {
+            if self.advance_if('=').is_some() {
+                Token::Assign(Assign::Pow)
+            } else {
+                Token::Operator(Operator::Pow)
+            }
+        } else if self.advance_if('=').is_some() {
             Token::Assign(Assign::Mul)
         } else {
             Token::Operator(Operator::Factor(FactorOp::Mul))
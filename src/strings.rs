@@ -0,0 +1,437 @@
+//! The string library: methods callable on strings and `format`.
+//!
+//! Lengths, indices and padding count chars, not bytes.
+
+use crate::bigint::BigInt;
+use crate::error::{Error, Result};
+use crate::value::{BuiltinKind, NativeFn, Object, Unpacked, Value};
+use crate::vm::Vm;
+
+const METHODS: &[(&str, NativeFn)] = &[
+    ("len", len),
+    ("chars", chars),
+    ("bytes", bytes),
+    ("split", split),
+    ("join", join),
+    ("trim", trim),
+    ("trim_start", trim_start),
+    ("trim_end", trim_end),
+    ("starts_with", starts_with),
+    ("ends_with", ends_with),
+    ("find", find),
+    ("replace", replace),
+    ("to_upper", to_upper),
+    ("to_lower", to_lower),
+    ("parse_int", parse_int),
+    ("parse_float", parse_float),
+    ("repeat", repeat),
+    ("pad_start", pad_start),
+    ("pad_end", pad_end),
+    ("slice", slice),
+    ("format", format),
+];
+
+/// Adds the string methods and the global `format` to `vm`.
+pub fn define(vm: &mut Vm) {
+    for &(ident, fun) in METHODS {
+        vm.define_method(BuiltinKind::Str, ident, fun);
+    }
+    vm.define_native("format", format);
+}
+
+/// The receiver of a method, after checking it got at most `max` arguments.
+fn receiver<'a>(vm: &'a Vm, args: &[Value], max: usize) -> Result<&'a str> {
+    if args.len() > max + 1 {
+        return Err(Error::TypeError("too many arguments"));
+    }
+    args.first()
+        .and_then(|&value| vm.str(value))
+        .ok_or(Error::TypeError("expected a string"))
+}
+
+/// A string argument, or a char standing for a string of one char.
+fn pattern(vm: &Vm, arg: Option<&Value>) -> Result<String> {
+    match arg.map(|value| (value, value.unpack())) {
+        Some((_, Unpacked::Char(c))) => Ok(c.to_string()),
+        Some((&value, _)) => vm
+            .str(value)
+            .map(str::to_owned)
+            .ok_or(Error::TypeError("expected a string or char")),
+        None => Err(Error::TypeError("missing string argument")),
+    }
+}
+
+fn int_arg(vm: &Vm, arg: Option<&Value>) -> Result<i64> {
+    arg.and_then(|&value| vm.int(value))
+        .ok_or(Error::TypeError("expected an int"))
+}
+
+fn len(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    Ok(Value::int(receiver(vm, args, 0)?.chars().count() as i64))
+}
+
+fn chars(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let chars = receiver(vm, args, 0)?.chars().map(Value::char).collect();
+    vm.alloc_array(chars)
+}
+
+fn bytes(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let bytes = receiver(vm, args, 0)?
+        .bytes()
+        .map(|b| Value::int(b as i64))
+        .collect();
+    vm.alloc_array(bytes)
+}
+
+/// Splits on a separator, or on runs of whitespace if there is none.
+fn split(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let s = receiver(vm, args, 1)?;
+    let parts: Vec<String> = match args.get(1) {
+        None => s.split_whitespace().map(str::to_owned).collect(),
+        sep => {
+            let sep = pattern(vm, sep)?;
+            if sep.is_empty() {
+                return Err(Error::TypeError("separator cannot be empty"));
+            }
+            s.split(sep.as_str()).map(str::to_owned).collect()
+        }
+    };
+    let parts = parts.into_iter().map(|part| vm.alloc_str(part)).collect();
+    vm.alloc_array(parts)
+}
+
+/// Joins the elements of an array with the receiver between them. Elements
+/// other than strings are written the way `print` shows them.
+fn join(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let sep = receiver(vm, args, 1)?;
+    let elems = match args.get(1).and_then(|value| value.as_obj()) {
+        Some(obj) => match vm.heap().get(obj) {
+            Object::Array(elems) => elems,
+            _ => return Err(Error::TypeError("join takes an array")),
+        },
+        _ => return Err(Error::TypeError("join takes an array")),
+    };
+    let parts: Vec<_> = elems.iter().map(|&elem| vm.display(elem)).collect();
+    let joined = parts.join(sep);
+    vm.check_str_len(joined.len())?;
+    Ok(vm.alloc_str(joined))
+}
+
+fn trim(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let trimmed = receiver(vm, args, 0)?.trim().to_owned();
+    Ok(vm.alloc_str(trimmed))
+}
+
+fn trim_start(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let trimmed = receiver(vm, args, 0)?.trim_start().to_owned();
+    Ok(vm.alloc_str(trimmed))
+}
+
+fn trim_end(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let trimmed = receiver(vm, args, 0)?.trim_end().to_owned();
+    Ok(vm.alloc_str(trimmed))
+}
+
+fn starts_with(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let prefix = pattern(vm, args.get(1))?;
+    Ok(Value::bool(receiver(vm, args, 1)?.starts_with(&prefix)))
+}
+
+fn ends_with(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let suffix = pattern(vm, args.get(1))?;
+    Ok(Value::bool(receiver(vm, args, 1)?.ends_with(&suffix)))
+}
+
+/// The char index of the first occurrence of the argument, or null.
+fn find(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let needle = pattern(vm, args.get(1))?;
+    let s = receiver(vm, args, 1)?;
+    Ok(match s.find(&needle) {
+        Some(idx) => Value::int(s[..idx].chars().count() as i64),
+        None => Value::NULL,
+    })
+}
+
+fn replace(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let (from, to) = (pattern(vm, args.get(1))?, pattern(vm, args.get(2))?);
+    let replaced = receiver(vm, args, 2)?.replace(&from, &to);
+    vm.check_str_len(replaced.len())?;
+    Ok(vm.alloc_str(replaced))
+}
+
+fn to_upper(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let s = receiver(vm, args, 0)?;
+    vm.check_str_len(mapped_len(s, char::to_uppercase))?;
+    let upper = s.to_uppercase();
+    Ok(vm.alloc_str(upper))
+}
+
+fn to_lower(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let s = receiver(vm, args, 0)?;
+    vm.check_str_len(mapped_len(s, char::to_lowercase))?;
+    let lower = s.to_lowercase();
+    Ok(vm.alloc_str(lower))
+}
+
+/// Bytes of `s` once each char is mapped by `map`, as case mapping may
+/// lengthen a string.
+fn mapped_len<I: Iterator<Item = char>>(s: &str, map: impl Fn(char) -> I) -> usize {
+    s.chars().flat_map(map).map(char::len_utf8).sum()
+}
+
+/// Parses an optionally signed decimal int into `Result::Ok`, or returns
+/// `Result::Err` with a message.
+fn parse_int(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let s = receiver(vm, args, 0)?;
+    let digits = s.strip_prefix('+').unwrap_or(s);
+    let parsed = match digits.parse() {
+        Ok(i) => Some(vm.int_value(i)),
+        Err(_) => BigInt::from_decimal(digits).map(|big| vm.alloc_big(big)),
+    };
+    Ok(match parsed {
+        Some(value) => vm.ok(value),
+        None => parse_error(vm, "int", args[0]),
+    })
+}
+
+fn parse_float(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    Ok(match receiver(vm, args, 0)?.parse() {
+        Ok(f) => vm.ok(Value::float(f)),
+        Err(_) => parse_error(vm, "float", args[0]),
+    })
+}
+
+fn parse_error(vm: &mut Vm, kind: &str, value: Value) -> Value {
+    let message = format!("invalid {}: {:?}", kind, vm.str(value).unwrap());
+    let message = vm.alloc_str(message);
+    vm.err(message)
+}
+
+fn repeat(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let count = usize::try_from(int_arg(vm, args.get(1))?)
+        .map_err(|_| Error::TypeError("repeat count cannot be negative"))?;
+    let len = receiver(vm, args, 1)?.len().saturating_mul(count);
+    vm.check_str_len(len)?;
+    let repeated = receiver(vm, args, 1)?.repeat(count);
+    Ok(vm.alloc_str(repeated))
+}
+
+fn pad_start(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    pad(vm, args, true)
+}
+
+fn pad_end(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    pad(vm, args, false)
+}
+
+/// Pads to a width in chars by repeating a fill char, a space by default.
+fn pad(vm: &mut Vm, args: &[Value], start: bool) -> Result<Value> {
+    let width = int_arg(vm, args.get(1))?;
+    let fill = match args.get(2).map(|&value| (value, value.unpack())) {
+        None => ' ',
+        Some((_, Unpacked::Char(c))) => c,
+        Some((value, _)) => {
+            let mut chars = vm.str(value).into_iter().flat_map(str::chars);
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => c,
+                _ => return Err(Error::TypeError("fill must be a single char")),
+            }
+        }
+    };
+    let s = receiver(vm, args, 2)?;
+    let missing = usize::try_from(width)
+        .unwrap_or(0)
+        .saturating_sub(s.chars().count());
+    vm.check_str_len(
+        s.len()
+            .saturating_add(missing.saturating_mul(fill.len_utf8())),
+    )?;
+    let fill: String = std::iter::repeat_n(fill, missing).collect();
+    let padded = if start {
+        fill + s
+    } else {
+        s.to_owned() + &fill
+    };
+    Ok(vm.alloc_str(padded))
+}
+
+/// The chars from a start index up to an end index, or to the end.
+fn slice(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let s = receiver(vm, args, 2)?;
+    let len = s.chars().count() as i64;
+    let start = int_arg(vm, args.get(1))?;
+    let end = match args.get(2) {
+        None => len,
+        end => int_arg(vm, end)?,
+    };
+    if !(0..=len).contains(&end) {
+        return Err(Error::IndexOutOfRange(end));
+    }
+    if !(0..=end).contains(&start) {
+        return Err(Error::IndexOutOfRange(start));
+    }
+    let sliced = s
+        .chars()
+        .skip(start as usize)
+        .take((end - start) as usize)
+        .collect();
+    Ok(vm.alloc_str(sliced))
+}
+
+/// Fills in a template: `{}` takes the next argument, `{n}` the argument at
+/// index `n`, and `{{` and `}}` stand for braces. Arguments are written the
+/// way `print` shows them.
+fn format(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let template = args
+        .first()
+        .and_then(|&value| vm.str(value))
+        .ok_or(Error::TypeError("format takes a template string"))?;
+    let values = &args[1..];
+    let mut out = String::new();
+    let mut next = 0;
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' => {
+                let mut spec = String::new();
+                loop {
+                    match chars.next() {
+                        Some('{') if spec.is_empty() => {
+                            out.push('{');
+                            break;
+                        }
+                        Some('}') => {
+                            let idx = if spec.is_empty() {
+                                next += 1;
+                                next - 1
+                            } else {
+                                spec.parse()
+                                    .map_err(|_| Error::TypeError("invalid format placeholder"))?
+                            };
+                            let value = values
+                                .get(idx)
+                                .ok_or(Error::TypeError("format needs more arguments"))?;
+                            out.push_str(&vm.display(*value));
+                            break;
+                        }
+                        Some(c) => spec.push(c),
+                        None => return Err(Error::TypeError("unclosed format placeholder")),
+                    }
+                }
+            }
+            '}' if chars.next() == Some('}') => out.push('}'),
+            '}' => return Err(Error::TypeError("unmatched `}` in format string")),
+            c => out.push(c),
+        }
+    }
+    vm.check_str_len(out.len())?;
+    Ok(vm.alloc_str(out))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::testing::{eval, eval_err};
+
+    #[test]
+    fn test_unicode_lengths_and_indices() {
+        assert_eq!(
+            "[5, ['h', 'é', 'l', 'l', 'ö'], [104, 195, 169], 2, null, \"él\", \"lö\", 'é']",
+            eval(
+                "let s = \"héllö\";
+                [s.len(), s.chars(), \"hé\".bytes(), s.find('l'),
+                    s.find(\"x\"), s.slice(1, 3), s.slice(3), s[1]];"
+            )
+        );
+        assert_eq!(
+            "[\"ÉCOLE\", \"straße\", \"STRASSE\"]",
+            eval("[\"école\".to_upper(), \"STRAßE\".to_lower(), \"straße\".to_upper()];")
+        );
+        assert!(matches!(
+            eval_err("\"abc\".slice(2, 4);"),
+            Error::IndexOutOfRange(4)
+        ));
+        assert!(matches!(
+            eval_err("\"abc\".slice(2, 1);"),
+            Error::IndexOutOfRange(2)
+        ));
+    }
+
+    #[test]
+    fn test_split_join_and_trim() {
+        assert_eq!(
+            "[[\"a\", \"b\", \"\", \"c\"], [\"one\", \"two\"], [\"x\", \"y\"], \"1-2-true-z\"]",
+            eval(
+                "[\"a,b,,c\".split(\",\"), \"  one \\t two\\n\".split(), \"x→y\".split('→'),
+                    \"-\".join([1, 2, true, \"z\"])];"
+            )
+        );
+        assert_eq!(
+            "[\"a b\", \"a b  \", \"  a b\", true, false, true]",
+            eval(
+                "let s = \"  a b  \";
+                [s.trim(), s.trim_start(), s.trim_end(), \"naïve\".starts_with(\"na\"),
+                    \"naïve\".starts_with('ï'), \"naïve\".ends_with(\"ïve\")];"
+            )
+        );
+        assert!(matches!(
+            eval_err("\"a\".split(\"\");"),
+            Error::TypeError(_)
+        ));
+        assert!(matches!(eval_err("\",\".join(1);"), Error::TypeError(_)));
+    }
+
+    #[test]
+    fn test_replace_repeat_and_pad() {
+        assert_eq!(
+            "[\"b-b-c\", \"ababab\", \"\", \"  ab\", \"ab..\", \"ééab\", \"abcd\"]",
+            eval(
+                "[\"a-a-c\".replace('a', \"b\"), \"ab\".repeat(3), \"ab\".repeat(0),
+                    \"ab\".pad_start(4), \"ab\".pad_end(4, '.'), \"ab\".pad_start(4, \"é\"),
+                    \"abcd\".pad_end(2)];"
+            )
+        );
+        assert!(matches!(eval_err("\"a\".repeat(-1);"), Error::TypeError(_)));
+        assert!(matches!(
+            eval_err("\"a\".pad_end(3, \"ab\");"),
+            Error::TypeError(_)
+        ));
+    }
+
+    #[test]
+    fn test_parsing() {
+        assert_eq!(
+            "[42, -7, 100000000000000000000, 2.5, true, true]",
+            eval(
+                "fn parse() {
+                    [\"42\".parse_int()?, \"-7\".parse_int()?, \"100000000000000000000\".parse_int()?,
+                        \"2.5\".parse_float()?, \"4x\".parse_int() == err(\"invalid int: \\\"4x\\\"\"),
+                        \"\".parse_float() == err(\"invalid float: \\\"\\\"\")]
+                }
+                parse();"
+            )
+        );
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(
+            "[\"1 + 2 = 3\", \"b a b\", \"{x} [1, \\\"s\\\"]\", \"ü!\"]",
+            eval(
+                "[format(\"{} + {} = {}\", 1, 2, 1 + 2), \"{1} {0} {1}\".format(\"a\", \"b\"),
+                    format(\"{{x}} {}\", [1, \"s\"]), \"{}!\".format(\"ü\")];"
+            )
+        );
+        assert!(matches!(
+            eval_err("format(\"{} {}\", 1);"),
+            Error::TypeError(_)
+        ));
+        assert!(matches!(eval_err("format(\"{\", 1);"), Error::TypeError(_)));
+        assert!(matches!(eval_err("format(\"}\");"), Error::TypeError(_)));
+        assert!(matches!(eval_err("\"a\".len(1);"), Error::TypeError(_)));
+        assert!(matches!(
+            eval_err("\"a\".nope();"),
+            Error::UndefinedMethod(_)
+        ));
+    }
+}
@@ -0,0 +1,21 @@
+//! Helpers for the tests of modules that run scripts.
+
+use crate::error::Error;
+use crate::vm::Vm;
+
+/// Evaluates `source` in a new `Vm` and formats the result the way `print`
+/// shows it.
+pub fn eval(source: &str) -> String {
+    eval_in(&mut Vm::new(), source)
+}
+
+/// Like `eval`, but in `vm`.
+pub fn eval_in(vm: &mut Vm, source: &str) -> String {
+    let value = vm.eval(source).unwrap();
+    vm.display(value)
+}
+
+/// The error of evaluating `source` in a new `Vm`.
+pub fn eval_err(source: &str) -> Error {
+    Vm::new().eval(source).unwrap_err()
+}
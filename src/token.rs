@@ -16,6 +16,8 @@ pub enum Literal {
     Bool(bool),
     Null,
     Int(i64),
+    /// Digits of an int literal too large for `i64`.
+    BigInt(IntStr),
     Float(f64),
     Char(char),
     Str(IntStr),
@@ -30,15 +32,24 @@ pub enum Keyword {
     If,
     Else,
     Struct,
+    Enum,
+    Trait,
+    Impl,
     Fn,
     Let,
     SelfKw,
     Return,
     Break,
     Map,
+    Set,
     New,
     Use,
     Mod,
+    Throw,
+    Try,
+    Catch,
+    Finally,
+    Yield,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -54,6 +65,7 @@ pub enum Delimiter {
     Colon,
     Semicolon,
     Doublecolon,
+    Ellipsis,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -64,6 +76,7 @@ pub enum Assign {
     Mul,
     Div,
     Mod,
+    Pow,
     BitAnd,
     BitOr,
     BitXor,
@@ -82,8 +95,17 @@ pub enum Operator {
     Shift(ShiftOp),
     Term(TermOp),
     Factor(FactorOp),
+    /// `**`
+    Pow,
     Not,
     QMark,
+    /// `?.`
+    QMarkDot,
+    /// `??`
+    Coalesce,
+    /// `|>`
+    Pipe,
+    Range(RangeOp),
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -105,6 +127,14 @@ pub enum ShiftOp {
     Right,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RangeOp {
+    /// `..`
+    Exclusive,
+    /// `..=`
+    Inclusive,
+}
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum CmpOp {
     Eq,
@@ -114,3 +144,16 @@ pub enum CmpOp {
     LessEq,
     GreaterEq,
 }
+
+/// Byte range `start..end` of a piece of source text.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+}
@@ -0,0 +1,720 @@
+use crate::bigint::BigInt;
+use crate::bytecode::Proto;
+use crate::capability::Capability;
+use crate::error::Result;
+use crate::str_interner::IntStr;
+use crate::vm::Vm;
+use std::collections::HashMap;
+use std::mem;
+use std::rc::Rc;
+
+/// Value of a script, NaN-boxed into 8 bytes.
+///
+/// Floats are stored as their bits, with every NaN made the same quiet NaN.
+/// Other values live in the payload of NaN bit patterns a float never has:
+/// the top 13 bits set, a 3-bit tag and 48 bits of payload. Ints too large
+/// for 48 bits are bignums on the heap instead.
+///
+/// `unpack` turns a value into an `Unpacked` one for matching.
+#[derive(Clone, Copy)]
+pub struct Value(u64);
+
+/// A `Value` in a form that can be matched on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Unpacked {
+    Null,
+    Bool(bool),
+    /// Int within `Value::MIN_INT..=Value::MAX_INT`.
+    Int(i64),
+    Float(f64),
+    Char(char),
+    Obj(ObjRef),
+}
+
+const BOXED: u64 = 0xfff8 << 48;
+const CANONICAL_NAN: u64 = 0x7ff8 << 48;
+const PAYLOAD: u64 = (1 << 48) - 1;
+const TAG_NULL: u64 = BOXED | 1 << 48;
+const TAG_BOOL: u64 = BOXED | 2 << 48;
+const TAG_INT: u64 = BOXED | 3 << 48;
+const TAG_CHAR: u64 = BOXED | 4 << 48;
+const TAG_OBJ: u64 = BOXED | 5 << 48;
+const TAG: u64 = BOXED | 7 << 48;
+
+impl Value {
+    pub const NULL: Value = Value(TAG_NULL);
+    pub const TRUE: Value = Value(TAG_BOOL | 1);
+    pub const FALSE: Value = Value(TAG_BOOL);
+    pub const MIN_INT: i64 = -(1 << 47);
+    pub const MAX_INT: i64 = (1 << 47) - 1;
+
+    pub fn bool(b: bool) -> Value {
+        Value(TAG_BOOL | b as u64)
+    }
+
+    /// Packs an int, which must be within `MIN_INT..=MAX_INT`. Use
+    /// `Vm::int_value` for any other.
+    pub fn int(i: i64) -> Value {
+        Value::try_int(i).expect("int out of range")
+    }
+
+    /// Packs an int if it is within `MIN_INT..=MAX_INT`.
+    pub fn try_int(i: i64) -> Option<Value> {
+        (Value::MIN_INT..=Value::MAX_INT)
+            .contains(&i)
+            .then_some(Value(TAG_INT | (i as u64 & PAYLOAD)))
+    }
+
+    pub fn float(f: f64) -> Value {
+        if f.is_nan() {
+            Value(CANONICAL_NAN)
+        } else {
+            Value(f.to_bits())
+        }
+    }
+
+    pub fn char(c: char) -> Value {
+        Value(TAG_CHAR | c as u64)
+    }
+
+    pub fn obj(obj: ObjRef) -> Value {
+        Value(TAG_OBJ | obj.0 as u64)
+    }
+
+    pub fn unpack(self) -> Unpacked {
+        if self.0 & BOXED != BOXED {
+            return Unpacked::Float(f64::from_bits(self.0));
+        }
+        let payload = self.0 & PAYLOAD;
+        match self.0 & TAG {
+            TAG_NULL => Unpacked::Null,
+            TAG_BOOL => Unpacked::Bool(payload != 0),
+            // Shifting back and forth extends the sign of the payload.
+            TAG_INT => Unpacked::Int(((payload << 16) as i64) >> 16),
+            TAG_CHAR => Unpacked::Char(char::from_u32(payload as u32).unwrap()),
+            TAG_OBJ => Unpacked::Obj(ObjRef(payload as u32)),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn as_int(self) -> Option<i64> {
+        (self.0 & TAG == TAG_INT).then_some((((self.0 & PAYLOAD) << 16) as i64) >> 16)
+    }
+
+    pub fn as_float(self) -> Option<f64> {
+        (self.0 & BOXED != BOXED).then(|| f64::from_bits(self.0))
+    }
+
+    pub fn as_obj(self) -> Option<ObjRef> {
+        (self.0 & TAG == TAG_OBJ).then_some(ObjRef((self.0 & PAYLOAD) as u32))
+    }
+
+    /// Whether both are the same value bit for bit, which unlike `==` holds
+    /// for a NaN and itself.
+    pub fn is(self, other: Value) -> bool {
+        self.0 == other.0
+    }
+
+    pub fn is_null(self) -> bool {
+        self.0 == TAG_NULL
+    }
+
+    pub fn is_truthy(self) -> bool {
+        self.0 != TAG_NULL && self.0 != Value::FALSE.0
+    }
+}
+
+impl From<Unpacked> for Value {
+    fn from(unpacked: Unpacked) -> Value {
+        match unpacked {
+            Unpacked::Null => Value::NULL,
+            Unpacked::Bool(b) => Value::bool(b),
+            Unpacked::Int(i) => Value::int(i),
+            Unpacked::Float(f) => Value::float(f),
+            Unpacked::Char(c) => Value::char(c),
+            Unpacked::Obj(obj) => Value::obj(obj),
+        }
+    }
+}
+
+/// Compares like `Unpacked`: NaN is not equal to itself, and zero equals
+/// negative zero.
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self.as_float(), other.as_float()) {
+            (Some(a), Some(b)) => a == b,
+            (None, None) => self.0 == other.0,
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Debug for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.unpack().fmt(f)
+    }
+}
+
+/// Handle to an object living in a `Heap`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ObjRef(u32);
+
+#[derive(Debug)]
+pub enum Object {
+    Str(String),
+    Array(Vec<Value>),
+    Tuple(Vec<Value>),
+    Map(Map),
+    Set(Set),
+    Instance(Instance),
+    Struct(StructDef),
+    Enum(EnumDef),
+    Variant(Variant),
+    VariantCtor(VariantCtor),
+    Trait(TraitDef),
+    Closure(Closure),
+    Upvalue(Upvalue),
+    Native(Native),
+    BoundMethod(BoundMethod),
+    Module(Module),
+    Iter(Iter),
+    Range(Range),
+    Generator(Generator),
+    /// Integer outside the range of ints packed into a `Value`.
+    BigInt(BigInt),
+}
+
+impl Object {
+    /// Adds the objects this one refers to to `out`.
+    fn children(&self, out: &mut Vec<ObjRef>) {
+        let mut values = |values: &mut dyn Iterator<Item = &Value>| {
+            out.extend(values.filter_map(|value| value.as_obj()))
+        };
+        match self {
+            Object::Str(_)
+            | Object::Native(_)
+            | Object::Range(_)
+            | Object::BigInt(_)
+            | Object::Upvalue(Upvalue::Open(_)) => (),
+            Object::Array(elems) | Object::Tuple(elems) => values(&mut elems.iter()),
+            Object::Map(map) => values(&mut map.entries.iter().flat_map(|(k, v)| [k, v])),
+            Object::Set(set) => values(&mut set.elems.iter()),
+            Object::Instance(instance) => {
+                values(&mut instance.fields.iter().map(|(_, value)| value));
+                out.push(instance.def);
+            }
+            Object::Struct(StructDef { methods, .. })
+            | Object::Enum(EnumDef { methods, .. })
+            | Object::Trait(TraitDef { methods, .. }) => values(&mut methods.values()),
+            Object::Variant(variant) => {
+                values(&mut variant.fields.iter());
+                out.push(variant.def);
+            }
+            Object::VariantCtor(ctor) => out.push(ctor.def),
+            Object::Closure(closure) => {
+                values(&mut closure.upvalues.iter());
+                out.push(closure.module);
+            }
+            Object::Upvalue(Upvalue::Closed(value)) => values(&mut std::iter::once(value)),
+            Object::BoundMethod(bound) => values(&mut [&bound.receiver, &bound.method].into_iter()),
+            Object::Module(module) => values(&mut module.globals.values()),
+            Object::Iter(iter) => values(&mut std::iter::once(&iter.source)),
+            Object::Generator(generator) => {
+                values(&mut generator.stack.iter());
+                out.extend(generator.upvalues.iter().map(|&(_, upvalue)| upvalue));
+                out.push(generator.closure);
+            }
+        }
+    }
+
+    /// Estimated number of bytes the object takes up, counting what it owns
+    /// but not the objects it refers to.
+    fn size(&self) -> usize {
+        let values = |len: usize| len * mem::size_of::<Value>();
+        let owned = match self {
+            Object::Str(s) => s.len(),
+            Object::Array(elems) | Object::Tuple(elems) => values(elems.len()),
+            // Entries plus a bucket entry in the key index for each.
+            Object::Map(map) => values(map.entries.len() * 2) + map.entries.len() * 24,
+            Object::Set(set) => values(set.elems.len()) + set.elems.len() * 24,
+            Object::Instance(instance) => instance.fields.len() * mem::size_of::<(IntStr, Value)>(),
+            Object::Variant(variant) => values(variant.fields.len()),
+            Object::Module(module) => module.globals.len() * mem::size_of::<(IntStr, Value)>(),
+            Object::Generator(generator) => values(generator.stack.len()),
+            _ => 0,
+        };
+        mem::size_of::<Object>() + owned
+    }
+}
+
+/// Kinds of built-in objects with native methods.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BuiltinKind {
+    Str,
+    Array,
+    Map,
+    Set,
+}
+
+/// Map keeping its entries in insertion order. Entries are added and removed
+/// through the `Vm`, which hashes the keys.
+#[derive(Clone, Debug, Default)]
+pub struct Map {
+    pub entries: Vec<(Value, Value)>,
+    pub index: KeyIndex,
+}
+
+impl Map {
+    /// Appends an entry whose key is not in the map yet.
+    pub fn push(&mut self, hash: u64, key: Value, value: Value) {
+        self.index.insert(hash, self.entries.len());
+        self.entries.push((key, value));
+    }
+
+    pub fn remove(&mut self, pos: usize) -> (Value, Value) {
+        self.index.remove(pos);
+        self.entries.remove(pos)
+    }
+}
+
+/// Set keeping its elements in order like a `Map`. Elements are told apart
+/// like map keys.
+#[derive(Clone, Debug, Default)]
+pub struct Set {
+    pub elems: Vec<Value>,
+    pub index: KeyIndex,
+}
+
+impl Set {
+    /// Appends an element that is not in the set yet.
+    pub fn push(&mut self, hash: u64, elem: Value) {
+        self.index.insert(hash, self.elems.len());
+        self.elems.push(elem);
+    }
+
+    pub fn remove(&mut self, pos: usize) -> Value {
+        self.index.remove(pos);
+        self.elems.remove(pos)
+    }
+}
+
+/// Positions of the keys of a map or the elements of a set by their hash.
+#[derive(Clone, Debug, Default)]
+pub struct KeyIndex {
+    buckets: HashMap<u64, Vec<usize>>,
+    /// Hash of the key at each position.
+    hashes: Vec<u64>,
+}
+
+impl KeyIndex {
+    /// Positions of the keys with the given hash.
+    pub fn get(&self, hash: u64) -> &[usize] {
+        self.buckets.get(&hash).map_or(&[], Vec::as_slice)
+    }
+
+    fn insert(&mut self, hash: u64, pos: usize) {
+        self.buckets.entry(hash).or_default().push(pos);
+        self.hashes.push(hash);
+    }
+
+    /// Forgets the key at `pos` and shifts the keys after it down by one,
+    /// like `Vec::remove`.
+    fn remove(&mut self, pos: usize) {
+        let hash = self.hashes.remove(pos);
+        let bucket = self.buckets.get_mut(&hash).unwrap();
+        bucket.retain(|&p| p != pos);
+        if bucket.is_empty() {
+            self.buckets.remove(&hash);
+        }
+        for (moved, hash) in self.hashes.iter().enumerate().skip(pos) {
+            let bucket = self.buckets.get_mut(hash).unwrap();
+            *bucket.iter_mut().find(|p| **p == moved + 1).unwrap() = moved;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    pub def: ObjRef,
+    /// Fields in the order they were first assigned.
+    pub fields: Vec<(IntStr, Value)>,
+}
+
+#[derive(Debug)]
+pub struct StructDef {
+    pub ident: IntStr,
+    pub methods: HashMap<IntStr, Value>,
+}
+
+#[derive(Debug)]
+pub struct EnumDef {
+    pub ident: IntStr,
+    pub variants: Vec<VariantDef>,
+    pub methods: HashMap<IntStr, Value>,
+}
+
+#[derive(Debug)]
+pub struct VariantDef {
+    pub ident: IntStr,
+    pub kind: VariantKind,
+    pub fields: Vec<IntStr>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VariantKind {
+    Unit,
+    Tuple,
+    Struct,
+}
+
+/// Value of an enum, holding the fields of `variant` in declaration order.
+#[derive(Debug)]
+pub struct Variant {
+    pub def: ObjRef,
+    pub variant: usize,
+    pub fields: Vec<Value>,
+}
+
+/// Constructor of a tuple-like variant, as in `Shape::Circle`.
+#[derive(Debug)]
+pub struct VariantCtor {
+    pub def: ObjRef,
+    pub variant: usize,
+}
+
+#[derive(Debug)]
+pub struct TraitDef {
+    pub ident: IntStr,
+    pub required: Vec<IntStr>,
+    pub methods: HashMap<IntStr, Value>,
+}
+
+#[derive(Debug)]
+pub struct Closure {
+    pub proto: Rc<Proto>,
+    /// Module whose globals the closure sees.
+    pub module: ObjRef,
+    /// Captured values, or `Upvalue` objects for captures by reference.
+    pub upvalues: Vec<Value>,
+}
+
+#[derive(Debug)]
+pub enum Upvalue {
+    /// The variable still lives on the stack at this index.
+    Open(usize),
+    Closed(Value),
+}
+
+pub type NativeFn = fn(&mut Vm, &[Value]) -> Result<Value>;
+
+pub struct Native {
+    pub ident: IntStr,
+    pub fun: NativeFn,
+    /// Module the function was defined in, if any.
+    pub module: Option<IntStr>,
+    /// Capability the script needs to call the function.
+    pub capability: Option<Capability>,
+}
+
+impl std::fmt::Debug for Native {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Native")
+            .field("ident", &self.ident)
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: Value,
+}
+
+#[derive(Debug)]
+pub struct Module {
+    pub ident: Option<IntStr>,
+    pub globals: HashMap<IntStr, Value>,
+}
+
+/// Position in an array, map, string or range. For strings `idx` is a byte
+/// offset.
+#[derive(Debug)]
+pub struct Iter {
+    pub source: Value,
+    pub idx: usize,
+}
+
+/// Integers from `start` up to `end`, counting by `step`. Either end may be
+/// left open.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Range {
+    pub start: Option<i64>,
+    pub end: Option<i64>,
+    pub inclusive: bool,
+    pub step: i64,
+}
+
+/// Call of a function containing `yield`, run a step at a time by `next`
+/// and `for` loops.
+#[derive(Debug)]
+pub struct Generator {
+    pub closure: ObjRef,
+    pub state: GenState,
+    pub ip: usize,
+    pub given: Vec<bool>,
+    /// Values of the suspended frame, from slot 0 up.
+    pub stack: Vec<Value>,
+    /// Captures by reference of the frame's variables and their slots.
+    pub upvalues: Vec<(usize, ObjRef)>,
+    /// Slot the stack is cut back to and handler of each open `try` block.
+    pub handlers: Vec<(usize, usize)>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GenState {
+    Suspended,
+    Running,
+    Done,
+}
+
+/// Storage for all objects created by a script. Objects no longer
+/// reachable are freed by `collect`, and their slots reused.
+#[derive(Debug)]
+pub struct Heap {
+    /// Objects by index, `None` for free slots.
+    objects: Vec<Option<Object>>,
+    /// Estimated size of each object when it was last measured.
+    sizes: Vec<usize>,
+    bytes: usize,
+    free: Vec<u32>,
+    marks: Vec<bool>,
+    /// Size after which the next collection is due.
+    next_collection: usize,
+    stats: GcStats,
+}
+
+/// Counts of the work done by the garbage collector.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct GcStats {
+    pub collections: u64,
+    /// Objects freed by all collections.
+    pub freed: u64,
+    /// Objects alive, or not yet found to be garbage.
+    pub objects: usize,
+    /// Estimated size of those objects.
+    pub bytes: usize,
+}
+
+/// Size of the heap below which it is never collected automatically.
+const MIN_COLLECTION: usize = 1 << 20;
+
+impl Default for Heap {
+    fn default() -> Heap {
+        Heap {
+            objects: Vec::new(),
+            sizes: Vec::new(),
+            bytes: 0,
+            free: Vec::new(),
+            marks: Vec::new(),
+            next_collection: MIN_COLLECTION,
+            stats: GcStats::default(),
+        }
+    }
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap::default()
+    }
+
+    pub fn alloc(&mut self, object: Object) -> ObjRef {
+        let size = object.size();
+        self.bytes += size;
+        self.stats.objects += 1;
+        match self.free.pop() {
+            Some(idx) => {
+                self.objects[idx as usize] = Some(object);
+                self.sizes[idx as usize] = size;
+                ObjRef(idx)
+            }
+            None => {
+                let idx = self.objects.len();
+                self.objects.push(Some(object));
+                self.sizes.push(size);
+                ObjRef(idx as u32)
+            }
+        }
+    }
+
+    /// Estimated size in bytes of all objects.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Measures `obj` again after it grew or shrank in place.
+    pub fn resized(&mut self, obj: ObjRef) {
+        let idx = obj.0 as usize;
+        let size = self.get(obj).size();
+        self.bytes = self.bytes - self.sizes[idx] + size;
+        self.sizes[idx] = size;
+    }
+
+    pub fn get(&self, obj: ObjRef) -> &Object {
+        self.objects[obj.0 as usize]
+            .as_ref()
+            .expect("object was freed")
+    }
+
+    pub fn get_mut(&mut self, obj: ObjRef) -> &mut Object {
+        self.objects[obj.0 as usize]
+            .as_mut()
+            .expect("object was freed")
+    }
+
+    /// Number of objects alive, or not yet found to be garbage.
+    pub fn len(&self) -> usize {
+        self.stats.objects
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stats.objects == 0
+    }
+
+    /// Whether the heap grew enough since the last collection to collect it
+    /// again.
+    pub fn collection_due(&self) -> bool {
+        self.bytes > self.next_collection
+    }
+
+    pub fn stats(&self) -> GcStats {
+        GcStats {
+            bytes: self.bytes,
+            ..self.stats
+        }
+    }
+
+    /// Frees the objects not reachable from `roots`, returning how many
+    /// there were.
+    pub fn collect(&mut self, roots: impl IntoIterator<Item = ObjRef>) -> usize {
+        self.marks.clear();
+        self.marks.resize(self.objects.len(), false);
+        let mut gray: Vec<ObjRef> = roots.into_iter().collect();
+        while let Some(obj) = gray.pop() {
+            let mark = &mut self.marks[obj.0 as usize];
+            if !*mark {
+                *mark = true;
+                self.get(obj).children(&mut gray);
+            }
+        }
+
+        let mut freed = 0;
+        for (idx, slot) in self.objects.iter_mut().enumerate() {
+            if slot.is_some() && !self.marks[idx] {
+                *slot = None;
+                self.bytes -= self.sizes[idx];
+                self.free.push(idx as u32);
+                freed += 1;
+            }
+        }
+        self.stats.collections += 1;
+        self.stats.freed += freed as u64;
+        self.stats.objects -= freed;
+        self.next_collection = MIN_COLLECTION.max(self.bytes * 2);
+        freed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Edge cases of every kind of value, and pseudo-random ones.
+    fn samples() -> Vec<Unpacked> {
+        let mut out = vec![Unpacked::Null, Unpacked::Bool(false), Unpacked::Bool(true)];
+        for i in [0, 1, -1, Value::MIN_INT, Value::MAX_INT, 1 << 32, -1 << 32] {
+            out.push(Unpacked::Int(i));
+        }
+        for f in [
+            0.0,
+            -0.0,
+            1.5,
+            f64::MIN_POSITIVE,
+            f64::MIN_POSITIVE / 4.0,
+            f64::MAX,
+            f64::MIN,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+            f64::NAN,
+            -f64::NAN,
+        ] {
+            out.push(Unpacked::Float(f));
+        }
+        for c in ['\0', 'a', 'é', '\u{10ffff}'] {
+            out.push(Unpacked::Char(c));
+        }
+        for obj in [0, 1, u32::MAX] {
+            out.push(Unpacked::Obj(ObjRef(obj)));
+        }
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        for i in 0..200 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            out.push(match i % 4 {
+                0 => Unpacked::Int((state as i64) >> (16 + i % 48)),
+                1 => Unpacked::Float(f64::from_bits(state)),
+                2 => Unpacked::Char(char::from_u32(state as u32 % 0x11_0000).unwrap_or('?')),
+                _ => Unpacked::Obj(ObjRef(state as u32)),
+            });
+        }
+        out
+    }
+
+    #[test]
+    fn test_packing_round_trips() {
+        assert_eq!(8, mem::size_of::<Value>());
+        for unpacked in samples() {
+            let value = Value::from(unpacked);
+            match (unpacked, value.unpack()) {
+                // NaNs lose their payload, but zeros keep their sign.
+                (Unpacked::Float(a), Unpacked::Float(b)) if a.is_nan() => assert!(b.is_nan()),
+                (Unpacked::Float(a), Unpacked::Float(b)) => assert_eq!(a.to_bits(), b.to_bits()),
+                (a, b) => assert_eq!(a, b),
+            }
+            assert_eq!(
+                matches!(unpacked, Unpacked::Int(_)),
+                value.as_int().is_some()
+            );
+            assert_eq!(
+                matches!(unpacked, Unpacked::Float(_)),
+                value.as_float().is_some()
+            );
+            assert_eq!(
+                matches!(unpacked, Unpacked::Obj(_)),
+                value.as_obj().is_some()
+            );
+            assert_eq!(unpacked == Unpacked::Null, value.is_null());
+        }
+        for i in [Value::MIN_INT - 1, Value::MAX_INT + 1, i64::MIN, i64::MAX] {
+            assert!(Value::try_int(i).is_none(), "{}", i);
+        }
+    }
+
+    #[test]
+    fn test_equality_and_truthiness_match_unpacked() {
+        let samples = samples();
+        for &a in &samples {
+            let truthy = !matches!(a, Unpacked::Null | Unpacked::Bool(false));
+            assert_eq!(truthy, Value::from(a).is_truthy(), "{:?}", a);
+            for &b in &samples {
+                assert_eq!(
+                    a == b,
+                    Value::from(a) == Value::from(b),
+                    "{:?} and {:?}",
+                    a,
+                    b
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,4346 @@
+use crate::arith::{self, Arith, Num, Overflow};
+use crate::bigint::BigInt;
+use crate::bytecode::{ArgKind, BinOp, Const, Op, Proto, ProtoKind};
+use crate::capability::{Capabilities, Capability};
+use crate::collections;
+use crate::compiler;
+use crate::error::{Error, Result};
+use crate::gc;
+use crate::host;
+use crate::json;
+use crate::limits::{Limits, DEADLINE_INTERVAL, MAX_REENTRIES};
+use crate::parser::Parser;
+use crate::resolver;
+use crate::scanner::Scanner;
+use crate::str_interner::{IntStr, Interner};
+use crate::strings;
+use crate::token::Span;
+use crate::value::{
+    BoundMethod, BuiltinKind, Closure, EnumDef, GcStats, GenState, Generator, Heap, Instance, Iter,
+    Map, Module, Native, NativeFn, ObjRef, Object, Range, Set, StructDef, TraitDef, Unpacked,
+    Upvalue, Value, Variant, VariantCtor, VariantDef, VariantKind,
+};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+use std::result::Result as StdResult;
+use std::time::Instant;
+
+/// Deepest nesting of collections that comparisons and hashing go through.
+/// The traversals recurse, so this bounds the native stack they use.
+pub const MAX_VALUE_DEPTH: usize = 512;
+
+/// Stack machine running compiled scripts.
+///
+/// Globals defined by one call to `eval` stay visible to the next one.
+pub struct Vm {
+    interner: Interner,
+    heap: Heap,
+    stack: Vec<Value>,
+    frames: Vec<Frame>,
+    /// Captures by reference of variables that still live on the stack,
+    /// ordered by stack slot.
+    open_upvalues: Vec<(usize, ObjRef)>,
+    builtins: HashMap<IntStr, Value>,
+    /// Native methods of built-in objects, which get the object as first
+    /// argument.
+    native_methods: HashMap<(BuiltinKind, IntStr), NativeFn>,
+    /// String objects for string constants.
+    strings: HashMap<IntStr, ObjRef>,
+    root: ObjRef,
+    /// The built-in `Result` enum, whose variants `?` unwraps.
+    result_def: ObjRef,
+    /// The built-in struct runtime errors are caught as.
+    error_def: ObjRef,
+    /// The built-in `done`, which iterators return once they are exhausted.
+    done: ObjRef,
+    iter_ident: IntStr,
+    next_ident: IntStr,
+    arith: Arith,
+    handlers: Vec<Handler>,
+    /// Trace of the error being propagated out of nested calls to `run`.
+    pending_trace: Option<Vec<TraceEntry>>,
+    /// Trace of the last error `eval` returned.
+    trace: Vec<TraceEntry>,
+    limits: Limits,
+    /// Instructions executed since the host last called into the VM.
+    steps: u64,
+    deadline: Option<Instant>,
+    capabilities: Capabilities,
+    /// Modules of host functions and the capability each needs.
+    restricted: HashMap<IntStr, Capability>,
+    audit_hook: Option<AuditHook>,
+    /// Values the host keeps alive, by handle.
+    handles: HashMap<u64, Value>,
+    /// Value the last call from the host returned or threw, kept alive until
+    /// the next one.
+    returned: Value,
+    next_handle: u64,
+    /// Calls into the VM from natives that are still running.
+    reentries: usize,
+    /// Whether a collection was asked for while it was not safe.
+    collection_requested: bool,
+}
+
+/// Value the host keeps alive across garbage collections, created by
+/// `Vm::handle`.
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub struct Handle(u64);
+
+/// Called before every call of a native function by a script.
+pub type AuditHook = Box<dyn FnMut(&Vm, &HostCall)>;
+
+/// Call of a native function by a script, as passed to the audit hook.
+pub struct HostCall<'a> {
+    /// Name of the function, prefixed with that of its module if it has one,
+    /// such as `fs.read`.
+    pub function: String,
+    pub args: &'a [Value],
+    /// Script function making the call and the position of the call.
+    pub caller: TraceEntry,
+}
+
+/// Handler of an open `try` block.
+struct Handler {
+    /// Number of frames when the block started.
+    frames: usize,
+    stack: usize,
+    target: usize,
+}
+
+/// Function call in the stack trace of an error, with the position of the
+/// instruction it was executing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceEntry {
+    pub function: String,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl std::fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "at {} ({}:{})", self.function, self.line, self.col)
+    }
+}
+
+struct Frame {
+    closure: ObjRef,
+    proto: Rc<Proto>,
+    ip: usize,
+    /// Stack index of slot 0.
+    base: usize,
+    /// Parameters the caller passed a value for, empty if none has a
+    /// default.
+    given: Vec<bool>,
+    module: ObjRef,
+    ret: Ret,
+    /// Generator the frame runs for.
+    generator: Option<ObjRef>,
+}
+
+/// What the caller does with the value a frame returns.
+#[derive(Clone, Copy, PartialEq)]
+enum Ret {
+    Push,
+    /// Pushes the negation of the value, for operators derived from others.
+    Not,
+    Discard,
+    /// Pushes the value for the body of a `for` loop, or jumps to the target
+    /// at the end of the iteration.
+    ForIter(u32),
+    /// Pushes the value `iter` returned for a `for` loop, running `Op::Iter`
+    /// again on it unless it is a struct or enum value.
+    Iter,
+}
+
+impl Vm {
+    pub fn new() -> Vm {
+        let mut heap = Heap::new();
+        let root = heap.alloc(Object::Module(Module {
+            ident: None,
+            globals: HashMap::new(),
+        }));
+        let mut interner = Interner::new();
+        let variant = |interner: &mut Interner, ident, field| VariantDef {
+            ident: interner.intern_str(ident),
+            kind: VariantKind::Tuple,
+            fields: vec![interner.intern_str(field)],
+        };
+        let result_def = heap.alloc(Object::Enum(EnumDef {
+            ident: interner.intern_str("Result"),
+            variants: vec![
+                variant(&mut interner, "Ok", "value"),
+                variant(&mut interner, "Err", "error"),
+            ],
+            methods: HashMap::new(),
+        }));
+        let error_def = heap.alloc(Object::Struct(StructDef {
+            ident: interner.intern_str("RuntimeError"),
+            methods: HashMap::new(),
+        }));
+        let done_def = heap.alloc(Object::Struct(StructDef {
+            ident: interner.intern_str("Done"),
+            methods: HashMap::new(),
+        }));
+        let done = heap.alloc(Object::Instance(Instance {
+            def: done_def,
+            fields: Vec::new(),
+        }));
+        let mut vm = Vm {
+            iter_ident: interner.intern_str("iter"),
+            next_ident: interner.intern_str("next"),
+            arith: Arith::default(),
+            interner,
+            heap,
+            stack: Vec::new(),
+            frames: Vec::new(),
+            open_upvalues: Vec::new(),
+            builtins: HashMap::new(),
+            native_methods: HashMap::new(),
+            strings: HashMap::new(),
+            root,
+            result_def,
+            error_def,
+            done,
+            handlers: Vec::new(),
+            pending_trace: None,
+            trace: Vec::new(),
+            limits: Limits::default(),
+            steps: 0,
+            deadline: None,
+            capabilities: Capabilities::default(),
+            restricted: HashMap::new(),
+            audit_hook: None,
+            handles: HashMap::new(),
+            returned: Value::NULL,
+            next_handle: 0,
+            reentries: 0,
+            collection_requested: false,
+        };
+        let ident = vm.interner.intern_str("Result");
+        vm.builtins.insert(ident, Value::obj(result_def));
+        let ident = vm.interner.intern_str("done");
+        vm.builtins.insert(ident, Value::obj(done));
+        vm.define_native("print", builtin_print);
+        vm.define_native("ok", builtin_ok);
+        vm.define_native("err", builtin_err);
+        vm.define_native("iter", builtin_iter);
+        vm.define_native("range", builtin_range);
+        strings::define(&mut vm);
+        collections::define(&mut vm);
+        json::define(&mut vm);
+        gc::define(&mut vm);
+        host::define(&mut vm);
+        vm
+    }
+
+    pub fn interner(&self) -> &Interner {
+        &self.interner
+    }
+
+    pub fn interner_mut(&mut self) -> &mut Interner {
+        &mut self.interner
+    }
+
+    /// Sets how numeric operators handle int overflow and division.
+    pub fn set_arith(&mut self, arith: Arith) {
+        self.arith = arith;
+    }
+
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    /// Makes `fun` callable from every module under `ident`.
+    pub fn define_native(&mut self, ident: &str, fun: NativeFn) {
+        let ident = self.interner.intern_str(ident);
+        let native = self.heap.alloc(Object::Native(Native {
+            ident,
+            fun,
+            module: None,
+            capability: None,
+        }));
+        self.builtins.insert(ident, Value::obj(native));
+    }
+
+    /// Makes a module of native functions available from every module under
+    /// `ident`.
+    pub fn define_module(&mut self, ident: &str, funs: &[(&str, NativeFn)]) {
+        self.define_native_module(ident, None, funs);
+    }
+
+    /// Makes a module of native functions that need `capability` available
+    /// like `define_module`. Scripts of a `Vm` without the capability fail
+    /// to compile if they refer to the module.
+    pub fn define_host_module(
+        &mut self,
+        ident: &str,
+        capability: Capability,
+        funs: &[(&str, NativeFn)],
+    ) {
+        self.define_native_module(ident, Some(capability), funs);
+    }
+
+    fn define_native_module(
+        &mut self,
+        ident: &str,
+        capability: Option<Capability>,
+        funs: &[(&str, NativeFn)],
+    ) {
+        let ident = self.interner.intern_str(ident);
+        let mut globals = HashMap::new();
+        for &(fun_ident, fun) in funs {
+            let fun_ident = self.interner.intern_str(fun_ident);
+            let native = self.heap.alloc(Object::Native(Native {
+                ident: fun_ident,
+                fun,
+                module: Some(ident),
+                capability,
+            }));
+            globals.insert(fun_ident, Value::obj(native));
+        }
+        match capability {
+            Some(capability) => self.restricted.insert(ident, capability),
+            None => self.restricted.remove(&ident),
+        };
+        let module = self.heap.alloc(Object::Module(Module {
+            ident: Some(ident),
+            globals,
+        }));
+        self.builtins.insert(ident, Value::obj(module));
+    }
+
+    /// Makes `fun` callable as a method of every object of `kind`.
+    pub fn define_method(&mut self, kind: BuiltinKind, ident: &str, fun: NativeFn) {
+        let ident = self.interner.intern_str(ident);
+        self.native_methods.insert((kind, ident), fun);
+    }
+
+    pub fn heap_mut(&mut self) -> &mut Heap {
+        &mut self.heap
+    }
+
+    /// Limits the resources later calls to `eval` and `call` may use.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Grants scripts the `capabilities`, which applies to the next call to
+    /// `eval`. Calls of natives needing a capability no longer granted also
+    /// fail at runtime.
+    pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.capabilities = capabilities;
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// Calls `hook` before every call of a native function by a script.
+    pub fn set_audit_hook(&mut self, hook: impl FnMut(&Vm, &HostCall) + 'static) {
+        self.audit_hook = Some(Box::new(hook));
+    }
+
+    pub fn clear_audit_hook(&mut self) {
+        self.audit_hook = None;
+    }
+
+    /// Fails if a string of `len` bytes would exceed the length limit.
+    pub fn check_str_len(&self, len: usize) -> Result<()> {
+        match self.limits.max_str_len {
+            Some(max) if len > max => Err(Error::StringTooLong { len, max }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails if an int of `bits` bits would exceed the bignum limit.
+    fn check_int_bits(&self, bits: u64) -> Result<()> {
+        match self.limits.max_int_bits {
+            Some(max) if bits > max => Err(Error::IntegerOverflow),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails if a collection of `len` elements would exceed the length limit.
+    pub fn check_array_len(&self, len: usize) -> Result<()> {
+        match self.limits.max_array_len {
+            Some(max) if len > max => Err(Error::ArrayTooLong { len, max }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Runs `source` in the top-level module, returning the value of its last
+    /// expression statement.
+    ///
+    /// An error thrown by the script and not caught is returned as
+    /// `Error::Thrown`, errors of the runtime keep their own variant. Either
+    /// way `trace` tells where it happened.
+    ///
+    /// Like that of `call`, the value returned or thrown is only kept alive
+    /// until the next `eval` or `call` returns. After that a collection may
+    /// free it unless the host holds a `Handle` to it.
+    pub fn eval(&mut self, source: &str) -> Result<Value> {
+        self.trace.clear();
+        let mut parser = Parser::new(Scanner::new(source, &mut self.interner));
+        parser.set_max_nesting(self.limits.max_nesting);
+        let mut program = parser.program()?;
+        resolver::resolve_denying(&mut program, &self.denied())?;
+        let proto = compiler::compile(&program, source.into(), &mut self.interner)?;
+        let closure = self.heap.alloc(Object::Closure(Closure {
+            proto: Rc::new(proto),
+            module: self.root,
+            upvalues: Vec::new(),
+        }));
+
+        let result = self.call(Value::obj(closure), &[]);
+        if result.is_err() {
+            self.trace = self
+                .pending_trace
+                .take()
+                .unwrap_or_else(|| self.capture_trace());
+            self.truncate_frames(0);
+            self.stack.clear();
+            self.open_upvalues.clear();
+            self.handlers.clear();
+        }
+        result
+    }
+
+    /// Host modules the scripts may not refer to, unless they have been
+    /// shadowed by a global of the script.
+    fn denied(&self) -> HashMap<IntStr, Capability> {
+        let globals = match self.heap.get(self.root) {
+            Object::Module(module) => &module.globals,
+            _ => unreachable!(),
+        };
+        self.restricted
+            .iter()
+            .filter(|&(ident, &cap)| {
+                !self.capabilities.contains(cap) && !globals.contains_key(ident)
+            })
+            .map(|(&ident, &cap)| (ident, cap))
+            .collect()
+    }
+
+    /// Stack trace of the last error returned by `eval`, innermost call
+    /// first.
+    pub fn trace(&self) -> &[TraceEntry] {
+        &self.trace
+    }
+
+    /// Calls `callee` with `args`, running it to completion. A call from the
+    /// host rather than a native starts counting steps and time anew, and
+    /// keeps the value it returns or throws alive until the next one returns.
+    pub fn call(&mut self, callee: Value, args: &[Value]) -> Result<Value> {
+        let reentry = !self.frames.is_empty();
+        if reentry {
+            if self.reentries >= MAX_REENTRIES {
+                return Err(Error::CallDepthExceeded);
+            }
+            self.reentries += 1;
+        } else {
+            self.steps = 0;
+            self.deadline = self.limits.timeout.map(|timeout| Instant::now() + timeout);
+        }
+        let depth = self.frames.len();
+        let height = self.stack.len();
+        let handlers = self.handlers.len();
+        self.stack.push(callee);
+        self.stack.extend_from_slice(args);
+
+        let result = match self.call_value(callee, args.len(), Vec::new(), Span::default()) {
+            Ok(()) if self.frames.len() > depth => self.run(depth),
+            Ok(()) => Ok(self.pop()),
+            Err(err) => Err(err),
+        };
+        if result.is_err() {
+            if self.pending_trace.is_none() {
+                self.pending_trace = Some(self.capture_trace());
+            }
+            self.close_upvalues(height);
+            self.truncate_frames(depth);
+            self.stack.truncate(height);
+            self.handlers.truncate(handlers);
+        }
+        if reentry {
+            self.reentries -= 1;
+        } else {
+            self.returned = match &result {
+                Ok(value) | Err(Error::Thrown(value)) => *value,
+                Err(_) => Value::NULL,
+            };
+        }
+        result
+    }
+
+    /// Keeps `value` alive across garbage collections until the handle is
+    /// released. Values the host holds on to between calls into the VM
+    /// need one.
+    pub fn handle(&mut self, value: Value) -> Handle {
+        let id = self.next_handle;
+        self.next_handle += 1;
+        self.handles.insert(id, value);
+        Handle(id)
+    }
+
+    pub fn handle_value(&self, handle: &Handle) -> Value {
+        self.handles[&handle.0]
+    }
+
+    /// Lets the value of `handle` be collected once nothing else refers to
+    /// it, and returns it.
+    pub fn release(&mut self, handle: Handle) -> Value {
+        self.handles.remove(&handle.0).unwrap()
+    }
+
+    /// Frees the objects that can no longer be reached, returning how many
+    /// there were.
+    ///
+    /// Natives may hold values the collector cannot see, so it does not run
+    /// while a native calls back into the VM. It is postponed until that
+    /// native returns, and `None` is returned.
+    pub fn collect(&mut self) -> Option<usize> {
+        if self.reentries > 0 {
+            self.collection_requested = true;
+            return None;
+        }
+        self.collection_requested = false;
+
+        let values = self
+            .stack
+            .iter()
+            .chain(self.builtins.values())
+            .chain(self.handles.values())
+            .chain([&self.returned]);
+        let mut roots: Vec<ObjRef> = values
+            .filter_map(|&value| match value.unpack() {
+                Unpacked::Obj(obj) => Some(obj),
+                _ => None,
+            })
+            .collect();
+        for frame in &self.frames {
+            roots.extend([frame.closure, frame.module]);
+            roots.extend(frame.generator);
+        }
+        roots.extend(self.open_upvalues.iter().map(|&(_, upvalue)| upvalue));
+        roots.extend(self.strings.values());
+        roots.extend([self.root, self.result_def, self.error_def, self.done]);
+        Some(self.heap.collect(roots))
+    }
+
+    pub fn gc_stats(&self) -> GcStats {
+        self.heap.stats()
+    }
+
+    pub fn alloc_str(&mut self, s: String) -> Value {
+        Value::obj(self.heap.alloc(Object::Str(s)))
+    }
+
+    /// Allocates an array, failing if it would exceed the length limit.
+    pub fn alloc_array(&mut self, elems: Vec<Value>) -> Result<Value> {
+        self.check_array_len(elems.len())?;
+        Ok(Value::obj(self.heap.alloc(Object::Array(elems))))
+    }
+
+    /// Packs `i` into a value, or allocates a bignum if it does not fit.
+    pub fn int_value(&mut self, i: i64) -> Value {
+        match Value::try_int(i) {
+            Some(value) => value,
+            None => Value::obj(self.heap.alloc(Object::BigInt(i.into()))),
+        }
+    }
+
+    pub fn alloc_big(&mut self, big: BigInt) -> Value {
+        self.num_value(Num::from_big(big))
+    }
+
+    /// Wraps `value` in `Result::Ok`.
+    pub fn ok(&mut self, value: Value) -> Value {
+        self.result_variant(0, &[value])
+    }
+
+    /// Wraps `value` in `Result::Err`.
+    pub fn err(&mut self, value: Value) -> Value {
+        self.result_variant(1, &[value])
+    }
+
+    pub fn str(&self, value: Value) -> Option<&str> {
+        match value.unpack() {
+            Unpacked::Obj(obj) => match self.heap.get(obj) {
+                Object::Str(s) => Some(s),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Creates `Result::Ok` or `Result::Err` holding the first of `args`.
+    fn result_variant(&mut self, variant: usize, args: &[Value]) -> Value {
+        let value = args.first().copied().unwrap_or(Value::NULL);
+        Value::obj(self.heap.alloc(Object::Variant(Variant {
+            def: self.result_def,
+            variant,
+            fields: vec![value],
+        })))
+    }
+
+    fn intern_value(&mut self, ident: IntStr) -> Value {
+        if let Some(&obj) = self.strings.get(&ident) {
+            return Value::obj(obj);
+        }
+        let s = self.interner.lookup(ident).unwrap().to_owned();
+        let obj = self.heap.alloc(Object::Str(s));
+        self.strings.insert(ident, obj);
+        Value::obj(obj)
+    }
+
+    fn frame(&self) -> &Frame {
+        self.frames.last().unwrap()
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().unwrap()
+    }
+
+    fn peek(&self, distance: usize) -> Value {
+        self.stack[self.stack.len() - 1 - distance]
+    }
+
+    /// Executes instructions until the frame at `depth` returns, passing
+    /// errors to the handlers of `try` blocks above it.
+    fn run(&mut self, depth: usize) -> Result<Value> {
+        loop {
+            match self.execute(depth) {
+                Ok(value) => return Ok(value),
+                Err(err) => self.catch(err, depth)?,
+            }
+        }
+    }
+
+    /// Unwinds to the innermost handler above `depth` and gives it the
+    /// thrown value, or returns the error if there is none or it is fatal.
+    fn catch(&mut self, err: Error, depth: usize) -> Result<()> {
+        let handler = match self.handlers.last() {
+            Some(handler) if handler.frames > depth && !err.is_fatal() => {
+                self.handlers.pop().unwrap()
+            }
+            _ => {
+                if self.pending_trace.is_none() {
+                    self.pending_trace = Some(self.capture_trace());
+                }
+                return Err(err);
+            }
+        };
+        let trace = self
+            .pending_trace
+            .take()
+            .unwrap_or_else(|| self.capture_trace());
+
+        let value = match err {
+            Error::Thrown(value) => value,
+            err => {
+                let message = err.message(&self.interner);
+                let message = self.alloc_str(message);
+                let trace = trace
+                    .iter()
+                    .map(|entry| self.alloc_str(entry.to_string()))
+                    .collect();
+                let trace = Value::obj(self.heap.alloc(Object::Array(trace)));
+                let message_ident = self.interner.intern_str("message");
+                let trace_ident = self.interner.intern_str("trace");
+                Value::obj(self.heap.alloc(Object::Instance(Instance {
+                    def: self.error_def,
+                    fields: vec![(message_ident, message), (trace_ident, trace)],
+                })))
+            }
+        };
+
+        self.close_upvalues(handler.stack);
+        self.truncate_frames(handler.frames);
+        self.stack.truncate(handler.stack);
+        self.stack.push(value);
+        self.jump(handler.target as u32);
+        Ok(())
+    }
+
+    /// The calls on the stack, innermost first.
+    fn capture_trace(&self) -> Vec<TraceEntry> {
+        self.frames
+            .iter()
+            .rev()
+            .map(|frame| self.trace_entry(frame))
+            .collect()
+    }
+
+    fn trace_entry(&self, frame: &Frame) -> TraceEntry {
+        let proto = &frame.proto;
+        let function = match (proto.kind, proto.ident) {
+            (ProtoKind::Script, _) => "<script>".to_owned(),
+            (ProtoKind::Closure, _) | (_, None) => "<closure>".to_owned(),
+            (_, Some(ident)) => self.interner.lookup(ident).unwrap_or("?").to_owned(),
+        };
+        let span = proto.spans[frame.ip.saturating_sub(1)];
+        let (line, col) = line_col(&proto.source, span.start);
+        TraceEntry {
+            function,
+            line,
+            col,
+        }
+    }
+
+    fn execute(&mut self, depth: usize) -> Result<Value> {
+        loop {
+            let frame = self.frames.last_mut().unwrap();
+            let op = frame.proto.code[frame.ip];
+            let span = frame.proto.spans[frame.ip];
+            frame.ip += 1;
+            self.check_limits()?;
+
+            match op {
+                Op::Const(idx) => {
+                    let value = match self.frame().proto.consts[idx as usize] {
+                        Const::Int(i) => self.int_value(i),
+                        Const::BigInt(digits) => {
+                            let digits = self.interner.lookup(digits).unwrap();
+                            let big = BigInt::from_decimal(digits).unwrap();
+                            Value::obj(self.heap.alloc(Object::BigInt(big)))
+                        }
+                        Const::Float(f) => Value::float(f),
+                        Const::Char(c) => Value::char(c),
+                        Const::Str(s) => self.intern_value(s),
+                    };
+                    self.stack.push(value);
+                }
+                Op::Null => self.stack.push(Value::NULL),
+                Op::True => self.stack.push(Value::bool(true)),
+                Op::False => self.stack.push(Value::bool(false)),
+                Op::Pop => {
+                    self.pop();
+                }
+                Op::PopN(n) => {
+                    let len = self.stack.len() - n as usize;
+                    self.close_upvalues(len);
+                    self.stack.truncate(len);
+                }
+                Op::Squash(n) => {
+                    let top = self.pop();
+                    let len = self.stack.len() - n as usize;
+                    self.close_upvalues(len);
+                    self.stack.truncate(len);
+                    self.stack.push(top);
+                }
+                Op::Dup => self.stack.push(self.peek(0)),
+                Op::Dup2 => {
+                    let (a, b) = (self.peek(1), self.peek(0));
+                    self.stack.push(a);
+                    self.stack.push(b);
+                }
+                Op::GetLocal(slot) => {
+                    let value = self.stack[self.frame().base + slot as usize];
+                    self.stack.push(value);
+                }
+                Op::SetLocal(slot) => {
+                    let idx = self.frame().base + slot as usize;
+                    self.stack[idx] = self.pop();
+                }
+                Op::GetUpvalue(idx) => {
+                    let value = match self.heap.get(self.upvalue(idx)) {
+                        Object::Upvalue(Upvalue::Open(slot)) => self.stack[*slot],
+                        Object::Upvalue(Upvalue::Closed(value)) => *value,
+                        _ => unreachable!(),
+                    };
+                    self.stack.push(value);
+                }
+                Op::SetUpvalue(idx) => {
+                    let value = self.pop();
+                    let upvalue = self.upvalue(idx);
+                    match self.heap.get_mut(upvalue) {
+                        Object::Upvalue(Upvalue::Open(slot)) => self.stack[*slot] = value,
+                        Object::Upvalue(upvalue) => *upvalue = Upvalue::Closed(value),
+                        _ => unreachable!(),
+                    }
+                }
+                Op::GetCaptured(idx) => {
+                    let value = match self.heap.get(self.frame().closure) {
+                        Object::Closure(closure) => closure.upvalues[idx as usize],
+                        _ => unreachable!(),
+                    };
+                    self.stack.push(value);
+                }
+                Op::GetGlobal(ident) => {
+                    let value = self.global(self.frame().module, ident)?;
+                    self.stack.push(value);
+                }
+                Op::SetGlobal(ident) => {
+                    let value = self.pop();
+                    let module = self.frame().module;
+                    match self.module_mut(module).globals.get_mut(&ident) {
+                        Some(global) => *global = value,
+                        None => return Err(Error::UndefinedVariable(ident)),
+                    }
+                }
+                Op::DefineGlobal(ident) => {
+                    let value = self.pop();
+                    let module = self.frame().module;
+                    self.module_mut(module).globals.insert(ident, value);
+                }
+                Op::GetField(ident) => {
+                    let object = self.pop();
+                    let value = self.get_field(object, ident)?;
+                    self.stack.push(value);
+                }
+                Op::SetField(ident) => {
+                    let value = self.pop();
+                    let object = self.pop();
+                    self.set_field(object, ident, value)?;
+                }
+                Op::GetPath(ident) => {
+                    let object = self.pop();
+                    let value = self.get_path(object, ident)?;
+                    self.stack.push(value);
+                }
+                Op::Index => {
+                    if self.overload(op, span)? {
+                        continue;
+                    }
+                    let idx = self.pop();
+                    let object = self.pop();
+                    let value = self.index(object, idx)?;
+                    self.stack.push(value);
+                }
+                Op::SetIndex => {
+                    if self.overload(op, span)? {
+                        continue;
+                    }
+                    let value = self.pop();
+                    let idx = self.pop();
+                    let object = self.pop();
+                    self.set_index(object, idx, value)?;
+                }
+                Op::Binary(binop) => {
+                    let len = self.stack.len();
+                    let (left, right) = (self.stack[len - 2], self.stack[len - 1]);
+                    if let Some(value) = self.fast_binary(binop, left, right) {
+                        self.stack.truncate(len - 1);
+                        self.stack[len - 2] = value;
+                        continue;
+                    }
+                    if self.overload(op, span)? {
+                        continue;
+                    }
+                    let right = self.pop();
+                    let left = self.pop();
+                    let value = self.binary(binop, left, right)?;
+                    self.stack.push(value);
+                }
+                Op::Range(inclusive) => {
+                    let end = self.pop();
+                    let start = self.pop();
+                    let bound = |vm: &Vm, value: Value| match value.is_null() {
+                        true => Ok(None),
+                        false => vm
+                            .int(value)
+                            .map(Some)
+                            .ok_or(Error::TypeError("range bounds must be ints")),
+                    };
+                    let range = Range {
+                        start: bound(self, start)?,
+                        end: bound(self, end)?,
+                        inclusive,
+                        step: 1,
+                    };
+                    let range = self.heap.alloc(Object::Range(range));
+                    self.stack.push(Value::obj(range));
+                }
+                Op::Negate => {
+                    if self.overload(op, span)? {
+                        continue;
+                    }
+                    let value = self.pop();
+                    let num = self.num(value).ok_or(Error::InvalidOperands("-"))?;
+                    let num = self.arith.negate(&num)?;
+                    let value = self.num_value(num);
+                    self.stack.push(value);
+                }
+                Op::Not => {
+                    let value = self.pop();
+                    self.stack.push(Value::bool(!value.is_truthy()));
+                }
+                Op::Jump(target) => self.jump(target),
+                Op::JumpIfFalse(target) => {
+                    if !self.pop().is_truthy() {
+                        self.jump(target);
+                    }
+                }
+                Op::JumpIfFalseKeep(target) => {
+                    if !self.peek(0).is_truthy() {
+                        self.jump(target);
+                    }
+                }
+                Op::JumpIfTrueKeep(target) => {
+                    if self.peek(0).is_truthy() {
+                        self.jump(target);
+                    }
+                }
+                Op::JumpIfNullKeep(target) => {
+                    if self.peek(0) == Value::NULL {
+                        self.jump(target);
+                    }
+                }
+                Op::JumpIfNotNullKeep(target) => {
+                    if self.peek(0) != Value::NULL {
+                        self.jump(target);
+                    }
+                }
+                Op::JumpIfGiven(slot, target) => {
+                    if self.frame().given[slot as usize - 1] {
+                        self.jump(target);
+                    }
+                }
+                Op::Call(argc) => {
+                    let callee = self.peek(argc as usize);
+                    self.call_value(callee, argc as usize, Vec::new(), span)?;
+                }
+                Op::CallArgs(shape) => {
+                    let (argc, named) = self.spread_args(shape)?;
+                    let callee = self.peek(argc);
+                    self.call_value(callee, argc, named, span)?;
+                }
+                Op::Invoke(ident, argc) => {
+                    self.invoke(ident, argc as usize, Vec::new(), span)?;
+                }
+                Op::InvokeArgs(ident, shape) => {
+                    let (argc, named) = self.spread_args(shape)?;
+                    self.invoke(ident, argc, named, span)?;
+                }
+                Op::Return => {
+                    let result = self.pop();
+                    if let Some(result) = self.return_value(result, depth) {
+                        return Ok(result);
+                    }
+                }
+                Op::Closure(idx) => {
+                    let closure = self.closure(idx);
+                    self.stack.push(Value::obj(closure));
+                }
+                Op::NewArray(n) => {
+                    let elems = self.stack.split_off(self.stack.len() - n as usize);
+                    let array = self.alloc_array(elems)?;
+                    self.stack.push(array);
+                }
+                Op::NewTuple(n) => {
+                    self.check_array_len(n as usize)?;
+                    let elems = self.stack.split_off(self.stack.len() - n as usize);
+                    let tuple = self.heap.alloc(Object::Tuple(elems));
+                    self.stack.push(Value::obj(tuple));
+                }
+                Op::NewMap(n) => {
+                    let values = self.stack.split_off(self.stack.len() - 2 * n as usize);
+                    let map = self.heap.alloc(Object::Map(Map::default()));
+                    for pair in values.chunks(2) {
+                        self.map_insert(map, pair[0], pair[1])?;
+                    }
+                    self.stack.push(Value::obj(map));
+                }
+                Op::NewSet(n) => {
+                    let elems = self.stack.split_off(self.stack.len() - n as usize);
+                    let set = self.heap.alloc(Object::Set(Set::default()));
+                    for elem in elems {
+                        self.set_insert(set, elem)?;
+                    }
+                    self.stack.push(Value::obj(set));
+                }
+                Op::NewInstance(names) => {
+                    let names = self.frame().proto.names[names as usize].clone();
+                    let values = self.stack.split_off(self.stack.len() - names.len());
+                    let fields = names.into_iter().zip(values).collect();
+                    let def = match self.pop().unpack() {
+                        Unpacked::Obj(def) if matches!(self.heap.get(def), Object::Struct(_)) => {
+                            def
+                        }
+                        _ => return Err(Error::TypeError("expected a struct")),
+                    };
+                    let instance = self.heap.alloc(Object::Instance(Instance { def, fields }));
+                    self.stack.push(Value::obj(instance));
+                }
+                Op::NewVariant(variant, names) => {
+                    let names = self.frame().proto.names[names as usize].clone();
+                    let values = self.stack.split_off(self.stack.len() - names.len());
+                    let def = self.pop();
+                    let value = self.new_struct_variant(def, variant, &names, values)?;
+                    self.stack.push(value);
+                }
+                Op::Struct(desc) => {
+                    let proto = self.frame().proto.clone();
+                    let desc = &proto.types[desc as usize];
+                    let methods = self.methods(&proto, &desc.methods);
+                    let def = self.heap.alloc(Object::Struct(StructDef {
+                        ident: desc.ident.unwrap(),
+                        methods,
+                    }));
+                    self.stack.push(Value::obj(def));
+                }
+                Op::Enum(desc) => {
+                    let proto = self.frame().proto.clone();
+                    let desc = &proto.types[desc as usize];
+                    let variants = desc
+                        .variants
+                        .iter()
+                        .map(|(ident, kind, fields)| VariantDef {
+                            ident: *ident,
+                            kind: *kind,
+                            fields: fields.clone(),
+                        })
+                        .collect();
+                    let methods = self.methods(&proto, &desc.methods);
+                    let def = self.heap.alloc(Object::Enum(EnumDef {
+                        ident: desc.ident.unwrap(),
+                        variants,
+                        methods,
+                    }));
+                    self.stack.push(Value::obj(def));
+                }
+                Op::Trait(desc) => {
+                    let proto = self.frame().proto.clone();
+                    let desc = &proto.types[desc as usize];
+                    let methods = self.methods(&proto, &desc.methods);
+                    let def = self.heap.alloc(Object::Trait(TraitDef {
+                        ident: desc.ident.unwrap(),
+                        required: desc.required.clone(),
+                        methods,
+                    }));
+                    self.stack.push(Value::obj(def));
+                }
+                Op::Impl(desc) => {
+                    let proto = self.frame().proto.clone();
+                    let methods = self.methods(&proto, &proto.types[desc as usize].methods);
+                    let target = self.pop();
+                    let trait_def = self.pop();
+                    self.implement(trait_def, target, methods)?;
+                }
+                Op::Module(idx, ident) => {
+                    let proto = self.frame().proto.protos[idx as usize].clone();
+                    let module = self.heap.alloc(Object::Module(Module {
+                        ident: Some(ident),
+                        globals: HashMap::new(),
+                    }));
+                    let closure = self.heap.alloc(Object::Closure(Closure {
+                        proto: proto.clone(),
+                        module,
+                        upvalues: Vec::new(),
+                    }));
+                    self.stack.push(Value::obj(closure));
+                    self.frames.push(Frame {
+                        closure,
+                        proto,
+                        ip: 0,
+                        base: self.stack.len() - 1,
+                        given: Vec::new(),
+                        module,
+                        ret: Ret::Push,
+                        generator: None,
+                    });
+                }
+                Op::CurrentModule => self.stack.push(Value::obj(self.frame().module)),
+                Op::Use(names) => {
+                    let path = self.frame().proto.names[names as usize].clone();
+                    let mut value = self.global(self.frame().module, path[0])?;
+                    for &ident in &path[1..] {
+                        value = self.get_path(value, ident)?;
+                    }
+                    self.stack.push(value);
+                }
+                Op::Iter => {
+                    let source = self.peek(0);
+                    if self.def_of(source).is_some() {
+                        if self.method(source, self.next_ident).is_none() {
+                            let iter = self
+                                .method(source, self.iter_ident)
+                                .ok_or(Error::TypeError("value is not iterable"))?;
+                            self.call_overload(iter, 0, Ret::Iter, span)?;
+                        }
+                        continue;
+                    }
+                    let source = self.pop();
+                    let iter = self.iter(source)?;
+                    self.stack.push(iter);
+                }
+                Op::ForIter(target) => {
+                    let iter = self.peek(0);
+                    if let Some(generator) = self.generator(iter) {
+                        self.resume(generator, Ret::ForIter(target))?;
+                        continue;
+                    }
+                    if self.def_of(iter).is_some() {
+                        let next = self
+                            .method(iter, self.next_ident)
+                            .ok_or(Error::TypeError("value is not an iterator"))?;
+                        self.stack.push(iter);
+                        self.call_overload(next, 0, Ret::ForIter(target), span)?;
+                        continue;
+                    }
+                    match self.iter_next(iter)? {
+                        Some(value) => self.stack.push(value),
+                        None => self.jump(target),
+                    }
+                }
+                Op::Try(target) => {
+                    let value = self.pop();
+                    match self.unwrap_result(value) {
+                        Ok(value) => {
+                            self.stack.push(value);
+                            self.jump(target);
+                        }
+                        Err(failure) => self.stack.push(failure),
+                    }
+                }
+                Op::PushHandler(target) => self.handlers.push(Handler {
+                    frames: self.frames.len(),
+                    stack: self.stack.len(),
+                    target: target as usize,
+                }),
+                Op::PopHandler => {
+                    self.handlers.pop();
+                }
+                Op::Throw => return Err(Error::Thrown(self.pop())),
+                Op::Yield => {
+                    let value = self.pop();
+                    let frame = self.frames.pop().unwrap();
+                    self.suspend(&frame);
+                    if let Some(value) = self.hand_back(frame.ret, value, depth) {
+                        return Ok(value);
+                    }
+                }
+                Op::MatchVariant(pattern, target) => {
+                    let def = self.pop();
+                    let scrutinee = self.peek(0);
+                    match self.match_variant(scrutinee, def, pattern)? {
+                        Some(fields) => self.stack.extend(fields),
+                        None => self.jump(target),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Pops the running frame, handing `result` to its caller. Returns the
+    /// result if the frame was the one `run` was called for.
+    fn return_value(&mut self, mut result: Value, depth: usize) -> Option<Value> {
+        let frame = self.frames.pop().unwrap();
+        self.close_upvalues(frame.base);
+        self.stack.truncate(frame.base);
+        if let Some(generator) = frame.generator {
+            self.generator_mut(generator).state = GenState::Done;
+            result = Value::obj(self.done);
+        }
+        self.hand_back(frame.ret, result, depth)
+    }
+
+    /// Passes the value of a frame that returned or yielded to its caller.
+    fn hand_back(&mut self, ret: Ret, result: Value, depth: usize) -> Option<Value> {
+        if self.frames.len() == depth {
+            return Some(result);
+        }
+        self.deliver(ret, result);
+        None
+    }
+
+    /// Does what `ret` asks with the result of a call.
+    fn deliver(&mut self, ret: Ret, result: Value) {
+        match ret {
+            Ret::Push => self.stack.push(result),
+            Ret::Not => self.stack.push(Value::bool(!result.is_truthy())),
+            Ret::Discard => (),
+            Ret::ForIter(target) if result.as_obj() == Some(self.done) => self.jump(target),
+            Ret::ForIter(_) => self.stack.push(result),
+            Ret::Iter => {
+                self.stack.push(result);
+                if self.def_of(result).is_none() {
+                    self.frames.last_mut().unwrap().ip -= 1;
+                }
+            }
+        }
+    }
+
+    /// Pops frames down to `len`, finishing the generators they ran for.
+    fn truncate_frames(&mut self, len: usize) {
+        for frame in self.frames.drain(len..) {
+            if let Some(generator) = frame.generator {
+                if let Object::Generator(generator) = self.heap.get_mut(generator) {
+                    generator.state = GenState::Done;
+                }
+            }
+        }
+    }
+
+    fn generator(&self, value: Value) -> Option<ObjRef> {
+        match value.unpack() {
+            Unpacked::Obj(obj) if matches!(self.heap.get(obj), Object::Generator(_)) => Some(obj),
+            _ => None,
+        }
+    }
+
+    fn generator_mut(&mut self, generator: ObjRef) -> &mut Generator {
+        match self.heap.get_mut(generator) {
+            Object::Generator(generator) => generator,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Continues `generator` in a new frame on top of the stack, which hands
+    /// the next value it yields to the caller according to `ret`.
+    fn resume(&mut self, generator: ObjRef, ret: Ret) -> Result<()> {
+        let state = self.generator_mut(generator).state;
+        match state {
+            GenState::Running => return Err(Error::TypeError("generator is already running")),
+            GenState::Done => {
+                self.deliver(ret, Value::obj(self.done));
+                return Ok(());
+            }
+            GenState::Suspended => (),
+        }
+        self.check_call_depth()?;
+
+        let base = self.stack.len();
+        let frames = self.frames.len() + 1;
+        let gen = self.generator_mut(generator);
+        gen.state = GenState::Running;
+        let (closure, ip, given) = (gen.closure, gen.ip, gen.given.clone());
+        let stack = std::mem::take(&mut gen.stack);
+        let upvalues = std::mem::take(&mut gen.upvalues);
+        let handlers = std::mem::take(&mut gen.handlers);
+
+        self.stack.extend(stack);
+        for (slot, upvalue) in upvalues {
+            // The variable may have been assigned through the capture while
+            // the generator was suspended.
+            if let Object::Upvalue(Upvalue::Closed(value)) = self.heap.get(upvalue) {
+                self.stack[base + slot] = *value;
+            }
+            *self.heap.get_mut(upvalue) = Object::Upvalue(Upvalue::Open(base + slot));
+            self.open_upvalues.push((base + slot, upvalue));
+        }
+        self.handlers
+            .extend(handlers.into_iter().map(|(stack, target)| Handler {
+                frames,
+                stack: base + stack,
+                target,
+            }));
+
+        let (proto, module) = match self.heap.get(closure) {
+            Object::Closure(closure) => (closure.proto.clone(), closure.module),
+            _ => unreachable!(),
+        };
+        self.frames.push(Frame {
+            closure,
+            proto,
+            ip,
+            base,
+            given,
+            module,
+            ret,
+            generator: Some(generator),
+        });
+        Ok(())
+    }
+
+    /// Saves the state of the generator `frame`, which was just popped, and
+    /// takes its values off the stack.
+    fn suspend(&mut self, frame: &Frame) {
+        let base = frame.base;
+        let stack = self.stack.split_off(base);
+        let idx = self.open_upvalues.partition_point(|&(slot, _)| slot < base);
+        let upvalues: Vec<_> = self
+            .open_upvalues
+            .split_off(idx)
+            .into_iter()
+            .map(|(slot, upvalue)| (slot - base, upvalue))
+            .collect();
+        for &(slot, upvalue) in &upvalues {
+            *self.heap.get_mut(upvalue) = Object::Upvalue(Upvalue::Closed(stack[slot]));
+        }
+        let idx = self
+            .handlers
+            .partition_point(|handler| handler.frames <= self.frames.len());
+        let handlers = self
+            .handlers
+            .split_off(idx)
+            .into_iter()
+            .map(|handler| (handler.stack - base, handler.target))
+            .collect();
+
+        let gen = self.generator_mut(frame.generator.unwrap());
+        gen.state = GenState::Suspended;
+        gen.ip = frame.ip;
+        gen.stack = stack;
+        gen.upvalues = upvalues;
+        gen.handlers = handlers;
+        self.heap.resized(frame.generator.unwrap());
+    }
+
+    /// Unwraps `Result::Ok(v)` to `v` and passes other successful values
+    /// through. `null` and `Result::Err` are failures.
+    fn unwrap_result(&self, value: Value) -> StdResult<Value, Value> {
+        let obj = match value.unpack() {
+            Unpacked::Null => return Err(value),
+            Unpacked::Obj(obj) => obj,
+            _ => return Ok(value),
+        };
+        match self.heap.get(obj) {
+            Object::Variant(variant) if variant.def == self.result_def => match variant.variant {
+                0 => Ok(variant.fields[0]),
+                _ => Err(value),
+            },
+            _ => Ok(value),
+        }
+    }
+
+    /// Passes a call of a native function to the audit hook, if there is one.
+    fn audit(&mut self, ident: IntStr, module: Option<IntStr>, args: &[Value]) {
+        let mut hook = match self.audit_hook.take() {
+            Some(hook) => hook,
+            None => return,
+        };
+        let name = |ident| self.interner.lookup(ident).unwrap_or("?");
+        let function = match module {
+            Some(module) => format!("{}.{}", name(module), name(ident)),
+            None => name(ident).to_owned(),
+        };
+        let call = HostCall {
+            function,
+            args,
+            caller: self.trace_entry(self.frame()),
+        };
+        hook(self, &call);
+        self.audit_hook = Some(hook);
+    }
+
+    /// Counts a step and fails if the script ran out of steps, time or heap.
+    /// Collects garbage first if it is due.
+    fn check_limits(&mut self) -> Result<()> {
+        let over_limit = matches!(self.limits.max_heap_bytes, Some(max) if self.heap.bytes() > max);
+        if (self.heap.collection_due() || self.collection_requested || over_limit)
+            && self.reentries == 0
+        {
+            self.collect();
+        }
+        self.steps += 1;
+        if matches!(self.limits.max_steps, Some(max) if self.steps > max) {
+            return Err(Error::StepLimitExceeded);
+        }
+        if self.steps.is_multiple_of(DEADLINE_INTERVAL)
+            && matches!(self.deadline, Some(deadline) if Instant::now() >= deadline)
+        {
+            return Err(Error::DeadlineExceeded);
+        }
+        if matches!(self.limits.max_heap_bytes, Some(max) if self.heap.bytes() > max) {
+            return Err(Error::HeapLimitExceeded);
+        }
+        Ok(())
+    }
+
+    fn check_call_depth(&self) -> Result<()> {
+        match self.limits.max_call_depth {
+            Some(max) if self.frames.len() >= max => Err(Error::CallDepthExceeded),
+            _ => Ok(()),
+        }
+    }
+
+    fn jump(&mut self, target: u32) {
+        self.frames.last_mut().unwrap().ip = target as usize;
+    }
+
+    fn module_mut(&mut self, module: ObjRef) -> &mut Module {
+        match self.heap.get_mut(module) {
+            Object::Module(module) => module,
+            _ => unreachable!(),
+        }
+    }
+
+    fn global(&self, module: ObjRef, ident: IntStr) -> Result<Value> {
+        let globals = match self.heap.get(module) {
+            Object::Module(module) => &module.globals,
+            _ => unreachable!(),
+        };
+        globals
+            .get(&ident)
+            .or_else(|| self.builtins.get(&ident))
+            .copied()
+            .ok_or(Error::UndefinedVariable(ident))
+    }
+
+    /// The upvalue object of a capture by reference of the running closure.
+    fn upvalue(&self, idx: u32) -> ObjRef {
+        match self.heap.get(self.frame().closure) {
+            Object::Closure(closure) => match closure.upvalues[idx as usize].unpack() {
+                Unpacked::Obj(upvalue) => upvalue,
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        let idx = match self
+            .open_upvalues
+            .binary_search_by_key(&slot, |&(slot, _)| slot)
+        {
+            Ok(idx) => return self.open_upvalues[idx].1,
+            Err(idx) => idx,
+        };
+        let upvalue = self.heap.alloc(Object::Upvalue(Upvalue::Open(slot)));
+        self.open_upvalues.insert(idx, (slot, upvalue));
+        upvalue
+    }
+
+    /// Moves the variables at or above stack index `from` that are captured by
+    /// reference off the stack.
+    fn close_upvalues(&mut self, from: usize) {
+        let idx = self.open_upvalues.partition_point(|&(slot, _)| slot < from);
+        for (slot, upvalue) in self.open_upvalues.split_off(idx) {
+            *self.heap.get_mut(upvalue) = Object::Upvalue(Upvalue::Closed(self.stack[slot]));
+        }
+    }
+
+    fn closure(&mut self, idx: u32) -> ObjRef {
+        let frame = self.frame();
+        let proto = frame.proto.protos[idx as usize].clone();
+        let (base, module, enclosing) = (frame.base, frame.module, frame.closure);
+
+        let mut upvalues = Vec::with_capacity(proto.upvalues.len());
+        for desc in &proto.upvalues {
+            let value = if desc.is_local {
+                let slot = base + desc.idx as usize;
+                if desc.by_ref {
+                    Value::obj(self.capture_upvalue(slot))
+                } else {
+                    self.stack[slot]
+                }
+            } else {
+                match self.heap.get(enclosing) {
+                    Object::Closure(closure) => closure.upvalues[desc.idx as usize],
+                    _ => unreachable!(),
+                }
+            };
+            upvalues.push(value);
+        }
+
+        self.heap.alloc(Object::Closure(Closure {
+            proto,
+            module,
+            upvalues,
+        }))
+    }
+
+    /// Creates the closures of the methods of a struct, enum, trait or impl
+    /// declared in `proto`.
+    fn methods(&mut self, proto: &Proto, methods: &[(IntStr, u32)]) -> HashMap<IntStr, Value> {
+        let module = self.frame().module;
+        methods
+            .iter()
+            .map(|&(ident, idx)| {
+                let closure = self.heap.alloc(Object::Closure(Closure {
+                    proto: proto.protos[idx as usize].clone(),
+                    module,
+                    upvalues: Vec::new(),
+                }));
+                (ident, Value::obj(closure))
+            })
+            .collect()
+    }
+
+    /// Adds the methods of an impl block and the default methods of its trait
+    /// to a struct or enum.
+    fn implement(
+        &mut self,
+        trait_def: Value,
+        target: Value,
+        mut methods: HashMap<IntStr, Value>,
+    ) -> Result<()> {
+        let trait_def = match trait_def.unpack() {
+            Unpacked::Obj(obj) => match self.heap.get(obj) {
+                Object::Trait(trait_def) => trait_def,
+                _ => return Err(Error::TypeError("expected a trait")),
+            },
+            _ => return Err(Error::TypeError("expected a trait")),
+        };
+
+        for ident in methods.keys() {
+            if !trait_def.required.contains(ident) && !trait_def.methods.contains_key(ident) {
+                return Err(Error::UndefinedMethod(*ident));
+            }
+        }
+        if let Some(&method) = trait_def
+            .required
+            .iter()
+            .find(|ident| !methods.contains_key(ident))
+        {
+            return Err(Error::MissingTraitMethod {
+                trait_ident: trait_def.ident,
+                method,
+            });
+        }
+        let defaults: Vec<_> = trait_def
+            .methods
+            .iter()
+            .filter(|(ident, _)| !methods.contains_key(ident))
+            .map(|(&ident, &method)| (ident, method))
+            .collect();
+
+        let own = match target.unpack() {
+            Unpacked::Obj(obj) => match self.heap.get_mut(obj) {
+                Object::Struct(def) => &mut def.methods,
+                Object::Enum(def) => &mut def.methods,
+                _ => return Err(Error::TypeError("expected a struct or enum")),
+            },
+            _ => return Err(Error::TypeError("expected a struct or enum")),
+        };
+        if let Some(&ident) = methods.keys().find(|ident| own.contains_key(ident)) {
+            return Err(Error::MethodDefinedTwice(ident));
+        }
+        // Methods of the type itself take precedence over default methods.
+        for (ident, method) in defaults {
+            own.entry(ident).or_insert(method);
+        }
+        own.extend(methods.drain());
+        Ok(())
+    }
+
+    /// Pops the arguments of a call with named or spread arguments, pushing
+    /// back the positional ones and returning their count and the named ones.
+    fn spread_args(&mut self, shape: u32) -> Result<(usize, Vec<(IntStr, Value)>)> {
+        let proto = self.frame().proto.clone();
+        let shape = &proto.arg_shapes[shape as usize];
+        let values = self.stack.split_off(self.stack.len() - shape.len());
+
+        let mut argc = 0;
+        let mut named = Vec::new();
+        for (kind, value) in shape.iter().zip(values) {
+            match kind {
+                ArgKind::Positional => {
+                    self.stack.push(value);
+                    argc += 1;
+                }
+                ArgKind::Named(ident) => named.push((*ident, value)),
+                ArgKind::Spread => {
+                    let elems = match value.unpack() {
+                        Unpacked::Obj(obj) => match self.heap.get(obj) {
+                            Object::Array(elems) => elems,
+                            _ => return Err(Error::TypeError("only arrays can be spread")),
+                        },
+                        _ => return Err(Error::TypeError("only arrays can be spread")),
+                    };
+                    argc += elems.len();
+                    self.stack.extend_from_slice(elems);
+                }
+            }
+        }
+        Ok((argc, named))
+    }
+
+    /// Calls `callee` with the `argc` values on top of the stack as arguments.
+    /// Closures get a new frame whose slot 0 is the value below the
+    /// arguments, other callees replace the arguments with their result.
+    fn call_value(
+        &mut self,
+        callee: Value,
+        argc: usize,
+        named: Vec<(IntStr, Value)>,
+        span: Span,
+    ) -> Result<()> {
+        let obj = match callee.unpack() {
+            Unpacked::Obj(obj) => obj,
+            _ => return Err(Error::TypeError("value is not callable")),
+        };
+        let base = self.stack.len() - argc - 1;
+
+        match self.heap.get(obj) {
+            Object::Closure(closure) => {
+                let proto = closure.proto.clone();
+                let module = closure.module;
+                let given = self.bind_args(&proto, base, argc, named, span)?;
+                if proto.generator {
+                    let stack = self.stack.split_off(base);
+                    let generator = self.heap.alloc(Object::Generator(Generator {
+                        closure: obj,
+                        state: GenState::Suspended,
+                        ip: 0,
+                        given,
+                        stack,
+                        upvalues: Vec::new(),
+                        handlers: Vec::new(),
+                    }));
+                    self.stack.push(Value::obj(generator));
+                    return Ok(());
+                }
+                self.check_call_depth()?;
+                self.frames.push(Frame {
+                    closure: obj,
+                    proto,
+                    ip: 0,
+                    base,
+                    given,
+                    module,
+                    ret: Ret::Push,
+                    generator: None,
+                });
+            }
+            Object::BoundMethod(bound) => {
+                let (receiver, method) = (bound.receiver, bound.method);
+                self.stack[base] = receiver;
+                self.call_value(method, argc, named, span)?;
+            }
+            Object::Native(native) => {
+                let (fun, ident, module) = (native.fun, native.ident, native.module);
+                if let Some(cap) = native.capability {
+                    if !self.capabilities.contains(cap) {
+                        return Err(Error::CapabilityDenied(module.unwrap_or(ident), cap, span));
+                    }
+                }
+                if let Some((ident, _)) = named.first() {
+                    return Err(Error::UnknownArgName(*ident, span));
+                }
+                let args = self.stack.split_off(base + 1);
+                if !self.frames.is_empty() {
+                    self.audit(ident, module, &args);
+                }
+                let result = fun(self, &args)?;
+                self.stack.truncate(base);
+                self.stack.push(result);
+            }
+            Object::VariantCtor(ctor) => {
+                let (def, variant) = (ctor.def, ctor.variant);
+                let fields = match self.heap.get(def) {
+                    Object::Enum(def) => &def.variants[variant].fields,
+                    _ => unreachable!(),
+                };
+                let expected = fields.len();
+                let missing = fields.get(argc).copied();
+                if let Some((ident, _)) = named.first() {
+                    return Err(Error::UnknownArgName(*ident, span));
+                }
+                if argc > expected {
+                    return Err(Error::TooManyArgs {
+                        expected,
+                        found: argc,
+                        span,
+                    });
+                }
+                if let Some(field) = missing {
+                    return Err(Error::MissingArg(field, span));
+                }
+                let fields = self.stack.split_off(base + 1);
+                self.stack.truncate(base);
+                let value = self.heap.alloc(Object::Variant(Variant {
+                    def,
+                    variant,
+                    fields,
+                }));
+                self.stack.push(Value::obj(value));
+            }
+            _ => return Err(Error::TypeError("value is not callable")),
+        }
+        Ok(())
+    }
+
+    /// Lays out the arguments above `base` in the parameter slots of `proto`,
+    /// returning which parameters were given if any has a default.
+    fn bind_args(
+        &mut self,
+        proto: &Proto,
+        base: usize,
+        argc: usize,
+        named: Vec<(IntStr, Value)>,
+        span: Span,
+    ) -> Result<Vec<bool>> {
+        let params = proto.params.len();
+        if argc > params && !proto.rest {
+            return Err(Error::TooManyArgs {
+                expected: params,
+                found: argc,
+                span,
+            });
+        }
+        let rest = if argc > params {
+            self.stack.split_off(base + 1 + params)
+        } else {
+            Vec::new()
+        };
+
+        self.stack.resize(base + 1 + params, Value::NULL);
+        // Only parameters with a default ask whether they were given.
+        let defaults = proto.required < params;
+        let given = if named.is_empty() {
+            if argc < proto.required {
+                return Err(Error::MissingArg(proto.params[argc], span));
+            }
+            if defaults {
+                (0..params).map(|idx| idx < argc).collect()
+            } else {
+                Vec::new()
+            }
+        } else {
+            let mut given = vec![false; params];
+            given[..argc.min(params)].fill(true);
+            for (ident, value) in named {
+                let idx = proto
+                    .params
+                    .iter()
+                    .position(|param| *param == ident)
+                    .ok_or(Error::UnknownArgName(ident, span))?;
+                if given[idx] {
+                    return Err(Error::ArgGivenTwice(ident, span));
+                }
+                self.stack[base + 1 + idx] = value;
+                given[idx] = true;
+            }
+            if let Some(idx) = (0..proto.required).find(|&idx| !given[idx]) {
+                return Err(Error::MissingArg(proto.params[idx], span));
+            }
+            if defaults {
+                given
+            } else {
+                Vec::new()
+            }
+        };
+
+        if proto.rest {
+            let rest = self.alloc_array(rest)?;
+            self.stack.push(rest);
+        }
+        Ok(given)
+    }
+
+    /// Calls the method or callable field `ident` of the value below the
+    /// arguments.
+    fn invoke(
+        &mut self,
+        ident: IntStr,
+        argc: usize,
+        named: Vec<(IntStr, Value)>,
+        span: Span,
+    ) -> Result<()> {
+        let receiver = self.peek(argc);
+        let base = self.stack.len() - argc - 1;
+        let obj = match receiver.unpack() {
+            Unpacked::Obj(obj) => obj,
+            _ => return Err(Error::UndefinedMethod(ident)),
+        };
+        let key = self.intern_value(ident);
+
+        match self.heap.get(obj) {
+            Object::Generator(_) | Object::Iter(_) if ident == self.next_ident => {
+                if argc > 0 || !named.is_empty() {
+                    return Err(Error::TooManyArgs {
+                        expected: 0,
+                        found: argc + named.len(),
+                        span,
+                    });
+                }
+                self.stack.truncate(base);
+                if let Some(generator) = self.generator(receiver) {
+                    return self.resume(generator, Ret::Push);
+                }
+                let next = self.iter_next(receiver)?;
+                self.stack.push(next.unwrap_or(Value::obj(self.done)));
+                return Ok(());
+            }
+            Object::Instance(instance) => {
+                if let Some(&(_, field)) = instance.fields.iter().find(|(name, _)| *name == ident) {
+                    self.stack[base] = field;
+                    return self.call_value(field, argc, named, span);
+                }
+            }
+            Object::Module(module) => {
+                let member = module
+                    .globals
+                    .get(&ident)
+                    .copied()
+                    .ok_or(Error::UndefinedVariable(ident))?;
+                self.stack[base] = member;
+                return self.call_value(member, argc, named, span);
+            }
+            Object::Map(_) => {
+                if let Some(member) = self.map_get(obj, key)? {
+                    self.stack[base] = member;
+                    return self.call_value(member, argc, named, span);
+                }
+            }
+            Object::Tuple(elems) => {
+                if let Some(elem) = self.tuple_field(elems, ident).transpose()? {
+                    self.stack[base] = elem;
+                    return self.call_value(elem, argc, named, span);
+                }
+            }
+            _ => (),
+        }
+
+        let kind = match self.heap.get(obj) {
+            Object::Str(_) => Some(BuiltinKind::Str),
+            Object::Array(_) => Some(BuiltinKind::Array),
+            Object::Map(_) => Some(BuiltinKind::Map),
+            Object::Set(_) => Some(BuiltinKind::Set),
+            _ => None,
+        };
+        if let Some(&fun) = kind.and_then(|kind| self.native_methods.get(&(kind, ident))) {
+            if let Some((ident, _)) = named.first() {
+                return Err(Error::UnknownArgName(*ident, span));
+            }
+            let args = self.stack.split_off(base);
+            let result = fun(self, &args)?;
+            self.stack.push(result);
+            return Ok(());
+        }
+
+        let method = self
+            .method(receiver, ident)
+            .ok_or(Error::UndefinedMethod(ident))?;
+        self.call_value(method, argc, named, span)
+    }
+
+    /// The struct or enum `value` belongs to.
+    fn def_of(&self, value: Value) -> Option<ObjRef> {
+        match value.unpack() {
+            Unpacked::Obj(obj) => match self.heap.get(obj) {
+                Object::Instance(instance) => Some(instance.def),
+                Object::Variant(variant) => Some(variant.def),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Looks up a method defined on the struct or enum of `value`.
+    fn method(&self, value: Value, ident: IntStr) -> Option<Value> {
+        match self.heap.get(self.def_of(value)?) {
+            Object::Struct(def) => def.methods.get(&ident).copied(),
+            Object::Enum(def) => def.methods.get(&ident).copied(),
+            _ => None,
+        }
+    }
+
+    /// Dispatches an operator whose left operand is a struct or enum value to
+    /// the method overloading it, returning whether it did.
+    ///
+    /// Binary operators call `add`, `sub`, `mul`, `div`, `mod`, `bitand`,
+    /// `bitor`, `bitxor`, `shl`, `shr`, `eq`, `ne`, `lt`, `gt`, `le` or `ge`
+    /// with the right operand, `-x` calls `neg`, `x[i]` calls `index` and
+    /// `x[i] = v` calls `index_set`. Without `ne`, `!=` negates `eq`. Without
+    /// `ge`, `>=` negates `lt`, and without `gt` and `le` the operands are
+    /// swapped to use the `lt` of the right operand.
+    fn overload(&mut self, op: Op, span: Span) -> Result<bool> {
+        let (argc, name) = match op {
+            Op::Binary(op) => (1, binop_method(op)),
+            Op::Negate => (0, "neg"),
+            Op::Index => (1, "index"),
+            Op::SetIndex => (2, "index_set"),
+            _ => unreachable!(),
+        };
+        let receiver = self.peek(argc);
+        if self.def_of(receiver).is_none() {
+            return Ok(false);
+        }
+
+        let ident = self.interner.intern_str(name);
+        if let Some(method) = self.method(receiver, ident) {
+            let ret = if op == Op::SetIndex {
+                Ret::Discard
+            } else {
+                Ret::Push
+            };
+            self.call_overload(method, argc, ret, span)?;
+            return Ok(true);
+        }
+
+        let (name, swap, ret) = match op {
+            Op::Binary(BinOp::NotEq) => ("eq", false, Ret::Not),
+            Op::Binary(BinOp::GreaterEq) => ("lt", false, Ret::Not),
+            Op::Binary(BinOp::Greater) => ("lt", true, Ret::Push),
+            Op::Binary(BinOp::LessEq) => ("lt", true, Ret::Not),
+            _ => return Ok(false),
+        };
+        let ident = self.interner.intern_str(name);
+        let receiver = if swap { self.peek(0) } else { receiver };
+        let method = match self.method(receiver, ident) {
+            Some(method) => method,
+            None => return Ok(false),
+        };
+        if swap {
+            let len = self.stack.len();
+            self.stack.swap(len - 1, len - 2);
+        }
+        self.call_overload(method, argc, ret, span)?;
+        Ok(true)
+    }
+
+    fn call_overload(&mut self, method: Value, argc: usize, ret: Ret, span: Span) -> Result<()> {
+        let frames = self.frames.len();
+        self.call_value(method, argc, Vec::new(), span)?;
+        if self.frames.len() > frames {
+            self.frames.last_mut().unwrap().ret = ret;
+        } else {
+            // Methods containing `yield` return a generator without running.
+            let result = self.pop();
+            self.deliver(ret, result);
+        }
+        Ok(())
+    }
+
+    fn bind(&mut self, receiver: Value, method: Value) -> Value {
+        Value::obj(
+            self.heap
+                .alloc(Object::BoundMethod(BoundMethod { receiver, method })),
+        )
+    }
+
+    fn get_field(&mut self, object: Value, ident: IntStr) -> Result<Value> {
+        let key = self.intern_value(ident);
+        if let Unpacked::Obj(obj) = object.unpack() {
+            match self.heap.get(obj) {
+                Object::Instance(instance) => {
+                    if let Some(&(_, value)) =
+                        instance.fields.iter().find(|(name, _)| *name == ident)
+                    {
+                        return Ok(value);
+                    }
+                }
+                Object::Variant(variant) => {
+                    let def = match self.heap.get(variant.def) {
+                        Object::Enum(def) => &def.variants[variant.variant],
+                        _ => unreachable!(),
+                    };
+                    if def.kind == VariantKind::Struct {
+                        if let Some(idx) = def.fields.iter().position(|field| *field == ident) {
+                            return Ok(variant.fields[idx]);
+                        }
+                    }
+                }
+                Object::Map(_) => {
+                    return Ok(self.map_get(obj, key)?.unwrap_or(Value::NULL));
+                }
+                Object::Tuple(elems) => {
+                    return self
+                        .tuple_field(elems, ident)
+                        .unwrap_or(Err(Error::UndefinedField(ident)));
+                }
+                Object::Module(module) => {
+                    return module
+                        .globals
+                        .get(&ident)
+                        .copied()
+                        .ok_or(Error::UndefinedField(ident));
+                }
+                _ => (),
+            }
+        }
+
+        match self.method(object, ident) {
+            Some(method) => Ok(self.bind(object, method)),
+            None => Err(Error::UndefinedField(ident)),
+        }
+    }
+
+    /// The element of a tuple named by a field like `0`, or `None` if the
+    /// field is not a number.
+    fn tuple_field(&self, elems: &[Value], ident: IntStr) -> Option<Result<Value>> {
+        let name = self.interner.lookup(ident)?;
+        if !name.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        // Indices too large for an int are out of range all the same.
+        let idx = name.parse().unwrap_or(i64::MAX);
+        Some(
+            elems
+                .get(idx as usize)
+                .copied()
+                .ok_or(Error::IndexOutOfRange(idx)),
+        )
+    }
+
+    fn set_field(&mut self, object: Value, ident: IntStr, value: Value) -> Result<()> {
+        let obj = match object.unpack() {
+            Unpacked::Obj(obj) => obj,
+            _ => return Err(Error::TypeError("only instances and maps have fields")),
+        };
+        let key = self.intern_value(ident);
+        match self.heap.get_mut(obj) {
+            Object::Instance(instance) => {
+                match instance.fields.iter_mut().find(|(name, _)| *name == ident) {
+                    Some((_, field)) => *field = value,
+                    None => {
+                        instance.fields.push((ident, value));
+                        self.heap.resized(obj);
+                    }
+                }
+            }
+            Object::Map(_) => self.map_insert(obj, key, value)?,
+            _ => return Err(Error::TypeError("only instances and maps have fields")),
+        }
+        Ok(())
+    }
+
+    fn get_path(&mut self, object: Value, ident: IntStr) -> Result<Value> {
+        let obj = match object.unpack() {
+            Unpacked::Obj(obj) => obj,
+            _ => return Err(Error::TypeError("only enums and modules have paths")),
+        };
+        match self.heap.get(obj) {
+            Object::Enum(def) => {
+                let variant = def
+                    .variants
+                    .iter()
+                    .position(|variant| variant.ident == ident)
+                    .ok_or(Error::UndefinedVariant(ident))?;
+                let value = match def.variants[variant].kind {
+                    VariantKind::Unit => Object::Variant(Variant {
+                        def: obj,
+                        variant,
+                        fields: Vec::new(),
+                    }),
+                    VariantKind::Tuple => Object::VariantCtor(VariantCtor { def: obj, variant }),
+                    VariantKind::Struct => {
+                        return Err(Error::TypeError("struct variants are created with `new`"))
+                    }
+                };
+                Ok(Value::obj(self.heap.alloc(value)))
+            }
+            Object::Module(module) => module
+                .globals
+                .get(&ident)
+                .copied()
+                .ok_or(Error::UndefinedVariable(ident)),
+            _ => Err(Error::TypeError("only enums and modules have paths")),
+        }
+    }
+
+    fn new_struct_variant(
+        &mut self,
+        def: Value,
+        ident: IntStr,
+        names: &[IntStr],
+        values: Vec<Value>,
+    ) -> Result<Value> {
+        let def = match def.unpack() {
+            Unpacked::Obj(obj) if matches!(self.heap.get(obj), Object::Enum(_)) => obj,
+            _ => return Err(Error::TypeError("expected an enum")),
+        };
+        let enum_def = match self.heap.get(def) {
+            Object::Enum(def) => def,
+            _ => unreachable!(),
+        };
+        let variant = enum_def
+            .variants
+            .iter()
+            .position(|variant| variant.ident == ident)
+            .ok_or(Error::UndefinedVariant(ident))?;
+        let variant_def = &enum_def.variants[variant];
+        if variant_def.kind != VariantKind::Struct {
+            return Err(Error::TypeError("variant has no named fields"));
+        }
+
+        let mut fields = vec![None; variant_def.fields.len()];
+        for (name, value) in names.iter().zip(values) {
+            let idx = variant_def
+                .fields
+                .iter()
+                .position(|field| field == name)
+                .ok_or(Error::UndefinedField(*name))?;
+            fields[idx] = Some(value);
+        }
+        let fields = fields
+            .into_iter()
+            .zip(&variant_def.fields)
+            .map(|(value, field)| value.ok_or(Error::MissingField(*field)))
+            .collect::<Result<_>>()?;
+
+        Ok(Value::obj(self.heap.alloc(Object::Variant(Variant {
+            def,
+            variant,
+            fields,
+        }))))
+    }
+
+    fn match_variant(
+        &self,
+        scrutinee: Value,
+        def: Value,
+        pattern: u32,
+    ) -> Result<Option<Vec<Value>>> {
+        let pattern = &self.frame().proto.patterns[pattern as usize];
+        let (def, enum_def) = match def.unpack() {
+            Unpacked::Obj(obj) => match self.heap.get(obj) {
+                Object::Enum(enum_def) => (obj, enum_def),
+                _ => return Err(Error::TypeError("expected an enum")),
+            },
+            _ => return Err(Error::TypeError("expected an enum")),
+        };
+        let idx = enum_def
+            .variants
+            .iter()
+            .position(|variant| variant.ident == pattern.variant)
+            .ok_or(Error::UndefinedVariant(pattern.variant))?;
+        let variant_def = &enum_def.variants[idx];
+        if variant_def.kind != pattern.kind
+            || (pattern.kind == VariantKind::Tuple
+                && variant_def.fields.len() != pattern.fields.len())
+        {
+            return Err(Error::TypeError("pattern does not fit the variant"));
+        }
+
+        let variant = match scrutinee.unpack() {
+            Unpacked::Obj(obj) => match self.heap.get(obj) {
+                Object::Variant(variant) if variant.def == def && variant.variant == idx => variant,
+                _ => return Ok(None),
+            },
+            _ => return Ok(None),
+        };
+        match pattern.kind {
+            VariantKind::Struct => pattern
+                .fields
+                .iter()
+                .map(|field| {
+                    variant_def
+                        .fields
+                        .iter()
+                        .position(|name| name == field)
+                        .map(|idx| variant.fields[idx])
+                        .ok_or(Error::UndefinedField(*field))
+                })
+                .collect::<Result<_>>()
+                .map(Some),
+            _ => Ok(Some(variant.fields.clone())),
+        }
+    }
+
+    fn index(&mut self, object: Value, idx: Value) -> Result<Value> {
+        let obj = match object.unpack() {
+            Unpacked::Obj(obj) => obj,
+            _ => return Err(Error::TypeError("value cannot be indexed")),
+        };
+        if let Some(range) = self.range(idx) {
+            if let Some(sliced) = self.slice(obj, range)? {
+                return Ok(sliced);
+            }
+        }
+        match self.heap.get(obj) {
+            Object::Array(elems) => {
+                let idx = array_index(self.int(idx), elems.len())?;
+                Ok(elems[idx])
+            }
+            Object::Map(_) => Ok(self.map_get(obj, idx)?.unwrap_or(Value::NULL)),
+            Object::Str(s) => {
+                let i = self
+                    .int(idx)
+                    .ok_or(Error::TypeError("index must be an int"))?;
+                usize::try_from(i)
+                    .ok()
+                    .and_then(|i| s.chars().nth(i))
+                    .map(Value::char)
+                    .ok_or(Error::IndexOutOfRange(i))
+            }
+            _ => Err(Error::TypeError("value cannot be indexed")),
+        }
+    }
+
+    fn range(&self, value: Value) -> Option<Range> {
+        match value.unpack() {
+            Unpacked::Obj(obj) => match self.heap.get(obj) {
+                Object::Range(range) => Some(*range),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Copies the elements of an array or the chars of a string in `range`,
+    /// or returns `None` for values that are not sliced.
+    fn slice(&mut self, obj: ObjRef, range: Range) -> Result<Option<Value>> {
+        let sliced = match self.heap.get(obj) {
+            Object::Array(elems) => {
+                let (start, end) = slice_bounds(range, elems.len())?;
+                Object::Array(elems[start..end].to_vec())
+            }
+            Object::Str(s) => {
+                let (start, end) = slice_bounds(range, s.chars().count())?;
+                Object::Str(s.chars().skip(start).take(end - start).collect())
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(Value::obj(self.heap.alloc(sliced))))
+    }
+
+    fn set_index(&mut self, object: Value, idx: Value, value: Value) -> Result<()> {
+        let obj = match object.unpack() {
+            Unpacked::Obj(obj) => obj,
+            _ => return Err(Error::TypeError("value cannot be indexed")),
+        };
+        let int = self.int(idx);
+        match self.heap.get_mut(obj) {
+            Object::Array(elems) => {
+                let idx = array_index(int, elems.len())?;
+                elems[idx] = value;
+            }
+            Object::Map(_) => self.map_insert(obj, idx, value)?,
+            _ => return Err(Error::TypeError("value cannot be indexed")),
+        }
+        Ok(())
+    }
+
+    /// Finds `key` among the keys of the map or the elements of the set
+    /// `obj`, returning the hash of `key` and its position if present.
+    pub fn find_key(&mut self, obj: ObjRef, key: Value) -> Result<(u64, Option<usize>)> {
+        let hash = self.hash_key(key)?;
+        let candidates = match self.heap.get(obj) {
+            Object::Map(map) => map.index.get(hash).to_vec(),
+            Object::Set(set) => set.index.get(hash).to_vec(),
+            _ => unreachable!(),
+        };
+        for pos in candidates {
+            // An `eq` method called by an earlier comparison may have
+            // removed keys.
+            let candidate = match self.heap.get(obj) {
+                Object::Map(map) => map.entries.get(pos).map(|&(k, _)| k),
+                Object::Set(set) => set.elems.get(pos).copied(),
+                _ => unreachable!(),
+            };
+            match candidate {
+                Some(candidate) if self.keys_eq(candidate, key)? => return Ok((hash, Some(pos))),
+                _ => (),
+            }
+        }
+        Ok((hash, None))
+    }
+
+    pub fn map_get(&mut self, obj: ObjRef, key: Value) -> Result<Option<Value>> {
+        let (_, pos) = self.find_key(obj, key)?;
+        Ok(pos.map(|pos| match self.heap.get(obj) {
+            Object::Map(map) => map.entries[pos].1,
+            _ => unreachable!(),
+        }))
+    }
+
+    /// Number of entries of a map or elements of a set.
+    fn key_count(&self, obj: ObjRef) -> usize {
+        match self.heap.get(obj) {
+            Object::Map(map) => map.entries.len(),
+            Object::Set(set) => set.elems.len(),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn map_insert(&mut self, obj: ObjRef, key: Value, value: Value) -> Result<()> {
+        let (hash, pos) = self.find_key(obj, key)?;
+        if pos.is_none() {
+            self.check_array_len(self.key_count(obj) + 1)?;
+        }
+        match self.heap.get_mut(obj) {
+            Object::Map(map) => match pos {
+                Some(pos) => map.entries[pos].1 = value,
+                None => map.push(hash, key, value),
+            },
+            _ => unreachable!(),
+        }
+        self.heap.resized(obj);
+        Ok(())
+    }
+
+    /// Removes `key` from a map, returning its value if it was present.
+    pub fn map_remove(&mut self, obj: ObjRef, key: Value) -> Result<Option<Value>> {
+        let (_, pos) = self.find_key(obj, key)?;
+        let value = pos.map(|pos| match self.heap.get_mut(obj) {
+            Object::Map(map) => map.remove(pos).1,
+            _ => unreachable!(),
+        });
+        self.heap.resized(obj);
+        Ok(value)
+    }
+
+    /// Adds `elem` to a set, returning whether it was missing.
+    pub fn set_insert(&mut self, obj: ObjRef, elem: Value) -> Result<bool> {
+        let (hash, pos) = self.find_key(obj, elem)?;
+        if pos.is_none() {
+            self.check_array_len(self.key_count(obj) + 1)?;
+            match self.heap.get_mut(obj) {
+                Object::Set(set) => set.push(hash, elem),
+                _ => unreachable!(),
+            }
+            self.heap.resized(obj);
+        }
+        Ok(pos.is_none())
+    }
+
+    /// Removes `elem` from a set, returning whether it was present.
+    pub fn set_remove(&mut self, obj: ObjRef, elem: Value) -> Result<bool> {
+        let (_, pos) = self.find_key(obj, elem)?;
+        if let Some(pos) = pos {
+            match self.heap.get_mut(obj) {
+                Object::Set(set) => set.remove(pos),
+                _ => unreachable!(),
+            };
+            self.heap.resized(obj);
+        }
+        Ok(pos.is_some())
+    }
+
+    /// Iterator over a built-in value: the elements of an array, tuple or set, the
+    /// entries of a map as `[key, value]` arrays, the chars of a string or the
+    /// integers of a range. Generators and iterators are their own.
+    ///
+    /// A `for` loop over a struct or enum value follows the iteration
+    /// protocol instead. A value with a `next` method is an iterator, and
+    /// `next` returns the next value or `done` once there is none. Otherwise
+    /// the loop iterates over what its `iter` method returns, which is an
+    /// iterator or a built-in value.
+    fn iter(&mut self, source: Value) -> Result<Value> {
+        let obj = match source.unpack() {
+            Unpacked::Obj(obj) => obj,
+            _ => return Err(Error::TypeError("value is not iterable")),
+        };
+        match self.heap.get(obj) {
+            Object::Generator(_) | Object::Iter(_) => Ok(source),
+            Object::Range(range) if range.start.is_none() => {
+                Err(Error::TypeError("range without a start is not iterable"))
+            }
+            Object::Array(_)
+            | Object::Tuple(_)
+            | Object::Map(_)
+            | Object::Set(_)
+            | Object::Str(_)
+            | Object::Range(_) => Ok(Value::obj(
+                self.heap.alloc(Object::Iter(Iter { source, idx: 0 })),
+            )),
+            _ => Err(Error::TypeError("value is not iterable")),
+        }
+    }
+
+    fn iter_next(&mut self, iter: Value) -> Result<Option<Value>> {
+        let iter = match iter.unpack() {
+            Unpacked::Obj(obj) => obj,
+            _ => unreachable!(),
+        };
+        let (source, idx) = match self.heap.get(iter) {
+            Object::Iter(iter) => (iter.source, iter.idx),
+            _ => unreachable!(),
+        };
+        let source = match source.unpack() {
+            Unpacked::Obj(obj) => obj,
+            _ => unreachable!(),
+        };
+        let mut step = 1;
+        let next = match self.heap.get(source) {
+            Object::Array(elems) | Object::Tuple(elems) => elems.get(idx).copied(),
+            Object::Set(set) => set.elems.get(idx).copied(),
+            Object::Map(map) => match map.entries.get(idx) {
+                Some(&(key, value)) => {
+                    Some(Value::obj(self.heap.alloc(Object::Array(vec![key, value]))))
+                }
+                None => None,
+            },
+            Object::Str(s) => s[idx..].chars().next().map(|c| {
+                step = c.len_utf8();
+                Value::char(c)
+            }),
+            &Object::Range(range) => (idx as i64)
+                .checked_mul(range.step)
+                .and_then(|offset| range.start?.checked_add(offset))
+                .filter(|&i| match range.end {
+                    Some(end) if range.inclusive && i == end => true,
+                    Some(end) if range.step > 0 => i < end,
+                    Some(end) => i > end,
+                    None => true,
+                })
+                .map(|i| self.int_value(i)),
+            _ => unreachable!(),
+        };
+        if next.is_some() {
+            if let Object::Iter(iter) = self.heap.get_mut(iter) {
+                iter.idx += step;
+            }
+        }
+        Ok(next)
+    }
+
+    /// Applies `op` to two ints or two floats without going through `Num`,
+    /// or returns `None` to leave it to `binary`.
+    fn fast_binary(&mut self, op: BinOp, left: Value, right: Value) -> Option<Value> {
+        if let (Some(a), Some(b)) = (left.as_int(), right.as_int()) {
+            // Packed ints have 48 bits, so only products can overflow.
+            return match op {
+                BinOp::Add => Some(self.int_value(a + b)),
+                BinOp::Sub => Some(self.int_value(a - b)),
+                BinOp::Mul => Some(self.int_value(a.checked_mul(b)?)),
+                BinOp::Eq => Some(Value::bool(a == b)),
+                BinOp::NotEq => Some(Value::bool(a != b)),
+                BinOp::Less => Some(Value::bool(a < b)),
+                BinOp::Greater => Some(Value::bool(a > b)),
+                BinOp::LessEq => Some(Value::bool(a <= b)),
+                BinOp::GreaterEq => Some(Value::bool(a >= b)),
+                _ => None,
+            };
+        }
+        let (a, b) = (left.as_float()?, right.as_float()?);
+        Some(match op {
+            BinOp::Add => Value::float(a + b),
+            BinOp::Sub => Value::float(a - b),
+            BinOp::Mul => Value::float(a * b),
+            BinOp::Div => Value::float(a / b),
+            BinOp::Eq => Value::bool(a == b),
+            BinOp::NotEq => Value::bool(a != b),
+            BinOp::Less => Value::bool(a < b),
+            BinOp::Greater => Value::bool(a > b),
+            BinOp::LessEq => Value::bool(a <= b),
+            BinOp::GreaterEq => Value::bool(a >= b),
+            _ => return None,
+        })
+    }
+
+    fn binary(&mut self, op: BinOp, left: Value, right: Value) -> Result<Value> {
+        let value = match (op, left.unpack(), right.unpack()) {
+            (BinOp::Eq, _, _) => Value::bool(self.values_eq(left, right)?),
+            (BinOp::NotEq, _, _) => Value::bool(!self.values_eq(left, right)?),
+            (BinOp::Less | BinOp::Greater | BinOp::LessEq | BinOp::GreaterEq, _, _) => {
+                match self.compare(left, right) {
+                    Some(ordering) => Value::bool(match op {
+                        BinOp::Less => ordering.is_lt(),
+                        BinOp::Greater => ordering.is_gt(),
+                        BinOp::LessEq => ordering.is_le(),
+                        _ => ordering.is_ge(),
+                    }),
+                    // NaN is unordered, so comparisons with it are false.
+                    None if self.num(left).is_some() && self.num(right).is_some() => {
+                        Value::bool(false)
+                    }
+                    None => return Err(Error::InvalidOperands(arith::symbol(op))),
+                }
+            }
+            (BinOp::BitAnd, Unpacked::Bool(a), Unpacked::Bool(b)) => Value::bool(a & b),
+            (BinOp::BitOr, Unpacked::Bool(a), Unpacked::Bool(b)) => Value::bool(a | b),
+            (BinOp::BitXor, Unpacked::Bool(a), Unpacked::Bool(b)) => Value::bool(a ^ b),
+            _ => match (op, self.str(left), self.str(right)) {
+                (BinOp::Add, Some(a), Some(b)) => {
+                    self.check_str_len(a.len() + b.len())?;
+                    let s = format!("{}{}", a, b);
+                    self.alloc_str(s)
+                }
+                _ => match (self.num(left), self.num(right)) {
+                    (Some(a), Some(b)) => {
+                        // Only checked before computing a bignum, as ints
+                        // that overflow otherwise fail or wrap.
+                        let big = matches!(a, Num::Big(_)) || matches!(b, Num::Big(_));
+                        if big || self.arith.overflow == Overflow::Promote {
+                            if let Some(bits) = arith::result_bits(op, &a, &b) {
+                                self.check_int_bits(bits)?;
+                            }
+                        }
+                        let num = self.arith.binary(op, &a, &b)?;
+                        if let Num::Big(big) = &num {
+                            self.check_int_bits(big.bits())?;
+                        }
+                        self.num_value(num)
+                    }
+                    _ => return Err(Error::InvalidOperands(arith::symbol(op))),
+                },
+            },
+        };
+        Ok(value)
+    }
+
+    /// Equality of `==`: numbers compare by value, strings by content, and
+    /// arrays, maps, sets, ranges and enum values structurally. Struct
+    /// instances are only equal to themselves unless their struct defines
+    /// `eq`, which enum values can also override.
+    pub fn values_eq(&mut self, left: Value, right: Value) -> Result<bool> {
+        self.equal(left, right, false, &mut HashSet::new())
+    }
+
+    /// Equality of map keys and set elements, which is that of `==` except
+    /// that NaN is equal to itself, so it can be found again.
+    fn keys_eq(&mut self, left: Value, right: Value) -> Result<bool> {
+        self.equal(left, right, true, &mut HashSet::new())
+    }
+
+    /// Compares `left` and `right`, assuming pairs of objects in `seen`,
+    /// whose comparison is under way, to be equal so cycles terminate.
+    fn equal(
+        &mut self,
+        left: Value,
+        right: Value,
+        nan_eq: bool,
+        seen: &mut HashSet<(ObjRef, ObjRef)>,
+    ) -> Result<bool> {
+        let (a, b) = match (left.unpack(), right.unpack()) {
+            (Unpacked::Float(a), Unpacked::Float(b)) if nan_eq && a.is_nan() && b.is_nan() => {
+                return Ok(true)
+            }
+            (Unpacked::Int(_) | Unpacked::Obj(_), Unpacked::Float(_))
+            | (Unpacked::Float(_), Unpacked::Int(_) | Unpacked::Obj(_)) => {
+                return Ok(self.compare(left, right) == Some(std::cmp::Ordering::Equal))
+            }
+            (Unpacked::Obj(a), Unpacked::Obj(b)) => (a, b),
+            _ => return Ok(left == right),
+        };
+        let eq = self.interner.intern_str("eq");
+        if let Some(method) = self.method(left, eq) {
+            let method = self.bind(left, method);
+            return Ok(self.call(method, &[right])?.is_truthy());
+        }
+        if a == b || seen.contains(&(a, b)) {
+            return Ok(true);
+        }
+        if seen.len() == MAX_VALUE_DEPTH {
+            return Err(Error::ValueTooDeep);
+        }
+
+        seen.insert((a, b));
+        let equal = match (self.heap.get(a), self.heap.get(b)) {
+            (Object::Str(a), Object::Str(b)) => a == b,
+            (Object::Range(a), Object::Range(b)) => a == b,
+            (Object::BigInt(a), Object::BigInt(b)) => a == b,
+            (Object::Array(x), Object::Array(y)) | (Object::Tuple(x), Object::Tuple(y))
+                if x.len() == y.len() =>
+            {
+                let pairs: Vec<_> = x.iter().copied().zip(y.iter().copied()).collect();
+                self.all_equal(pairs, nan_eq, seen)?
+            }
+            (Object::Variant(x), Object::Variant(y))
+                if x.def == y.def && x.variant == y.variant =>
+            {
+                let pairs: Vec<_> = x.fields.iter().copied().zip(y.fields.clone()).collect();
+                self.all_equal(pairs, nan_eq, seen)?
+            }
+            (Object::Map(x), Object::Map(y)) if x.entries.len() == y.entries.len() => {
+                let mut equal = true;
+                for (key, value) in x.entries.clone() {
+                    equal = match self.map_get(b, key)? {
+                        Some(other) => self.equal(value, other, nan_eq, seen)?,
+                        None => false,
+                    };
+                    if !equal {
+                        break;
+                    }
+                }
+                equal
+            }
+            (Object::Set(x), Object::Set(y)) if x.elems.len() == y.elems.len() => {
+                let mut equal = true;
+                for elem in x.elems.clone() {
+                    equal = self.find_key(b, elem)?.1.is_some();
+                    if !equal {
+                        break;
+                    }
+                }
+                equal
+            }
+            _ => false,
+        };
+        seen.remove(&(a, b));
+        Ok(equal)
+    }
+
+    fn all_equal(
+        &mut self,
+        pairs: Vec<(Value, Value)>,
+        nan_eq: bool,
+        seen: &mut HashSet<(ObjRef, ObjRef)>,
+    ) -> Result<bool> {
+        for (a, b) in pairs {
+            if !self.equal(a, b, nan_eq, seen)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Hash of a map key or set element, equal for keys equal by `keys_eq`.
+    ///
+    /// Tuples and enum values hash by content. Arrays, maps and sets cannot
+    /// be keys since changing one would make it impossible to find, and
+    /// neither can functions, iterators and generators. A struct or enum
+    /// defining `eq` must also define `hash`, returning an int.
+    fn hash_key(&mut self, key: Value) -> Result<u64> {
+        let mut state = DefaultHasher::new();
+        self.hash_value(key, &mut state, 0)?;
+        Ok(state.finish())
+    }
+
+    /// Hashes `value` into `state`. Keys cannot contain themselves, as only
+    /// immutable ones hash by content, so only `depth` needs checking.
+    fn hash_value(&mut self, value: Value, state: &mut DefaultHasher, depth: usize) -> Result<()> {
+        let obj = match value.unpack() {
+            Unpacked::Obj(obj) => obj,
+            _ => {
+                hash_scalar(value, state);
+                return Ok(());
+            }
+        };
+
+        let hash = self.interner.intern_str("hash");
+        if let Some(method) = self.method(value, hash) {
+            let method = self.bind(value, method);
+            let hash = self.call(method, &[])?;
+            match self.int(hash) {
+                Some(i) => (5u8, i).hash(state),
+                None => return Err(Error::TypeError("`hash` must return an int")),
+            }
+            return Ok(());
+        }
+        let eq = self.interner.intern_str("eq");
+        if self.method(value, eq).is_some() {
+            return Err(Error::UnhashableKey("value with `eq` but no `hash`"));
+        }
+        if depth == MAX_VALUE_DEPTH {
+            return Err(Error::ValueTooDeep);
+        }
+
+        match self.heap.get(obj) {
+            Object::Str(s) => (7u8, s).hash(state),
+            Object::Range(range) => {
+                (8u8, range.start, range.end, range.inclusive, range.step).hash(state)
+            }
+            Object::BigInt(big) => match big.to_i64() {
+                // Like a packed int of the same value.
+                Some(i) => (3u8, i).hash(state),
+                None => (4u8, big.to_f64().to_bits()).hash(state),
+            },
+            Object::Tuple(elems) => {
+                (9u8, elems.len()).hash(state);
+                for elem in elems.clone() {
+                    self.hash_value(elem, state, depth + 1)?;
+                }
+            }
+            Object::Variant(variant) => {
+                (10u8, variant.def, variant.variant).hash(state);
+                for field in variant.fields.clone() {
+                    self.hash_value(field, state, depth + 1)?;
+                }
+            }
+            Object::Array(_) => return Err(Error::UnhashableKey("array")),
+            Object::Map(_) => return Err(Error::UnhashableKey("map")),
+            Object::Set(_) => return Err(Error::UnhashableKey("set")),
+            Object::Instance(_)
+            | Object::Struct(_)
+            | Object::Enum(_)
+            | Object::Trait(_)
+            | Object::Module(_)
+            | Object::Upvalue(_) => (13u8, obj).hash(state),
+            Object::Closure(_) | Object::Native(_) | Object::BoundMethod(_) => {
+                return Err(Error::UnhashableKey("function"))
+            }
+            Object::VariantCtor(_) => return Err(Error::UnhashableKey("variant constructor")),
+            Object::Iter(_) | Object::Generator(_) => return Err(Error::UnhashableKey("iterator")),
+        }
+        Ok(())
+    }
+
+    /// Ordering of `<` without operator overloading: numbers by value, chars
+    /// and strings lexicographically. Returns `None` for other values and NaN.
+    pub fn compare(&self, left: Value, right: Value) -> Option<std::cmp::Ordering> {
+        match (left.unpack(), right.unpack()) {
+            (Unpacked::Char(a), Unpacked::Char(b)) => Some(a.cmp(&b)),
+            _ => match (self.num(left), self.num(right)) {
+                (Some(a), Some(b)) => arith::compare(&a, &b),
+                _ => Some(self.str(left)?.cmp(self.str(right)?)),
+            },
+        }
+    }
+
+    /// The number `value` holds, if it is one.
+    fn num(&self, value: Value) -> Option<Num> {
+        match value.unpack() {
+            Unpacked::Int(i) => Some(Num::Int(i)),
+            Unpacked::Float(f) => Some(Num::Float(f)),
+            Unpacked::Obj(obj) => match self.heap.get(obj) {
+                Object::BigInt(big) => Some(Num::from_big(big.clone())),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// The int `value` holds, if it is one that fits in 64 bits.
+    pub fn int(&self, value: Value) -> Option<i64> {
+        match value.unpack() {
+            Unpacked::Int(i) => Some(i),
+            Unpacked::Obj(obj) => match self.heap.get(obj) {
+                Object::BigInt(big) => big.to_i64(),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn num_value(&mut self, num: Num) -> Value {
+        match num {
+            Num::Int(i) => self.int_value(i),
+            Num::Big(big) => Value::obj(self.heap.alloc(Object::BigInt(big))),
+            Num::Float(f) => Value::float(f),
+        }
+    }
+
+    /// Formats `value` the way `print` shows it.
+    pub fn display(&self, value: Value) -> String {
+        let mut out = String::new();
+        self.write_value(&mut out, value, false, &mut Vec::new());
+        out
+    }
+
+    /// Writes `value` to `out`, quoting strings and chars if `quoted` is set.
+    /// `seen` holds the collections being written, to cut cycles short and to
+    /// stop at `MAX_VALUE_DEPTH` levels.
+    fn write_value(&self, out: &mut String, value: Value, quoted: bool, seen: &mut Vec<ObjRef>) {
+        let obj = match value.unpack() {
+            Unpacked::Null => return out.push_str("null"),
+            Unpacked::Bool(b) => return write!(out, "{}", b).unwrap(),
+            Unpacked::Int(i) => return write!(out, "{}", i).unwrap(),
+            Unpacked::Float(f) => return write!(out, "{:?}", f).unwrap(),
+            Unpacked::Char(c) if quoted => return write!(out, "{:?}", c).unwrap(),
+            Unpacked::Char(c) => return out.push(c),
+            Unpacked::Obj(obj) => obj,
+        };
+        if seen.len() == MAX_VALUE_DEPTH || seen.contains(&obj) {
+            return out.push_str("...");
+        }
+        seen.push(obj);
+
+        let name = |ident: IntStr| self.interner.lookup(ident).unwrap_or("?");
+        match self.heap.get(obj) {
+            Object::Str(s) if quoted => write!(out, "{:?}", s).unwrap(),
+            Object::Str(s) => out.push_str(s),
+            Object::Array(elems) => {
+                out.push('[');
+                self.write_list(out, elems, seen);
+                out.push(']');
+            }
+            Object::Tuple(elems) => {
+                out.push('(');
+                self.write_list(out, elems, seen);
+                out.push_str(if elems.len() == 1 { ",)" } else { ")" });
+            }
+            Object::Map(map) => {
+                out.push_str("map {");
+                for (idx, &(key, value)) in map.entries.iter().enumerate() {
+                    out.push_str(if idx == 0 { " " } else { ", " });
+                    self.write_value(out, key, true, seen);
+                    out.push_str(": ");
+                    self.write_value(out, value, true, seen);
+                }
+                out.push_str(if map.entries.is_empty() { "}" } else { " }" });
+            }
+            Object::Set(set) => {
+                out.push_str("set {");
+                for (idx, &elem) in set.elems.iter().enumerate() {
+                    out.push_str(if idx == 0 { " " } else { ", " });
+                    self.write_value(out, elem, true, seen);
+                }
+                out.push_str(if set.elems.is_empty() { "}" } else { " }" });
+            }
+            Object::Instance(_) if obj == self.done => out.push_str("done"),
+            Object::Instance(instance) => {
+                let ident = match self.heap.get(instance.def) {
+                    Object::Struct(def) => def.ident,
+                    _ => unreachable!(),
+                };
+                out.push_str(name(ident));
+                let fields: Vec<_> = instance
+                    .fields
+                    .iter()
+                    .map(|&(field, value)| (name(field), value))
+                    .collect();
+                self.write_fields(out, &fields, seen);
+            }
+            Object::Variant(variant) => {
+                let def = match self.heap.get(variant.def) {
+                    Object::Enum(def) => def,
+                    _ => unreachable!(),
+                };
+                let variant_def = &def.variants[variant.variant];
+                write!(out, "{}::{}", name(def.ident), name(variant_def.ident)).unwrap();
+                match variant_def.kind {
+                    VariantKind::Unit => (),
+                    VariantKind::Tuple => {
+                        out.push('(');
+                        self.write_list(out, &variant.fields, seen);
+                        out.push(')');
+                    }
+                    VariantKind::Struct => {
+                        let fields: Vec<_> = variant_def
+                            .fields
+                            .iter()
+                            .map(|&field| name(field))
+                            .zip(variant.fields.iter().copied())
+                            .collect();
+                        self.write_fields(out, &fields, seen);
+                    }
+                }
+            }
+            Object::Struct(def) => write!(out, "<struct {}>", name(def.ident)).unwrap(),
+            Object::Enum(def) => write!(out, "<enum {}>", name(def.ident)).unwrap(),
+            Object::Trait(def) => write!(out, "<trait {}>", name(def.ident)).unwrap(),
+            Object::VariantCtor(ctor) => match self.heap.get(ctor.def) {
+                Object::Enum(def) => write!(
+                    out,
+                    "<ctor {}::{}>",
+                    name(def.ident),
+                    name(def.variants[ctor.variant].ident)
+                )
+                .unwrap(),
+                _ => unreachable!(),
+            },
+            Object::Closure(closure) => match (closure.proto.kind, closure.proto.ident) {
+                (ProtoKind::Closure, _) | (_, None) => out.push_str("<closure>"),
+                (_, Some(ident)) => write!(out, "<fn {}>", name(ident)).unwrap(),
+            },
+            Object::Native(native) => write!(out, "<native {}>", name(native.ident)).unwrap(),
+            Object::BoundMethod(bound) => {
+                self.write_value(out, bound.method, quoted, seen);
+            }
+            Object::Module(module) => match module.ident {
+                Some(ident) => write!(out, "<mod {}>", name(ident)).unwrap(),
+                None => out.push_str("<mod>"),
+            },
+            Object::Upvalue(_) => out.push_str("<upvalue>"),
+            Object::Iter(_) => out.push_str("<iter>"),
+            Object::Range(range) => {
+                let bound = |bound: Option<i64>| bound.map(|i| i.to_string()).unwrap_or_default();
+                let op = if range.inclusive { "..=" } else { ".." };
+                write!(out, "{}{}{}", bound(range.start), op, bound(range.end)).unwrap();
+                if range.step != 1 {
+                    write!(out, " by {}", range.step).unwrap();
+                }
+            }
+            Object::Generator(_) => out.push_str("<generator>"),
+            Object::BigInt(big) => write!(out, "{}", big).unwrap(),
+        }
+        seen.pop();
+    }
+
+    fn write_list(&self, out: &mut String, values: &[Value], seen: &mut Vec<ObjRef>) {
+        for (idx, &value) in values.iter().enumerate() {
+            if idx > 0 {
+                out.push_str(", ");
+            }
+            self.write_value(out, value, true, seen);
+        }
+    }
+
+    fn write_fields(&self, out: &mut String, fields: &[(&str, Value)], seen: &mut Vec<ObjRef>) {
+        if fields.is_empty() {
+            return out.push_str(" {}");
+        }
+        out.push_str(" { ");
+        for (idx, &(field, value)) in fields.iter().enumerate() {
+            if idx > 0 {
+                out.push_str(", ");
+            }
+            write!(out, "{}: ", field).unwrap();
+            self.write_value(out, value, true, seen);
+        }
+        out.push_str(" }");
+    }
+}
+
+impl Default for Vm {
+    fn default() -> Vm {
+        Vm::new()
+    }
+}
+
+/// One-based line and column of the byte `offset` in `source`.
+fn line_col(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset.min(source.len())];
+    let line_start = before.rfind('\n').map_or(0, |idx| idx + 1);
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+/// An index into an array of `len` elements, given the int an index value
+/// holds, if it holds one.
+fn array_index(idx: Option<i64>, len: usize) -> Result<usize> {
+    match idx {
+        Some(i) => usize::try_from(i)
+            .ok()
+            .filter(|&idx| idx < len)
+            .ok_or(Error::IndexOutOfRange(i)),
+        _ => Err(Error::TypeError("index must be an int")),
+    }
+}
+
+/// Start and end index of the part of a sequence of `len` elements that
+/// `range` slices.
+fn slice_bounds(range: Range, len: usize) -> Result<(usize, usize)> {
+    if range.step != 1 {
+        return Err(Error::TypeError("cannot slice by a range with a step"));
+    }
+    let start = range.start.unwrap_or(0);
+    let end = match range.end {
+        Some(end) if range.inclusive => end.checked_add(1).ok_or(Error::IndexOutOfRange(end))?,
+        Some(end) => end,
+        None => len as i64,
+    };
+    let bound = |i: i64| {
+        usize::try_from(i)
+            .ok()
+            .filter(|&i| i <= len)
+            .ok_or(Error::IndexOutOfRange(i))
+    };
+    let (start_idx, end_idx) = (bound(start)?, bound(end)?);
+    if start_idx > end_idx {
+        return Err(Error::IndexOutOfRange(start));
+    }
+    Ok((start_idx, end_idx))
+}
+
+/// Hashes a value that is not an object. Integral floats hash like the ints
+/// or bignums equal to them, and all NaNs alike.
+fn hash_scalar(value: Value, state: &mut DefaultHasher) {
+    match value.unpack() {
+        Unpacked::Null => 0u8.hash(state),
+        Unpacked::Bool(b) => (1u8, b).hash(state),
+        Unpacked::Char(c) => (2u8, c).hash(state),
+        Unpacked::Int(i) => (3u8, i).hash(state),
+        Unpacked::Float(f) if f.fract() == 0.0 && f >= i64::MIN as f64 && f < i64::MAX as f64 => {
+            (3u8, f as i64).hash(state)
+        }
+        Unpacked::Float(f) if f.is_nan() => (4u8, f64::NAN.to_bits()).hash(state),
+        Unpacked::Float(f) => (4u8, f.to_bits()).hash(state),
+        Unpacked::Obj(_) => unreachable!(),
+    }
+}
+
+fn binop_method(op: BinOp) -> &'static str {
+    match op {
+        BinOp::Add => "add",
+        BinOp::Sub => "sub",
+        BinOp::Mul => "mul",
+        BinOp::Div => "div",
+        BinOp::Mod => "mod",
+        BinOp::Pow => "pow",
+        BinOp::BitAnd => "bitand",
+        BinOp::BitOr => "bitor",
+        BinOp::BitXor => "bitxor",
+        BinOp::Shl => "shl",
+        BinOp::Shr => "shr",
+        BinOp::Eq => "eq",
+        BinOp::NotEq => "ne",
+        BinOp::Less => "lt",
+        BinOp::Greater => "gt",
+        BinOp::LessEq => "le",
+        BinOp::GreaterEq => "ge",
+    }
+}
+
+fn builtin_ok(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    Ok(vm.result_variant(0, args))
+}
+
+fn builtin_err(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    Ok(vm.result_variant(1, args))
+}
+
+/// Iterator over the value, as a `for` loop would get it.
+fn builtin_iter(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let value = args.first().copied().unwrap_or(Value::NULL);
+    if vm.def_of(value).is_none() {
+        return vm.iter(value);
+    }
+    if vm.method(value, vm.next_ident).is_some() {
+        return Ok(value);
+    }
+    let method = vm
+        .method(value, vm.iter_ident)
+        .ok_or(Error::TypeError("value is not iterable"))?;
+    let method = vm.bind(value, method);
+    let iter = vm.call(method, &[])?;
+    if vm.def_of(iter).is_some() && vm.method(iter, vm.next_ident).is_none() {
+        return Err(Error::TypeError("value is not an iterator"));
+    }
+    builtin_iter(vm, &[iter])
+}
+
+/// `range(end)`, `range(start, end)` or `range(start, end, step)`.
+fn builtin_range(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let mut ints = Vec::with_capacity(args.len());
+    for &arg in args {
+        match vm.int(arg) {
+            Some(i) => ints.push(i),
+            None => return Err(Error::TypeError("range bounds must be ints")),
+        }
+    }
+    let (start, end, step) = match ints[..] {
+        [end] => (0, end, 1),
+        [start, end] => (start, end, 1),
+        [start, end, step] => (start, end, step),
+        _ => return Err(Error::TypeError("range takes 1 to 3 arguments")),
+    };
+    if step == 0 {
+        return Err(Error::TypeError("range step cannot be zero"));
+    }
+    let range = vm.heap.alloc(Object::Range(Range {
+        start: Some(start),
+        end: Some(end),
+        inclusive: false,
+        step,
+    }));
+    Ok(Value::obj(range))
+}
+
+fn builtin_print(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let line: Vec<_> = args.iter().map(|&arg| vm.display(arg)).collect();
+    println!("{}", line.join(" "));
+    Ok(Value::NULL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capability::Capabilities;
+    use crate::limits::DEFAULT_MAX_NESTING;
+    use crate::testing::eval;
+    use std::time::Duration;
+
+    fn eval_err(source: &str) -> (Error, Vm) {
+        let mut vm = Vm::new();
+        let err = vm.eval(source).unwrap_err();
+        (err, vm)
+    }
+
+    #[test]
+    fn test_arithmetic_and_control_flow() {
+        assert_eq!("7", eval("1 + 2 * 3;"));
+        assert_eq!("2.5", eval("5 / 2.0;"));
+        assert_eq!("\"ab\"", format!("{:?}", eval("\"a\" + \"b\";")));
+        assert_eq!("6", eval("let x = 0; for i in [1, 2, 3] { x += i; }; x;"));
+        assert_eq!("10", eval("let i = 0; while i < 10 { i += 1; }; i;"));
+        assert_eq!(
+            "3",
+            eval("let i = 0; let r = loop { i += 1; if i == 3 { break i; } }; r;")
+        );
+        assert_eq!(
+            "small",
+            eval("let x = 1; if x > 2 { \"big\" } else { \"small\" };")
+        );
+        assert!(matches!(eval_err("1 / 0;").0, Error::DivisionByZero));
+    }
+
+    #[test]
+    fn test_functions_and_closures() {
+        let source = "
+            fn counter() {
+                let n = 0;
+                || { n += 1; n }
+            }
+            let c = counter();
+            c();
+            c();
+            c();
+        ";
+        assert_eq!("3", eval(source));
+        assert_eq!(
+            "[1, 5, [3, 4]]",
+            eval("fn f(a, b = 5, ...rest) { [a, b, rest] } f(1, ...[]); [f(1)[0], f(1)[1], f(1, 2, 3, 4)[2]];")
+        );
+        assert_eq!("[2, 1]", eval("fn f(a, b) { [a, b] } f(b: 1, a: 2);"));
+        assert_eq!(
+            "[2, 4, 6]",
+            eval("let xs = [1, 2, 3]; let f = |x| x * 2; [f(xs[0]), f(xs[1]), f(xs[2])];")
+        );
+    }
+
+    #[test]
+    fn test_many_params() {
+        let params: Vec<_> = (0..68).map(|idx| format!("p{}", idx)).collect();
+        let args: Vec<_> = (0..70).map(|idx| idx.to_string()).collect();
+        let source = format!(
+            "fn f({}, p68 = -1, p69 = -2) {{ [p63, p67, p68, p69] }}
+            [f({}), f({}, p69: 5)];",
+            params.join(", "),
+            args.join(", "),
+            args[..68].join(", ")
+        );
+        assert_eq!("[[63, 67, 68, 69], [63, 67, -1, 5]]", eval(&source));
+        let (err, vm) = eval_err(&format!(
+            "fn f({}) {{}} f({});",
+            params.join(", "),
+            args[..67].join(", ")
+        ));
+        match err {
+            Error::MissingArg(ident, _) => assert_eq!(Some("p67"), vm.interner().lookup(ident)),
+            err => panic!("unexpected error {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_structs_and_enums() {
+        let source = "
+            struct Point {
+                fn len2(self) { self.x * self.x + self.y * self.y }
+            }
+            let p = new Point { x: 3, y: 4 };
+            p.len2();
+        ";
+        assert_eq!("25", eval(source));
+        let source = "
+            enum Shape {
+                Circle(r),
+                Rect { w, h },
+                fn area(self) {
+                    if let Shape::Circle(r) = self { 3 * r * r }
+                    else if let Shape::Rect { w, h } = self { w * h }
+                    else { 0 }
+                }
+            }
+            [Shape::Circle(2).area(), new Shape::Rect { w: 2, h: 5 }.area()];
+        ";
+        assert_eq!("[12, 10]", eval(source));
+        assert_eq!(
+            "Shape::Circle(1.5)",
+            eval("enum Shape { Circle(r), Square(s) } Shape::Circle(1.5);")
+        );
+        let (err, vm) = eval_err("enum Op { Add(left, right) } Op::Add(1);");
+        match err {
+            Error::MissingArg(ident, _) => assert_eq!(Some("right"), vm.interner().lookup(ident)),
+            err => panic!("unexpected error {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_trait_dispatch_and_defaults() {
+        let source = "
+            trait Shape {
+                fn area(self);
+                fn describe(self) { \"a \" + self.name() }
+                fn name(self) { \"shape\" }
+            }
+            struct Square {}
+            struct Circle {}
+            impl Shape for Square {
+                fn area(self) { self.side * self.side }
+            }
+            impl Shape for Circle {
+                fn area(self) { 3 * self.r * self.r }
+                fn name(self) { \"circle\" }
+            }
+            let shapes = [new Square { side: 2 }, new Circle { r: 1 }];
+            let total = 0;
+            for shape in shapes { total += shape.area(); };
+            [total, shapes[1].describe(), shapes[0].describe()];
+        ";
+        assert_eq!("[7, \"a circle\", \"a shape\"]", eval(source));
+    }
+
+    #[test]
+    fn test_trait_errors() {
+        let (err, vm) = eval_err(
+            "trait Shape { fn area(self); fn perimeter(self); }
+            struct Square {}
+            impl Shape for Square { fn area(self) { 1 } }",
+        );
+        match err {
+            Error::MissingTraitMethod {
+                trait_ident,
+                method,
+            } => {
+                assert_eq!(Some("Shape"), vm.interner().lookup(trait_ident));
+                assert_eq!(Some("perimeter"), vm.interner().lookup(method));
+            }
+            err => panic!("unexpected error {:?}", err),
+        }
+
+        let (err, _) = eval_err(
+            "trait Shape { fn area(self); }
+            struct Square {}
+            impl Shape for Square { fn area(self) { 1 } fn extra(self) { 2 } }",
+        );
+        assert!(matches!(err, Error::UndefinedMethod(_)));
+
+        let (err, _) = eval_err(
+            "trait Shape { fn area(self); }
+            struct Square { fn area(self) { 1 } }
+            impl Shape for Square { fn area(self) { 2 } }",
+        );
+        assert!(matches!(err, Error::MethodDefinedTwice(_)));
+
+        let (err, _) = eval_err("struct Square {} impl Square for Square {}");
+        assert!(matches!(err, Error::TypeError(_)));
+    }
+
+    #[test]
+    fn test_inherent_method_wins_over_default() {
+        let source = "
+            trait Named { fn name(self) { \"default\" } }
+            struct A { fn name(self) { \"own\" } }
+            impl Named for A {}
+            new A {}.name();
+        ";
+        assert_eq!("own", eval(source));
+    }
+
+    #[test]
+    fn test_modules_and_globals_persist() {
+        let mut vm = Vm::new();
+        vm.eval("mod geo { fn double(x) { x * 2 } }").unwrap();
+        let value = vm.eval("use geo::double; double(21);").unwrap();
+        assert_eq!("42", vm.display(value));
+        let value = vm.eval("geo.double(1);").unwrap();
+        assert_eq!("2", vm.display(value));
+    }
+
+    #[test]
+    fn test_operator_overloading() {
+        let source = "
+            struct Vec2 {
+                fn add(self, other) { new Vec2 { x: self.x + other.x, y: self.y + other.y } }
+                fn mul(self, k) { new Vec2 { x: self.x * k, y: self.y * k } }
+                fn neg(self) { new Vec2 { x: -self.x, y: -self.y } }
+                fn eq(self, other) { self.x == other.x && self.y == other.y }
+            }
+            let a = new Vec2 { x: 1, y: 2 };
+            let b = new Vec2 { x: 3, y: 4 };
+            [a + b * 2, -a, a == new Vec2 { x: 1, y: 2 }, a != b];
+        ";
+        assert_eq!(
+            "[Vec2 { x: 7, y: 10 }, Vec2 { x: -1, y: -2 }, true, true]",
+            eval(source)
+        );
+    }
+
+    #[test]
+    fn test_comparison_derived_from_lt() {
+        let source = "
+            struct Money {
+                fn lt(self, other) { self.cents < other.cents }
+            }
+            let a = new Money { cents: 100 };
+            let b = new Money { cents: 250 };
+            [a < b, a > b, a <= b, a >= b, b <= b, b >= b];
+        ";
+        assert_eq!("[true, false, true, false, true, true]", eval(source));
+    }
+
+    #[test]
+    fn test_index_overloading() {
+        let source = "
+            struct Grid {
+                fn index(self, i) { self.cells[i[0] * self.width + i[1]] }
+                fn index_set(self, i, v) { self.cells[i[0] * self.width + i[1]] = v; }
+            }
+            let g = new Grid { width: 2, cells: [0, 0, 0, 0] };
+            g[[1, 0]] = 5;
+            g[[1, 0]] += 2;
+            [g[[1, 0]], g.cells];
+        ";
+        assert_eq!("[7, [0, 0, 7, 0]]", eval(source));
+    }
+
+    #[test]
+    fn test_operators_without_overload() {
+        let source = "struct P {} let p = new P {}; [p == p, p == new P {}];";
+        assert_eq!("[true, false]", eval(source));
+        assert!(matches!(
+            eval_err("struct P {} new P {} + 1;").0,
+            Error::InvalidOperands("+")
+        ));
+    }
+
+    #[test]
+    fn test_qmark_unwraps_and_returns_early() {
+        let source = "
+            fn parse(x) { if x < 0 { err(\"negative\") } else { ok(x * 2) } }
+            fn sum(a, b) { ok(parse(a)? + parse(b)?) }
+            [sum(1, 2), sum(1, -2), sum(-1, 2)];
+        ";
+        assert_eq!(
+            "[Result::Ok(6), Result::Err(\"negative\"), Result::Err(\"negative\")]",
+            eval(source)
+        );
+
+        let source = "
+            let m = map { \"a\": map { \"b\": 1 } };
+            let get = |k| { let inner = m[k]?; inner[\"b\"]? + 1 };
+            [get(\"a\"), get(\"x\")];
+        ";
+        assert_eq!("[2, null]", eval(source));
+        assert_eq!("3", eval("fn f(x) { x? } f(3);"));
+    }
+
+    #[test]
+    fn test_result_patterns() {
+        let source = "
+            let r = err(5);
+            if let Result::Err(e) = r { e } else { 0 };
+        ";
+        assert_eq!("5", eval(source));
+    }
+
+    #[test]
+    fn test_qmark_outside_function() {
+        assert!(matches!(
+            eval_err("let x = ok(1)?;").0,
+            Error::QMarkOutsideFunction
+        ));
+        assert!(matches!(
+            eval_err("mod m { let x = ok(1)?; }").0,
+            Error::QMarkOutsideFunction
+        ));
+    }
+
+    #[test]
+    fn test_throw_and_catch() {
+        let source = "
+            fn check(x) { if x < 0 { throw \"negative\"; }; x }
+            let a = try { check(1) } catch e { 0 };
+            let b = try { check(-1) } catch e { e };
+            [a, b];
+        ";
+        assert_eq!("[1, \"negative\"]", eval(source));
+    }
+
+    #[test]
+    fn test_runtime_errors_are_catchable() {
+        let source = "fn div(a, b) {
+    a / b
+}
+let e = try { div(1, 0) } catch e { e };
+[e.message, e.trace];";
+        assert_eq!(
+            "[\"division by zero\", [\"at div (2:5)\", \"at <script> (4:15)\"]]",
+            eval(source)
+        );
+        let source = "try { [1, 2][5] } catch e { e.message };";
+        assert_eq!("index 5 out of range", eval(source));
+        let source = "struct P {} try { new P {}.missing() } catch e { e.message };";
+        assert_eq!("undefined method `missing`", eval(source));
+        let source = "let f = |x| x; try { f(1, 2) } catch e { e.message };";
+        assert_eq!("expected at most 1 argument, found 2", eval(source));
+    }
+
+    #[test]
+    fn test_finally() {
+        let source = "
+            let log = [];
+            let i = 0;
+            fn step(log, i, fail) {
+                try {
+                    if fail { throw i; };
+                    i
+                } catch e {
+                    -e
+                } finally {
+                    log[i] = \"done\";
+                }
+            }
+            let log = [0, 0];
+            [step(log, 0, false), step(log, 1, true), log];
+        ";
+        assert_eq!("[0, -1, [\"done\", \"done\"]]", eval(source));
+
+        let source = "
+            let log = [0, 0, 0];
+            fn early(log) {
+                try { return 1; } finally { log[0] = 1; }
+            }
+            let r = early(log);
+            loop { try { break; } finally { log[1] = 2; } };
+            let caught = try {
+                try { throw \"inner\"; } finally { log[2] = 3; }
+            } catch e { e };
+            [r, caught, log];
+        ";
+        assert_eq!("[1, \"inner\", [1, 2, 3]]", eval(source));
+    }
+
+    #[test]
+    fn test_rethrow_from_catch() {
+        let source = "
+            let log = [0];
+            try {
+                try { throw 1; } catch e { throw e + 1; } finally { log[0] = 1; }
+            } catch e { [e, log] };
+        ";
+        assert_eq!("[2, [1]]", eval(source));
+    }
+
+    #[test]
+    fn test_uncaught_errors() {
+        let (err, vm) = eval_err("fn f() {\n    throw \"boom\";\n}\nf();");
+        match err {
+            Error::Thrown(value) => assert_eq!("boom", vm.display(value)),
+            err => panic!("unexpected error {:?}", err),
+        }
+        let trace: Vec<_> = vm.trace().iter().map(|entry| entry.to_string()).collect();
+        assert_eq!(vec!["at f (2:11)", "at <script> (4:1)"], trace);
+
+        let (err, vm) = eval_err("let x = 1;\nx / 0;");
+        assert!(matches!(err, Error::DivisionByZero));
+        assert_eq!(2, vm.trace()[0].line);
+    }
+
+    #[test]
+    fn test_vm_usable_after_error() {
+        let mut vm = Vm::new();
+        assert!(vm
+            .eval("fn f() { try { throw 1; } finally { } } f();")
+            .is_err());
+        let value = vm.eval("try { 1 } catch e { 2 };").unwrap();
+        assert_eq!("1", vm.display(value));
+        assert!(vm.eval("try { 1 };").is_err());
+    }
+
+    #[test]
+    fn test_generators() {
+        assert_eq!(
+            "[0, 1, 2, done, done]",
+            eval(
+                "fn count(n) { let i = 0; while i < n { yield i; i = i + 1; } }
+                let g = count(3);
+                [g.next(), g.next(), g.next(), g.next(), g.next()];"
+            )
+        );
+        assert_eq!(
+            "33",
+            eval(
+                "fn fib() { let a = 0; let b = 1; loop { yield a; let t = a; a = b; b = t + b; } }
+                let total = 0;
+                for x in fib() { if x > 20 { break; }; total = total + x; };
+                total;"
+            )
+        );
+        assert_eq!(
+            "[2, 4, <generator>]",
+            eval(
+                "struct Bag {
+                    fn doubled(self) { for x in self.xs { yield x * 2; } }
+                }
+                let g = new Bag { xs: [1, 2] }.doubled();
+                [g.next(), g.next(), g];"
+            )
+        );
+    }
+
+    #[test]
+    fn test_generator_state_survives_suspension() {
+        assert_eq!(
+            "20",
+            eval(
+                "fn gen() { let n = 0; let bump = || { n = n + 10; }; yield bump; yield n; }
+                let g = gen();
+                let bump = g.next();
+                bump();
+                bump();
+                g.next();"
+            )
+        );
+        assert_eq!(
+            "[1, \"boom\", done]",
+            eval(
+                "fn gen() { try { yield 1; throw \"boom\"; } catch e { yield e; } }
+                let g = gen();
+                [g.next(), g.next(), g.next()];"
+            )
+        );
+    }
+
+    #[test]
+    fn test_deep_generator_pipeline() {
+        assert_eq!(
+            "15003",
+            eval(
+                "fn range(n) { let i = 0; while i < n { yield i; i = i + 1; } }
+                fn inc(g) { for x in g { yield x + 1; } }
+                let g = range(3);
+                let i = 0;
+                while i < 5000 { g = inc(g); i = i + 1; };
+                let total = 0;
+                for x in g { total = total + x; };
+                total;"
+            )
+        );
+    }
+
+    #[test]
+    fn test_generator_errors() {
+        assert!(matches!(
+            eval_err("yield 1;").0,
+            Error::YieldOutsideFunction
+        ));
+        assert!(matches!(
+            eval_err("fn gen() { yield g.next(); } let g = gen(); g.next();").0,
+            Error::TypeError("generator is already running")
+        ));
+        assert_eq!(
+            "[\"x\", done]",
+            eval(
+                "fn gen() { yield 1; throw \"x\"; }
+                let g = gen();
+                g.next();
+                let r = try { g.next() } catch e { e };
+                [r, g.next()];"
+            )
+        );
+    }
+
+    #[test]
+    fn test_builtin_iterators() {
+        assert_eq!(
+            "[6, [\"b\", 3], [3, 'c']]",
+            eval(
+                "let sum = 0;
+                for x in [1, 2, 3] { sum = sum + x; };
+                let entries = [null, 0];
+                for pair in map { \"a\": 1, \"b\": 2 } { entries = [pair[0], entries[1] + pair[1]]; };
+                let chars = [0, null];
+                for c in \"abc\" { chars = [chars[0] + 1, c]; };
+                [sum, entries, chars];"
+            )
+        );
+        assert_eq!(
+            "[10, -3, 0..5, 0..6 by 2]",
+            eval(
+                "let up = 0;
+                for i in range(5) { up = up + i; };
+                let down = 0;
+                for i in range(3, -3, -2) { down = down - i; };
+                [up, down, range(0, 5), range(0, 6, 2)];"
+            )
+        );
+        assert_eq!(
+            "['h', 'é', done]",
+            eval("let it = iter(\"hé\"); [it.next(), it.next(), it.next()];")
+        );
+    }
+
+    #[test]
+    fn test_iteration_protocol() {
+        let countdown = "
+            struct Countdown {
+                fn next(self) {
+                    if self.n == 0 { return done; };
+                    self.n = self.n - 1;
+                    self.n + 1
+                }
+            }";
+        assert_eq!(
+            "[3, 2, 1]",
+            eval(&format!(
+                "{} let out = []; for x in new Countdown {{ n: 3 }} {{ out = [out, x]; }};
+                [out[0][0][1], out[0][1], out[1]];",
+                countdown
+            ))
+        );
+        assert_eq!(
+            "[6, 6, 3]",
+            eval(&format!(
+                "{}
+                struct Launch {{ fn iter(self) {{ new Countdown {{ n: self.from }} }} }}
+                struct Bag {{ fn iter(self) {{ self.items }} }}
+                struct Evens {{ fn iter(self) {{ for x in range(self.n) {{ yield x * 2; }} }} }}
+                let a = 0;
+                for x in new Launch {{ from: 3 }} {{ a = a + x; }};
+                let b = 0;
+                for x in new Evens {{ n: 3 }} {{ b = b + x; }};
+                let c = 0;
+                for x in new Bag {{ items: [1, 2] }} {{ c = c + x; }};
+                [a, b, c];",
+                countdown
+            ))
+        );
+        assert!(matches!(
+            eval_err("struct S {} for x in new S {} {};").0,
+            Error::TypeError("value is not iterable")
+        ));
+        assert!(matches!(
+            eval_err("struct S { fn iter(self) { self } } for x in new S {} {};").0,
+            Error::TypeError("value is not an iterator")
+        ));
+    }
+
+    #[test]
+    fn test_ranges() {
+        assert_eq!(
+            "[10, 7, 0..3, ..=2, 5.., true]",
+            eval(
+                "let sum = 0;
+                for i in 1..=4 { sum = sum + i; };
+                let last = 0;
+                for i in 5.. { if i == 8 { break; }; last = i; };
+                [sum, last, 0..3, ..=2, 5.., 0..3 == 0..3];"
+            )
+        );
+        assert_eq!(
+            "[2..6, true, 1.5]",
+            eval("let n = 3; [1 + 1..n * 2, 0..n == 0..3, 1.5];")
+        );
+        assert!(matches!(
+            eval_err("for i in ..3 {};").0,
+            Error::TypeError("range without a start is not iterable")
+        ));
+    }
+
+    #[test]
+    fn test_slicing() {
+        assert_eq!(
+            "[[2, 3], [1, 2], [3, 4], [2, 3], \"él\", \"abc\", []]",
+            eval(
+                "let xs = [1, 2, 3, 4];
+                [xs[1..3], xs[..2], xs[2..], xs[1..=2], \"héllo\"[1..3], \"abc\"[..], xs[4..]];"
+            )
+        );
+        assert!(matches!(
+            eval_err("[1, 2][1..3];").0,
+            Error::IndexOutOfRange(3)
+        ));
+        assert!(matches!(
+            eval_err("[1, 2][2..1];").0,
+            Error::IndexOutOfRange(2)
+        ));
+    }
+
+    #[test]
+    fn test_null_safe_navigation() {
+        assert_eq!(
+            "[\"x\", null, null, \"x\", null, null]",
+            eval(
+                "let cfg = map { \"db\": map { \"host\": \"x\" } };
+                let f = null;
+                [cfg?.db?.host, cfg.cache?.host, cfg.cache?.host.port[0](), cfg[\"db\"]?.[\"host\"],
+                    f?.(1), cfg.cache?.size()];"
+            )
+        );
+        assert_eq!("1", eval("fn f(r) { (r?).x } f(ok(map { \"x\": 1 }));"));
+        assert!(matches!(
+            eval_err("let m = map {}; m?.a.b;").0,
+            Error::UndefinedField(_)
+        ));
+    }
+
+    #[test]
+    fn test_null_coalescing() {
+        assert_eq!(
+            "[\"default\", false, 1, 3, 3, 5]",
+            eval(
+                "let cfg = map {};
+                [cfg.name ?? \"default\", false ?? 1, 1 ?? [][5], null ?? null ?? 3,
+                    null ?? 1 + 2, false || null ?? 5];"
+            )
+        );
+    }
+
+    #[test]
+    fn test_pipeline() {
+        assert_eq!(
+            "[8, 7, 14, 5, null]",
+            eval(
+                "fn double(x) { x * 2 }
+                fn add(x, y) { x + y }
+                struct Calc { fn scale(self, x, k) { x * k } }
+                let calc = new Calc {};
+                let inc = |x| x + 1;
+                let m = null;
+                [3 |> inc |> double, 3 |> add(4), 3 + 4 |> calc.scale(2),
+                    null ?? 4 |> inc, 1 |> m?.f()];"
+            )
+        );
+        assert_eq!(
+            "[\"gx2\", 3]",
+            eval(
+                "let order = \"\";
+                fn trace(name, v) { order = order + name; v }
+                fn add(x, y) { x + y }
+                let sum = trace(\"x\", 1) |> trace(\"g\", add)(trace(\"2\", 2));
+                [order, sum];"
+            )
+        );
+        assert!(matches!(
+            eval_err("fn add(x, y) { x + y } 1 |> add(2, 3);").0,
+            Error::TooManyArgs { found: 3, .. }
+        ));
+    }
+
+    #[test]
+    fn test_power_and_numeric_semantics() {
+        assert_eq!(
+            "[1024, -4, 4, 512, 0.5, 9.0, 100]",
+            eval(
+                "let x = 10; x **= 2;
+                [2 ** 10, -2 ** 2, (-2) ** 2, 2 ** 3 ** 2, 2 ** -1, 2 * 3 ** 2 / 4 ** 0.5, x];"
+            )
+        );
+        assert_eq!(
+            "[-3, -1, -3.5, true, false, false, true]",
+            eval(
+                "let nan = 0.0 / 0.0;
+                [-7 / 2, -7 % 2, -7 / 2.0, 9007199254740993 > 9007199254740992.0,
+                    nan < 1, nan == nan, 1 == 1.0];"
+            )
+        );
+        assert!(matches!(
+            eval_err("3 ** 9223372036854775807;").0,
+            Error::IntegerOverflow
+        ));
+        assert!(matches!(eval_err("1 << -1;").0, Error::NegativeShift(-1)));
+        assert!(matches!(
+            eval_err("let x = (1 << 600000) - 1; x * x;").0,
+            Error::IntegerOverflow
+        ));
+        assert!(matches!(
+            eval_err("true ** 2;").0,
+            Error::InvalidOperands("**")
+        ));
+        assert_eq!(
+            "8",
+            eval("struct N { fn pow(self, e) { self.n ** e } } new N { n: 2 } ** 3;")
+        );
+
+        let mut vm = Vm::new();
+        vm.set_arith(Arith {
+            overflow: arith::Overflow::Wrapping,
+            division: arith::Division::Floor,
+        });
+        let value = vm
+            .eval("[9223372036854775807 + 1, -7 / 2, -7 % 2, 2 ** 64];")
+            .unwrap();
+        assert_eq!("[-9223372036854775808, -4, 1, 0]", vm.display(value));
+    }
+
+    #[test]
+    fn test_bignums() {
+        assert_eq!(
+            "[100000000000000000000, -9223372036854775808, 9223372036854775808, 18446744073709551616]",
+            eval(
+                "[100000000000000000000, -9223372036854775808, 9223372036854775807 + 1,
+                    1 << 64];"
+            )
+        );
+        assert_eq!(
+            "[265252859812191058636308480000000, 7, 26525285981219105863630848]",
+            eval(
+                "fn fact(n) { if n < 2 { return 1; }; n * fact(n - 1) }
+                let f = fact(30);
+                let n = 0;
+                while f % 10 == 0 { f = f / 10; n += 1; };
+                [fact(30), n, f];"
+            )
+        );
+        assert_eq!(
+            "[9223372036854775807, 1, -18446744073709551617, 3, 6, 4, 1, 2]",
+            eval(
+                "let big = 18446744073709551616;
+                [big - 9223372036854775809, big % 5, -big - 1, big / 6148914691236517205,
+                    big & 7 | 6, (big | 4) ^ big, big >> 64, big ** 2 / big / 9223372036854775808];"
+            )
+        );
+        assert_eq!(
+            "[true, true, false, true, true, true, 1.8446744073709552e19]",
+            eval(
+                "let big = 18446744073709551616;
+                [big == 18446744073709551616, big > 9223372036854775807, big < -big,
+                    big == 18446744073709551616.0, big < 18446744073709551617,
+                    -big <= 0.5, big + 0.5];"
+            )
+        );
+    }
+
+    /// Ints around the edges of packed ints and of `i64`, and pseudo-random
+    /// ones of every size.
+    fn int_samples() -> Vec<i64> {
+        let mut out = vec![0, 1, -1, 3037000499, i64::MIN, i64::MAX];
+        for edge in [Value::MIN_INT, Value::MAX_INT] {
+            out.extend([edge - 1, edge, edge + 1, edge / 2]);
+        }
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        for i in 0..24 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            out.push((state as i64) >> (i * 5 % 63));
+        }
+        out
+    }
+
+    #[test]
+    fn test_packed_ints_match_reference() {
+        let mut vm = Vm::new();
+        let samples = int_samples();
+        for &a in &samples {
+            for &b in &samples {
+                let (x, y) = (a as i128, b as i128);
+                let mut expected = format!(
+                    "[{}, {}, {}, {}, {}, {}, {}",
+                    x + y,
+                    x - y,
+                    x * y,
+                    a < b,
+                    a == b,
+                    a >= b,
+                    x == y + 1
+                );
+                let mut source = format!(
+                    "let a = {}; let b = {};
+                    [a + b, a - b, a * b, a < b, a == b, a >= b, a == b + 1",
+                    a, b
+                );
+                if b != 0 {
+                    expected += &format!(", {}, {}", x / y, x % y);
+                    source += ", a / b, a % b";
+                }
+                let value = vm.eval(&(source + "];")).unwrap();
+                assert_eq!(expected + "]", vm.display(value), "{} and {}", a, b);
+            }
+        }
+        // Ints that do not fit a packed int hash like the floats equal to them.
+        assert_eq!(
+            "[1, 1, 2, 2]",
+            eval(
+                "let m = map {};
+                m[2 ** 50] = 1;
+                m[9223372036854775807.0 * 2] = 2;
+                [m[2.0 ** 50], m[2 ** 49 * 2], m[2 ** 64], m[9223372036854775807 * 2 + 2]];"
+            )
+        );
+    }
+
+    #[test]
+    fn test_packed_floats_match_reference() {
+        let mut vm = Vm::new();
+        let mut samples = vec![0.0, -0.0, 1.0, f64::INFINITY, f64::NAN];
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        for _ in 0..16 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            // Multiples of 1/64 print exactly, so they can be written as literals.
+            samples.push((state as i32 % 1_000_000) as f64 / 64.0);
+        }
+        let literal = |f: f64| match f {
+            f if f.is_nan() => "(0.0 / 0.0)".to_owned(),
+            f if f.is_infinite() => "(1.0 / 0.0)".to_owned(),
+            f => format!("({:?})", f),
+        };
+        for &a in &samples {
+            for &b in &samples {
+                let expected = format!(
+                    "[{:?}, {:?}, {:?}, {:?}, {}, {}, {}, {}]",
+                    a + b,
+                    a - b,
+                    a * b,
+                    a / b,
+                    a < b,
+                    a == b,
+                    a != b,
+                    a >= b
+                );
+                let source = format!(
+                    "let a = {}; let b = {}; [a + b, a - b, a * b, a / b, a < b, a == b, a != b, a >= b];",
+                    literal(a),
+                    literal(b)
+                );
+                let value = vm.eval(&source).unwrap();
+                assert_eq!(expected, vm.display(value), "{} and {}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_structural_equality_and_keys() {
+        assert_eq!(
+            "[true, true, false, true, true, false, true]",
+            eval(
+                "[[1, [2.0]] == [1.0, [2]], map { 1: 2, 3: 4 } == map { 3: 4, 1: 2 },
+                    map { 1: 2 } == map { 1: 3 }, set { 1, 2 } == set { 2.0, 1 },
+                    1..3 == 1..3, [1] == [1, 2], \"ab\" == \"a\" + \"b\"];"
+            )
+        );
+        assert_eq!(
+            "[\"one\", \"pair\", 2, [false, false], 1, [[2, 3], 3]]",
+            eval(
+                "let nan = 0.0 / 0.0;
+                let m = map { 1.0: \"one\", (1, (2,)): \"pair\" };
+                m[nan] = 1;
+                m[nan] = 2;
+                let n = map { 1: 1, 2: 2, 3: 3 };
+                n.remove(1);
+                [m[1], m[(1.0, (2,))], m[nan], [nan == nan, m.contains_key(0.5)],
+                    set { nan, nan }.len(), [n.keys(), n[3]]];"
+            )
+        );
+        assert!(matches!(
+            eval_err("map { || 1: 2 };").0,
+            Error::UnhashableKey("function")
+        ));
+        assert!(matches!(
+            eval_err("set { 1 }.contains((1, || 1));").0,
+            Error::UnhashableKey("function")
+        ));
+        for (source, what) in [
+            ("let k = [1]; let m = map {}; m[k] = 1;", "array"),
+            ("set { (1, map {}) };", "map"),
+            ("map { set {}: 1 };", "set"),
+        ] {
+            assert!(matches!(
+                eval_err(source).0,
+                Error::UnhashableKey(found) if found == what
+            ));
+        }
+    }
+
+    #[test]
+    fn test_deep_values() {
+        let source = "let a = []; let b = [];
+            for i in range(0, 10000) { a = [a]; b = [b]; };
+            let t = (0,); for i in range(0, 10000) { t = (t,); };";
+        for check in ["a == b;", "map { t: 1 };"] {
+            let mut vm = Vm::new();
+            vm.eval(source).unwrap();
+            assert!(matches!(vm.eval(check), Err(Error::ValueTooDeep)));
+        }
+        // Printing stops at the same depth.
+        assert_eq!(
+            format!("{}...{}", "[".repeat(512), "]".repeat(512)),
+            eval("let a = []; for i in range(0, 10000) { a = [a]; }; a;")
+        );
+        // Cycles still compare, and the error can be caught.
+        assert_eq!(
+            "[true, \"value nested too deeply\"]",
+            eval(
+                "let a = [1]; a.push(a); let b = [1]; b.push(b);
+                let c = []; let d = [];
+                for i in range(0, 1000) { c = [c]; d = [d]; };
+                [a == b, try { c == d } catch err { err.message }];"
+            )
+        );
+    }
+
+    #[test]
+    fn test_struct_keys() {
+        let source = "
+            struct Id {}
+            struct Point {
+                fn eq(self, other) { self.x == other.x && self.y == other.y }
+                fn hash(self) { self.x * 31 + self.y }
+            }
+            let a = new Id {};
+            let m = map { a: 1, new Id {}: 2 };
+            let points = set { new Point { x: 1, y: 2 }, new Point { x: 1, y: 2 } };
+            [m.len(), m[a], points.len(), points.contains(new Point { x: 1, y: 2 }),
+                [new Point { x: 0, y: 0 }] == [new Point { x: 0, y: 0 }]];
+        ";
+        assert_eq!("[2, 1, 1, true, true]", eval(source));
+        assert!(matches!(
+            eval_err(
+                "struct Bad { fn eq(self, other) { true } }
+                map { new Bad {}: 1 };"
+            )
+            .0,
+            Error::UnhashableKey(_)
+        ));
+    }
+
+    #[test]
+    fn test_tuples() {
+        assert_eq!(
+            "[(1, \"a\"), (2,), 2, 3, 3, true, false, 'b', 3]",
+            eval(
+                "fn pair() { (1, \"a\") }
+                let t = ((1, (2, 3)), 'b',);
+                let sum = 0;
+                for x in (1, 2) { sum += x; };
+                [pair(), (1 + 1,), (2), t.0.1.1, sum, (1, 2) == (1.0, 2), (1, 2) == [1, 2], t.1,
+                    (|| 3,).0()];"
+            )
+        );
+        assert_eq!(
+            "[\"origin\", 2, 1]",
+            eval(
+                "let m = map { (0, 0): \"origin\" };
+                m[(1, 2)] = 1;
+                m[(1.0, 2)] = 2;
+                [m[(0, 0)], m[(1, 2)], set { (1, 2), (1, 2) }.len()];"
+            )
+        );
+        assert!(matches!(eval_err("(1, 2).2;").0, Error::IndexOutOfRange(2)));
+        assert!(matches!(
+            eval_err("(1, 2).5();").0,
+            Error::IndexOutOfRange(5)
+        ));
+        assert!(matches!(
+            eval_err("(1, 2).99999999999999999999;").0,
+            Error::IndexOutOfRange(i64::MAX)
+        ));
+        assert!(matches!(eval_err("(1, 2).x;").0, Error::UndefinedField(_)));
+        assert!(matches!(eval_err("(1, 2).01;").0, Error::InvalidTupleIndex));
+        assert!(matches!(
+            eval_err("(1, 2).1.00;").0,
+            Error::InvalidTupleIndex
+        ));
+    }
+
+    fn eval_limited(source: &str, limits: Limits) -> Result<Value> {
+        let mut vm = Vm::new();
+        vm.set_limits(limits);
+        vm.eval(source)
+    }
+
+    #[test]
+    fn test_nesting_limit() {
+        // The default limit is meant for a main thread's stack, which is
+        // larger than a test thread's.
+        let test = || {
+            let nested = |depth: usize, open: &str, inner: &str, close: &str| {
+                format!("{}{}{};", open.repeat(depth), inner, close.repeat(depth))
+            };
+            let max = DEFAULT_MAX_NESTING;
+            assert_eq!("1", eval(&nested(max - 1, "(", "1", ")")));
+            assert_eq!("1", eval(&nested(max - 1, "(1 + ", "0", ") * 0 + 1")));
+            // Chains of operators and of `else if` do not nest.
+            assert_eq!("100000", eval(&nested(100_000, "1 + ", "0", "")));
+            assert_eq!("1", eval(&nested(100_000, "", "1", " ?? 1")));
+            assert_eq!(
+                "1",
+                eval(&format!(
+                    "fn f(x) {{ x }} {}",
+                    nested(100_000, "", "1", " |> f")
+                ))
+            );
+            assert_eq!(
+                "1",
+                eval(&format!(
+                    "{}{{ 1 }};",
+                    "if false { 0 } else ".repeat(100_000)
+                ))
+            );
+            assert_eq!(
+                "1",
+                eval(&format!("let x = {} x;", nested(20, "{", "1", "}")))
+            );
+            for source in [
+                nested(max, "(", "1", ")"),
+                nested(max, "(1 + ", "0", ")"),
+                nested(100_000, "[", "1", "]"),
+                nested(100_000, "!", "true", ""),
+                nested(100_000, "2 ** ", "1", ""),
+                nested(100_000, "|| ", "1", ""),
+                nested(100_000, "f(", "1", ")"),
+                nested(100_000, "if true { ", "1", "}"),
+                nested(100_000, "fn f() { ", "1", "}"),
+                nested(100_000, "mod m { ", "", "}"),
+            ] {
+                assert!(matches!(eval_err(&source).0, Error::NestingTooDeep));
+            }
+        };
+        std::thread::Builder::new()
+            .stack_size(8 << 20)
+            .spawn(test)
+            .unwrap()
+            .join()
+            .unwrap();
+
+        let limits = Limits {
+            max_nesting: 5,
+            ..Limits::default()
+        };
+        assert!(eval_limited("((1));", limits).is_ok());
+        assert!(matches!(
+            eval_limited("((((((1))))));", limits),
+            Err(Error::NestingTooDeep)
+        ));
+    }
+
+    #[test]
+    fn test_step_and_time_limits() {
+        let limits = Limits {
+            max_steps: Some(10_000),
+            ..Limits::default()
+        };
+        assert!(eval_limited("let i = 0; while i < 100 { i += 1; }; i;", limits).is_ok());
+        // Fatal errors are not caught by `try`.
+        assert!(matches!(
+            eval_limited("try { loop {} } catch e { 1 };", limits),
+            Err(Error::StepLimitExceeded)
+        ));
+
+        // Steps are counted anew by each call from the host.
+        let mut vm = Vm::new();
+        vm.set_limits(limits);
+        let f = vm
+            .eval("fn f() { let i = 0; while i < 500 { i += 1; }; i } f;")
+            .unwrap();
+        for _ in 0..10 {
+            assert!(vm.call(f, &[]).is_ok());
+        }
+
+        let limits = Limits {
+            timeout: Some(Duration::from_millis(20)),
+            ..Limits::default()
+        };
+        assert!(matches!(
+            eval_limited("loop {};", limits),
+            Err(Error::DeadlineExceeded)
+        ));
+    }
+
+    #[test]
+    fn test_call_depth_limit() {
+        let limits = Limits {
+            max_call_depth: Some(50),
+            ..Limits::default()
+        };
+        let source = "fn down(n) { if n == 0 { 0 } else { down(n - 1) } }";
+        assert!(eval_limited(&format!("{} down(40);", source), limits).is_ok());
+        assert!(matches!(
+            eval_limited(&format!("{} down(60);", source), limits),
+            Err(Error::CallDepthExceeded)
+        ));
+        // Going too deep can be caught, and the stack is usable afterwards.
+        let mut vm = Vm::new();
+        vm.set_limits(limits);
+        let value = vm
+            .eval(&format!(
+                "{} let r = try {{ down(60) }} catch e {{ e.message }}; [r, down(10)];",
+                source
+            ))
+            .unwrap();
+        assert_eq!("[\"call depth limit exceeded\", 0]", vm.display(value));
+
+        // Runaway recursion fails by default, as does recursion through
+        // natives, which would otherwise overflow the Rust stack.
+        let test = || {
+            let mut vm = Vm::new();
+            let value = vm
+                .eval(
+                    "fn up(n) { up(n + 1) }
+                    fn through(n) { [n].map(|n| through(n + 1)) }
+                    [try { up(0) } catch e { e.message }, try { through(0) } catch e { e.message }];",
+                )
+                .unwrap();
+            assert_eq!(
+                "[\"call depth limit exceeded\", \"call depth limit exceeded\"]",
+                vm.display(value)
+            );
+        };
+        std::thread::Builder::new()
+            .stack_size(8 << 20)
+            .spawn(test)
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_heap_and_length_limits() {
+        let limits = Limits {
+            max_heap_bytes: Some(64 * 1024),
+            ..Limits::default()
+        };
+        assert!(eval_limited(
+            "let a = []; for i in range(0, 100) { a.push(i); }; a;",
+            limits
+        )
+        .is_ok());
+        assert!(matches!(
+            eval_limited(
+                "let a = []; try { loop { a.push([]); } } catch e { 1 };",
+                limits
+            ),
+            Err(Error::HeapLimitExceeded)
+        ));
+
+        let limits = Limits {
+            max_str_len: Some(100),
+            max_array_len: Some(10),
+            ..Limits::default()
+        };
+        let mut vm = Vm::new();
+        vm.set_limits(limits);
+        for (source, message) in [
+            (
+                "\"ab\".repeat(51);",
+                "string of length 102 exceeds the limit of 100",
+            ),
+            (
+                "\"a\".repeat(60) + \"a\".repeat(41);",
+                "string of length 101 exceeds the limit of 100",
+            ),
+            (
+                "\"a\".pad_start(101);",
+                "string of length 101 exceeds the limit of 100",
+            ),
+            (
+                "\"\u{149}\".repeat(50).to_upper();",
+                "string of length 150 exceeds the limit of 100",
+            ),
+            (
+                "\"\u{130}\".repeat(50).to_lower();",
+                "string of length 150 exceeds the limit of 100",
+            ),
+            (
+                "let a = []; loop { a.push(1); };",
+                "collection of length 11 exceeds the limit of 10",
+            ),
+            (
+                "let m = map {}; for i in range(0, 20) { m[i] = i; };",
+                "collection of length 11 exceeds the limit of 10",
+            ),
+            (
+                "let s = set {}; for i in range(0, 20) { s.add(i); };",
+                "collection of length 11 exceeds the limit of 10",
+            ),
+        ] {
+            let source = format!("try {{ {} }} catch e {{ e.message }};", source);
+            let value = vm.eval(&source).unwrap();
+            assert_eq!(message, vm.display(value));
+        }
+        // Every way of building a collection checks its length, including
+        // from collections made before the limit was set.
+        let mut vm = Vm::new();
+        vm.eval(
+            "let xs = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+            let m = map {};
+            for x in xs { m[x] = x; };
+            fn f(...r) { r }",
+        )
+        .unwrap();
+        vm.set_limits(limits);
+        for (source, len) in [
+            ("\"abcdefghijkl\".chars();", 12),
+            ("\"abcdefghijkl\".bytes();", 12),
+            ("\"a,\".repeat(12).split(\",\");", 13),
+            ("xs.zip(xs);", 12),
+            ("xs.map(|x| x);", 12),
+            ("xs.filter(|x| true);", 12),
+            ("xs.enumerate();", 12),
+            ("xs.chunks(1);", 12),
+            ("m.keys();", 12),
+            ("m.values();", 12),
+            ("m.entries();", 12),
+            ("f(1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12);", 12),
+            ("f(...xs);", 12),
+            ("[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];", 12),
+            ("(1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12);", 12),
+            (
+                "map { 1: 1, 2: 2, 3: 3, 4: 4, 5: 5, 6: 6, 7: 7, 8: 8, 9: 9, 10: 10, 11: 11 };",
+                11,
+            ),
+            ("set { 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11 };", 11),
+            (
+                "json.parse(\"[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]\");",
+                12,
+            ),
+        ] {
+            match vm.eval(source) {
+                Err(Error::ArrayTooLong { len: found, max }) => {
+                    assert_eq!((len, 10), (found, max), "{}", source)
+                }
+                result => panic!("{}: unexpected result {:?}", source, result.map(|_| ())),
+            }
+        }
+
+        let limits = Limits {
+            max_int_bits: Some(1000),
+            ..Limits::default()
+        };
+        let mut vm = Vm::new();
+        vm.set_limits(limits);
+        let value = vm
+            .eval("[((1 << 999) - 1 + (1 << 999)) >> 998, 1 ** 100000];")
+            .unwrap();
+        assert_eq!("[3, 1]", vm.display(value));
+        for source in [
+            "3 ** 1000000000;",
+            "(1 << 600) * (1 << 600);",
+            "1 << 1000;",
+            "(1 << 999) + (1 << 999);",
+        ] {
+            assert!(matches!(vm.eval(source), Err(Error::IntegerOverflow)));
+        }
+    }
+
+    #[test]
+    fn test_capabilities() {
+        assert_eq!(Capabilities::none(), Vm::new().capabilities());
+        let mut vm = Vm::new();
+        vm.set_capabilities(Capabilities::none().with(Capability::Time));
+        let value = vm.eval("time.now() > 0;").unwrap();
+        assert_eq!("true", vm.display(value));
+
+        for source in [
+            "fs.read(\"data.txt\");",
+            "try { fs.read(\"data.txt\") } catch e { null };",
+            "use env::get;",
+            "fn f() { || process.id() }",
+            "let r = random;",
+        ] {
+            match vm.eval(source) {
+                Err(err @ Error::CapabilityDenied(..)) => assert!(err
+                    .message(&vm.interner)
+                    .ends_with("capability, which is not granted")),
+                result => panic!("{} gave {:?}", source, result),
+            }
+        }
+        let err = vm.eval("\n  fs.read(\"data.txt\");").unwrap_err();
+        assert_eq!(
+            "`fs` needs the file I/O capability, which is not granted",
+            err.message(&vm.interner)
+        );
+        assert!(
+            matches!(err, Error::CapabilityDenied(_, Capability::FileIo, span) if span.start == 3)
+        );
+
+        // Names the script binds itself are not host modules.
+        let value = vm
+            .eval("fn f(fs) { fs.len() } let env = [1]; [f(\"ab\"), env];")
+            .unwrap();
+        assert_eq!("[2, [1]]", vm.display(value));
+
+        // Natives obtained while the capability was granted fail when called.
+        let mut vm = Vm::new();
+        vm.set_capabilities(Capabilities::all());
+        let id = vm.eval("process.id;").unwrap();
+        assert!(vm.call(id, &[]).is_ok());
+        vm.set_capabilities(Capabilities::none());
+        assert!(matches!(
+            vm.call(id, &[]),
+            Err(Error::CapabilityDenied(_, Capability::Process, _))
+        ));
+    }
+
+    #[test]
+    fn test_audit_hook() {
+        let calls = Rc::new(std::cell::RefCell::new(Vec::new()));
+        let mut vm = Vm::new();
+        let log = calls.clone();
+        vm.set_audit_hook(move |vm, call| {
+            let args: Vec<_> = call.args.iter().map(|&arg| vm.display(arg)).collect();
+            log.borrow_mut().push(format!(
+                "{}({}) in {} at {}:{}",
+                call.function,
+                args.join(", "),
+                call.caller.function,
+                call.caller.line,
+                call.caller.col
+            ));
+        });
+        vm.eval(
+            "fn f(x) { ok(x) }
+            f(1);
+            json.stringify([2, 3]);
+            [4].map(err).len();",
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                "ok(1) in f at 1:11",
+                "json.stringify([2, 3]) in <script> at 3:13",
+                "err(4) in <script> at 4:13",
+            ],
+            *calls.borrow()
+        );
+    }
+}